@@ -0,0 +1,136 @@
+//! POSIX access ACLs: permissions for named users and groups beyond the mode
+//! bits, which `fs::copy` drops. Read and written with the `acls` feature on
+//! Linux (through libacl); elsewhere every call fails with Unsupported.
+
+use std::io;
+use std::path::Path;
+
+/// ACL entries in `getfacl`'s text form with numeric ids ("user:1000:r--"),
+/// as stored in the sidecar. Empty when the ACL says no more than the mode bits.
+pub(crate) type Acl = Vec<String>;
+
+const READ: u32 = 4;
+const WRITE: u32 = 2;
+const EXECUTE: u32 = 1;
+
+/// The access ACL of `path`; empty if it only has the owner, group and other entries.
+#[cfg(all(target_os = "linux", feature = "acls"))]
+pub(crate) fn read(op: &'static str, path: &Path) -> io::Result<Acl> {
+    use crate::error::Context;
+    use posix_acl::{PosixACL, Qualifier};
+
+    let acl = PosixACL::read_acl(path).map_err(acl_error).at(op, "cannot read ACL of", path)?;
+    let entries = acl.entries();
+    if entries.iter().all(|e| matches!(e.qual, Qualifier::UserObj | Qualifier::GroupObj | Qualifier::Other)) {
+        return Ok(Acl::new());
+    }
+    Ok(entries
+        .iter()
+        .filter_map(|e| {
+            let (tag, id) = match e.qual {
+                Qualifier::UserObj => ("user", None),
+                Qualifier::User(uid) => ("user", Some(uid)),
+                Qualifier::GroupObj => ("group", None),
+                Qualifier::Group(gid) => ("group", Some(gid)),
+                Qualifier::Mask => ("mask", None),
+                Qualifier::Other => ("other", None),
+                Qualifier::Undefined => return None,
+            };
+            Some(format_entry(tag, id, e.perm))
+        })
+        .collect())
+}
+
+/// Replace the access ACL of `path` with `acl`. libacl recomputes the mask
+/// on writing, so entries it limits are cut down to it first: what anyone
+/// may effectively do is as recorded.
+#[cfg(all(target_os = "linux", feature = "acls"))]
+pub(crate) fn apply(op: &'static str, acl: &Acl, path: &Path) -> io::Result<()> {
+    use crate::error::Context;
+    use posix_acl::{PosixACL, Qualifier};
+
+    let entries: Vec<(&str, Option<u32>, u32)> = acl.iter().map(|e| parse_entry(e)).collect::<io::Result<_>>()?;
+    let mask = entries.iter().find(|(tag, ..)| *tag == "mask").map(|(.., perm)| *perm);
+    let mut out = PosixACL::empty();
+    for (tag, id, perm) in entries {
+        let (qual, limited) = match (tag, id) {
+            ("user", None) => (Qualifier::UserObj, false),
+            ("user", Some(uid)) => (Qualifier::User(uid), true),
+            ("group", None) => (Qualifier::GroupObj, true),
+            ("group", Some(gid)) => (Qualifier::Group(gid), true),
+            ("other", None) => (Qualifier::Other, false),
+            _ => continue,
+        };
+        out.set(qual, if limited { perm & mask.unwrap_or(perm) } else { perm });
+    }
+    out.write_acl(path).map_err(acl_error).at(op, "cannot set ACL on", path)
+}
+
+#[cfg(all(target_os = "linux", feature = "acls"))]
+fn acl_error(e: posix_acl::ACLError) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "acls")))]
+pub(crate) fn read(_op: &'static str, _path: &Path) -> io::Result<Acl> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "acls")))]
+pub(crate) fn apply(_op: &'static str, _acl: &Acl, _path: &Path) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "acls")))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "ACLs need the `acls` feature on Linux")
+}
+
+/// "user:1000:r-x"; no id for the owner, owning group, mask and other entries.
+#[cfg_attr(not(all(target_os = "linux", feature = "acls")), allow(dead_code))]
+fn format_entry(tag: &str, id: Option<u32>, perm: u32) -> String {
+    let bit = |b: u32, c: char| if perm & b != 0 { c } else { '-' };
+    let id = id.map_or(String::new(), |id| id.to_string());
+    format!("{tag}:{id}:{}{}{}", bit(READ, 'r'), bit(WRITE, 'w'), bit(EXECUTE, 'x'))
+}
+
+/// The tag, id and permission bits of an entry written by [`format_entry`].
+#[cfg_attr(not(all(target_os = "linux", feature = "acls")), allow(dead_code))]
+fn parse_entry(entry: &str) -> io::Result<(&str, Option<u32>, u32)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("malformed ACL entry {entry:?}"));
+    let mut parts = entry.split(':');
+    let (Some(tag), Some(id), Some(perm), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let named = matches!(tag, "user" | "group");
+    if !matches!(tag, "user" | "group" | "mask" | "other") || (!named && !id.is_empty()) {
+        return Err(invalid());
+    }
+    let id = if id.is_empty() { None } else { Some(id.parse().map_err(|_| invalid())?) };
+    let bits = perm.as_bytes();
+    let bit = |i: usize, c: u8, b: u32| match bits.get(i) {
+        Some(&x) if x == c => Ok(b),
+        Some(b'-') => Ok(0),
+        _ => Err(invalid()),
+    };
+    if bits.len() != 3 {
+        return Err(invalid());
+    }
+    Ok((tag, id, bit(0, b'r', READ)? | bit(1, b'w', WRITE)? | bit(2, b'x', EXECUTE)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_round_trip_through_text() {
+        assert_eq!(format_entry("user", Some(1000), READ | EXECUTE), "user:1000:r-x");
+        assert_eq!(format_entry("mask", None, READ | WRITE), "mask::rw-");
+        assert_eq!(parse_entry("user:1000:r-x").unwrap(), ("user", Some(1000), READ | EXECUTE));
+        assert_eq!(parse_entry("other::---").unwrap(), ("other", None, 0));
+        for bad in ["user:1000", "user:x:r--", "mask:5:r--", "team::r--", "user::rw", "user::wr-", "user::r--:"] {
+            assert_eq!(parse_entry(bad).unwrap_err().kind(), io::ErrorKind::InvalidData, "{bad}");
+        }
+    }
+}
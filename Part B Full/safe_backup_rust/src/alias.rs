@@ -0,0 +1,327 @@
+//! Earlier names of renamed files ([`BackupManager::rename_tracked`]), kept in
+//! ".safe_backup.aliases.json" in the backup directory. The backups a file
+//! got under an earlier name stay its backups: listing, restoring and pruning
+//! it by its new name take them in, following renames back as far as they go.
+//! Each rename records when it happened: backups made under the old name
+//! after that belong to whatever file has that name now.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{invalid, no_backup, Context};
+use crate::format::{parse_versioned, unversioned, FORMAT_VERSION};
+use crate::meta::{read_meta, sidecar_for, store_meta, Layout};
+use crate::naming::ts_backup_for;
+use crate::{BackupManager, Window, ANY_TIME};
+
+/// File name of the aliases in the backup directory.
+const ALIAS_FILE: &str = ".safe_backup.aliases.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Aliases {
+    #[serde(default = "unversioned")]
+    format_version: u32,
+    /// Each name a file was renamed to, with the names it was renamed from.
+    earlier: BTreeMap<String, Vec<Rename>>,
+    /// Names a file was renamed from and that no file was renamed back to,
+    /// with when.
+    #[serde(default, deserialize_with = "away_from_list")]
+    renamed_away: BTreeMap<String, Since>,
+}
+
+/// A name a file was renamed from, and when.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Rename {
+    At { name: String, at: u64 },
+    /// From an aliases file that didn't record when: any time.
+    Undated(String),
+}
+
+impl Rename {
+    fn name(&self) -> &str {
+        match self {
+            Self::At { name, .. } | Self::Undated(name) => name,
+        }
+    }
+
+    /// The timestamps of backups under [`name`](Self::name) that were made before the rename.
+    fn before(&self) -> Window {
+        match self {
+            Self::At { at, .. } => 0..=*at,
+            Self::Undated(_) => ANY_TIME,
+        }
+    }
+}
+
+/// Names files were renamed away from, with when, where known.
+pub(crate) type RenamedAway = BTreeMap<String, Option<u64>>;
+
+/// When a name was renamed away from; `None` in aliases files that didn't record it.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct Since(Option<u64>);
+
+/// `renamed_away` as it was first written: a list of names, with no times.
+fn away_from_list<'de, D: serde::Deserializer<'de>>(d: D) -> Result<BTreeMap<String, Since>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Away {
+        Names(Vec<String>),
+        Dated(BTreeMap<String, Since>),
+    }
+    Ok(match Away::deserialize(d)? {
+        Away::Names(names) => names.into_iter().map(|n| (n, Since(None))).collect(),
+        Away::Dated(map) => map,
+    })
+}
+
+/// Outcome of [`BackupManager::rename_tracked`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenameReport {
+    /// Whether the file was there, and renamed.
+    pub renamed_file: bool,
+    /// Backups renamed to the new name, from where to where (`migrate_backups`).
+    pub migrated: Vec<(PathBuf, PathBuf)>,
+}
+
+impl BackupManager {
+    fn alias_path(&self, root: &Path) -> PathBuf {
+        self.backup_root(root).join(ALIAS_FILE)
+    }
+
+    fn read_aliases(&self, root: &Path) -> io::Result<Aliases> {
+        let path = self.alias_path(root);
+        match self.fs.read_to_string(&path) {
+            Ok(text) => parse_versioned("rename", "malformed aliases", &path, &text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Aliases::default()),
+            Err(e) => Err(e).at("rename", "cannot read aliases", &path),
+        }
+    }
+
+    /// The names the backups of the original `name` are under, each with
+    /// the timestamps that count: `name` itself; then the names it had
+    /// before, most recent first, up to when it was renamed from them. For
+    /// "c" renamed from "b", itself renamed from "a", that is "c", "b" then
+    /// "a". If a file was renamed away from `name`, only backups made after
+    /// that count.
+    pub(crate) fn backup_names(&self, root: &Path, name: &Path) -> io::Result<Vec<(PathBuf, Window)>> {
+        let aliases = self.read_aliases(root)?;
+        let renamed = !aliases.earlier.is_empty() || !aliases.renamed_away.is_empty();
+        let logical = self.logical_in(root, name).filter(|_| renamed);
+        let Some(logical) = logical else { return Ok(vec![(name.to_path_buf(), ANY_TIME)]) };
+        // Renamed away, the backups it had then, under any name, went with the file.
+        let since = match aliases.renamed_away.get(&logical) {
+            Some(Since(Some(at))) => at.saturating_add(1),
+            _ => 0,
+        };
+        let mut found = vec![(name.to_path_buf(), since..=u64::MAX)];
+        let mut seen = BTreeSet::from([logical.clone()]);
+        let mut next = VecDeque::from([(logical, u64::MAX)]);
+        while let Some((name, until)) = next.pop_front() {
+            for older in aliases.earlier.get(&name).into_iter().flatten().rev() {
+                let until = until.min(*older.before().end());
+                if until >= since && seen.insert(older.name().to_string()) {
+                    found.push((PathBuf::from(older.name()), since..=until));
+                    next.push_back((older.name().to_string(), until));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Names a file was renamed away from, with when, where known; and the
+    /// names files were renamed to.
+    pub(crate) fn renamed_names(&self, root: &Path) -> io::Result<(RenamedAway, BTreeSet<String>)> {
+        let aliases = self.read_aliases(root)?;
+        let away = aliases.renamed_away.into_iter().map(|(name, Since(at))| (name, at)).collect();
+        Ok((away, aliases.earlier.into_keys().collect()))
+    }
+
+    /// `name` as the log and sidecars record it, if it is a valid name in `root`.
+    fn logical_in(&self, root: &Path, name: &Path) -> Option<String> {
+        let path = self.resolve(root, name).ok()?;
+        Some(crate::naming::logical_name(path.strip_prefix(root).unwrap_or(&path)))
+    }
+
+    /// Rename the original `old` to `new`, if it is there, and record `old`
+    /// as an earlier name of `new`, so that `new`'s backups include those
+    /// made as `old` (and as whatever `old` was renamed from). With
+    /// `migrate_backups`, those backups are renamed too where that can be
+    /// done on its own: whole-file backups no delta builds on, with no
+    /// backup of `new` at their time already. Fails if `new` is there, or
+    /// if `old` has neither a file nor a backup.
+    pub fn rename_tracked(
+        &self,
+        old: impl AsRef<Path>,
+        new: impl AsRef<Path>,
+        migrate_backups: bool,
+    ) -> io::Result<RenameReport> {
+        let root = self.root()?;
+        let (old_path, old_name) = self.validate_path_parts(old)?;
+        let (new_path, new_name) = self.validate_path_parts(new.as_ref())?;
+        if old_name == new_name {
+            return Err(invalid(new.as_ref(), "is the name it already has"));
+        }
+        if self.fs.exists(&new_path) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists)).at("rename", "already exists:", &new_path);
+        }
+        // Read first: aliases this version can't read must stop the rename before the file moves.
+        let mut aliases = self.read_aliases(&root)?;
+        let renamed_file = self.fs.exists(&old_path);
+        if !renamed_file && self.list_backups(&old_name)?.is_empty() {
+            return Err(no_backup("rename", "no file or backup found for", &old_path));
+        }
+        if renamed_file {
+            if let Some(dir) = new_path.parent() {
+                self.create_dirs("rename", dir, "cannot create directory")?;
+            }
+            self.fs.rename(&old_path, &new_path).copying("rename", &old_path, &new_path)?;
+        }
+
+        aliases.format_version = FORMAT_VERSION;
+        let at = self.now();
+        let earlier = aliases.earlier.entry(new_name.clone()).or_default();
+        earlier.retain(|n| n.name() != old_name);
+        earlier.push(Rename::At { name: old_name.clone(), at });
+        aliases.renamed_away.insert(old_name.clone(), Since(Some(at)));
+        aliases.renamed_away.remove(&new_name);
+        let dir = self.ensure_backup_root("rename", &root)?;
+        let path = self.alias_path(&root);
+        let tmp = crate::vfs::unique_temp(&dir.join(ALIAS_FILE));
+        serde_json::to_string(&aliases)
+            .map_err(io::Error::other)
+            .and_then(|json| self.fs.write(&tmp, json.as_bytes()))
+            .at("rename", "cannot write", &tmp)
+            .and_then(|_| self.fs.rename(&tmp, &path).copying("rename", &tmp, &path))
+            .inspect_err(|_| {
+                let _ = self.fs.remove_file(&tmp);
+            })?;
+
+        let migrated = if migrate_backups { self.migrate_backups(&new_path, &new_name)? } else { Vec::new() };
+        let result = match migrated.len() {
+            0 => format!("ok (now {new_name})"),
+            n => format!("ok (now {new_name}, {n} backup(s) renamed)"),
+        };
+        self.log_action(&root, "rename", Path::new(&old_name), &result)?;
+        Ok(RenameReport { renamed_file, migrated })
+    }
+
+    /// Rename the backups `new_name` has under earlier names to its own name.
+    fn migrate_backups(&self, new_path: &Path, new_name: &str) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        let entries = self.list_backups(new_name)?;
+        let metas: Vec<_> = entries.iter().map(|e| read_meta(&*self.fs, &e.path).ok()).collect();
+        let bases: BTreeSet<String> = metas.iter().flatten().filter_map(|m| m.base.clone()).collect();
+        let mut migrated = Vec::new();
+        for (entry, meta) in entries.iter().zip(metas) {
+            let Some(mut meta) = meta.filter(|m| m.layout == Layout::Whole) else { continue };
+            let from = &entry.path;
+            let built_on = from.file_name().and_then(|f| f.to_str()).is_some_and(|f| bases.contains(f));
+            if entry.renamed_from.is_none() || built_on {
+                continue;
+            }
+            let to = ts_backup_for(from.parent().unwrap_or(Path::new("")), Path::new(new_name), entry.ts)?;
+            if self.fs.exists(&to) {
+                continue;
+            }
+            self.fs.rename(from, &to).copying("rename", from, &to)?;
+            let (old_sidecar, new_sidecar) = (sidecar_for(from), sidecar_for(&to));
+            self.fs.rename(&old_sidecar, &new_sidecar).copying("rename", &old_sidecar, &new_sidecar)?;
+            meta.original = new_name.to_string();
+            meta.path = new_path.to_string_lossy().into_owned();
+            store_meta(&*self.fs, "rename", &to, &meta)?;
+            migrated.push((from.clone(), to));
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use std::fs;
+
+    #[test]
+    fn backups_follow_a_file_through_renames() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("draft.md"), "v1").unwrap();
+        manager(root, 100).backup_file("draft.md").unwrap();
+        let mgr = manager(root, 200);
+        let report = mgr.rename_tracked("draft.md", "final.md", false).unwrap();
+        assert!(report.renamed_file && report.migrated.is_empty());
+        assert!(!root.join("draft.md").exists());
+        fs::write(root.join("final.md"), "v2").unwrap();
+        mgr.backup_file("final.md").unwrap();
+        // And on again: the chain resolves all the way back.
+        manager(root, 300).rename_tracked("final.md", "published.md", false).unwrap();
+        fs::write(root.join("published.md"), "v3").unwrap();
+
+        let mgr = manager(root, 400);
+        let list = mgr.list_backups("published.md").unwrap();
+        let ts: Vec<u64> = list.iter().map(|e| e.ts).collect();
+        assert_eq!(ts, [200, 100]);
+        let from: Vec<Option<&str>> = list.iter().map(|e| e.renamed_from.as_deref()).collect();
+        assert_eq!(from, [Some("final.md"), Some("draft.md")]);
+        assert_eq!(mgr.tracked_originals().unwrap(), ["published.md"]);
+        // Restoring by the new name writes the new name.
+        let report = mgr.restore_file_with("published.md", &crate::RestoreOptions::new().previous(1)).unwrap();
+        assert_eq!(report.path, root.join("published.md"));
+        assert_eq!(fs::read_to_string(root.join("published.md")).unwrap(), "v1");
+        assert!(!root.join("draft.md").exists() && !root.join("final.md").exists());
+
+        let e = mgr.rename_tracked("nothing.md", "x.md", false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        fs::write(root.join("other.md"), "").unwrap();
+        let e = mgr.rename_tracked("published.md", "other.md", false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn a_new_file_under_an_old_name_keeps_its_own_backups() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("draft.md"), "old draft").unwrap();
+        manager(root, 100).backup_file("draft.md").unwrap();
+        manager(root, 200).rename_tracked("draft.md", "final.md", false).unwrap();
+        fs::write(root.join("draft.md"), "new draft").unwrap();
+        manager(root, 300).backup_file("draft.md").unwrap();
+
+        let mgr = manager(root, 400);
+        let ts = |name| mgr.list_backups(name).unwrap().iter().map(|e| e.ts).collect::<Vec<_>>();
+        assert_eq!((ts("final.md"), ts("draft.md")), (vec![100], vec![300]));
+        assert_eq!(mgr.tracked_originals().unwrap(), ["draft.md", "final.md"]);
+        mgr.restore_file("final.md").unwrap();
+        assert_eq!(fs::read_to_string(root.join("final.md")).unwrap(), "old draft");
+        assert_eq!(mgr.prune_backups("final.md", 0, false).unwrap().len(), 1);
+        assert!(root.join("draft.md.300.bak").exists());
+
+        // Aliases written before renames were dated still read, counting every backup as before.
+        let undated = r#"{"earlier":{"final.md":["draft.md"]},"renamed_away":["draft.md"]}"#;
+        fs::write(root.join(ALIAS_FILE), undated).unwrap();
+        assert_eq!(ts("final.md"), [300]);
+        assert_eq!(mgr.tracked_originals().unwrap(), ["final.md"]);
+    }
+
+    #[test]
+    fn migrating_renames_the_backups_themselves() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "one").unwrap();
+        manager(root, 100).backup_file("a.txt").unwrap();
+        let mgr = manager(root, 200);
+        let report = mgr.rename_tracked("a.txt", "b.txt", true).unwrap();
+        assert_eq!(report.migrated, [(root.join("a.txt.100.bak"), root.join("b.txt.100.bak"))]);
+        assert!(!root.join("a.txt.100.bak.meta.json").exists());
+        let list = mgr.list_backups("b.txt").unwrap();
+        assert_eq!((list.len(), list[0].renamed_from.as_deref()), (1, None));
+        assert_eq!(crate::read_backup_meta(&list[0].path).unwrap().original, "b.txt");
+        mgr.verify_backup("b.txt.100.bak").unwrap();
+        let last = mgr.read_log().unwrap().pop().unwrap();
+        assert_eq!((last.action.as_str(), last.result.as_str()), ("rename", "ok (now b.txt, 1 backup(s) renamed)"));
+    }
+}
@@ -0,0 +1,184 @@
+//! Guarding the log against being cut short (`Config::log_audit`). After
+//! each write the log's length and the hash of its last line are recorded
+//! in ".safe_backup/log_audit"; a log found shorter than that, or with
+//! another line there, is not written to again. Together with the hash
+//! chain, which catches entries altered in between, this covers edits that
+//! remove entries from the end. Someone who can rewrite the record as well
+//! isn't stopped: it resists casual tampering, nothing more.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BackupError, Context};
+use crate::log::line_hash;
+use crate::tidy::TIDY_DIR;
+use crate::BackupManager;
+
+/// What was recorded after the last write to the log.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditRecord {
+    /// File name of the log it is about; a record for another log is ignored.
+    log: String,
+    /// Length of the log.
+    offset: u64,
+    /// Hash of the line that ended at `offset`.
+    last_hash: String,
+}
+
+fn record_path(root: &Path) -> PathBuf {
+    root.join(TIDY_DIR).join("log_audit")
+}
+
+fn log_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+impl BackupManager {
+    /// With `Config::log_audit`, check the log at `path`, holding `text`,
+    /// against the record: `BackupError::LogTruncated` if it is shorter,
+    /// `BackupError::LogTampered` if the line recorded last differs.
+    pub(crate) fn check_audit(&self, root: &Path, path: &Path, text: &str) -> io::Result<()> {
+        if !self.config.log_audit {
+            return Ok(());
+        }
+        let record = record_path(root);
+        let record: AuditRecord = match self.fs.read_to_string(&record) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                .at("log", "malformed log audit record", &record)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).at("log", "cannot read log audit record", &record),
+        };
+        if record.log != log_name(path) {
+            return Ok(());
+        }
+        let found = text.len() as u64;
+        if found < record.offset {
+            return Err(BackupError::LogTruncated { path: path.to_path_buf(), recorded: record.offset, found }.into());
+        }
+        let head = usize::try_from(record.offset).ok().and_then(|n| text.get(..n)).unwrap_or_default();
+        let last = head.strip_suffix('\n').unwrap_or(head).rsplit('\n').next().unwrap_or_default();
+        if line_hash(last) != record.last_hash {
+            let line = head.lines().count();
+            let reason = "the last entry recorded was altered";
+            return Err(BackupError::LogTampered { path: path.to_path_buf(), line, reason }.into());
+        }
+        Ok(())
+    }
+
+    /// With `Config::log_audit`, record the log at `path`, which held
+    /// `before`, as having had `written` appended; on Linux, with
+    /// `Config::log_append_only`, also try to make a new log append-only.
+    pub(crate) fn record_audit(&self, root: &Path, path: &Path, before: &str, written: &str) -> io::Result<()> {
+        if !self.config.log_audit {
+            return Ok(());
+        }
+        let offset = (before.len() + written.len()) as u64;
+        let last_hash = line_hash(written.lines().last().unwrap_or_default());
+        let record = AuditRecord { log: log_name(path), offset, last_hash };
+        let json = serde_json::to_string(&record).map_err(io::Error::other);
+        let dir = root.join(TIDY_DIR);
+        self.create_dirs("log", &dir, "cannot create directory")?;
+        let file = record_path(root);
+        let tmp = crate::vfs::unique_temp(&file);
+        json.and_then(|json| self.fs.write(&tmp, json.as_bytes()))
+            .at("log", "cannot write", &tmp)
+            .and_then(|_| self.fs.rename(&tmp, &file).copying("log", &tmp, &file))
+            .inspect_err(|_| {
+                let _ = self.fs.remove_file(&tmp);
+            })?;
+        if self.config.log_append_only && before.is_empty() {
+            match append_only(path) {
+                // Without the capability, or on a file system without the attribute, the log stays as it is.
+                Err(e) if !matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported) => {
+                    return Err(e).at("log", "cannot make log append-only", path);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the append-only attribute on `path`, as `chattr +a` does. Fails with
+/// PermissionDenied without `CAP_LINUX_IMMUTABLE` and Unsupported on a file
+/// system that has no such attribute.
+#[cfg(target_os = "linux")]
+fn append_only(path: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    const FS_APPEND_FL: libc::c_int = 0x20;
+    let fd = std::fs::File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    // SAFETY: both calls read or write the one int `flags` points to, on a descriptor `fd` keeps open.
+    let set = unsafe {
+        libc::ioctl(fd.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) == 0 && {
+            flags |= FS_APPEND_FL;
+            libc::ioctl(fd.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) == 0
+        }
+    };
+    if set {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => Err(io::Error::new(io::ErrorKind::Unsupported, e)),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn append_only(_: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "append-only files need Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::Config;
+    use std::fs;
+
+    fn manager(root: &Path) -> BackupManager {
+        BackupManager::new(Config { log_audit: true, log_max_bytes: Some(1), ..config(root, 100) })
+    }
+
+    #[test]
+    fn a_log_cut_short_is_refused_and_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = manager(root);
+        for f in ["a", "b", "c"] {
+            mgr.log_action(root, "backup", Path::new(f), "ok").unwrap();
+        }
+        // Rotation is off while auditing.
+        let log = root.join("logfile.txt");
+        assert!(!root.join("logfile.txt.1.gz").exists());
+        assert_eq!(crate::read_log(root).unwrap().len(), 3);
+        assert_eq!(mgr.verify_log_chain().unwrap(), 3);
+
+        // Dropping the last entry leaves a valid chain, but not the recorded length.
+        let text = fs::read_to_string(&log).unwrap();
+        let cut: String = text.lines().take(2).map(|l| format!("{l}\n")).collect();
+        fs::write(&log, &cut).unwrap();
+        let e = mgr.verify_log_chain().unwrap_err();
+        let Some(BackupError::LogTruncated { recorded, found, .. }) = BackupError::from_io(&e) else { panic!("{e}") };
+        assert_eq!((*recorded, *found), (text.len() as u64, cut.len() as u64));
+        let e = mgr.log_action(root, "backup", Path::new("d"), "ok").unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::LogTruncated { .. })), "{e}");
+        assert_eq!(fs::read_to_string(&log).unwrap(), cut);
+
+        // Growing it back to length with another last entry is caught too.
+        let forged = format!("{cut}{}\n", text.lines().nth(2).unwrap().replace("\"c\"", "\"x\""));
+        fs::write(&log, forged).unwrap();
+        let e = mgr.verify_log_chain().unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::LogTampered { line: 3, .. })), "{e}");
+
+        // Without auditing the record isn't checked; with it, repairing is refused.
+        fs::write(&log, &cut).unwrap();
+        let off = BackupManager::new(Config { log_audit: false, ..mgr.config().clone() });
+        assert_eq!(off.verify_log_chain().unwrap(), 2);
+        assert!(mgr.repair_log().is_err());
+    }
+}
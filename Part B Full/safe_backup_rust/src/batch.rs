@@ -0,0 +1,586 @@
+//! Operations over every original the tool knows about, or over many files
+//! at once, and the [`BatchReport`] they give.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+use crate::cache::{DigestCache, CACHE_FILE};
+use crate::cancel::is_cancelled;
+use crate::dedup;
+use crate::error::{error_chain, error_code, BackupError, Context};
+use crate::meta::is_tool_backup;
+use crate::naming::{display_name, split_backup_name};
+use crate::versions::stored_size;
+use crate::{
+    BackupManager, BackupOptions, BackupOutcome, BackupReport, ConflictStrategy, RestoreOptions, RestoreOutcome,
+    RestoreReport,
+};
+
+/// How far back [`RepoStats::recent`] looks, in seconds.
+pub const RECENT_WINDOW: u64 = 7 * 24 * 60 * 60;
+
+/// How many sets [`RepoStats::largest`] keeps.
+const LARGEST_SETS: usize = 5;
+
+/// Aggregate numbers over every timestamped file backup in the backup directory.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RepoStats {
+    /// Distinct originals with at least one backup.
+    pub originals: usize,
+    pub backups: usize,
+    /// Bytes on disk, as stored (after compression), counting every piece of
+    /// chunked backups and the whole dedup object store.
+    pub bytes: u64,
+    /// Timestamps of the oldest and newest backups; `None` without backups.
+    pub oldest: Option<u64>,
+    pub newest: Option<u64>,
+    /// Successful "backup" log entries within [`RECENT_WINDOW`] of now; 0 without a log.
+    pub recent: usize,
+    /// The biggest backup sets by bytes on disk, largest first, at most five.
+    pub largest: Vec<BackupSet>,
+}
+
+/// All the backups of one original.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackupSet {
+    pub name: String,
+    pub backups: usize,
+    pub bytes: u64,
+}
+
+impl RepoStats {
+    /// Backups per original; 0 without any.
+    pub fn average_per_original(&self) -> f64 {
+        if self.originals == 0 { 0.0 } else { self.backups as f64 / self.originals as f64 }
+    }
+}
+
+/// How one file of a batch went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Ok,
+    /// Nothing to do, or not done for now: unchanged, missing, too recent, left in place.
+    Skipped,
+    Failed,
+}
+
+impl fmt::Display for BatchStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ok => "ok",
+            Self::Skipped => "skipped",
+            Self::Failed => "FAILED",
+        })
+    }
+}
+
+/// One file of a [`BatchReport`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BatchEntry {
+    pub name: String,
+    pub status: BatchStatus,
+    /// What was written, why nothing was, or the error chain.
+    pub detail: String,
+    /// For a failure, its [`error_code`].
+    pub code: Option<&'static str>,
+    /// Bytes read or written for it.
+    pub bytes: u64,
+}
+
+impl BatchEntry {
+    pub(crate) fn ok(name: String, detail: String, bytes: u64) -> Self {
+        Self { name, status: BatchStatus::Ok, detail, code: None, bytes }
+    }
+
+    pub(crate) fn skipped(name: String, detail: String) -> Self {
+        Self { name, status: BatchStatus::Skipped, detail, code: None, bytes: 0 }
+    }
+
+    pub(crate) fn failed(name: String, e: &io::Error) -> Self {
+        Self { name, status: BatchStatus::Failed, detail: error_chain(e), code: Some(error_code(e)), bytes: 0 }
+    }
+}
+
+/// What an operation over many files did, file by file, with totals: the
+/// result of [`backup_many`](BackupManager::backup_many) and
+/// [`restore_all_report`](BackupManager::restore_all_report), and part of
+/// the reports of scripts, scheduled cycles and directory backups. Displays as a
+/// tab-separated line per file then the [`totals`](Self::totals); serializes
+/// with the totals alongside the entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchReport {
+    pub entries: Vec<BatchEntry>,
+    pub elapsed: Duration,
+}
+
+impl BatchReport {
+    fn count(&self, status: BatchStatus) -> usize {
+        self.entries.iter().filter(|e| e.status == status).count()
+    }
+
+    pub fn succeeded(&self) -> usize {
+        self.count(BatchStatus::Ok)
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(BatchStatus::Skipped)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(BatchStatus::Failed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.entries.iter().map(|e| e.bytes).sum()
+    }
+
+    /// Whether no entry failed.
+    pub fn all_ok(&self) -> bool {
+        self.failed() == 0
+    }
+
+    /// "N succeeded, M skipped, K failed, B bytes in 0.25s".
+    pub fn totals(&self) -> String {
+        format!(
+            "{} succeeded, {} skipped, {} failed, {} bytes in {:.2}s",
+            self.succeeded(),
+            self.skipped(),
+            self.failed(),
+            self.bytes(),
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for e in &self.entries {
+            writeln!(f, "{}\t{}\t{}", e.status, e.name, e.detail)?;
+        }
+        writeln!(f, "{}", self.totals())
+    }
+}
+
+impl Serialize for BatchReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("BatchReport", 6)?;
+        s.serialize_field("entries", &self.entries)?;
+        s.serialize_field("succeeded", &self.succeeded())?;
+        s.serialize_field("skipped", &self.skipped())?;
+        s.serialize_field("failed", &self.failed())?;
+        s.serialize_field("bytes", &self.bytes())?;
+        s.serialize_field("elapsed_ms", &(self.elapsed.as_millis() as u64))?;
+        s.end()
+    }
+}
+
+impl BackupManager {
+    /// Totals over the marked "<name>.<ts>.bak" files in the backup directory,
+    /// from a single scan of it, plus recent activity from the log. Plain
+    /// "<stem>.bak" copies and directory backups don't count. A missing log or
+    /// backup directory gives zeros.
+    pub fn stats(&self) -> io::Result<RepoStats> {
+        let root = self.root()?;
+        let broot = self.backup_root(&root);
+        let cutoff = self.now().saturating_sub(RECENT_WINDOW);
+        let recent = self
+            .read_log()?
+            .iter()
+            .filter(|e| e.action == "backup" && (e.result == "ok" || e.result == "auto") && e.ts >= cutoff)
+            .count();
+        let mut stats = RepoStats { recent, ..RepoStats::default() };
+        let fs = &*self.fs;
+        if !fs.metadata(&broot).is_ok_and(|m| m.is_dir()) {
+            return Ok(stats);
+        }
+        let mut sets: BTreeMap<OsString, BackupSet> = BTreeMap::new();
+        for fname in fs.read_dir_names(&broot).at("stats", "cannot list directory", &broot)? {
+            let fname = fname.at("stats", "cannot list directory", &broot)?;
+            let Some((logical, Some(ts))) = split_backup_name(&fname) else { continue };
+            let path = broot.join(&fname);
+            let md = fs.symlink_metadata(&path).at("stats", "cannot stat", &path)?;
+            if !md.is_file() || !is_tool_backup(fs, &path) {
+                continue;
+            }
+            let size = stored_size(fs, "stats", &path)?;
+            let set = sets.entry(logical.to_os_string()).or_insert_with(|| BackupSet {
+                name: display_name(logical).into_owned(),
+                backups: 0,
+                bytes: 0,
+            });
+            set.backups += 1;
+            set.bytes += size;
+            stats.backups += 1;
+            stats.bytes += size;
+            stats.oldest = Some(stats.oldest.map_or(ts, |t| t.min(ts)));
+            stats.newest = Some(stats.newest.map_or(ts, |t| t.max(ts)));
+        }
+        stats.originals = sets.len();
+        stats.bytes += dedup::store_size(&broot)?;
+        let mut largest: Vec<BackupSet> = sets.into_values().collect();
+        largest.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+        largest.truncate(LARGEST_SETS);
+        stats.largest = largest;
+        Ok(stats)
+    }
+
+    /// Originals that have been backed up: names from "backup" log entries plus
+    /// those inferred from marked "<name>.<ts>.bak" files in the root. A file
+    /// renamed since counts under its new name ([`rename_tracked`](Self::rename_tracked)).
+    /// Sorted, no duplicates.
+    pub fn tracked_originals(&self) -> io::Result<Vec<OsString>> {
+        let root = self.root()?;
+        let mut names: BTreeMap<String, OsString> = BTreeMap::new();
+        for e in self.read_log()? {
+            if e.action == "backup" && (e.result == "ok" || e.result == "auto") {
+                names.entry(e.file.clone()).or_insert_with(|| e.file.into());
+            }
+        }
+        let broot = self.backup_root(&root);
+        if self.fs.metadata(&broot).is_ok_and(|m| m.is_dir()) {
+            for fname in self.fs.read_dir_names(&broot).at("restore_all", "cannot list directory", &broot)? {
+                let fname = fname.at("restore_all", "cannot list directory", &broot)?;
+                if let Some((logical, Some(_))) = split_backup_name(&fname) {
+                    let path = broot.join(&fname);
+                    if self.fs.symlink_metadata(&path).is_ok_and(|m| m.is_file()) && is_tool_backup(&*self.fs, &path) {
+                        names.insert(display_name(logical).into_owned(), logical.to_os_string());
+                    }
+                }
+            }
+        }
+        // A name renamed away from counts again once a new file of that name has a backup.
+        let (away, current) = self.renamed_names(&root)?;
+        let mut kept = BTreeMap::new();
+        for (name, logical) in names {
+            let counts = match away.get(&name) {
+                None => true,
+                Some(Some(_)) => !self.list_backups(&name)?.is_empty(),
+                Some(None) => false,
+            };
+            if counts {
+                kept.insert(name, logical);
+            }
+        }
+        let mut names = kept;
+        for name in current.into_iter().filter(|n| !away.contains_key(n)) {
+            if !names.contains_key(&name) && !self.list_backups(&name)?.is_empty() {
+                names.insert(name.clone(), name.into());
+            }
+        }
+        Ok(names.into_values().collect())
+    }
+
+    /// Restore the latest backup of every tracked original, overwriting the
+    /// working copies. One `(name, result)` per original; failures don't stop the rest.
+    pub fn restore_all(&self) -> io::Result<Vec<(String, io::Result<PathBuf>)>> {
+        let all = self.restore_all_with(ConflictStrategy::Overwrite)?;
+        Ok(all.into_iter().map(|(name, result)| (name, result.map(|r| r.path))).collect())
+    }
+
+    /// [`restore_all`](Self::restore_all), treating each original that is
+    /// there with `strategy`; each report says what was done.
+    pub fn restore_all_with(&self, strategy: ConflictStrategy) -> io::Result<Vec<(String, io::Result<RestoreReport>)>> {
+        let opts = RestoreOptions::new().on_conflict(strategy);
+        Ok(self
+            .tracked_originals()?
+            .into_iter()
+            .map(|name| (display_name(&name).into_owned(), self.restore_file_with(&name, &opts)))
+            .collect())
+    }
+
+    /// [`restore_all_with`](Self::restore_all_with) as a [`BatchReport`]: a
+    /// file left in place counts as skipped.
+    pub fn restore_all_report(&self, strategy: ConflictStrategy) -> io::Result<BatchReport> {
+        let started = Instant::now();
+        let entries = self.restore_all_with(strategy)?;
+        let entries = entries.into_iter().map(|(name, result)| self.restore_entry(name, result.as_ref())).collect();
+        Ok(BatchReport { entries, elapsed: started.elapsed() })
+    }
+
+    /// The [`BatchReport`] entry for backing up `name`, which gave `result`.
+    /// A file unchanged, missing or too recent for `Config::min_age_secs` counts as skipped.
+    pub(crate) fn backup_entry(&self, name: String, result: Result<&BackupReport, &io::Error>) -> BatchEntry {
+        match result {
+            Ok(r) => match r.outcome {
+                BackupOutcome::Created => BatchEntry::ok(name, r.path.display().to_string(), r.bytes),
+                BackupOutcome::Unchanged => BatchEntry::skipped(name, format!("unchanged since {}", r.path.display())),
+                BackupOutcome::Skipped => BatchEntry::skipped(name, "does not exist".to_string()),
+            },
+            Err(e) if matches!(BackupError::from_io(e), Some(BackupError::TooRecent { .. })) => {
+                BatchEntry::skipped(name, error_chain(e))
+            }
+            Err(e) => BatchEntry::failed(name, e),
+        }
+    }
+
+    /// The [`BatchReport`] entry for restoring `name`, which gave `result`.
+    /// A file left in place counts as skipped.
+    pub(crate) fn restore_entry(&self, name: String, result: Result<&RestoreReport, &io::Error>) -> BatchEntry {
+        match result {
+            Ok(r) => {
+                let detail = format!("{} ({})", r.path.display(), r.outcome);
+                match r.outcome {
+                    RestoreOutcome::Skipped | RestoreOutcome::Unchanged => BatchEntry::skipped(name, detail),
+                    _ => BatchEntry::ok(name, detail, self.fs.metadata(&r.path).map_or(0, |m| m.len)),
+                }
+            }
+            Err(e) => BatchEntry::failed(name, e),
+        }
+    }
+
+    /// Back up each of `names` with `opts`, as a [`BatchReport`]. A file
+    /// unchanged or missing (with `if_changed` or `skip_missing`), or too
+    /// recent for `Config::min_age_secs`, counts as skipped. Failures don't
+    /// stop the rest; cancelling does, after recording the file it stopped.
+    pub fn backup_many(&self, names: &[PathBuf], opts: &BackupOptions) -> BatchReport {
+        let started = Instant::now();
+        let mut report = BatchReport::default();
+        for name in names {
+            let result = self.backup_file_with(name, opts);
+            report.entries.push(self.backup_entry(display_name(name.as_os_str()).into_owned(), result.as_ref()));
+            if result.as_ref().is_err_and(is_cancelled) {
+                break;
+            }
+        }
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    /// Verify every backup of every tracked original, as [`verify_backup`](Self::verify_backup)
+    /// does, newest first per original. One `(backup file name, result)` per
+    /// backup; failures don't stop the rest, but `Config::cancel` does, with
+    /// `BackupError::Cancelled`. The digest of each backup file is remembered
+    /// in the backup directory, and taken from there next time while the file
+    /// keeps its size and mtime (also after a cancelled sweep); pieces,
+    /// objects and delta chains are read every time.
+    /// [`clear_digest_cache`](Self::clear_digest_cache) makes the next sweep read everything.
+    pub fn verify_all(&self) -> io::Result<Vec<(String, io::Result<()>)>> {
+        let cache_path = self.backup_root(&self.root()?).join(CACHE_FILE);
+        let mut cache = DigestCache::load(&cache_path);
+        let mut results = Vec::new();
+        let mut cancelled = Ok(());
+        'sweep: for name in self.tracked_originals()? {
+            match self.list_backups(&name) {
+                Ok(backups) => {
+                    for b in backups {
+                        let result = self.cancel_for(None).check("verify", &b.path);
+                        let result = result.and_then(|_| self.verify_path(&b.path, Some(&mut cache)));
+                        if result.as_ref().is_err_and(is_cancelled) {
+                            cancelled = result;
+                            break 'sweep;
+                        }
+                        let fname = display_name(b.path.file_name().unwrap_or_default()).into_owned();
+                        results.push((fname, result));
+                    }
+                }
+                Err(e) => results.push((display_name(&name).into_owned(), Err(e))),
+            }
+        }
+        // A cache that can't be written only makes the next sweep slower.
+        if cache_path.parent().is_some_and(|d| d.is_dir()) {
+            let _ = cache.save("verify");
+        }
+        cancelled.map(|_| results)
+    }
+
+    /// Forget the digests [`verify_all`](Self::verify_all) remembers.
+    pub fn clear_digest_cache(&self) -> io::Result<()> {
+        let path = self.backup_root(&self.root()?).join(CACHE_FILE);
+        match self.fs.remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).at("verify", "cannot remove", &path),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use crate::{BackupError, Config};
+    use crate::vfs::RealFs;
+    use std::fs;
+
+    #[test]
+    fn restores_everything_and_reports_per_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = BackupManager::new(Config { root: Some(root.to_path_buf()), ..Config::default() });
+        fs::write(root.join("a.txt"), "a1").unwrap();
+        fs::write(root.join("b.txt"), "b1").unwrap();
+        mgr.backup_file("a.txt").unwrap();
+        mgr.backup_file("b.txt").unwrap();
+        // Only known from a stray backup file, not from the log.
+        fs::write(root.join("c.md.1700000000.bak"), "c1").unwrap();
+        let stray = root.join("c.md.1700000000.bak");
+        crate::meta::write_meta(&RealFs, "backup", &stray, "c.md".as_ref(), &stray, crate::Compression::None, crate::DigestAlgo::Sha256).unwrap();
+        // Looks like a backup, but this tool never made it.
+        fs::write(root.join("decoy.txt.1700000000.bak"), "d").unwrap();
+        // In the log, but its backups are gone.
+        fs::write(root.join("gone.txt"), "g").unwrap();
+        mgr.backup_file("gone.txt").unwrap();
+        for e in fs::read_dir(root).unwrap() {
+            let p = e.unwrap().path();
+            if p.file_name().unwrap().to_string_lossy().starts_with("gone") {
+                fs::remove_file(p).unwrap();
+            }
+        }
+        fs::write(root.join("a.txt"), "changed").unwrap();
+        fs::remove_file(root.join("b.txt")).unwrap();
+
+        let results = mgr.restore_all().unwrap();
+        let names: Vec<&str> = results.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b.txt", "c.md", "gone.txt"]);
+        assert!(results[..3].iter().all(|(_, r)| r.is_ok()));
+        assert_eq!(results[3].1.as_ref().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "a1");
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "b1");
+        assert_eq!(fs::read_to_string(root.join("c.md")).unwrap(), "c1");
+    }
+
+    #[test]
+    fn batches_report_each_file_and_the_totals() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = manager(root, 100);
+        fs::write(root.join("a.txt"), "aaa").unwrap();
+        fs::write(root.join("b.txt"), "bb").unwrap();
+        mgr.backup_file("b.txt").unwrap();
+        let names: Vec<PathBuf> = ["a.txt", "b.txt", "gone.txt"].iter().map(PathBuf::from).collect();
+
+        let report = mgr.backup_many(&names, &BackupOptions::new().if_changed(true));
+        let statuses: Vec<_> = report.entries.iter().map(|e| (e.name.as_str(), e.status)).collect();
+        assert_eq!(
+            statuses,
+            [("a.txt", BatchStatus::Ok), ("b.txt", BatchStatus::Skipped), ("gone.txt", BatchStatus::Failed)]
+        );
+        assert_eq!((report.succeeded(), report.skipped(), report.failed(), report.bytes()), (1, 1, 1, 3));
+        assert!(!report.all_ok());
+        assert_eq!(report.entries[2].code, Some("not_found"));
+        let shown = report.to_string();
+        let lines: Vec<&str> = shown.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("ok\ta.txt\t"), "{shown}");
+        assert!(lines[3].starts_with("1 succeeded, 1 skipped, 1 failed, 3 bytes in "), "{shown}");
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!((json["succeeded"].as_u64(), json["failed"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(json["entries"][1]["status"], "skipped");
+
+        // Files left in place count as skipped.
+        let report = mgr.restore_all_report(ConflictStrategy::Skip).unwrap();
+        assert_eq!((report.succeeded(), report.skipped()), (0, 2));
+        fs::remove_file(root.join("a.txt")).unwrap();
+        let report = mgr.restore_all_report(ConflictStrategy::Skip).unwrap();
+        assert_eq!((report.succeeded(), report.skipped(), report.bytes()), (1, 1, 3));
+        assert!(report.all_ok());
+    }
+
+    #[test]
+    fn repeated_sweeps_take_unchanged_digests_from_the_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let at = |ts| manager(root, ts);
+        fs::write(root.join("a.txt"), "aaaa").unwrap();
+        fs::write(root.join("b.txt"), "bbbb").unwrap();
+        for (ts, name) in [(100, "a.txt"), (200, "a.txt"), (100, "b.txt")] {
+            at(ts).backup_file(name).unwrap();
+        }
+        let mgr = at(300);
+        let results = mgr.verify_all().unwrap();
+        let names: Vec<&str> = results.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["a.txt.200.bak", "a.txt.100.bak", "b.txt.100.bak"]);
+        assert!(results.iter().all(|(_, r)| r.is_ok()));
+
+        // Rewritten behind the cache's back, same size and mtime: not read again.
+        let bak = root.join("a.txt.100.bak");
+        let mtime = fs::metadata(&bak).unwrap().modified().unwrap();
+        fs::write(&bak, "AAAA").unwrap();
+        fs::File::options().write(true).open(&bak).unwrap().set_modified(mtime).unwrap();
+        // A new mtime is noticed right away.
+        fs::write(root.join("b.txt.100.bak"), "BBBB").unwrap();
+        let failed = |results: Vec<(String, io::Result<()>)>| -> Vec<String> {
+            results.into_iter().filter(|(_, r)| r.is_err()).map(|(n, _)| n).collect()
+        };
+        assert_eq!(failed(mgr.verify_all().unwrap()), ["b.txt.100.bak"]);
+
+        mgr.clear_digest_cache().unwrap();
+        assert_eq!(failed(mgr.verify_all().unwrap()), ["a.txt.100.bak", "b.txt.100.bak"]);
+        mgr.clear_digest_cache().unwrap();
+        mgr.clear_digest_cache().unwrap();
+
+        let token = crate::CancellationToken::new();
+        token.cancel();
+        let config = Config { root: Some(root.to_path_buf()), cancel: Some(token), ..Config::default() };
+        let mgr = BackupManager::new(config);
+        let e = mgr.verify_all().unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::Cancelled { op: "verify", .. })), "{e}");
+    }
+
+    #[test]
+    fn stats_cover_marked_backups_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = BackupManager::new(Config { root: Some(root.to_path_buf()), ..Config::default() });
+        assert_eq!(mgr.stats().unwrap(), RepoStats::default());
+        assert_eq!(RepoStats::default().average_per_original(), 0.0);
+
+        fs::write(root.join("a.txt"), "aa").unwrap();
+        fs::write(root.join("b.txt"), "bbb").unwrap();
+        for (name, ts) in [("a.txt", 100), ("a.txt", 300), ("b.txt", 200)] {
+            let bak = root.join(format!("{name}.{ts}.bak"));
+            fs::copy(root.join(name), &bak).unwrap();
+            crate::meta::write_meta(&RealFs, "backup", &bak, name.as_ref(), &bak, crate::Compression::None, crate::DigestAlgo::Sha256).unwrap();
+        }
+        fs::write(root.join("decoy.txt.50.bak"), "d").unwrap();
+        fs::write(root.join("a.bak"), "aa").unwrap();
+
+        let stats = mgr.stats().unwrap();
+        assert_eq!(
+            stats,
+            RepoStats {
+                originals: 2,
+                backups: 3,
+                bytes: 7,
+                oldest: Some(100),
+                newest: Some(300),
+                recent: 0,
+                largest: vec![
+                    BackupSet { name: "a.txt".into(), backups: 2, bytes: 4 },
+                    BackupSet { name: "b.txt".into(), backups: 1, bytes: 3 },
+                ],
+            }
+        );
+        assert_eq!(stats.average_per_original(), 1.5);
+    }
+
+    #[test]
+    fn stats_count_recent_backups_from_the_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let at = |now: u64| {
+            manager(root, now)
+        };
+        let now = 1_700_000_000;
+        for (i, ts) in [now - RECENT_WINDOW - 1, now - RECENT_WINDOW, now - 60].into_iter().enumerate() {
+            let name = format!("f{i}.txt");
+            fs::write(root.join(&name), "x".repeat(i + 1)).unwrap();
+            at(ts).backup_file(&name).unwrap();
+        }
+        let stats = at(now).stats().unwrap();
+        assert_eq!((stats.backups, stats.recent), (3, 2));
+        let names: Vec<&str> = stats.largest.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["f2.txt", "f1.txt", "f0.txt"]);
+
+        // Without the log, only the directory scan is left.
+        fs::remove_file(root.join(crate::log::LOG_FILE)).unwrap();
+        assert_eq!(at(now).stats().unwrap().recent, 0);
+    }
+}
@@ -0,0 +1,98 @@
+//! Files locked by another program (Windows sharing/lock violations).
+
+use std::io;
+use std::path::Path;
+
+/// `ERROR_SHARING_VIOLATION`: another process opened the file without sharing.
+pub(crate) const ERROR_SHARING_VIOLATION: i32 = 32;
+/// `ERROR_LOCK_VIOLATION`: a byte range of the file is locked.
+pub(crate) const ERROR_LOCK_VIOLATION: i32 = 33;
+
+/// Whether a raw Windows error code means "file in use".
+pub(crate) fn is_busy_code(code: i32) -> bool {
+    code == ERROR_SHARING_VIOLATION || code == ERROR_LOCK_VIOLATION
+}
+
+/// Whether `e` is a sharing/lock violation. Always false off Windows.
+pub(crate) fn is_file_busy(e: &io::Error) -> bool {
+    cfg!(windows) && e.raw_os_error().is_some_and(is_busy_code)
+}
+
+/// Copy `from` to `to`, opening the source with FILE_SHARE_READ|FILE_SHARE_WRITE so a
+/// file another program holds open for writing (e.g. Excel) can still be snapshotted.
+#[cfg(windows)]
+pub(crate) fn shared_read_copy(from: &Path, to: &Path) -> io::Result<u64> {
+    use std::fs::{File, OpenOptions};
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_READ: u32 = 0x1;
+    const FILE_SHARE_WRITE: u32 = 0x2;
+    let mut src = OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+        .open(from)?;
+    let mut dst = File::create(to)?;
+    io::copy(&mut src, &mut dst)
+}
+
+/// Which side of a failed copy is the locked one: the source if it can't even be opened for reading.
+#[cfg(windows)]
+pub(crate) fn locked_side<'a>(from: &'a Path, to: &'a Path) -> &'a Path {
+    match std::fs::File::open(from) {
+        Err(e) if is_file_busy(&e) => from,
+        _ => to,
+    }
+}
+
+/// Off Windows there are no sharing modes; a plain copy.
+#[cfg(not(windows))]
+pub(crate) fn shared_read_copy(from: &Path, to: &Path) -> io::Result<u64> {
+    std::fs::copy(from, to)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn locked_side<'a>(from: &'a Path, _to: &'a Path) -> &'a Path {
+    from
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_codes() {
+        assert!(is_busy_code(32));
+        assert!(is_busy_code(33));
+        assert!(!is_busy_code(5)); // ERROR_ACCESS_DENIED
+        assert!(!is_busy_code(2)); // ERROR_FILE_NOT_FOUND
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn sharing_violation_maps_to_busy() {
+        assert!(is_file_busy(&io::Error::from_raw_os_error(ERROR_SHARING_VIOLATION)));
+        assert!(is_file_busy(&io::Error::from_raw_os_error(ERROR_LOCK_VIOLATION)));
+        assert!(!is_file_busy(&io::Error::from_raw_os_error(5)));
+        assert!(crate::is_transient(&io::Error::from_raw_os_error(ERROR_SHARING_VIOLATION)));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn raw_32_is_not_busy_off_windows() {
+        // EPIPE on Linux is 32; it must not be mistaken for a sharing violation.
+        assert!(!is_file_busy(&io::Error::from_raw_os_error(32)));
+    }
+
+    #[test]
+    fn busy_error_renders_hint() {
+        let e: io::Error = crate::BackupError::FileBusy {
+            op: "backup",
+            path: "C:\\Users\\me\\book.xlsx".into(),
+            source: io::Error::from(io::ErrorKind::ResourceBusy),
+        }
+        .into();
+        assert_eq!(e.kind(), io::ErrorKind::ResourceBusy);
+        let msg = e.to_string();
+        assert!(msg.contains("book.xlsx") && msg.contains("open in another program"), "{msg}");
+    }
+}
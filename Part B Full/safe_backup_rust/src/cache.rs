@@ -0,0 +1,147 @@
+//! Digests of backup files remembered between integrity sweeps, in
+//! ".safe_backup.digests.json" in the backup directory. An entry holds while
+//! the file keeps the size and mtime it had when hashed; the least recently
+//! used entries go once there are more than [`MAX_ENTRIES`]. A cache in a
+//! newer `format_version` is ignored like an unreadable one, and replaced.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::digest::{file_digest, DigestAlgo};
+use crate::error::Context;
+use crate::format::{parse_versioned, unversioned, FORMAT_VERSION};
+use crate::naming::display_name;
+
+/// File name of the cache in the backup directory.
+pub(crate) const CACHE_FILE: &str = ".safe_backup.digests.json";
+
+/// How many files the cache remembers at most.
+const MAX_ENTRIES: usize = 4096;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    mtime: u64,
+    mtime_nanos: u32,
+    /// Tagged digest, "sha256:<hex>" etc.
+    digest: String,
+    /// When it was last looked up, seconds since the epoch.
+    used: u64,
+}
+
+/// The cache file: the entries by path, beside its format version.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    #[serde(default = "unversioned")]
+    format_version: u32,
+    #[serde(flatten)]
+    entries: BTreeMap<String, Entry>,
+}
+
+/// The cache, loaded for one sweep and saved at its end.
+pub(crate) struct DigestCache {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl DigestCache {
+    /// The cache at `path`; empty if it doesn't exist or can't be read, as
+    /// losing it only costs time.
+    pub(crate) fn load(path: &Path) -> Self {
+        let text = fs::read_to_string(path).ok();
+        let stored = text.and_then(|t| parse_versioned::<Stored>("verify", "malformed cache", path, &t).ok());
+        let entries = stored.map(|s| s.entries).unwrap_or_default();
+        Self { path: path.to_path_buf(), entries }
+    }
+
+    /// The `algo` digest of `file`: remembered if it has the size and mtime
+    /// it had when last hashed with `algo`, else read and remembered. `now`
+    /// marks the entry as used.
+    pub(crate) fn digest(&mut self, file: &Path, algo: DigestAlgo, now: u64) -> io::Result<String> {
+        let md = fs::metadata(file)?;
+        let mtime = md.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let key = display_name(std::path::absolute(file)?.as_os_str()).into_owned();
+        if let Some(e) = self.entries.get_mut(&key) {
+            let same = e.size == md.len() && e.mtime == mtime.as_secs() && e.mtime_nanos == mtime.subsec_nanos();
+            if same && DigestAlgo::of(&e.digest).is_ok_and(|a| a == algo) {
+                e.used = now;
+                return Ok(e.digest.clone());
+            }
+        }
+        let digest = file_digest(file, algo)?;
+        let (mtime, mtime_nanos) = (mtime.as_secs(), mtime.subsec_nanos());
+        self.entries.insert(key, Entry { size: md.len(), mtime, mtime_nanos, digest: digest.clone(), used: now });
+        Ok(digest)
+    }
+
+    /// Write the cache back, keeping the [`MAX_ENTRIES`] most recently used;
+    /// of those used at the same time, the first by path.
+    pub(crate) fn save(mut self, op: &'static str) -> io::Result<()> {
+        if self.entries.len() > MAX_ENTRIES {
+            let mut order: Vec<(u64, String)> = self.entries.iter().map(|(k, e)| (e.used, k.clone())).collect();
+            order.sort_unstable_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+            for (_, key) in order.drain(MAX_ENTRIES..) {
+                self.entries.remove(&key);
+            }
+        }
+        let stored = Stored { format_version: FORMAT_VERSION, entries: self.entries };
+        let json = serde_json::to_string(&stored).map_err(io::Error::other).at(op, "cannot write", &self.path)?;
+        fs::write(&self.path, json).at(op, "cannot write", &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_hold_until_size_or_mtime_change() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.txt.1.bak");
+        let path = tmp.path().join(CACHE_FILE);
+        fs::write(&file, "one").unwrap();
+        let mtime = fs::metadata(&file).unwrap().modified().unwrap();
+        let set_mtime = |t| fs::File::options().write(true).open(&file).unwrap().set_modified(t).unwrap();
+        let mut cache = DigestCache::load(&path);
+        let one = cache.digest(&file, DigestAlgo::Sha256, 1).unwrap();
+        cache.save("verify").unwrap();
+
+        // Same size and mtime: the remembered digest, without reading the file.
+        fs::write(&file, "two").unwrap();
+        set_mtime(mtime);
+        let mut cache = DigestCache::load(&path);
+        assert_eq!(cache.digest(&file, DigestAlgo::Sha256, 2).unwrap(), one);
+        // Another algorithm, or a new mtime, are hashed again.
+        let two = file_digest(&file, DigestAlgo::Sha256).unwrap();
+        assert_eq!(cache.digest(&file, DigestAlgo::Sha1, 3).unwrap(), file_digest(&file, DigestAlgo::Sha1).unwrap());
+        set_mtime(mtime + std::time::Duration::from_secs(5));
+        assert_eq!(cache.digest(&file, DigestAlgo::Sha256, 4).unwrap(), two);
+
+        fs::write(&path, "not json").unwrap();
+        assert!(DigestCache::load(&path).entries.is_empty());
+    }
+
+    #[test]
+    fn saving_keeps_exactly_the_most_recently_used() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(CACHE_FILE);
+        let mut cache = DigestCache::load(&path);
+        let entry = |used| Entry { size: 1, mtime: 1, mtime_nanos: 0, digest: "sha256:00".into(), used };
+        // All but one used in the same second.
+        for i in 0..MAX_ENTRIES + 10 {
+            cache.entries.insert(format!("/b/{i:05}"), entry(5));
+        }
+        cache.entries.insert("/z".into(), entry(6));
+        cache.save("verify").unwrap();
+
+        let cache = DigestCache::load(&path);
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert!(cache.entries.contains_key("/z") && cache.entries.contains_key("/b/00000"));
+        assert!(!cache.entries.contains_key(&format!("/b/{:05}", MAX_ENTRIES - 1)));
+    }
+}
@@ -0,0 +1,172 @@
+//! Stopping long operations from outside: a [`CancellationToken`] set from
+//! another thread (a Ctrl-C handler, a window closing) is checked between
+//! files of a directory operation, between reads of a chunked backup and
+//! between backups and pieces when verifying. The step that puts a finished
+//! backup in place (its index, sidecar or rename) is never interrupted, so a
+//! cancelled operation leaves the backup either complete or gone.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use crate::error::BackupError;
+
+/// A shared flag asking operations to stop with `BackupError::Cancelled`.
+/// Clones share the flag, so one can be kept by the caller and another
+/// handed to `Config::cancel` or the per-call options.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every operation watching this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clear a cancellation, so the token can watch the next operation.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Two tokens are equal when they share the flag.
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancellationToken {}
+
+/// The deadline of an operation run under `Config::timeout`: a token the
+/// timer cancels, the bytes copied so far for the error saying how far it
+/// got, and whether the work is putting a result in place.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Timer {
+    pub(crate) token: CancellationToken,
+    pub(crate) copied: Arc<AtomicU64>,
+    phase: Arc<AtomicU8>,
+}
+
+/// [`Timer`] phases: working, between [`Cancel::commit`] and the end of
+/// what it guards, and given up on.
+const WORKING: u8 = 0;
+const COMMITTING: u8 = 1;
+const EXPIRED: u8 = 2;
+
+impl Timer {
+    /// Give up on the work, unless it is putting a result in place; once
+    /// this returns true, it never will.
+    pub(crate) fn expire(&self) -> bool {
+        self.phase.compare_exchange(WORKING, EXPIRED, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+}
+
+/// What stops one operation: `Config::cancel`, the token in its options or
+/// its timer, any of which stops it. Copies count their bytes into the timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cancel<'a> {
+    tokens: [Option<&'a CancellationToken>; 3],
+    copied: Option<&'a AtomicU64>,
+    phase: Option<&'a AtomicU8>,
+}
+
+impl<'a> Cancel<'a> {
+    pub(crate) fn new(
+        config: Option<&'a CancellationToken>,
+        per_call: Option<&'a CancellationToken>,
+        timer: Option<&'a Timer>,
+    ) -> Self {
+        Self {
+            tokens: [config, per_call, timer.map(|t| &t.token)],
+            copied: timer.map(|t| &*t.copied),
+            phase: timer.map(|t| &*t.phase),
+        }
+    }
+
+    /// Whether the operation is to stop.
+    pub fn is_set(&self) -> bool {
+        self.tokens.iter().flatten().any(|t| t.is_cancelled())
+    }
+
+    /// `BackupError::Cancelled` for `op` on `path` if set.
+    pub(crate) fn check(&self, op: &'static str, path: &Path) -> io::Result<()> {
+        match self.is_set() {
+            true => Err(BackupError::Cancelled { op, path: path.to_path_buf() }.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// The last check before `op` puts its result at `path` in place: while
+    /// the guard lives, a timer that runs out waits for it rather than
+    /// reporting a timeout for work that completes.
+    pub(crate) fn commit(&self, op: &'static str, path: &Path) -> io::Result<Committing<'a>> {
+        let guard = match self.phase {
+            Some(phase) if phase.compare_exchange(WORKING, COMMITTING, Ordering::SeqCst, Ordering::SeqCst).is_err() => {
+                return Err(BackupError::Cancelled { op, path: path.to_path_buf() }.into());
+            }
+            phase => Committing(phase),
+        };
+        self.check(op, path)?;
+        Ok(guard)
+    }
+
+    /// Note `n` more bytes copied.
+    pub(crate) fn count(&self, n: usize) {
+        if let Some(copied) = self.copied {
+            copied.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returned by [`Cancel::commit`]; the result is in place, or has failed, when dropped.
+#[must_use]
+pub(crate) struct Committing<'a>(Option<&'a AtomicU8>);
+
+impl Drop for Committing<'_> {
+    fn drop(&mut self) {
+        if let Some(phase) = self.0 {
+            let _ = phase.compare_exchange(COMMITTING, WORKING, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Whether `e` is `BackupError::Cancelled`.
+pub(crate) fn is_cancelled(e: &io::Error) -> bool {
+    matches!(BackupError::from_io(e), Some(BackupError::Cancelled { .. }))
+}
+
+/// `io::copy` from `from` to `to` that checks `cancel` before every read and
+/// stops with `BackupError::Cancelled` for `op` on `path`. (`io::copy` itself
+/// would retry, as the error has kind `Interrupted`.) Returns the bytes copied.
+pub(crate) fn copy(
+    cancel: Cancel<'_>,
+    op: &'static str,
+    path: &Path,
+    from: &mut dyn Read,
+    to: &mut dyn Write,
+) -> io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        cancel.check(op, path)?;
+        let n = match from.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        to.write_all(&buf[..n])?;
+        cancel.count(n);
+        copied += n as u64;
+    }
+}
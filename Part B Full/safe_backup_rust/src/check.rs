@@ -0,0 +1,232 @@
+//! Restore dry runs: find the backup a restore would use and make sure it is
+//! intact and readable, or that it won't overwrite newer work, without
+//! writing anything.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::chunk;
+use crate::dedup;
+use crate::delta;
+use crate::compress::{gunzip_to, Compression};
+use crate::digest::{file_digest, tagged, DigestAlgo};
+use crate::error::{error_chain, BackupError, Context};
+use crate::meta::Layout;
+use crate::naming::split_backup_name;
+use crate::options::RestoreOptions;
+use crate::{BackupManager, RestorePlan};
+
+/// Verdict of [`BackupManager::check_restore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckStatus {
+    /// The contents match the recorded digest and can be read back in full.
+    Intact,
+    /// The contents no longer match the recorded digest.
+    Corrupt,
+    /// Readable, but there is no recorded digest to compare with.
+    Unverified,
+    /// The digest matches, but decompressing the backup fails.
+    Unreadable { error: String },
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Intact => f.write_str("intact"),
+            Self::Corrupt => f.write_str("corrupt"),
+            Self::Unverified => f.write_str("unverified (no digest recorded)"),
+            Self::Unreadable { error } => write!(f, "unreadable: {error}"),
+        }
+    }
+}
+
+/// What a restore would do, and whether the backup it would read is sound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreCheck {
+    /// The backup the restore would read.
+    pub source: PathBuf,
+    /// The file the restore would write.
+    pub destination: PathBuf,
+    /// Digest recorded in the backup's sidecar, if any.
+    pub expected: Option<String>,
+    /// Digest of the backup as it is now, with the recorded algorithm.
+    pub actual: String,
+    pub status: CheckStatus,
+}
+
+impl RestoreCheck {
+    /// Whether restoring is safe: intact, or readable with nothing to compare.
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, CheckStatus::Intact | CheckStatus::Unverified)
+    }
+}
+
+/// A restore that would overwrite a file modified after its backup was made;
+/// see [`BackupManager::restore_conflict`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreConflict {
+    /// The file the restore would overwrite.
+    pub destination: PathBuf,
+    /// When it was last modified, in Unix seconds.
+    pub modified: u64,
+    /// The backup the restore would read.
+    pub source: PathBuf,
+    /// When that backup was made, in Unix seconds.
+    pub backed_up: u64,
+}
+
+impl BackupManager {
+    /// Whether [`restore_file_with`](Self::restore_file_with) would overwrite
+    /// a file modified after the backup it restores was made, which would lose
+    /// that later work. The backup's time is the one in its name, else its
+    /// mtime (a plain ".bak"); a file modified within the same second is not
+    /// newer. `None` when the destination is missing or older.
+    pub fn restore_conflict(
+        &self,
+        name: impl AsRef<Path>,
+        opts: &RestoreOptions,
+    ) -> io::Result<Option<RestoreConflict>> {
+        let RestorePlan { source, dest, .. } = self.restore_plan(name.as_ref(), opts)?;
+        let secs = |path: &Path| -> io::Result<Option<u64>> {
+            let stat = match self.fs.metadata(path) {
+                Ok(stat) => stat,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e).at("restore", "cannot stat", path),
+            };
+            Ok(stat.modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()))
+        };
+        let Some(modified) = secs(&dest)? else { return Ok(None) };
+        let named = source.file_name().and_then(split_backup_name).and_then(|(_, ts)| ts);
+        let Some(backed_up) = named.map_or_else(|| secs(&source), |ts| Ok(Some(ts)))? else { return Ok(None) };
+        Ok((modified > backed_up).then_some(RestoreConflict { destination: dest, modified, source, backed_up }))
+    }
+
+    /// Dry run of [`restore_file_with`](Self::restore_file_with): select the
+    /// backup the same way, check it against the digest in its sidecar and
+    /// read it in full (decompressing it if needed). Nothing is written, not
+    /// even the log. A backup that can't be found or opened is an error; one
+    /// that is damaged is reported in [`RestoreCheck::status`].
+    pub fn check_restore(&self, name: impl AsRef<Path>, opts: &RestoreOptions) -> io::Result<RestoreCheck> {
+        let RestorePlan { source, dest, compression, digest, layout, .. } = self.restore_plan(name.as_ref(), opts)?;
+        let expected = digest.as_deref().map(tagged);
+        let algo = match &expected {
+            Some(d) => DigestAlgo::of(d)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                .at("check", "malformed digest for", &source)?,
+            None => self.config.digest,
+        };
+        let actual = file_digest(&source, algo).at("check", "cannot read", &source)?;
+        // Reading a plain backup for its digest already read all of it. For a
+        // chunked or deduplicated one that was only the index or manifest,
+        // for a delta only its tail: check and read every piece or link.
+        let unreadable = match (layout, compression) {
+            (Layout::Deduped, _) => dedup::verify_deduped("check", &source).err(),
+            (Layout::Chunked, _) => chunk::read_to("check", &source, compression, &mut io::sink()).err(),
+            (Layout::Delta, _) => delta::read_to("check", &source, &mut io::sink()).err(),
+            (Layout::Whole, Compression::None) => None,
+            (Layout::Whole, Compression::Gzip) => gunzip_to("check", &source, &mut io::sink()).err(),
+        };
+        let damaged = matches!(unreadable.as_ref().and_then(BackupError::from_io), Some(BackupError::Corrupt { .. }));
+        let status = match (&expected, unreadable) {
+            (Some(e), _) if *e != actual => CheckStatus::Corrupt,
+            _ if damaged => CheckStatus::Corrupt,
+            (_, Some(e)) => CheckStatus::Unreadable { error: error_chain(&e) },
+            (Some(_), None) => CheckStatus::Intact,
+            (None, None) => CheckStatus::Unverified,
+        };
+        Ok(RestoreCheck { source, destination: dest, expected, actual, status })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::Config;
+    use std::fs;
+
+    fn manager(root: &Path, now: u64, compression: Compression) -> BackupManager {
+        BackupManager::new(Config { compression, ..config(root, now) })
+    }
+
+    #[test]
+    fn check_finds_corruption_without_touching_the_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("notes.txt"), "v1").unwrap();
+        let bak = manager(root, 100, Compression::None).backup_file("notes.txt").unwrap();
+        fs::write(root.join("notes.txt"), "live").unwrap();
+        let log_before = fs::read_to_string(root.join("logfile.txt")).unwrap();
+
+        let mgr = manager(root, 200, Compression::None);
+        let check = mgr.check_restore("notes.txt", &RestoreOptions::default()).unwrap();
+        assert_eq!((&check.source, &check.destination), (&bak, &root.join("notes.txt")));
+        assert_eq!(check.status, CheckStatus::Intact);
+        assert_eq!(check.expected.as_deref(), Some(check.actual.as_str()));
+        assert!(check.is_ok());
+
+        fs::write(&bak, "v2").unwrap();
+        let check = mgr.check_restore("notes.txt", &RestoreOptions::default()).unwrap();
+        assert_eq!(check.status, CheckStatus::Corrupt);
+        assert_ne!(check.expected.as_deref(), Some(check.actual.as_str()));
+        assert!(!check.is_ok());
+
+        assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "live");
+        assert_eq!(fs::read_to_string(root.join("logfile.txt")).unwrap(), log_before);
+    }
+
+    #[test]
+    fn check_reads_compressed_backups_in_full() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("data.csv"), "a,b\n1,2\n".repeat(100)).unwrap();
+        let mgr = manager(root, 100, Compression::Gzip);
+        let bak = mgr.backup_file("data.csv").unwrap();
+        let check = mgr.check_restore("data.csv", &RestoreOptions::new().version(100)).unwrap();
+        assert_eq!(check.status, CheckStatus::Intact);
+
+        // Damage that still matches the digest can only come from a bad sidecar,
+        // so rewrite both: a truncated stream is caught by reading it back.
+        let mut gz = fs::read(&bak).unwrap();
+        gz.truncate(gz.len() / 2);
+        fs::write(&bak, &gz).unwrap();
+        let mut meta = crate::read_backup_meta(&bak).unwrap();
+        meta.digest = Some(file_digest(&bak, DigestAlgo::Sha256).unwrap());
+        fs::write(crate::meta::sidecar_for(&bak), serde_json::to_string(&meta).unwrap()).unwrap();
+        let check = mgr.check_restore("data.csv", &RestoreOptions::default()).unwrap();
+        assert!(matches!(check.status, CheckStatus::Unreadable { .. }), "{:?}", check.status);
+
+        let e = mgr.check_restore("missing.txt", &RestoreOptions::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn newer_destination_is_a_conflict() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let notes = root.join("notes.txt");
+        let set_mtime = |t: u64| {
+            let mtime = UNIX_EPOCH + std::time::Duration::from_secs(t);
+            fs::File::options().write(true).open(&notes).unwrap().set_modified(mtime).unwrap();
+        };
+        fs::write(&notes, "v1").unwrap();
+        let bak = manager(root, 1_000, Compression::None).backup_file("notes.txt").unwrap();
+        let mgr = manager(root, 3_000, Compression::None);
+        let opts = RestoreOptions::default();
+
+        // Edited after the backup: restoring would lose the edit.
+        fs::write(&notes, "v2").unwrap();
+        set_mtime(2_000);
+        let conflict = mgr.restore_conflict("notes.txt", &opts).unwrap().unwrap();
+        let expected = RestoreConflict { destination: notes.clone(), modified: 2_000, source: bak, backed_up: 1_000 };
+        assert_eq!(conflict, expected);
+
+        // Not modified since, or gone: nothing to lose.
+        set_mtime(1_000);
+        assert_eq!(mgr.restore_conflict("notes.txt", &opts).unwrap(), None);
+        fs::remove_file(&notes).unwrap();
+        assert_eq!(mgr.restore_conflict("notes.txt", &opts).unwrap(), None);
+    }
+}
@@ -0,0 +1,457 @@
+//! Checkpoints: a named set of backups, one per file, taken together so the
+//! files can be put back to that moment together. Each is a manifest,
+//! ".safe_backup/checkpoints/<name>.json" in the backup directory.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dedup::STORE_DIR;
+use crate::digest::tagged;
+use crate::error::{invalid, missing, no_backup, BackupError, Context};
+use crate::format::{parse_versioned, unversioned, FORMAT_VERSION};
+use crate::meta::read_meta;
+use crate::naming::{display_name, trim_name};
+use crate::conflict::{ConflictStrategy, Resolution};
+use crate::meta::BackupMeta;
+use crate::vfs::FileSystem;
+use crate::{restored_result, BackupManager, RestoreOptions, RestoreOutcome, Warning};
+
+/// A checkpoint as stored in its manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Format the manifest was written in; see [`FORMAT_VERSION`].
+    #[serde(default = "unversioned")]
+    pub format_version: u32,
+    pub name: String,
+    /// When it was created, by the configured clock.
+    pub created: u64,
+    /// The files in it, in the order given.
+    pub files: Vec<CheckpointFile>,
+}
+
+/// Outcome of [`BackupManager::restore_checkpoint_with`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckpointReport {
+    /// Every file of the checkpoint, in its order, with what was done about it.
+    pub files: Vec<(PathBuf, RestoreOutcome)>,
+    /// Non-fatal problems: metadata not put back, a failed post-hook or log write.
+    pub warnings: Vec<Warning>,
+}
+
+/// One file of a [`Checkpoint`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointFile {
+    /// The original, relative to the root, as given.
+    pub file: String,
+    /// File name of its backup in the backup directory.
+    pub backup: String,
+    /// Tagged digest of the backup, as recorded in its sidecar.
+    pub digest: Option<String>,
+}
+
+impl BackupManager {
+    /// Back up each of `files` and record the backups as the checkpoint
+    /// `name`. A checkpoint of that name must not exist yet. If a backup
+    /// fails, no checkpoint is written; the backups made until then stay as
+    /// ordinary backups.
+    pub fn create_checkpoint(&self, name: &str, files: &[impl AsRef<Path>]) -> io::Result<Checkpoint> {
+        let root = self.root()?;
+        let path = self.checkpoint_path(&root, name)?;
+        if self.fs.exists(&path) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists)).at("checkpoint", "already exists", &path);
+        }
+        if files.is_empty() {
+            return Err(invalid(Path::new(name), "a checkpoint needs at least one file"));
+        }
+        let mut seen = HashSet::new();
+        let mut checkpoint = Checkpoint {
+            format_version: FORMAT_VERSION,
+            name: name.to_string(),
+            created: self.now(),
+            files: Vec::new(),
+        };
+        for file in files {
+            let file = trim_name(file.as_ref());
+            if !seen.insert(self.validate_path(file)?) {
+                continue;
+            }
+            let report = self.backup_file_report(file)?;
+            let digest = read_meta(&*self.fs, &report.path).ok().and_then(|m| m.digest);
+            let backup = report.path.file_name().unwrap_or_default();
+            checkpoint.files.push(CheckpointFile {
+                file: display_name(file.as_os_str()).into_owned(),
+                backup: display_name(backup).into_owned(),
+                digest,
+            });
+        }
+        let dir = path.parent().unwrap_or(&root);
+        self.fs.create_dir_all(dir).at("checkpoint", "cannot create directory", dir)?;
+        let json = serde_json::to_string_pretty(&checkpoint).map_err(io::Error::other);
+        json.and_then(|json| self.fs.write(&path, json.as_bytes())).at("checkpoint", "cannot write", &path)?;
+        let result = format!("ok (created, {} files)", checkpoint.files.len());
+        self.log_action(&root, "checkpoint", Path::new(name), &result)?;
+        Ok(checkpoint)
+    }
+
+    /// Put every file of the checkpoint `name` back as it was. All backups
+    /// are checked against their recorded digests first, and each is restored
+    /// next to its file before any file is replaced, so a missing or damaged
+    /// backup changes nothing. Returns the files restored. Should replacing
+    /// fail part way, the error is [`BackupError::CheckpointPartial`], naming
+    /// the files that were and weren't restored.
+    pub fn restore_checkpoint(&self, name: &str) -> io::Result<Vec<PathBuf>> {
+        let report = self.restore_checkpoint_with(name, ConflictStrategy::Overwrite)?;
+        Ok(report.files.into_iter().map(|(file, _)| file).collect())
+    }
+
+    /// [`restore_checkpoint`](Self::restore_checkpoint), treating each file
+    /// that is there with `strategy`. Reports every file of the checkpoint
+    /// with what was done about it. A `ConflictStrategy::Fail` conflict is
+    /// found before anything is replaced. The pre-hook runs for every file
+    /// to be written before any is staged, the post-hook for each once it
+    /// is in place.
+    pub fn restore_checkpoint_with(&self, name: &str, strategy: ConflictStrategy) -> io::Result<CheckpointReport> {
+        let root = self.root()?;
+        let checkpoint = self.read_checkpoint(name)?;
+        let mut plan = Vec::new();
+        for f in &checkpoint.files {
+            let backup = self.backup_named(&root, Path::new(&f.backup))?;
+            if !self.fs.metadata(&backup).is_ok_and(|m| m.is_file()) {
+                return Err(no_backup("checkpoint", "backup file not found", &backup));
+            }
+            let recorded = read_meta(&*self.fs, &backup)?.digest;
+            if let (Some(expected), Some(actual)) = (f.digest.as_deref().map(tagged), recorded.as_deref().map(tagged)) {
+                if expected != actual {
+                    return Err(BackupError::Corrupt { path: backup, expected, actual }.into());
+                }
+            }
+            self.verify_backup(&f.backup)?;
+            let (dest, logical) = self.validate_path_parts(&f.file)?;
+            let resolution = self.resolve_conflict(strategy, &backup, &dest)?;
+            plan.push((f, dest, logical, resolution));
+        }
+
+        // Every hook runs before anything is staged, so a refusing hook changes nothing.
+        for (_, dest, _, resolution) in &plan {
+            if let Resolution::Write(_) = resolution {
+                self.pre_hook("restore", dest)?;
+            }
+        }
+
+        // Restore everything beside its target first; nothing is replaced yet.
+        let cancel = self.cancel_for(None);
+        let mut warnings = Vec::new();
+        let mut staged: Vec<Staged> = Vec::new();
+        let mut left = Vec::new();
+        for (f, dest, logical, resolution) in &plan {
+            let outcome = match resolution {
+                Resolution::Write(outcome) => *outcome,
+                Resolution::Leave(outcome) => {
+                    left.push((dest.clone(), *outcome));
+                    continue;
+                }
+            };
+            let mut stage = || {
+                let restore = self.restore_plan(Path::new(&f.backup), &RestoreOptions::new().to(&f.file))?;
+                let (tmp, verified) = self.stage_restore(&restore, false, cancel, &mut warnings)?;
+                let (dest, logical, meta) = (dest.clone(), logical.clone(), restore.meta);
+                Ok::<_, io::Error>(Staged { tmp, dest, logical, outcome, verified, meta })
+            };
+            match stage() {
+                Ok(s) => staged.push(s),
+                Err(e) => {
+                    for s in &staged {
+                        let _ = self.fs.remove_file(&s.tmp);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut restored = Vec::new();
+        for (i, s) in staged.iter().enumerate() {
+            let aside = match s.outcome {
+                RestoreOutcome::RenamedExisting => self.move_aside(&s.dest).map(drop),
+                _ => Ok(()),
+            };
+            if let Err(source) = aside.and_then(|_| self.fs.move_file(&s.tmp, &s.dest)) {
+                let not_restored = staged[i..].iter().map(|s| s.dest.clone()).collect();
+                for s in &staged[i..] {
+                    let _ = self.fs.remove_file(&s.tmp);
+                }
+                let result = format!("failed (restored {} of {} files)", restored.len(), staged.len());
+                // The partial restore is what needs reporting, even if the log can't be written.
+                let _ = self.log_action(&root, "checkpoint", Path::new(name), &result);
+                let name = name.to_string();
+                return Err(BackupError::CheckpointPartial { name, restored, not_restored, source }.into());
+            }
+            self.apply_restored_meta(s.meta.as_ref(), &s.dest, &mut warnings);
+            let result = restored_result(s.verified);
+            self.log_or_warn(&root, "restore", Path::new(&s.logical), result, &mut warnings);
+            warnings.extend(self.post_hook(&root, "restore", &s.dest));
+            restored.push(s.dest.clone());
+        }
+        let result = match left.len() {
+            0 => format!("ok (restored {} files)", restored.len()),
+            n => format!("ok (restored {} files, left {n})", restored.len()),
+        };
+        self.log_or_warn(&root, "checkpoint", Path::new(name), &result, &mut warnings);
+        let mut files: Vec<(PathBuf, RestoreOutcome)> = staged.into_iter().map(|s| (s.dest, s.outcome)).collect();
+        files.extend(left);
+        // In the checkpoint's order.
+        files.sort_by_key(|(d, _)| plan.iter().position(|(_, p, ..)| p == d));
+        Ok(CheckpointReport { files, warnings })
+    }
+
+    /// Every checkpoint, oldest first.
+    pub fn list_checkpoints(&self) -> io::Result<Vec<Checkpoint>> {
+        let dir = checkpoints_dir(&self.backup_root(&self.root()?));
+        if !self.fs.metadata(&dir).is_ok_and(|m| m.is_dir()) {
+            return Ok(Vec::new());
+        }
+        let mut all = Vec::new();
+        for fname in self.fs.read_dir_names(&dir).at("checkpoint", "cannot list directory", &dir)? {
+            let path = dir.join(fname.at("checkpoint", "cannot list directory", &dir)?);
+            if path.extension().is_some_and(|e| e == "json") {
+                all.push(read_manifest(&*self.fs, &path)?);
+            }
+        }
+        all.sort_by(|a, b| (a.created, &a.name).cmp(&(b.created, &b.name)));
+        Ok(all)
+    }
+
+    /// The checkpoint `name`.
+    pub fn read_checkpoint(&self, name: &str) -> io::Result<Checkpoint> {
+        let path = self.checkpoint_path(&self.root()?, name)?;
+        if !self.fs.metadata(&path).is_ok_and(|m| m.is_file()) {
+            return Err(missing("checkpoint", "no such checkpoint", &path));
+        }
+        read_manifest(&*self.fs, &path)
+    }
+
+    /// The backups some checkpoint holds, which pruning, retention and
+    /// compacting leave alone.
+    pub(crate) fn checkpointed(&self, root: &Path) -> io::Result<HashSet<PathBuf>> {
+        let mut held = HashSet::new();
+        for checkpoint in self.list_checkpoints()? {
+            for f in &checkpoint.files {
+                held.insert(self.backup_named(root, Path::new(&f.backup))?);
+            }
+        }
+        Ok(held)
+    }
+
+    /// Remove the checkpoint `name`. Its backups stay, as ordinary backups
+    /// that prune and retention may then remove.
+    pub fn delete_checkpoint(&self, name: &str) -> io::Result<()> {
+        let root = self.root()?;
+        let path = self.checkpoint_path(&root, name)?;
+        if !self.fs.metadata(&path).is_ok_and(|m| m.is_file()) {
+            return Err(missing("checkpoint", "no such checkpoint", &path));
+        }
+        self.fs.remove_file(&path).at("checkpoint", "cannot remove", &path)?;
+        self.log_action(&root, "checkpoint", Path::new(name), "ok (deleted)")
+    }
+
+    /// The manifest of checkpoint `name`, which must be usable as a file name.
+    fn checkpoint_path(&self, root: &Path, name: &str) -> io::Result<PathBuf> {
+        let plain = matches!(Path::new(name).components().collect::<Vec<_>>()[..], [Component::Normal(_)]);
+        if !plain || name.trim() != name || name.contains(['/', '\\']) {
+            return Err(invalid(Path::new(name), "a checkpoint name must be a plain name"));
+        }
+        Ok(checkpoints_dir(&self.backup_root(root)).join(format!("{name}.json")))
+    }
+}
+
+/// A file of a checkpoint restored beside its target, to be put in place.
+struct Staged {
+    tmp: PathBuf,
+    dest: PathBuf,
+    /// The file's canonical name, which its log entry is under.
+    logical: String,
+    outcome: RestoreOutcome,
+    /// Whether it matched the recorded digest, as for a restore.
+    verified: Option<bool>,
+    meta: Option<BackupMeta>,
+}
+
+fn checkpoints_dir(broot: &Path) -> PathBuf {
+    broot.join(STORE_DIR).join("checkpoints")
+}
+
+fn read_manifest(fs: &dyn FileSystem, path: &Path) -> io::Result<Checkpoint> {
+    let text = fs.read_to_string(path).at("checkpoint", "cannot read", path)?;
+    parse_versioned("checkpoint", "not a checkpoint", path, &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use crate::Config;
+    use std::fs;
+
+    fn write_all(root: &Path, text: &str) {
+        for f in ["a.rs", "b.rs", "c.toml"] {
+            fs::write(root.join(f), format!("{f} {text}")).unwrap();
+        }
+    }
+
+    fn read(root: &Path, f: &str) -> String {
+        fs::read_to_string(root.join(f)).unwrap()
+    }
+
+    #[test]
+    fn create_list_restore_delete() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write_all(root, "before");
+        let cp = manager(root, 100).create_checkpoint("pre-refactor", &["a.rs", "b.rs", "c.toml", "a.rs"]).unwrap();
+        assert_eq!(cp.files.len(), 3);
+        assert_eq!(cp.files[1].backup, "b.rs.100.bak");
+        assert!(cp.files.iter().all(|f| f.digest.is_some()));
+        let again = manager(root, 101).create_checkpoint("pre-refactor", &["a.rs"]).unwrap_err();
+        assert_eq!(again.kind(), io::ErrorKind::AlreadyExists);
+
+        // Later backups don't change what the checkpoint restores.
+        write_all(root, "after");
+        let mgr = manager(root, 200);
+        mgr.backup_file("a.rs").unwrap();
+        let restored = mgr.restore_checkpoint("pre-refactor").unwrap();
+        assert_eq!(restored.len(), 3);
+        assert_eq!((read(root, "a.rs"), read(root, "c.toml")), ("a.rs before".into(), "c.toml before".into()));
+        assert_eq!(temps(root), Vec::<PathBuf>::new());
+        // Each file is logged under its own name once it is in place, then the checkpoint.
+        let log = mgr.read_log().unwrap();
+        let entry = |e: &crate::LogEntry| (e.action.clone(), e.file.clone(), e.result.clone());
+        let tail: Vec<_> = log[log.len() - 4..].iter().map(entry).collect();
+        let expected = [
+            ("restore", "a.rs", "ok (verified)"),
+            ("restore", "b.rs", "ok (verified)"),
+            ("restore", "c.toml", "ok (verified)"),
+            ("checkpoint", "pre-refactor", "ok (restored 3 files)"),
+        ];
+        assert_eq!(tail, expected.map(|(a, f, r)| (a.to_string(), f.to_string(), r.to_string())));
+
+        assert_eq!(mgr.list_checkpoints().unwrap(), [cp]);
+        mgr.delete_checkpoint("pre-refactor").unwrap();
+        assert!(mgr.list_checkpoints().unwrap().is_empty());
+        assert_eq!(mgr.restore_checkpoint("pre-refactor").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(mgr.create_checkpoint("../x", &["a.rs"]).is_err());
+        assert!(mgr.create_checkpoint("x", &[] as &[&str]).is_err());
+    }
+
+    #[test]
+    fn pruning_leaves_the_backups_of_checkpoints() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write_all(root, "before");
+        manager(root, 100).create_checkpoint("pre-refactor", &["a.rs"]).unwrap();
+        for ts in [200, 300] {
+            manager(root, ts).backup_file("a.rs").unwrap();
+        }
+        let mgr = manager(root, 400);
+        let ts = || mgr.list_backups("a.rs").unwrap().iter().map(|e| e.ts).collect::<Vec<_>>();
+        assert_eq!(mgr.prune_backups("a.rs", 1, false).unwrap(), [root.join("a.rs.200.bak")]);
+        assert_eq!(ts(), [300, 100]);
+
+        // Nor do retention and compacting take them.
+        let policy = crate::RetentionPolicy { keep_last: 1, keep_within: 0, daily: 0, weekly: 0 };
+        assert!(manager(root, 10_000_000).apply_retention("a.rs", &policy).unwrap().removed.is_empty());
+        assert!(manager(root, 10_000_000).compact_backups("a.rs", 0).unwrap().archived.is_empty());
+        let mgr = BackupManager::new(Config { keep_last: Some(1), ..manager(root, 500).config });
+        mgr.backup_file("a.rs").unwrap();
+        assert_eq!(ts(), [500, 100]);
+        assert_eq!(mgr.restore_checkpoint("pre-refactor").unwrap().len(), 1);
+        assert_eq!(read(root, "a.rs"), "a.rs before");
+
+        // Once the checkpoint is gone, they prune like any other.
+        mgr.delete_checkpoint("pre-refactor").unwrap();
+        assert_eq!(mgr.prune_backups("a.rs", 1, false).unwrap(), [root.join("a.rs.100.bak")]);
+    }
+
+    /// Temporary files left in `dir`.
+    fn temps(dir: &Path) -> Vec<PathBuf> {
+        let paths = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path());
+        paths.filter(|p| p.extension() == Some("tmp".as_ref())).collect()
+    }
+
+    #[test]
+    fn a_bad_backup_stops_the_restore_before_anything_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write_all(root, "before");
+        manager(root, 100).create_checkpoint("cp", &["a.rs", "b.rs", "c.toml"]).unwrap();
+        write_all(root, "after");
+        let mgr = manager(root, 200);
+
+        fs::write(root.join("c.toml.100.bak"), "tampered").unwrap();
+        let e = mgr.restore_checkpoint("cp").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(root.join("b.rs.100.bak")).unwrap();
+        assert_eq!(mgr.restore_checkpoint("cp").unwrap_err().kind(), io::ErrorKind::NotFound);
+        for f in ["a.rs", "b.rs", "c.toml"] {
+            assert_eq!(read(root, f), format!("{f} after"));
+        }
+    }
+
+    #[test]
+    fn partial_restore_names_what_was_and_was_not_restored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("a.rs"), "a before").unwrap();
+        fs::write(root.join("sub/b.rs"), "b before").unwrap();
+        manager(root, 100).create_checkpoint("cp", &["a.rs", "sub/b.rs"]).unwrap();
+        fs::write(root.join("a.rs"), "a after").unwrap();
+        // A directory where "sub/b.rs" goes: it can be staged beside it, but not replaced.
+        fs::remove_file(root.join("sub/b.rs")).unwrap();
+        fs::create_dir(root.join("sub/b.rs")).unwrap();
+        fs::write(root.join("sub/b.rs/x"), "").unwrap();
+
+        let e = manager(root, 200).restore_checkpoint("cp").unwrap_err();
+        match BackupError::from_io(&e) {
+            Some(BackupError::CheckpointPartial { restored, not_restored, .. }) => {
+                assert_eq!(restored, &[root.join("a.rs")]);
+                assert_eq!(not_restored, &[root.join("sub/b.rs")]);
+            }
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(read(root, "a.rs"), "a before");
+        assert_eq!(temps(&root.join("sub")), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn a_conflict_strategy_applies_to_each_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write_all(root, "before");
+        manager(root, 100).create_checkpoint("cp", &["a.rs", "b.rs", "c.toml"]).unwrap();
+        fs::write(root.join("a.rs"), "a.rs after").unwrap();
+        fs::remove_file(root.join("c.toml")).unwrap();
+        let mgr = manager(root, 200);
+
+        // One changed file fails the lot before anything is replaced.
+        let e = mgr.restore_checkpoint_with("cp", ConflictStrategy::Fail).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::DestinationExists { .. })), "{e}");
+        assert!(!root.join("c.toml").exists());
+
+        let outcomes = mgr.restore_checkpoint_with("cp", ConflictStrategy::Skip).unwrap().files;
+        let expected = [
+            (root.join("a.rs"), RestoreOutcome::Skipped),
+            (root.join("b.rs"), RestoreOutcome::Skipped),
+            (root.join("c.toml"), RestoreOutcome::Restored),
+        ];
+        assert_eq!(outcomes, expected);
+        assert_eq!(read(root, "a.rs"), "a.rs after");
+
+        let outcomes = mgr.restore_checkpoint_with("cp", ConflictStrategy::RenameExisting).unwrap().files;
+        assert!(outcomes.iter().all(|(_, o)| *o == RestoreOutcome::RenamedExisting), "{outcomes:?}");
+        assert_eq!(read(root, "a.rs"), "a.rs before");
+        assert_eq!(read(root, "a.rs.pre-restore.200"), "a.rs after");
+        assert_eq!(mgr.read_log().unwrap().last().unwrap().result, "ok (restored 3 files)");
+    }
+}
@@ -0,0 +1,389 @@
+//! Chunked backups: a large file stored as fixed-size pieces
+//! "<backup>.part0001", "<backup>.part0002", ... with a small JSON index at
+//! "<backup>" itself recording each piece's size and digest. The sidecar marks
+//! the backup as chunked, so listing and pruning see one backup as usual.
+//! Every piece is checked against the index before anything is restored.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::{self, Cancel};
+use crate::compress::Compression;
+use crate::digest::{file_digest, DigestAlgo, Hasher};
+use crate::error::{missing, BackupError, Context};
+use crate::format::{parse_versioned, unversioned, FORMAT_VERSION};
+use crate::vfs::{FileSystem, RealFs};
+
+/// A sensible `Config::chunk_size`: 1 GiB.
+pub const DEFAULT_CHUNK_SIZE: u64 = 1 << 30;
+
+/// Contents of the index file of a chunked backup.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChunkIndex {
+    /// Format the index is written in; 1 in indexes from before the field.
+    #[serde(default = "unversioned")]
+    pub format_version: u32,
+    /// Size of every piece but the last, in bytes.
+    pub chunk_size: u64,
+    pub count: usize,
+    pub chunks: Vec<Chunk>,
+}
+
+/// One piece, as stored (after compression).
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Chunk {
+    pub size: u64,
+    /// Tagged digest ("sha256:<hex>", ...) of the piece.
+    pub digest: String,
+}
+
+impl ChunkIndex {
+    /// Bytes stored over all pieces.
+    pub(crate) fn stored_size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.size).sum()
+    }
+}
+
+/// "<backup>.partNNNN", the `n`-th piece (1-based) of `backup`.
+pub(crate) fn part_path(backup: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(backup.as_os_str());
+    name.push(format!(".part{n:04}"));
+    PathBuf::from(name)
+}
+
+/// Splits everything written to it into pieces of `chunk_size` bytes.
+struct ChunkWriter<'a> {
+    backup: &'a Path,
+    chunk_size: u64,
+    algo: DigestAlgo,
+    current: Option<Part>,
+    chunks: Vec<Chunk>,
+}
+
+struct Part {
+    file: BufWriter<File>,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl ChunkWriter<'_> {
+    fn close_part(&mut self) -> io::Result<()> {
+        if let Some(mut part) = self.current.take() {
+            part.file.flush()?;
+            self.chunks.push(Chunk { size: part.size, digest: part.hasher.finish() });
+        }
+        Ok(())
+    }
+
+    /// Close the last piece and write the index.
+    fn finish(mut self) -> io::Result<()> {
+        self.close_part()?;
+        let index = ChunkIndex {
+            format_version: FORMAT_VERSION,
+            chunk_size: self.chunk_size,
+            count: self.chunks.len(),
+            chunks: self.chunks,
+        };
+        let json = serde_json::to_string(&index).map_err(io::Error::other)?;
+        fs::write(self.backup, json)
+    }
+}
+
+impl Write for ChunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current.as_ref().is_some_and(|p| p.size >= self.chunk_size) {
+            self.close_part()?;
+        }
+        let part = match &mut self.current {
+            Some(part) => part,
+            None => {
+                let path = part_path(self.backup, self.chunks.len() + 1);
+                let file = BufWriter::new(crate::vfs::create_private(&path)?);
+                self.current.insert(Part { file, hasher: Hasher::new(self.algo), size: 0 })
+            }
+        };
+        let room = usize::try_from(self.chunk_size - part.size).unwrap_or(usize::MAX);
+        let n = part.file.write(&buf[..buf.len().min(room)])?;
+        part.hasher.write_all(&buf[..n])?;
+        part.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.as_mut().map_or(Ok(()), |p| p.file.flush())
+    }
+}
+
+/// Store `from` as the chunked backup `backup`, compressed as `compression`,
+/// in pieces of `chunk_size` bytes digested with `algo`. Returns the size of
+/// `from`. A failure leaves no pieces behind, as does `cancel`, checked
+/// before every read; once everything is read the index is written anyway.
+pub(crate) fn write_chunked(
+    op: &'static str,
+    from: &Path,
+    backup: &Path,
+    chunk_size: u64,
+    compression: Compression,
+    algo: DigestAlgo,
+    cancel: Cancel<'_>,
+) -> io::Result<u64> {
+    let mut input = File::open(from).at(op, "cannot open", from)?;
+    let mut out = ChunkWriter { backup, chunk_size: chunk_size.max(1), algo, current: None, chunks: Vec::new() };
+    let written = match compression {
+        Compression::None => {
+            cancel::copy(cancel, op, backup, &mut input, &mut out).and_then(|n| out.finish().map(|_| n))
+        }
+        Compression::Gzip => {
+            let mut enc = GzEncoder::new(out, Default::default());
+            cancel::copy(cancel, op, backup, &mut input, &mut enc).and_then(|n| enc.finish()?.finish().map(|_| n))
+        }
+    };
+    written.or_else(|e| {
+        let _ = remove_chunked(&RealFs, backup);
+        match cancel::is_cancelled(&e) {
+            true => Err(e),
+            false => Err(e).copying(op, from, backup),
+        }
+    })
+}
+
+/// The index of the chunked backup `backup`.
+pub(crate) fn read_index(op: &'static str, backup: &Path) -> io::Result<ChunkIndex> {
+    let text = fs::read_to_string(backup).at(op, "cannot read chunk index", backup)?;
+    parse_versioned(op, "malformed chunk index", backup, &text)
+}
+
+/// Check every piece of `backup` against its index: a missing piece is a
+/// `Missing` error, a damaged one `BackupError::Corrupt` naming it. `cancel`
+/// is checked before each piece.
+pub(crate) fn verify_chunks(op: &'static str, backup: &Path, cancel: Cancel<'_>) -> io::Result<ChunkIndex> {
+    let index = read_index(op, backup)?;
+    if index.count != index.chunks.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "piece count does not match the pieces listed"))
+            .at(op, "malformed chunk index", backup);
+    }
+    for (i, chunk) in index.chunks.iter().enumerate() {
+        let part = part_path(backup, i + 1);
+        cancel.check(op, &part)?;
+        if !part.is_file() {
+            return Err(missing(op, "chunk not found", &part));
+        }
+        let algo = DigestAlgo::of(&chunk.digest)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .at(op, "malformed chunk index", backup)?;
+        let actual = file_digest(&part, algo).at(op, "cannot read", &part)?;
+        if actual != chunk.digest {
+            return Err(BackupError::Corrupt { path: part, expected: chunk.digest.clone(), actual }.into());
+        }
+    }
+    Ok(index)
+}
+
+/// Reads the pieces of a backup one after another, opening each when reached.
+struct PartsReader {
+    parts: std::vec::IntoIter<PathBuf>,
+    current: Option<File>,
+}
+
+impl Read for PartsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(file) = &mut self.current {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.parts.next() {
+                Some(part) => self.current = Some(File::open(part)?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Verify the pieces of `backup` (see [`verify_chunks`]), then write its
+/// reassembled, decompressed contents to `out`. Returns the bytes written.
+pub(crate) fn read_to(
+    op: &'static str,
+    backup: &Path,
+    compression: Compression,
+    out: &mut dyn Write,
+) -> io::Result<u64> {
+    let index = verify_chunks(op, backup, Cancel::default())?;
+    stream(op, backup, &index, compression, out)
+}
+
+/// Restore the chunked backup `backup` into `to`. Nothing is written unless
+/// every piece is present and intact.
+pub(crate) fn restore_to(op: &'static str, backup: &Path, compression: Compression, to: &Path) -> io::Result<u64> {
+    let index = verify_chunks(op, backup, Cancel::default())?;
+    let mut out = BufWriter::new(File::create(to).at(op, "cannot create", to)?);
+    let n = stream(op, backup, &index, compression, &mut out)?;
+    out.flush().copying(op, backup, to)?;
+    Ok(n)
+}
+
+fn stream(
+    op: &'static str,
+    backup: &Path,
+    index: &ChunkIndex,
+    compression: Compression,
+    out: &mut dyn Write,
+) -> io::Result<u64> {
+    let parts: Vec<PathBuf> = (1..=index.count).map(|n| part_path(backup, n)).collect();
+    let mut reader = BufReader::new(PartsReader { parts: parts.into_iter(), current: None });
+    match compression {
+        Compression::None => io::copy(&mut reader, out),
+        Compression::Gzip => io::copy(&mut GzDecoder::new(reader), out),
+    }
+    .at(op, "cannot stream", backup)
+}
+
+/// Remove the index `backup` and its pieces, if any. Errors carry no context.
+pub(crate) fn remove_chunked(fs: &dyn FileSystem, backup: &Path) -> io::Result<()> {
+    fs.remove_file(backup).or_else(ignore_missing)?;
+    for n in 1.. {
+        match fs.remove_file(&part_path(backup, n)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            r => r?,
+        }
+    }
+    Ok(())
+}
+
+fn ignore_missing(e: io::Error) -> io::Result<()> {
+    if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::{BackupManager, Config, RestoreOptions};
+
+    fn manager(root: &Path, now: u64, compression: Compression) -> BackupManager {
+        BackupManager::new(Config { compression, chunk_size: Some(10), ..config(root, now) })
+    }
+
+    #[test]
+    fn large_files_are_split_and_reassembled() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let data = "0123456789abcdefghijklmnopqrstuvwxyz";
+        fs::write(root.join("big.txt"), data).unwrap();
+        fs::write(root.join("small.txt"), "tiny").unwrap();
+        let mgr = manager(root, 100, Compression::None);
+
+        let bak = mgr.backup_file("big.txt").unwrap();
+        let index = read_index("test", &bak).unwrap();
+        assert_eq!((index.count, index.stored_size()), (4, 36));
+        assert_eq!(fs::read_to_string(part_path(&bak, 4)).unwrap(), "uvwxyz");
+        assert!(!part_path(&bak, 5).exists());
+        assert_eq!(crate::read_backup_meta(&bak).unwrap().layout, crate::Layout::Chunked);
+        mgr.verify_backup(bak.file_name().unwrap()).unwrap();
+        // Not larger than a chunk: stored whole.
+        let small = mgr.backup_file("small.txt").unwrap();
+        assert_eq!(fs::read_to_string(&small).unwrap(), "tiny");
+
+        let entries = mgr.list_backups("big.txt").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((&entries[0].path, entries[0].size), (&bak, 36));
+
+        fs::write(root.join("big.txt"), "changed").unwrap();
+        mgr.restore_file("big.txt").unwrap();
+        assert_eq!(fs::read_to_string(root.join("big.txt")).unwrap(), data);
+        let mut out = Vec::new();
+        mgr.cat_backup("big.txt", None, &mut out).unwrap();
+        assert_eq!(out, data.as_bytes());
+    }
+
+    #[test]
+    fn compressed_chunks_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let data = "line of text\n".repeat(50);
+        fs::write(root.join("log.txt"), &data).unwrap();
+        let mgr = manager(root, 100, Compression::Gzip);
+        let bak = mgr.backup_file("log.txt").unwrap();
+        assert!(read_index("test", &bak).unwrap().count > 1);
+        fs::remove_file(root.join("log.txt")).unwrap();
+        mgr.restore_file("log.txt").unwrap();
+        assert_eq!(fs::read_to_string(root.join("log.txt")).unwrap(), data);
+    }
+
+    #[test]
+    fn missing_or_damaged_chunks_stop_the_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("big.txt"), "0123456789abcdefghijklmnopqrstuvwxyz").unwrap();
+        let mgr = manager(root, 100, Compression::None);
+        let bak = mgr.backup_file("big.txt").unwrap();
+        fs::write(root.join("big.txt"), "live").unwrap();
+
+        fs::write(part_path(&bak, 2), "ABCDEFGHIJ").unwrap();
+        let e = mgr.restore_file("big.txt").unwrap_err();
+        let corrupt = BackupError::from_io(&e);
+        assert!(matches!(corrupt, Some(BackupError::Corrupt { path, .. }) if *path == part_path(&bak, 2)), "{e}");
+        let check = mgr.check_restore("big.txt", &RestoreOptions::default()).unwrap();
+        assert_eq!(check.status, crate::CheckStatus::Corrupt);
+        assert!(mgr.verify_backup(bak.file_name().unwrap()).is_err());
+
+        fs::write(part_path(&bak, 2), "abcdefghij").unwrap();
+        fs::remove_file(part_path(&bak, 3)).unwrap();
+        let e = mgr.restore_file("big.txt").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains("part0003"), "{e}");
+        assert_eq!(fs::read_to_string(root.join("big.txt")).unwrap(), "live");
+    }
+
+    #[test]
+    fn pruning_removes_every_piece() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("big.txt"), "0123456789abcdefghijklmnopqrstuvwxyz").unwrap();
+        let old = manager(root, 100, Compression::None).backup_file("big.txt").unwrap();
+        let mgr = manager(root, 200, Compression::None);
+        mgr.backup_file("big.txt").unwrap();
+        assert_eq!(mgr.stats().unwrap().bytes, 72);
+
+        assert_eq!(mgr.prune_backups("big.txt", 1, false).unwrap(), vec![old.clone()]);
+        assert!(!old.exists() && !part_path(&old, 1).exists() && !part_path(&old, 4).exists());
+        assert_eq!(mgr.list_backups("big.txt").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn cancelling_leaves_no_pieces_and_stops_checks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("big.txt"), "0123456789abcdefghijklmnopqrstuvwxyz").unwrap();
+        let token = crate::CancellationToken::new();
+        token.cancel();
+        let cancelled = |e: &io::Error| matches!(BackupError::from_io(e), Some(BackupError::Cancelled { .. }));
+
+        let bak = root.join("big.txt.1.bak");
+        let cancel = Cancel::new(Some(&token), None, None);
+        let e = write_chunked("backup", &root.join("big.txt"), &bak, 10, Compression::None, DigestAlgo::Sha256, cancel)
+            .unwrap_err();
+        assert!(cancelled(&e), "{e}");
+        assert!(!bak.exists() && !part_path(&bak, 1).exists());
+        let opts = crate::BackupOptions::new().cancel(token.clone());
+        let e = manager(root, 100, Compression::None).backup_file_with("big.txt", &opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(fs::read_dir(root).unwrap().count(), 1);
+
+        let bak = manager(root, 100, Compression::None).backup_file("big.txt").unwrap();
+        let config = Config { root: Some(root.to_path_buf()), cancel: Some(token), ..Config::default() };
+        let mgr = BackupManager::new(config);
+        assert!(cancelled(&mgr.verify_backup(bak.file_name().unwrap()).unwrap_err()));
+    }
+}
@@ -0,0 +1,278 @@
+//! Cleaning up what the tool leaves lying around: "<stem>.restored.<ts>"
+//! files from restoring a plain ".bak", empty timestamped backups that can't
+//! hold what their name promises, and temporary files of interrupted writes.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::error::Context;
+use crate::meta::{read_backup_meta, sidecar_for, Layout};
+use crate::naming::{display_name, split_backup_name};
+use crate::versions::remove_backup;
+use crate::vfs::FileStat;
+use crate::{BackupManager, Compression};
+
+/// A temporary file modified more recently than this may still be being
+/// written, by this process or another, and is left alone.
+const TEMP_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// What a file found by [`BackupManager::clean`] is left over from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrayKind {
+    /// "<stem>.restored.<ts>", written by restoring "<stem>.bak".
+    Restored,
+    /// A zero-byte timestamped backup whose sidecar doesn't say it backs up an empty file.
+    EmptyBackup,
+    /// "<name>.<tag>.tmp" (or "<name>.tmp") of the log, an archive or a backup,
+    /// left by an interrupted write.
+    Temp,
+}
+
+impl StrayKind {
+    /// Short description for messages and the log.
+    pub fn describe(self) -> &'static str {
+        match self {
+            StrayKind::Restored => "restore leftover",
+            StrayKind::EmptyBackup => "empty backup",
+            StrayKind::Temp => "temporary file",
+        }
+    }
+}
+
+/// One file found by [`BackupManager::clean`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrayFile {
+    pub path: PathBuf,
+    pub kind: StrayKind,
+    /// Size in bytes, sidecar included for an empty backup.
+    pub size: u64,
+}
+
+/// Outcome of [`BackupManager::clean`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanReport {
+    /// Files removed (with `dry_run`, that would be removed), by path.
+    pub removed: Vec<StrayFile>,
+    /// Their total size in bytes.
+    pub bytes: u64,
+}
+
+impl CleanReport {
+    /// How many of the files are of `kind`.
+    pub fn count(&self, kind: StrayKind) -> usize {
+        self.removed.iter().filter(|f| f.kind == kind).count()
+    }
+}
+
+impl BackupManager {
+    /// Remove what restores and interrupted writes left behind; with
+    /// `dry_run`, only report it. That is: "<stem>.restored.<ts>" in the root,
+    /// only when the log has a restore of "<stem>.bak" from that second on,
+    /// so a file of yours that happens to look like one stays; zero-byte
+    /// timestamped backups (with their sidecar) unless it records an empty,
+    /// uncompressed file or a delta; and temporary files of the log, an
+    /// archive or a backup, next to the log or in the backup directory, once
+    /// untouched for an hour. Leftovers in the dedup store are [`gc`](Self::gc)'s. Each
+    /// removal is logged.
+    pub fn clean(&self, dry_run: bool) -> io::Result<CleanReport> {
+        let found = self.find_strays()?;
+        if dry_run {
+            return Ok(found);
+        }
+        self.remove_strays(found)
+    }
+
+    /// What [`clean`](Self::clean) would remove, removing nothing.
+    pub fn find_strays(&self) -> io::Result<CleanReport> {
+        let root = self.root()?;
+        self.flush_log()?;
+        let log_path = self.log_path(&root);
+        let restores: Vec<(u64, String)> = crate::log::read_log_file(&*self.fs, &log_path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|e| e.action == "restore")
+            .map(|e| (e.ts, e.file))
+            .collect();
+        let mut dirs = vec![self.backup_root(&root)];
+        dirs.extend(self.flat_root(&root));
+        if let Some(dir) = log_path.parent() {
+            dirs.push(dir.to_path_buf());
+        }
+        dirs.push(root.clone());
+        dirs.dedup();
+        let mut found = Vec::new();
+        for dir in dirs.iter().filter(|d| self.fs.metadata(d).is_ok_and(|m| m.is_dir())) {
+            for fname in self.fs.read_dir_names(dir).at("clean", "cannot list directory", dir)? {
+                let fname = fname.at("clean", "cannot list directory", dir)?;
+                let path = dir.join(&fname);
+                let md = self.fs.symlink_metadata(&path).at("clean", "cannot stat", &path)?;
+                if !md.is_file() || found.iter().any(|f: &StrayFile| f.path == path) {
+                    continue;
+                }
+                let kind = if *dir == root && restored_by(&fname, &restores) {
+                    StrayKind::Restored
+                } else if md.len == 0 && is_empty_backup(&path) {
+                    StrayKind::EmptyBackup
+                } else if is_temp(&fname, log_path.file_name()) && !in_flight(&md) {
+                    StrayKind::Temp
+                } else {
+                    continue;
+                };
+                let sidecar = (kind == StrayKind::EmptyBackup).then(|| sidecar_for(&path));
+                let extra = sidecar.and_then(|s| self.fs.metadata(&s).ok()).map_or(0, |m| m.len);
+                found.push(StrayFile { path, kind, size: md.len + extra });
+            }
+        }
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(CleanReport { bytes: found.iter().map(|f| f.size).sum(), removed: found })
+    }
+
+    /// Remove the files of `found`, as [`find_strays`](Self::find_strays)
+    /// reported them (and someone confirmed), and nothing found since.
+    /// Returns what was removed; a file already gone is left out.
+    pub fn remove_strays(&self, found: CleanReport) -> io::Result<CleanReport> {
+        let root = self.root()?;
+        let mut report = CleanReport::default();
+        for stray in found.removed {
+            let removed = match stray.kind {
+                StrayKind::EmptyBackup => remove_backup(&*self.fs, &stray.path),
+                _ => self.fs.remove_file(&stray.path).at("clean", "cannot remove", &stray.path),
+            };
+            match removed {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                removed => removed?,
+            }
+            let name = stray.path.file_name().map_or_else(|| stray.path.clone(), PathBuf::from);
+            let result = format!("ok ({}, {} bytes)", stray.kind.describe(), stray.size);
+            self.log_action(&root, "clean", &name, &result)?;
+            report.bytes += stray.size;
+            report.removed.push(stray);
+        }
+        Ok(report)
+    }
+}
+
+/// Whether the temporary file with metadata `md` was modified within
+/// [`TEMP_MIN_AGE`], by the system clock its mtime is set from.
+fn in_flight(md: &FileStat) -> bool {
+    let age = md.modified.and_then(|m| SystemTime::now().duration_since(m).ok());
+    age.is_none_or(|age| age < TEMP_MIN_AGE)
+}
+
+/// Whether `fname` is "<stem>.restored.<ts>" and the log has a restore of
+/// "<stem>.bak" at `ts` or later (the name is picked before the copy).
+fn restored_by(fname: &OsStr, restores: &[(u64, String)]) -> bool {
+    let name = display_name(fname);
+    let Some((stem, ts)) = name.rsplit_once('.') else { return false };
+    let Some(stem) = stem.strip_suffix(".restored").filter(|s| !s.is_empty()) else { return false };
+    if ts.is_empty() || !ts.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let Ok(ts) = ts.parse::<u64>() else { return false };
+    let bak = format!("{stem}.bak");
+    restores.iter().any(|(at, file)| *at >= ts && Path::new(file).file_name() == Some(OsStr::new(&bak)))
+}
+
+/// Whether the zero-byte `path` is a timestamped backup that should have
+/// contents: it has no readable sidecar, or one that records anything but an
+/// empty uncompressed file or a delta (whose tail may be empty).
+fn is_empty_backup(path: &Path) -> bool {
+    let Some((_, Some(_))) = path.file_name().and_then(split_backup_name) else { return false };
+    match read_backup_meta(path) {
+        Ok(meta) if meta.layout == Layout::Delta => false,
+        Ok(meta) => meta.layout != Layout::Whole || meta.compression != Compression::None || meta.size != 0,
+        Err(_) => true,
+    }
+}
+
+/// Whether `fname` is a temporary file ([`unique_temp`](crate::vfs::unique_temp),
+/// or "<name>.tmp" from before it) of the log file `log`, an archive or a timestamped backup.
+fn is_temp(fname: &OsStr, log: Option<&OsStr>) -> bool {
+    let Some(name) = crate::vfs::temp_base(fname) else { return false };
+    if Some(name) == log {
+        return true;
+    }
+    if let Some(rest) = name.to_str().and_then(|n| n.strip_suffix(".tar.gz")) {
+        if let Some((base, ts)) = rest.rsplit_once(".archive.") {
+            return !base.is_empty() && !ts.is_empty() && ts.bytes().all(|b| b.is_ascii_digit());
+        }
+    }
+    matches!(split_backup_name(name), Some((_, Some(_))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use std::fs;
+
+    #[test]
+    fn removes_only_what_the_tool_left() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        manager(root, 100).backup_file("notes.txt").unwrap();
+        // A plain copy from before sidecars is restored next to the original.
+        fs::remove_file(root.join("notes.bak.meta.json")).unwrap();
+        let restored = manager(root, 200).restore_file("notes.bak").unwrap();
+        assert_eq!(restored, root.join("notes.restored.200"));
+        // Look-alikes of the user's, and a restore leftover nothing in the log accounts for.
+        for name in ["notes.restored.txt", "my.restored.notes", "restored.200", "notes.restored.", "other.restored.200"]
+        {
+            fs::write(root.join(name), "mine").unwrap();
+        }
+        fs::write(root.join("notes.restored.300"), "after the logged restore").unwrap();
+        // An empty backup of an empty file is a backup; one without a sidecar is not.
+        fs::write(root.join("empty.txt"), "").unwrap();
+        manager(root, 150).backup_file("empty.txt").unwrap();
+        fs::write(root.join("notes.txt.120.bak"), "").unwrap();
+        fs::write(root.join("notes.txt.130.bak"), "").unwrap();
+        fs::write(sidecar_for(&root.join("notes.txt.130.bak")), "{}").unwrap();
+        // Plain ".tmp" names from earlier versions, and tagged ones.
+        let temps = ["logfile.txt.tmp", "notes.txt.archive.9.tar.gz.tmp", "notes.txt.140.bak.41-0-9f3ac01b.tmp"];
+        let hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        for name in temps.into_iter().chain(["build.tmp", "build.41-0-9f3ac01b.tmp"]) {
+            fs::write(root.join(name), "partial").unwrap();
+            fs::File::options().write(true).open(root.join(name)).unwrap().set_modified(hours_ago).unwrap();
+        }
+        // One still being written.
+        fs::write(root.join("notes.txt.150.bak.42-0-0badcafe.tmp"), "partial").unwrap();
+
+        let mgr = manager(root, 400);
+        let dry = mgr.clean(true).unwrap();
+        let names: Vec<String> =
+            dry.removed.iter().map(|f| f.path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(
+            names,
+            [
+                "logfile.txt.tmp",
+                "notes.restored.200",
+                "notes.txt.120.bak",
+                "notes.txt.130.bak",
+                "notes.txt.140.bak.41-0-9f3ac01b.tmp",
+                "notes.txt.archive.9.tar.gz.tmp",
+            ]
+        );
+        assert_eq!((dry.count(StrayKind::Restored), dry.count(StrayKind::EmptyBackup)), (1, 2));
+        assert_eq!(dry.bytes, 5 + 2 + 3 * 7);
+        assert!(root.join("notes.restored.200").exists());
+
+        // What is removed is what was found, not what is there by then.
+        fs::write(root.join("notes.txt.160.bak"), "").unwrap();
+        fs::remove_file(root.join("logfile.txt.tmp")).unwrap();
+        let done = mgr.remove_strays(dry.clone()).unwrap();
+        assert_eq!(done.removed, dry.removed[1..]);
+        assert_eq!(done.bytes, dry.bytes - 7);
+        assert!(done.removed.iter().all(|f| !f.path.exists()));
+        assert!(root.join("notes.txt.160.bak").exists() && root.join("notes.txt.150.bak.42-0-0badcafe.tmp").exists());
+        fs::remove_file(root.join("notes.txt.160.bak")).unwrap();
+        assert!(!root.join("notes.txt.130.bak.meta.json").exists());
+        assert!(root.join("notes.restored.300").exists() && root.join("build.41-0-9f3ac01b.tmp").exists());
+        mgr.verify_backup("empty.txt.150.bak").unwrap();
+        let log = mgr.read_log().unwrap();
+        assert_eq!(log.iter().filter(|e| e.action == "clean").count(), 5);
+        assert!(mgr.clean(false).unwrap().removed.is_empty());
+    }
+}
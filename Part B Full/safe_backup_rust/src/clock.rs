@@ -0,0 +1,186 @@
+//! Time source for backup timestamps, log entries and age-based pruning,
+//! and the zone they are shown in.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone as _};
+use serde::{Deserialize, Serialize};
+
+/// Where "now" comes from. Swap in a [`FixedClock`] or a [`ManualClock`]
+/// for deterministic tests.
+pub trait Clock: Send + Sync {
+    /// Seconds since the Unix epoch.
+    fn now_unix(&self) -> u64;
+}
+
+/// The real system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_unix(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    }
+}
+
+/// A clock that always reads the same second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now_unix(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A clock that reads what it was last set to. Shared through an `Arc`
+/// between a test and the managers it drives, it moves them all on at once.
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn new(start: u64) -> Self {
+        Self(AtomicU64::new(start))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    /// Move the clock `secs` seconds on.
+    pub fn advance(&self, secs: u64) {
+        self.0.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_unix(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The zone Unix timestamps are shown in as dates (`Config::time_zone`).
+/// Timestamps themselves, in names and in the log, are always Unix seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeZone {
+    /// The system's local time zone.
+    #[default]
+    Local,
+    Utc,
+}
+
+impl TimeZone {
+    /// `ts` as "YYYY-MM-DD HH:MM:SS" in this zone; the bare number when out of range.
+    pub fn format(self, ts: u64) -> String {
+        self.format_with(ts, "%Y-%m-%d %H:%M:%S").unwrap_or_else(|| ts.to_string())
+    }
+
+    /// `ts` in RFC 3339 with this zone's offset; `None` when out of range.
+    pub fn rfc3339(self, ts: u64) -> Option<String> {
+        self.format_with(ts, "%Y-%m-%dT%H:%M:%S%:z")
+    }
+
+    fn format_with(self, ts: u64, fmt: &str) -> Option<String> {
+        let utc = DateTime::from_timestamp(i64::try_from(ts).ok()?, 0)?;
+        Some(match self {
+            Self::Local => utc.with_timezone(&Local).format(fmt).to_string(),
+            Self::Utc => utc.format(fmt).to_string(),
+        })
+    }
+
+    /// The start of `date` in this zone, as Unix seconds: for local time the
+    /// earlier instant when a DST change makes midnight ambiguous, UTC's when it was skipped.
+    pub fn start_of_day(self, date: NaiveDate) -> Option<u64> {
+        let midnight = date.and_hms_opt(0, 0, 0)?;
+        let utc = midnight.and_utc().timestamp();
+        let ts = match self {
+            Self::Local => Local.from_local_datetime(&midnight).earliest().map_or(utc, |t| t.timestamp()),
+            Self::Utc => utc,
+        };
+        u64::try_from(ts).ok()
+    }
+}
+
+impl FromStr for TimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "local" => Ok(Self::Local),
+            "utc" => Ok(Self::Utc),
+            other => Err(format!("unknown time zone {other:?} (expected utc or local)")),
+        }
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Local => "local",
+            Self::Utc => "utc",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackupManager, Config};
+    use std::sync::Arc;
+
+    #[test]
+    fn a_manual_clock_times_backups_log_entries_and_pruning() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::write(root.join("notes.txt"), "v1").unwrap();
+        let day = 86_400;
+        let clock = Arc::new(ManualClock::new(1_000 * day));
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            keep_last: Some(3),
+            max_age_secs: Some(30 * day),
+            clock: clock.clone(),
+            ..Config::default()
+        });
+        for _ in 0..5 {
+            mgr.backup_file("notes.txt").unwrap();
+            clock.advance(day);
+        }
+        let ts = |mgr: &BackupManager| -> Vec<u64> {
+            mgr.list_backups("notes.txt").unwrap().iter().map(|e| e.ts).collect()
+        };
+        assert_eq!(ts(&mgr), [1_004 * day, 1_003 * day, 1_002 * day]);
+        assert_eq!(mgr.read_log().unwrap().first().unwrap().ts, 1_000 * day);
+
+        // A month on, only the new backup is young enough.
+        clock.advance(30 * day);
+        mgr.backup_file("notes.txt").unwrap();
+        assert_eq!(ts(&mgr), [1_035 * day]);
+        // A plain copy without a sidecar is restored beside the original, under the time.
+        std::fs::remove_file(root.join("notes.bak.meta.json")).unwrap();
+        let restored = mgr.restore_file("notes.bak").unwrap();
+        assert_eq!(restored, root.join(format!("notes.restored.{}", 1_035 * day)));
+    }
+
+    #[test]
+    fn formats_an_instant_in_either_zone() {
+        // 2024-03-10 15:30:45 UTC.
+        let ts = 1_710_084_645;
+        assert_eq!(TimeZone::Utc.format(ts), "2024-03-10 15:30:45");
+        assert_eq!(TimeZone::Utc.rfc3339(ts).unwrap(), "2024-03-10T15:30:45+00:00");
+        let local = DateTime::from_timestamp(ts as i64, 0).unwrap().with_timezone(&Local);
+        assert_eq!(TimeZone::Local.format(ts), local.format("%Y-%m-%d %H:%M:%S").to_string());
+        assert_eq!(TimeZone::Local.rfc3339(ts).unwrap(), local.to_rfc3339());
+        assert_eq!(TimeZone::Utc.format(u64::MAX), u64::MAX.to_string());
+
+        let day = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        assert_eq!(TimeZone::Utc.start_of_day(day), Some(1_710_028_800));
+        assert_eq!(TimeZone::Local.format(TimeZone::Local.start_of_day(day).unwrap()), "2024-03-10 00:00:00");
+        assert_eq!("UTC".parse(), Ok(TimeZone::Utc));
+        assert!("Mars".parse::<TimeZone>().is_err());
+    }
+}
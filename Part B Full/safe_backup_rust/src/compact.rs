@@ -0,0 +1,210 @@
+//! Compacting old backups: moving the timestamped backups of one original
+//! that are past a cutoff, with their sidecars and pieces, into a single
+//! "<name>.archive.<ts>.tar.gz", and extracting such an archive again.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::error::{missing, Context};
+use crate::meta::{read_backup_meta, sidecar_for, Layout};
+use crate::naming::archive_for;
+use crate::validate::check_backup_name;
+use crate::versions::{remove_backup, BackupEntry};
+use crate::{chunk, delta, BackupManager};
+
+/// Outcome of [`BackupManager::compact_backups`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// The archive written; `None` if nothing was old enough.
+    pub archive: Option<PathBuf>,
+    /// The backups moved into it, newest first.
+    pub archived: Vec<BackupEntry>,
+}
+
+impl BackupManager {
+    /// Move the timestamped backups of `name` older than `older_than` seconds
+    /// (by the configured clock) into "<name>.archive.<ts>.tar.gz" in the
+    /// backup directory, then remove them, sidecars and pieces included. The
+    /// newest backup stays, as do backups a remaining delta builds on or a
+    /// checkpoint holds and deduplicated ones, whose contents are in the shared store. Restore or
+    /// list what was archived after [`extract_archive`](Self::extract_archive).
+    pub fn compact_backups(&self, name: impl AsRef<Path>, older_than: u64) -> io::Result<CompactReport> {
+        let name = name.as_ref();
+        let root = self.root()?;
+        let now = self.now();
+        let cutoff = now.saturating_sub(older_than);
+        let entries = self.list_backups(name)?;
+        let all: Vec<PathBuf> = entries.iter().map(|e| e.path.clone()).collect();
+        let held = self.checkpointed(&root)?;
+        let old = entries.iter().skip(1).filter(|e| e.ts < cutoff && !held.contains(&e.path));
+        let old = old.map(|e| e.path.clone()).collect();
+        let old = delta::spare_bases(&all, old);
+        let mut report = CompactReport::default();
+        let mut files = Vec::new();
+        for entry in entries {
+            if !old.contains(&entry.path) {
+                continue;
+            }
+            if let Some(members) = members(&entry.path)? {
+                files.extend(members);
+                report.archived.push(entry);
+            }
+        }
+        if report.archived.is_empty() {
+            return Ok(report);
+        }
+        let archive = archive_for(&self.backup_root(&root), name, now)?;
+        check_backup_name(&archive, &self.config.limits)?;
+        write_archive(&archive, &files)?;
+        for entry in &report.archived {
+            remove_backup(&*self.fs, &entry.path)?;
+        }
+        let fname = archive.file_name().unwrap_or(archive.as_os_str()).to_string_lossy();
+        self.log_action(&root, "compact", name, &format!("ok ({} archived into {fname})", report.archived.len()))?;
+        report.archive = Some(archive);
+        Ok(report)
+    }
+
+    /// Put the files of an archive made by [`compact_backups`](Self::compact_backups)
+    /// (named relative to the backup directory) back, then remove the archive.
+    /// Nothing is written if any of them exists already. Returns the files
+    /// extracted: backups, sidecars and pieces.
+    pub fn extract_archive(&self, archive: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+        let archive = archive.as_ref();
+        let root = self.root()?;
+        let broot = self.backup_root(&root);
+        let path = self.resolve(&broot, archive)?;
+        if !path.is_file() {
+            return Err(missing("extract", "archive not found", &path));
+        }
+        // Check every member first, so a bad archive or a clash changes nothing.
+        for name in read_members(&path, |_, _| Ok(()))? {
+            let dest = broot.join(name);
+            if fs::symlink_metadata(&dest).is_ok() {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists)).at("extract", "would overwrite", &dest);
+            }
+        }
+        let extracted: Vec<PathBuf> = read_members(&path, |name, entry| {
+            let dest = broot.join(name);
+            entry.unpack(&dest).map(drop).at("extract", "cannot write", &dest)
+        })?
+        .into_iter()
+        .map(|name| broot.join(name))
+        .collect();
+        fs::remove_file(&path).at("extract", "cannot remove", &path)?;
+        self.log_action(&root, "extract", archive, &format!("ok ({} files)", extracted.len()))?;
+        Ok(extracted)
+    }
+}
+
+/// The files making up `backup`, or `None` for a deduplicated one.
+pub(crate) fn members(backup: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+    let mut files = vec![backup.to_path_buf()];
+    match read_backup_meta(backup) {
+        Ok(meta) if meta.layout == Layout::Deduped => return Ok(None),
+        Ok(meta) if meta.layout == Layout::Chunked => {
+            let pieces = chunk::read_index("compact", backup)?.chunks.len();
+            files.extend((1..=pieces).map(|n| chunk::part_path(backup, n)));
+        }
+        _ => {}
+    }
+    let sidecar = sidecar_for(backup);
+    if sidecar.exists() {
+        files.push(sidecar);
+    }
+    Ok(Some(files))
+}
+
+/// Write `files` under their file names into the new gzipped tar `archive`,
+/// through a temporary file so a failure leaves no partial archive.
+fn write_archive(archive: &Path, files: &[PathBuf]) -> io::Result<()> {
+    if fs::symlink_metadata(archive).is_ok() {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists)).at("compact", "would overwrite", archive);
+    }
+    let tmp = crate::vfs::unique_temp(archive);
+    let out = File::create(&tmp).at("compact", "cannot create", &tmp)?;
+    let mut tar = tar::Builder::new(GzEncoder::new(out, flate2::Compression::default()));
+    let written = files
+        .iter()
+        .try_for_each(|f| tar.append_path_with_name(f, f.file_name().unwrap_or_default()).copying("compact", f, &tmp))
+        .and_then(|_| {
+            let out = tar.into_inner().and_then(GzEncoder::finish);
+            out.and_then(|f| f.sync_all()).at("compact", "cannot write", &tmp)
+        })
+        .and_then(|_| fs::rename(&tmp, archive).copying("compact", &tmp, archive));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Pass each member of `archive` to `each` with its file name, after checking
+/// it is a regular file with a plain name; returns the names.
+fn read_members(
+    archive: &Path,
+    mut each: impl FnMut(&Path, &mut tar::Entry<'_, GzDecoder<File>>) -> io::Result<()>,
+) -> io::Result<Vec<PathBuf>> {
+    let file = File::open(archive).at("extract", "cannot open", archive)?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    let mut names = Vec::new();
+    for entry in tar.entries().at("extract", "cannot read", archive)? {
+        let mut entry = entry.at("extract", "cannot read", archive)?;
+        let name = entry.path().at("extract", "cannot read", archive)?.into_owned();
+        let plain = matches!(name.components().collect::<Vec<_>>()[..], [Component::Normal(_)]);
+        if !plain || !entry.header().entry_type().is_file() {
+            let e = io::Error::new(io::ErrorKind::InvalidData, format!("unexpected member {}", name.display()));
+            return Err(e).at("extract", "not an archive of backups", archive);
+        }
+        each(&name, &mut entry)?;
+        names.push(name);
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+
+    #[test]
+    fn compact_and_extract_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for ts in [100, 200, 300, 400] {
+            fs::write(root.join("a.txt"), format!("v{ts}")).unwrap();
+            manager(root, ts).backup_file("a.txt").unwrap();
+        }
+        let before = manager(root, 1000).list_backups("a.txt").unwrap();
+
+        // Cutoff 350: the three older ones go, the newest always stays.
+        let mgr = manager(root, 1000);
+        let report = mgr.compact_backups("a.txt", 650).unwrap();
+        let archive = root.join("a.txt.archive.1000.tar.gz");
+        assert_eq!(report.archive.as_deref(), Some(archive.as_path()));
+        assert_eq!(report.archived, before[1..]);
+        assert_eq!(mgr.list_backups("a.txt").unwrap(), before[..1]);
+        assert!(!root.join("a.txt.100.bak.meta.json").exists());
+        assert!(mgr.compact_backups("a.txt", 650).unwrap().archive.is_none());
+
+        // A clash stops extraction before anything is written.
+        fs::write(root.join("a.txt.200.bak"), "in the way").unwrap();
+        let err = mgr.extract_archive("a.txt.archive.1000.tar.gz").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(!root.join("a.txt.100.bak").exists() && archive.exists());
+        fs::remove_file(root.join("a.txt.200.bak")).unwrap();
+
+        let extracted = mgr.extract_archive("a.txt.archive.1000.tar.gz").unwrap();
+        assert_eq!(extracted.len(), 6);
+        assert!(!archive.exists());
+        assert_eq!(mgr.list_backups("a.txt").unwrap(), before);
+        mgr.verify_backup("a.txt.100.bak").unwrap();
+        mgr.restore_file("a.txt.200.bak").unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "v200");
+        let actions: Vec<String> = mgr.read_log().unwrap().into_iter().map(|e| e.action).collect();
+        assert!(actions.ends_with(&["compact".into(), "extract".into(), "restore".into()]), "{actions:?}");
+    }
+}
@@ -0,0 +1,94 @@
+//! Optional compression of timestamped backups.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Context;
+
+/// How a timestamped backup's bytes are stored. Recorded in its sidecar so
+/// restore knows how to read it back; plain "<stem>.bak" copies are never compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" | "off" => Ok(Self::None),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            other => Err(format!("unknown compression {other:?} (expected none or gzip)")),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+        })
+    }
+}
+
+/// Write `from` gzip-compressed to the new file `to`; returns the uncompressed size.
+pub(crate) fn gzip_copy(op: &'static str, from: &Path, to: &Path) -> io::Result<u64> {
+    let mut input = BufReader::new(File::open(from).at(op, "cannot open", from)?);
+    gzip_from(&mut input, to).copying(op, from, to)
+}
+
+/// Write everything `input` yields gzip-compressed to the new file `to`;
+/// returns the uncompressed size. Errors carry no context.
+pub(crate) fn gzip_from(input: &mut dyn io::Read, to: &Path) -> io::Result<u64> {
+    let mut enc = GzEncoder::new(BufWriter::new(crate::vfs::create_private(to)?), Default::default());
+    let n = io::copy(input, &mut enc)?;
+    enc.finish().and_then(|mut w| io::Write::flush(&mut w))?;
+    Ok(n)
+}
+
+/// Decompress the gzip backup `from` into `to`, replacing it.
+pub(crate) fn gunzip_copy(op: &'static str, from: &Path, to: &Path) -> io::Result<u64> {
+    let mut dec = GzDecoder::new(BufReader::new(File::open(from).at(op, "cannot open", from)?));
+    let mut out = BufWriter::new(File::create(to).at(op, "cannot create", to)?);
+    let n = io::copy(&mut dec, &mut out).copying(op, from, to)?;
+    io::Write::flush(&mut out).copying(op, from, to)?;
+    Ok(n)
+}
+
+/// Decompress the gzip backup `from` into `out`; returns the bytes written.
+pub(crate) fn gunzip_to(op: &'static str, from: &Path, out: &mut dyn io::Write) -> io::Result<u64> {
+    let mut dec = GzDecoder::new(BufReader::new(File::open(from).at(op, "cannot open", from)?));
+    io::copy(&mut dec, out).at(op, "cannot stream", from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_and_round_trips() {
+        assert_eq!("GZ".parse::<Compression>(), Ok(Compression::Gzip));
+        assert_eq!(" none ".parse::<Compression>(), Ok(Compression::None));
+        assert!("zstd".parse::<Compression>().unwrap_err().contains("\"zstd\""));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let (src, gz, back) = (tmp.path().join("a"), tmp.path().join("a.gz"), tmp.path().join("b"));
+        std::fs::write(&src, "hello ".repeat(100)).unwrap();
+        assert_eq!(gzip_copy("backup", &src, &gz).unwrap(), 600);
+        assert!(std::fs::metadata(&gz).unwrap().len() < 600);
+        gunzip_copy("restore", &gz, &back).unwrap();
+        assert_eq!(std::fs::read(&back).unwrap(), std::fs::read(&src).unwrap());
+    }
+}
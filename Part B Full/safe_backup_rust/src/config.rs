@@ -0,0 +1,250 @@
+//! Tunable settings shared by all operations of a `BackupManager`.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cancel::CancellationToken;
+use crate::clock::{Clock, SystemClock, TimeZone};
+use crate::compress::Compression;
+use crate::digest::DigestAlgo;
+use crate::event::EventSink;
+use crate::hooks::HookOutput;
+use crate::log::LogUser;
+use crate::retry::RetryPolicy;
+use crate::validate::NameLimits;
+
+/// Settings for a `BackupManager`. `Config::default()` reproduces the classic behavior.
+#[derive(Clone)]
+pub struct Config {
+    /// Directory names are resolved against; `None` means the current directory.
+    pub root: Option<PathBuf>,
+    /// Where backups are written and looked up, relative to `root` unless
+    /// absolute; `None` means `root` itself. Created on first backup.
+    pub backup_dir: Option<PathBuf>,
+    /// Log file, relative to `root` unless absolute; `None` means "logfile.txt" in `root`
+    /// (or in ".safe_backup" there with `tidy`).
+    pub log_file: Option<PathBuf>,
+    /// Keep the log at ".safe_backup/logfile.txt" under `root` instead of in
+    /// `root` itself, creating the directory on demand. `log_file` takes precedence.
+    pub tidy: bool,
+    /// Rotate the log once it grows past this many bytes: it becomes the
+    /// segment "<log>.<n>", gzipped to "<log>.<n>.gz"; see
+    /// [`BackupManager::prune_log`](crate::BackupManager::prune_log). `None` never rotates.
+    pub log_max_bytes: Option<u64>,
+    /// Keep only this many gzipped log segments, removing the oldest (each
+    /// removal is logged). `None` keeps them all.
+    pub log_archives: Option<usize>,
+    /// Keep timestamped backups and their sidecars in ".safe_backup/backups"
+    /// under `root`, so only the plain copies stay next to the originals.
+    /// `backup_dir` takes precedence. Listing and restoring still find backups
+    /// made into `root` before; [`BackupManager::migrate_layout`](crate::BackupManager::migrate_layout)
+    /// moves them.
+    pub tidy_backups: bool,
+    /// Refresh the plain "<stem>.bak" copy with each file backup; see
+    /// [`BackupOptions::plain_copy`](crate::BackupOptions::plain_copy) for one call.
+    pub plain_copy: bool,
+    /// Retry policy for copy/rename steps.
+    pub retry: RetryPolicy,
+    /// Name written to the log's `user` field; falls back to `$SAFE_BACKUP_USER`, then `whoami`.
+    pub actor: Option<String>,
+    /// Whether that name is logged as it is, pseudonymized or not at all.
+    pub log_user: LogUser,
+    /// Zone timestamps are shown in as dates; they stay Unix seconds otherwise.
+    pub time_zone: TimeZone,
+    /// Record the log's length after each write and refuse to write to it
+    /// once it is found shorter (see [`BackupError::LogTruncated`](crate::BackupError::LogTruncated));
+    /// [`verify_log_chain`](crate::BackupManager::verify_log_chain) checks it too.
+    /// Rotation is off and [`repair_log`](crate::BackupManager::repair_log) refused while it is set.
+    pub log_audit: bool,
+    /// With `log_audit`, try to set the append-only attribute on a new log
+    /// (Linux, with `CAP_LINUX_IMMUTABLE` and a file system that has the
+    /// attribute; without them the log stays as it is, other failures are errors).
+    pub log_append_only: bool,
+    /// Unix permissions given to file backups, plain copies and a new log,
+    /// narrowed to the source's for a backup; `None` leaves backups with
+    /// their source's. Sidecars and other files the tool writes are always
+    /// created owner-only.
+    pub file_mode: Option<u32>,
+    /// Unix permissions given to the directories the tool creates: the
+    /// backup directory, ".safe_backup" and the log's; `None` leaves the umask's.
+    pub dir_mode: Option<u32>,
+    /// Maximum component/path lengths accepted by validation.
+    pub limits: NameLimits,
+    /// Reject Windows device names (`CON`, `aux.txt`, ...) on every platform
+    /// instead of only warning via `Event::ReservedName`. Always rejected on Windows.
+    pub reject_reserved_names: bool,
+    /// Name the plain backup "<name>.bak" instead of "<stem>.bak".
+    pub plain_keep_extension: bool,
+    /// Try a copy-on-write reflink before copying (needs the `reflink` feature;
+    /// falls back to a normal copy where unsupported or cross-filesystem).
+    pub reflink: bool,
+    /// Optional receiver for progress events (retries, ...).
+    pub on_event: Option<EventSink>,
+    /// After each file backup, remove that original's timestamped backups older
+    /// than this many seconds. The backup just made is never removed.
+    pub max_age_secs: Option<u64>,
+    /// Refuse to back up a file modified less than this many seconds ago,
+    /// with `BackupError::TooRecent`, so a file still being written isn't
+    /// caught half done. Scheduled cycles leave such files for the next one;
+    /// other batches and scripts count them as skipped, and directory backups
+    /// leave them out of the tree.
+    pub min_age_secs: Option<u64>,
+    /// After each file backup, keep only this many timestamped backups of that
+    /// original. Applied together with `max_age_secs`: a backup goes if either says so.
+    pub keep_last: Option<usize>,
+    /// How timestamped backups are stored; see [`BackupOptions::compress`](crate::BackupOptions::compress).
+    pub compression: Compression,
+    /// Hash recorded in sidecars and resume journals. Verification uses the
+    /// algorithm recorded with each digest, whatever this is now.
+    pub digest: DigestAlgo,
+    /// Store timestamped backups of files larger than this many bytes as
+    /// pieces of this size with an index (see [`DEFAULT_CHUNK_SIZE`](crate::DEFAULT_CHUNK_SIZE));
+    /// `None` stores every backup as one file.
+    pub chunk_size: Option<u64>,
+    /// Store file backups in the content-addressed object store of the backup
+    /// directory, so pieces repeated across backups are kept once. Takes
+    /// precedence over `compression` and `chunk_size`; see [`BackupManager::gc`](crate::BackupManager::gc).
+    pub dedup: bool,
+    /// Also treat backups of the old tool, "<name>.YYYYMMDDHHMMSS.bk" and
+    /// "<name>.bk", as backups of `<name>` when listing, restoring and pruning;
+    /// see [`BackupManager::migrate_legacy`](crate::BackupManager::migrate_legacy).
+    pub legacy_bk: bool,
+    /// Source of "now" for timestamps, the log, `max_age_secs` and `min_age_secs`.
+    pub clock: Arc<dyn Clock>,
+    /// Back up FIFOs, sockets and device files like regular files instead of
+    /// failing with `BackupError::UnsupportedFileType`. Reading a FIFO blocks
+    /// until its writer closes it.
+    pub allow_special_files: bool,
+    /// Read each copied backup back and check it against the digest taken
+    /// while copying, failing with `BackupError::Corrupt` on a mismatch. Only
+    /// the backup is re-read; the source is read once either way.
+    pub verify_writes: bool,
+    /// Hash a file to tell whether it changed since its latest backup even
+    /// when its size and mtime match the backup's (see `check_changed`).
+    pub paranoid: bool,
+    /// Record each file's extended attributes in its sidecar and copy them onto
+    /// the backups; restores put recorded attributes back. Needs the `xattrs`
+    /// feature on Unix, else every backup warns with `Warning::Xattrs`.
+    pub preserve_xattrs: bool,
+    /// Record each file's POSIX access ACL (entries for named users and
+    /// groups) in its sidecar; restores put it back. Needs the `acls` feature
+    /// on Linux, else every backup warns with `Warning::Acl`.
+    pub preserve_acls: bool,
+    /// Gitignore-style patterns left out of every directory backup (e.g.
+    /// `target/`, `*.tmp`, `.git/`); see [`DirOptions::exclude`](crate::DirOptions::exclude).
+    pub exclude: Vec<String>,
+    /// Cancel it (e.g. from a Ctrl-C handler) to stop backups, restores,
+    /// directory operations and verification with `BackupError::Cancelled`.
+    pub cancel: Option<CancellationToken>,
+    /// Give up on a file or directory backup or restore taking longer than
+    /// this, with `BackupError::TimedOut`; what it wrote is removed.
+    pub timeout: Option<Duration>,
+    /// Program run before each file backup, restore and delete, with the
+    /// action and the file's path as arguments; if it fails, so does the
+    /// action, with `BackupError::HookFailed`.
+    pub pre_hook: Option<PathBuf>,
+    /// Program run the same way after each of them succeeds; if it fails,
+    /// that is logged and reported as `Warning::PostHook`.
+    pub post_hook: Option<PathBuf>,
+    /// Where the hooks' output goes: `Config::on_event` (the default), the
+    /// tool's own stdout and stderr, or nowhere.
+    pub hook_output: HookOutput,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            root: None,
+            backup_dir: None,
+            log_file: None,
+            tidy: false,
+            log_max_bytes: None,
+            log_archives: None,
+            tidy_backups: false,
+            plain_copy: true,
+            retry: RetryPolicy::default(),
+            actor: None,
+            log_user: LogUser::Full,
+            time_zone: TimeZone::Local,
+            log_audit: false,
+            log_append_only: false,
+            file_mode: Some(0o600),
+            dir_mode: Some(0o700),
+            limits: NameLimits::default(),
+            reject_reserved_names: false,
+            plain_keep_extension: false,
+            reflink: true,
+            on_event: None,
+            max_age_secs: None,
+            min_age_secs: None,
+            keep_last: None,
+            compression: Compression::None,
+            digest: DigestAlgo::Sha256,
+            chunk_size: None,
+            dedup: false,
+            legacy_bk: false,
+            clock: Arc::new(SystemClock),
+            allow_special_files: false,
+            verify_writes: false,
+            paranoid: false,
+            preserve_xattrs: false,
+            preserve_acls: false,
+            exclude: Vec::new(),
+            cancel: None,
+            timeout: None,
+            pre_hook: None,
+            post_hook: None,
+            hook_output: HookOutput::Events,
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("root", &self.root)
+            .field("backup_dir", &self.backup_dir)
+            .field("log_file", &self.log_file)
+            .field("tidy", &self.tidy)
+            .field("log_max_bytes", &self.log_max_bytes)
+            .field("log_archives", &self.log_archives)
+            .field("tidy_backups", &self.tidy_backups)
+            .field("plain_copy", &self.plain_copy)
+            .field("retry", &self.retry)
+            .field("actor", &self.actor)
+            .field("log_user", &self.log_user)
+            .field("time_zone", &self.time_zone)
+            .field("log_audit", &self.log_audit)
+            .field("log_append_only", &self.log_append_only)
+            .field("file_mode", &self.file_mode.map(|m| format!("{m:o}")))
+            .field("dir_mode", &self.dir_mode.map(|m| format!("{m:o}")))
+            .field("limits", &self.limits)
+            .field("reject_reserved_names", &self.reject_reserved_names)
+            .field("plain_keep_extension", &self.plain_keep_extension)
+            .field("reflink", &self.reflink)
+            .field("on_event", &self.on_event.as_ref().map(|_| "<sink>"))
+            .field("max_age_secs", &self.max_age_secs)
+            .field("min_age_secs", &self.min_age_secs)
+            .field("keep_last", &self.keep_last)
+            .field("compression", &self.compression)
+            .field("digest", &self.digest)
+            .field("chunk_size", &self.chunk_size)
+            .field("dedup", &self.dedup)
+            .field("legacy_bk", &self.legacy_bk)
+            .field("clock", &self.clock.now_unix())
+            .field("allow_special_files", &self.allow_special_files)
+            .field("verify_writes", &self.verify_writes)
+            .field("paranoid", &self.paranoid)
+            .field("preserve_xattrs", &self.preserve_xattrs)
+            .field("preserve_acls", &self.preserve_acls)
+            .field("exclude", &self.exclude)
+            .field("cancel", &self.cancel)
+            .field("timeout", &self.timeout)
+            .field("pre_hook", &self.pre_hook)
+            .field("post_hook", &self.post_hook)
+            .field("hook_output", &self.hook_output)
+            .finish()
+    }
+}
@@ -0,0 +1,98 @@
+//! What a restore does when its destination is already there
+//! ([`RestoreOptions::on_conflict`](crate::RestoreOptions::on_conflict)).
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::digest::read_digest;
+use crate::error::{BackupError, Context};
+use crate::schedule::content_digest;
+use crate::{BackupManager, RestoreOutcome};
+
+/// How a restore treats an existing file at its destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Replace it.
+    #[default]
+    Overwrite,
+    /// Leave it, restoring nothing.
+    Skip,
+    /// Move it to "<name>.pre-restore.<ts>" first.
+    RenameExisting,
+    /// Fail with `BackupError::DestinationExists`, unless it already holds
+    /// what the backup does, in which case it is left as it is.
+    Fail,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::Skip),
+            "rename" => Ok(Self::RenameExisting),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!("unknown conflict strategy {s:?} (expected overwrite, skip, rename or fail)")),
+        }
+    }
+}
+
+impl fmt::Display for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Overwrite => "overwrite",
+            Self::Skip => "skip",
+            Self::RenameExisting => "rename",
+            Self::Fail => "fail",
+        })
+    }
+}
+
+/// What a restore of `source` over `dest` comes to under a strategy, before anything is written.
+pub(crate) enum Resolution {
+    /// Write the file; the outcome it will have.
+    Write(RestoreOutcome),
+    /// Leave `dest` as it is.
+    Leave(RestoreOutcome),
+}
+
+impl BackupManager {
+    pub(crate) fn resolve_conflict(
+        &self,
+        strategy: ConflictStrategy,
+        source: &Path,
+        dest: &Path,
+    ) -> io::Result<Resolution> {
+        if !self.fs.exists(dest) {
+            return Ok(Resolution::Write(RestoreOutcome::Restored));
+        }
+        Ok(match strategy {
+            ConflictStrategy::Overwrite => Resolution::Write(RestoreOutcome::Overwritten),
+            ConflictStrategy::Skip => Resolution::Leave(RestoreOutcome::Skipped),
+            ConflictStrategy::RenameExisting => Resolution::Write(RestoreOutcome::RenamedExisting),
+            ConflictStrategy::Fail => {
+                let (algo, expected) = content_digest(&*self.fs, source, self.config.digest)?;
+                let found = self.fs.open(dest).and_then(|f| read_digest(f, algo)).at("restore", "cannot read", dest)?;
+                if found != expected {
+                    return Err(BackupError::DestinationExists { path: dest.to_path_buf() }.into());
+                }
+                Resolution::Leave(RestoreOutcome::Unchanged)
+            }
+        })
+    }
+
+    /// Move the file at `dest` out of a restore's way, to "<dest>.pre-restore.<ts>"; returns where.
+    pub(crate) fn move_aside(&self, dest: &Path) -> io::Result<PathBuf> {
+        let mut aside = dest.as_os_str().to_owned();
+        aside.push(format!(".pre-restore.{}", self.now()));
+        let aside = PathBuf::from(aside);
+        if self.fs.exists(&aside) {
+            return Err(BackupError::Foreign { op: "restore", path: aside }.into());
+        }
+        self.fs.rename(dest, &aside).copying("restore", dest, &aside)?;
+        Ok(aside)
+    }
+}
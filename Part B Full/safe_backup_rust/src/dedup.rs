@@ -0,0 +1,375 @@
+//! Content-addressed store for `Config::dedup`. A backup's contents are cut
+//! into fixed-size pieces, each kept once as ".safe_backup/objects/<hex>"
+//! (named by its digest) in the backup directory, and the backup file itself
+//! becomes a small JSON manifest listing them. Backups of a file that changes
+//! a little then share all but the changed pieces.
+//!
+//! Removing a backup leaves its objects; [`BackupManager::gc`] deletes the
+//! ones no manifest refers to. Backups hold a shared lock on the store while
+//! they write, gc an exclusive one, so gc never takes an object a backup in
+//! progress is about to list.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+use crate::digest::{DigestAlgo, Hasher};
+use crate::error::{error_chain, missing, BackupError, Context};
+use crate::format::{parse_versioned, unversioned, FORMAT_VERSION};
+use crate::meta::{read_backup_meta, Layout};
+use crate::naming::split_backup_name;
+use crate::BackupManager;
+
+/// Size of the pieces contents are cut into.
+pub(crate) const DEDUP_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The store, in the backup directory.
+pub(crate) const STORE_DIR: &str = ".safe_backup";
+const LOCK_FILE: &str = "store.lock";
+
+/// Contents of a deduplicated backup file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Format the manifest is written in; 1 in manifests from before the field.
+    #[serde(default = "unversioned")]
+    pub format_version: u32,
+    pub chunk_size: usize,
+    /// Size of the original contents.
+    pub size: u64,
+    /// Tagged digest of the original contents, checked on every restore.
+    pub digest: String,
+    /// Tagged digests of the pieces, in order; each names an object.
+    pub chunks: Vec<String>,
+}
+
+/// ".safe_backup/objects" in the backup directory `broot`.
+pub(crate) fn objects_dir(broot: &Path) -> PathBuf {
+    broot.join(STORE_DIR).join("objects")
+}
+
+/// The object holding the piece with tagged digest `digest`. Backups sit
+/// directly in the backup directory, so the store is next to `backup`.
+fn object_for(backup: &Path, digest: &str) -> PathBuf {
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    objects_dir(backup.parent().unwrap_or(Path::new(""))).join(hex)
+}
+
+/// Bytes taken by the objects of the store in `broot`; 0 without a store.
+pub(crate) fn store_size(broot: &Path) -> io::Result<u64> {
+    let dir = objects_dir(broot);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(&dir).at("stats", "cannot list directory", &dir)? {
+        total += entry.and_then(|e| e.metadata()).at("stats", "cannot stat", &dir)?.len();
+    }
+    Ok(total)
+}
+
+/// Open the store's lock file in `broot`, creating the store if needed.
+fn lock_file(op: &'static str, broot: &Path) -> io::Result<File> {
+    let dir = objects_dir(broot);
+    fs::create_dir_all(&dir).at(op, "cannot create object store", &dir)?;
+    let path = broot.join(STORE_DIR).join(LOCK_FILE);
+    File::create(&path).at(op, "cannot create lock file", &path)
+}
+
+/// Store `from` as the deduplicated backup `backup`: pieces missing from the
+/// store are added, then the manifest is written. Returns the size of `from`.
+/// If this fails, the objects already added stay for the next backup or gc.
+pub(crate) fn write_deduped(op: &'static str, from: &Path, backup: &Path, algo: DigestAlgo) -> io::Result<u64> {
+    let broot = backup.parent().unwrap_or(Path::new(""));
+    let lock = lock_file(op, broot)?;
+    lock.lock_shared().at(op, "cannot lock object store in", broot)?;
+    let mut input = File::open(from).at(op, "cannot open", from)?;
+    let mut whole = Hasher::new(algo);
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    let mut buf = vec![0u8; DEDUP_CHUNK_SIZE];
+    loop {
+        let n = fill(&mut input, &mut buf).at(op, "cannot read", from)?;
+        if n == 0 {
+            break;
+        }
+        let piece = &buf[..n];
+        whole.write_all(piece)?;
+        let mut h = Hasher::new(algo);
+        h.write_all(piece)?;
+        let digest = h.finish();
+        store_object(op, &object_for(backup, &digest), piece)?;
+        chunks.push(digest);
+        size += n as u64;
+    }
+    let digest = whole.finish();
+    let manifest = Manifest { format_version: FORMAT_VERSION, chunk_size: DEDUP_CHUNK_SIZE, size, digest, chunks };
+    let json = serde_json::to_string(&manifest).map_err(io::Error::other).at(op, "cannot write", backup)?;
+    fs::write(backup, json).at(op, "cannot write", backup)?;
+    let _ = FileExt::unlock(&lock);
+    Ok(size)
+}
+
+/// Read from `r` until `buf` is full or the input ends; returns the bytes read.
+fn fill(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Add `piece` as `object` unless the store has it; written under a
+/// temporary name first, so an object is always complete.
+fn store_object(op: &'static str, object: &Path, piece: &[u8]) -> io::Result<()> {
+    if object.is_file() {
+        return Ok(());
+    }
+    let tmp = crate::vfs::unique_temp(object);
+    crate::vfs::create_private(&tmp)
+        .and_then(|mut f| f.write_all(piece))
+        .at(op, "cannot write", &tmp)
+        .and_then(|_| fs::rename(&tmp, object).copying(op, &tmp, object))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
+
+/// The manifest of the deduplicated backup `backup`.
+pub(crate) fn read_manifest(op: &'static str, backup: &Path) -> io::Result<Manifest> {
+    let text = fs::read_to_string(backup).at(op, "cannot read manifest", backup)?;
+    parse_versioned(op, "malformed manifest", backup, &text)
+}
+
+/// The objects listed by the manifest `backup`, in order (with repeats).
+pub(crate) fn objects_of(op: &'static str, backup: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(read_manifest(op, backup)?.chunks.iter().map(|d| object_for(backup, d)).collect())
+}
+
+/// Write the pieces of `manifest` in order to `out`. A missing object is a `Missing` error.
+fn stream(op: &'static str, backup: &Path, manifest: &Manifest, out: &mut dyn Write) -> io::Result<u64> {
+    let mut n = 0;
+    for digest in &manifest.chunks {
+        let object = object_for(backup, digest);
+        let mut f = match File::open(&object) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(missing(op, "object not found", &object)),
+            r => r.at(op, "cannot open", &object)?,
+        };
+        n += io::copy(&mut f, out).at(op, "cannot stream", &object)?;
+    }
+    Ok(n)
+}
+
+/// Reassemble `backup` and check it against the digest in its manifest; a
+/// mismatch is `BackupError::Corrupt`.
+pub(crate) fn verify_deduped(op: &'static str, backup: &Path) -> io::Result<Manifest> {
+    let manifest = read_manifest(op, backup)?;
+    let algo = DigestAlgo::of(&manifest.digest)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .at(op, "malformed manifest", backup)?;
+    let mut h = Hasher::new(algo);
+    stream(op, backup, &manifest, &mut h)?;
+    let actual = h.finish();
+    if actual != manifest.digest {
+        return Err(BackupError::Corrupt { path: backup.to_path_buf(), expected: manifest.digest, actual }.into());
+    }
+    Ok(manifest)
+}
+
+/// Verify `backup` (see [`verify_deduped`]), then write its contents to `out`.
+pub(crate) fn read_to(op: &'static str, backup: &Path, out: &mut dyn Write) -> io::Result<u64> {
+    let manifest = verify_deduped(op, backup)?;
+    stream(op, backup, &manifest, out)
+}
+
+/// Restore the deduplicated backup `backup` into `to`. Nothing is written
+/// unless the reassembled contents match the manifest's digest.
+pub(crate) fn restore_to(op: &'static str, backup: &Path, to: &Path) -> io::Result<u64> {
+    let manifest = verify_deduped(op, backup)?;
+    let mut out = BufWriter::new(File::create(to).at(op, "cannot create", to)?);
+    let n = stream(op, backup, &manifest, &mut out)?;
+    out.flush().copying(op, backup, to)?;
+    Ok(n)
+}
+
+/// Outcome of [`BackupManager::gc`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Objects removed (with `dry_run`, that would be removed).
+    pub removed: Vec<PathBuf>,
+    /// Their total size in bytes.
+    pub bytes: u64,
+    /// Objects still referenced by some backup.
+    pub kept: usize,
+}
+
+impl BackupManager {
+    /// Remove the objects of the dedup store no backup's manifest lists any
+    /// more, and leftovers of interrupted writes. With `dry_run`, only report
+    /// them. A manifest that can't be read stops gc before anything is removed,
+    /// and so does a backup writing to the store (ResourceBusy).
+    pub fn gc(&self, dry_run: bool) -> io::Result<GcReport> {
+        let root = self.root()?;
+        let broot = self.backup_root(&root);
+        let objects = objects_dir(&broot);
+        let mut report = GcReport::default();
+        if !objects.is_dir() {
+            return Ok(report);
+        }
+        let lock = lock_file("gc", &broot)?;
+        if lock.try_lock_exclusive().is_err() {
+            let e = io::Error::new(io::ErrorKind::ResourceBusy, "a backup is writing to the store");
+            return Err(e).at("gc", "cannot lock object store in", &broot);
+        }
+        let referenced = referenced_objects(&broot)?;
+        for entry in fs::read_dir(&objects).at("gc", "cannot list directory", &objects)? {
+            let entry = entry.at("gc", "cannot list directory", &objects)?;
+            let path = entry.path();
+            if referenced.contains(&path) {
+                report.kept += 1;
+                continue;
+            }
+            report.bytes += entry.metadata().map_or(0, |m| m.len());
+            if !dry_run {
+                fs::remove_file(&path).at("gc", "cannot remove", &path)?;
+            }
+            report.removed.push(path);
+        }
+        let _ = FileExt::unlock(&lock);
+        if !dry_run && !report.removed.is_empty() {
+            let result = format!("ok ({} removed, {} bytes)", report.removed.len(), report.bytes);
+            self.log_action(&root, "gc", Path::new("*"), &result)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Every object listed by a manifest among the marked "<name>.<ts>.bak" files in `broot`.
+fn referenced_objects(broot: &Path) -> io::Result<HashSet<PathBuf>> {
+    let mut referenced = HashSet::new();
+    for entry in fs::read_dir(broot).at("gc", "cannot list directory", broot)? {
+        let entry = entry.at("gc", "cannot list directory", broot)?;
+        let Some((_, Some(_))) = split_backup_name(&entry.file_name()) else { continue };
+        let path = entry.path();
+        match read_backup_meta(&path) {
+            Ok(meta) if meta.layout == Layout::Deduped => {
+                referenced.extend(objects_of("gc", &path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{}; nothing was removed", error_chain(&e)))
+                })?);
+            }
+            _ => {}
+        }
+    }
+    Ok(referenced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::Config;
+
+    fn manager(root: &Path, now: u64) -> BackupManager {
+        BackupManager::new(Config { dedup: true, ..config(root, now) })
+    }
+
+    /// Three pieces' worth of bytes with `tail` in the last one.
+    fn contents(tail: &str) -> Vec<u8> {
+        let mut data = vec![b'x'; 2 * DEDUP_CHUNK_SIZE];
+        data.extend_from_slice(tail.as_bytes());
+        data
+    }
+
+    fn object_count(root: &Path) -> usize {
+        fs::read_dir(objects_dir(root)).unwrap().count()
+    }
+
+    #[test]
+    fn similar_backups_share_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("app.conf"), contents("v1")).unwrap();
+        let first = manager(root, 100).backup_file("app.conf").unwrap();
+        // Two identical pieces of 'x' are one object, plus the tail.
+        assert_eq!(object_count(root), 2);
+        fs::write(root.join("app.conf"), contents("v2")).unwrap();
+        let mgr = manager(root, 200);
+        mgr.backup_file("app.conf").unwrap();
+        assert_eq!(object_count(root), 3);
+        assert_eq!(read_manifest("test", &first).unwrap().chunks.len(), 3);
+
+        mgr.restore_file_with("app.conf", &crate::RestoreOptions::new().version(100)).unwrap();
+        assert_eq!(fs::read(root.join("app.conf")).unwrap(), contents("v1"));
+        let mut out = Vec::new();
+        mgr.cat_backup("app.conf", None, &mut out).unwrap();
+        assert_eq!(out, contents("v2"));
+        mgr.verify_backup(first.file_name().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn damaged_or_missing_objects_stop_the_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("app.conf"), contents("v1")).unwrap();
+        let mgr = manager(root, 100);
+        let bak = mgr.backup_file("app.conf").unwrap();
+        fs::write(root.join("app.conf"), "live").unwrap();
+        let manifest = read_manifest("test", &bak).unwrap();
+        let tail = object_for(&bak, &manifest.chunks[2]);
+
+        fs::write(&tail, "v9").unwrap();
+        let e = mgr.restore_file("app.conf").unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::Corrupt { .. })), "{e}");
+        fs::remove_file(&tail).unwrap();
+        let e = mgr.restore_file("app.conf").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs::read_to_string(root.join("app.conf")).unwrap(), "live");
+    }
+
+    #[test]
+    fn gc_removes_only_unreferenced_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("app.conf"), contents("v1")).unwrap();
+        manager(root, 100).backup_file("app.conf").unwrap();
+        fs::write(root.join("app.conf"), contents("v2")).unwrap();
+        let mgr = manager(root, 200);
+        mgr.backup_file("app.conf").unwrap();
+        fs::write(objects_dir(root).join("leftover.tmp"), "x").unwrap();
+
+        mgr.prune_backups("app.conf", 1, false).unwrap();
+        let dry = mgr.gc(true).unwrap();
+        assert_eq!((dry.removed.len(), dry.kept), (2, 2));
+        assert_eq!(object_count(root), 4);
+        let done = mgr.gc(false).unwrap();
+        assert_eq!(done, dry);
+        assert_eq!(object_count(root), 2);
+        let mut out = Vec::new();
+        mgr.cat_backup("app.conf", None, &mut out).unwrap();
+        assert_eq!(out, contents("v2"));
+
+        fs::write(root.join("app.conf.300.bak"), "not json").unwrap();
+        crate::meta::write_meta_for(
+            &crate::vfs::RealFs,
+            "backup",
+            &root.join("app.conf.300.bak"),
+            Path::new("app.conf"),
+            &root.join("app.conf"),
+            crate::Compression::None,
+            DigestAlgo::Sha256,
+            Layout::Deduped,
+            None,
+        )
+        .unwrap();
+        assert_eq!(mgr.gc(false).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(object_count(root), 2);
+    }
+}
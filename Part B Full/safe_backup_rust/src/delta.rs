@@ -0,0 +1,353 @@
+//! Append-only deltas for files that only ever grow at the end, like logs.
+//! When a file still starts with exactly the contents of its latest backup,
+//! [`BackupManager::backup_append_delta`] stores just the bytes after them as
+//! "<name>.<ts>.delta", whose sidecar names that backup as its `base`.
+//! Restoring one reads the base (itself possibly a delta, down to a full
+//! backup) and appends each tail in turn.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compress::{self, Compression};
+use crate::digest::{file_digest, tagged, DigestAlgo, Hasher};
+use crate::error::{missing, BackupError, Context};
+use crate::meta::{
+    is_tool_backup, read_backup_meta, read_meta, record_base, sidecar_for, write_meta_for, BackupMeta, Layout,
+};
+use crate::naming::{delta_backup_for, logical_name};
+use crate::schedule::content_digest;
+use crate::validate::{check_backup_name, special_file_kind};
+use crate::vfs::{unique_temp, FileStat, RealFs};
+use crate::{chunk, dedup, newest_ts_backup, BackupManager, BackupOutcome, BackupReport};
+
+/// The backup the delta `backup` extends, from its sidecar `meta`.
+fn base_of(op: &'static str, backup: &Path, meta: &BackupMeta) -> io::Result<PathBuf> {
+    let name = meta.base.as_deref().ok_or_else(|| missing(op, "no base recorded for delta", backup))?;
+    Ok(backup.with_file_name(name))
+}
+
+/// The bases `backup` depends on, nearest first; empty unless it is a delta.
+pub(crate) fn bases_of(op: &'static str, backup: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut bases = Vec::new();
+    let mut current = backup.to_path_buf();
+    while let Ok(meta) = read_backup_meta(&current) {
+        if meta.layout != Layout::Delta {
+            break;
+        }
+        current = base_of(op, &current, &meta)?;
+        if bases.contains(&current) || current == backup {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "delta chain loops")).at(op, "cannot read", backup);
+        }
+        bases.push(current.clone());
+    }
+    Ok(bases)
+}
+
+/// `doomed` without the backups that one of `all` outside it builds on, so
+/// removing the rest leaves every remaining delta restorable.
+pub(crate) fn spare_bases(all: &[PathBuf], doomed: Vec<PathBuf>) -> Vec<PathBuf> {
+    let needed: HashSet<PathBuf> = all
+        .iter()
+        .filter(|p| !doomed.contains(p))
+        .flat_map(|p| bases_of("prune", p).unwrap_or_default())
+        .collect();
+    doomed.into_iter().filter(|p| !needed.contains(p)).collect()
+}
+
+/// Check every link from the full backup at the bottom up to the delta
+/// `backup`: each base must exist, and each tail must match its recorded
+/// digest and length. Returns the chain, full backup first.
+pub(crate) fn verify_chain(op: &'static str, backup: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut chain = bases_of(op, backup)?;
+    chain.reverse();
+    chain.push(backup.to_path_buf());
+    if !chain[0].is_file() {
+        return Err(missing(op, "base of delta not found", &chain[0]));
+    }
+    let mut prev_size = read_backup_meta(&chain[0]).map_or(0, |m| m.size);
+    for delta in &chain[1..] {
+        if !delta.is_file() {
+            return Err(missing(op, "base of delta not found", delta));
+        }
+        let meta = read_backup_meta(delta)?;
+        let expected = tagged(meta.digest.as_deref().ok_or_else(|| missing(op, "no digest recorded for", delta))?);
+        let algo = DigestAlgo::of(&expected)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .at(op, "malformed digest for", delta)?;
+        let actual = file_digest(delta, algo).at(op, "cannot read", delta)?;
+        let len = delta.metadata().at(op, "cannot stat", delta)?.len();
+        if actual != expected || prev_size.checked_add(len) != Some(meta.size) {
+            return Err(BackupError::Corrupt { path: delta.clone(), expected, actual }.into());
+        }
+        prev_size = meta.size;
+    }
+    Ok(chain)
+}
+
+/// Write the contents of the delta `backup` to `out`, after checking its chain.
+pub(crate) fn read_to(op: &'static str, backup: &Path, out: &mut dyn Write) -> io::Result<u64> {
+    let chain = verify_chain(op, backup)?;
+    stream(op, &chain, out)
+}
+
+/// Restore the delta `backup` into `to`. Nothing is written unless every
+/// link of its chain is present and intact.
+pub(crate) fn restore_to(op: &'static str, backup: &Path, to: &Path) -> io::Result<u64> {
+    let chain = verify_chain(op, backup)?;
+    let mut out = BufWriter::new(File::create(to).at(op, "cannot create", to)?);
+    let n = stream(op, &chain, &mut out)?;
+    out.flush().copying(op, backup, to)?;
+    Ok(n)
+}
+
+/// The full backup at the start of `chain`, then each tail.
+fn stream(op: &'static str, chain: &[PathBuf], out: &mut dyn Write) -> io::Result<u64> {
+    let mut n = contents_to(op, &chain[0], out)?;
+    for delta in &chain[1..] {
+        let mut f = File::open(delta).at(op, "cannot open", delta)?;
+        n += io::copy(&mut f, out).at(op, "cannot stream", delta)?;
+    }
+    Ok(n)
+}
+
+/// The contents of the full backup at the bottom of a chain.
+fn contents_to(op: &'static str, backup: &Path, out: &mut dyn Write) -> io::Result<u64> {
+    let (compression, layout) = if is_tool_backup(&RealFs, backup) {
+        let meta = read_backup_meta(backup)?;
+        (meta.compression, meta.layout)
+    } else {
+        (Compression::None, Layout::Whole)
+    };
+    match (layout, compression) {
+        (Layout::Deduped, _) => dedup::read_to(op, backup, out),
+        (Layout::Chunked, _) => chunk::read_to(op, backup, compression, out),
+        (Layout::Delta, _) => read_to(op, backup, out),
+        (Layout::Whole, Compression::None) => {
+            let mut f = File::open(backup).at(op, "cannot open", backup)?;
+            io::copy(&mut f, out).at(op, "cannot stream", backup)
+        }
+        (Layout::Whole, Compression::Gzip) => compress::gunzip_to(op, backup, out),
+    }
+}
+
+impl BackupManager {
+    /// Back up `name`, storing only what was appended since its latest
+    /// backup when the file merely grew: its first bytes must hash the same
+    /// as that backup's contents. Anything else, a file that was truncated
+    /// or rewritten, one without a previous backup or a special file, gets
+    /// a full backup as [`backup_file`](Self::backup_file) makes it. The
+    /// report's `path` ends in ".delta" or ".bak" accordingly, and its
+    /// `bytes` counts only the tail for a delta.
+    pub fn backup_append_delta(&self, name: impl AsRef<Path>) -> io::Result<BackupReport> {
+        let name = name.as_ref();
+        let root = self.root()?;
+        let src = self.resolve(&root, name)?;
+        let rel = src.strip_prefix(&root).unwrap_or(&src).to_path_buf();
+        let (name, logical) = (rel.as_path(), logical_name(&rel));
+        let broot = self.backup_root(&root);
+        let ts = self.now();
+        let delta = delta_backup_for(&broot, name, ts)?;
+        let fs = &*self.fs;
+        let is_file = fs.metadata(&src).is_ok_and(|m| m.is_file());
+        let latest = if is_file && special_file_kind(fs, "backup", &src)?.is_none() {
+            newest_ts_backup(fs, &broot, name, false, FileStat::is_file)?
+        } else {
+            None
+        };
+        let offset = match latest {
+            Some(base) if base != delta => self.appended_since(&base, &src)?.map(|n| (base, n)),
+            _ => None,
+        };
+        let Some((base, offset)) = offset else { return self.backup_file_report(name) };
+        self.check_min_age(&src)?;
+        check_backup_name(&sidecar_for(&delta), &self.config.limits)?;
+        self.check_overwrite("backup", &root, name, &delta)?;
+        let before = fs.metadata(&src).at("backup", "cannot stat", &src)?;
+        let mut input = fs.open(&src).at("backup", "cannot open", &src)?;
+        let skipped = io::copy(&mut (&mut input).take(offset), &mut io::sink()).at("backup", "cannot read", &src)?;
+        if skipped < offset {
+            // Shrunk since it was checked: take it as rewritten.
+            return self.backup_file_report(name);
+        }
+        // Written beside `delta` and renamed into place, so a failed copy leaves no half-written delta.
+        let tmp = unique_temp(&delta);
+        let written = fs.create(&tmp).at("backup", "cannot create", &tmp).and_then(|mut out| {
+            let copied = io::copy(&mut input, &mut out).and_then(|n| out.flush().map(|_| n));
+            copied.copying("backup", &src, &tmp)
+        });
+        let bytes = written
+            .and_then(|n| fs.rename(&tmp, &delta).copying("backup", &tmp, &delta).map(|_| n))
+            .inspect_err(|_| {
+                let _ = fs.remove_file(&tmp);
+            })?;
+        let src_mode = fs.metadata(&src).at("backup", "cannot stat", &src)?.mode;
+        self.restrict("backup", &delta, Some(src_mode))?;
+        let (digest, layout) = (self.config.digest, Layout::Delta);
+        write_meta_for(fs, "backup", &delta, name, &src, Compression::None, digest, layout, Some(&before))?;
+        record_base(fs, "backup", &delta, &base, offset + bytes)?;
+        let mut warnings = Vec::new();
+        self.log_or_warn(&root, "backup", Path::new(&logical), "ok", &mut warnings);
+        if self.config.max_age_secs.is_some() || self.config.keep_last.is_some() {
+            self.prune_after_backup(&root, name, ts, &delta, &mut warnings);
+        }
+        let outcome = BackupOutcome::Created;
+        Ok(BackupReport { path: delta, outcome, reflinked: false, bytes, plain: None, warnings })
+    }
+
+    /// How many bytes of `src` the backup `base` already holds: the length
+    /// of its contents, if `src` is at least that long and starts with them.
+    fn appended_since(&self, base: &Path, src: &Path) -> io::Result<Option<u64>> {
+        let Ok(meta) = read_meta(&*self.fs, base) else { return Ok(None) };
+        let len = self.fs.metadata(src).at("backup", "cannot stat", src)?.len;
+        if len < meta.size {
+            return Ok(None);
+        }
+        let (algo, expected) = content_digest(&*self.fs, base, self.config.digest)?;
+        let mut hasher = Hasher::new(algo);
+        let f = self.fs.open(src).at("backup", "cannot open", src)?;
+        let n = io::copy(&mut f.take(meta.size), &mut hasher).at("backup", "cannot read", src)?;
+        Ok((n == meta.size && hasher.finish() == expected).then_some(meta.size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use crate::vfs::{FileSystem, MockFs};
+    use std::fs::{self, OpenOptions};
+    use std::sync::Arc;
+
+    fn append(path: &Path, text: &str) {
+        OpenOptions::new().append(true).open(path).unwrap().write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn appends_become_deltas_that_restore_in_full() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let log = root.join("app.log");
+        fs::write(&log, "one\n").unwrap();
+        let first = manager(root, 10).backup_append_delta("app.log").unwrap();
+        assert_eq!(first.path, root.join("app.log.10.bak"));
+
+        append(&log, "two\n");
+        let second = manager(root, 20).backup_append_delta("app.log").unwrap();
+        assert_eq!((second.path.clone(), second.bytes), (root.join("app.log.20.delta"), 4));
+        assert_eq!(fs::read_to_string(&second.path).unwrap(), "two\n");
+        append(&log, "three\n");
+        let third = manager(root, 30).backup_append_delta("app.log").unwrap();
+        assert_eq!(fs::read_to_string(&third.path).unwrap(), "three\n");
+        assert_eq!(read_backup_meta(&third.path).unwrap().base.as_deref(), Some("app.log.20.delta"));
+
+        let mgr = manager(root, 40);
+        fs::write(&log, "lost").unwrap();
+        mgr.restore_file("app.log").unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "one\ntwo\nthree\n");
+        mgr.restore_file("app.log.20.delta").unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "one\ntwo\n");
+        mgr.verify_backup("app.log.30.delta").unwrap();
+        let mut out = Vec::new();
+        mgr.cat_backup("app.log", Some(20), &mut out).unwrap();
+        assert_eq!(out, b"one\ntwo\n");
+
+        // A damaged link stops the restore before anything is written.
+        fs::write(root.join("app.log.20.delta"), "TWO\n").unwrap();
+        fs::write(&log, "current").unwrap();
+        let e = mgr.restore_file("app.log").unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::Corrupt { .. })), "{e}");
+        assert_eq!(fs::read_to_string(&log).unwrap(), "current");
+    }
+
+    #[test]
+    fn truncated_or_rewritten_files_get_a_full_backup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let log = root.join("app.log");
+        fs::write(&log, "hello world\n").unwrap();
+        manager(root, 10).backup_append_delta("app.log").unwrap();
+
+        // Truncated: shorter than the backup.
+        fs::write(&log, "hello\n").unwrap();
+        let report = manager(root, 20).backup_append_delta("app.log").unwrap();
+        assert_eq!(report.path, root.join("app.log.20.bak"));
+
+        // Rewritten: as long as before and more, but the start differs.
+        fs::write(&log, "HELLO\nand more\n").unwrap();
+        let report = manager(root, 30).backup_append_delta("app.log").unwrap();
+        assert_eq!(report.path, root.join("app.log.30.bak"));
+
+        append(&log, "tail\n");
+        let report = manager(root, 40).backup_append_delta("app.log").unwrap();
+        assert_eq!(report.path, root.join("app.log.40.delta"));
+        manager(root, 50).restore_file("app.log").unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "HELLO\nand more\ntail\n");
+    }
+
+    #[test]
+    fn a_file_modified_too_recently_gets_no_delta() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let log = root.join("app.log");
+        fs::write(&log, "one\n").unwrap();
+        manager(root, 10).backup_append_delta("app.log").unwrap();
+        append(&log, "two\n");
+        let modified = fs::metadata(&log).unwrap().modified().unwrap();
+        let mtime = modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let with_min_age = |now| {
+            BackupManager::new(crate::Config { min_age_secs: Some(60), ..crate::test_support::config(root, now) })
+        };
+
+        let e = with_min_age(mtime + 5).backup_append_delta("app.log").unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::TooRecent { age: 5, min_age: 60, .. })), "{e}");
+        assert!(!root.join(format!("app.log.{}.delta", mtime + 5)).exists());
+        let report = with_min_age(mtime + 60).backup_append_delta("app.log").unwrap();
+        assert_eq!(report.path, root.join(format!("app.log.{}.delta", mtime + 60)));
+    }
+
+    #[test]
+    fn a_failed_tail_leaves_neither_delta_nor_temp() {
+        let mock = MockFs::new("/work");
+        let log = Path::new("/work/app.log");
+        mock.write(log, b"one\n").unwrap();
+        let at = |now| {
+            let config = crate::Config { clock: Arc::new(crate::FixedClock(now)), ..crate::Config::default() };
+            BackupManager::with_fs(config, Arc::new(mock.clone()))
+        };
+        at(10).backup_append_delta("app.log").unwrap();
+        mock.open_append(log).unwrap().write_all(b"two\n").unwrap();
+        let before = mock.files_in("/work");
+
+        for op in ["write", "rename"] {
+            mock.fail(op, "/work/app.log.20.delta.*.tmp", io::ErrorKind::StorageFull);
+            let e = at(20).backup_append_delta("app.log").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::StorageFull, "{op}: {e}");
+            assert_eq!(mock.files_in("/work"), before, "{op}");
+            mock.clear_failures();
+        }
+        let report = at(20).backup_append_delta("app.log").unwrap();
+        assert_eq!(mock.contents(&report.path).unwrap(), b"two\n");
+    }
+
+    #[test]
+    fn pruning_keeps_bases_of_kept_deltas() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let log = root.join("app.log");
+        fs::write(&log, "a").unwrap();
+        manager(root, 10).backup_append_delta("app.log").unwrap();
+        append(&log, "b");
+        manager(root, 20).backup_append_delta("app.log").unwrap();
+        append(&log, "c");
+        manager(root, 30).backup_append_delta("app.log").unwrap();
+
+        let mgr = manager(root, 40);
+        assert!(mgr.prune_backups("app.log", 1, false).unwrap().is_empty());
+        assert_eq!(mgr.list_backups("app.log").unwrap().len(), 3);
+        fs::write(&log, "").unwrap();
+        mgr.restore_file("app.log").unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "abc");
+    }
+}
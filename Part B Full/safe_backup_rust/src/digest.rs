@@ -0,0 +1,255 @@
+//! Content digests of backups, tagged with their algorithm ("blake3:<hex>").
+//! BLAKE3 needs the `blake3` feature (on by default); without it, it can't
+//! be chosen, and a digest recorded with it can't be checked.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::cancel::Cancel;
+
+/// Hash used for the digests recorded in sidecars and journals. Recorded
+/// with each digest, so changing it doesn't invalidate existing backups.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgo {
+    #[default]
+    Sha256,
+    /// Only for matching external systems; not collision resistant.
+    Sha1,
+    /// Several times faster than SHA-256 on large files.
+    Blake3,
+    /// Faster still; catches accidental damage, not deliberate changes.
+    Crc32,
+}
+
+impl DigestAlgo {
+    /// The algorithm of a tagged digest. An untagged one is SHA-256, as
+    /// written by versions before the tag.
+    pub(crate) fn of(digest: &str) -> Result<Self, String> {
+        match digest.split_once(':') {
+            // Known even when not built in, so checking it fails as unsupported.
+            Some(("blake3", _)) => Ok(Self::Blake3),
+            Some((tag, _)) => tag.parse(),
+            None => Ok(Self::Sha256),
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Sha1 => Hasher::Sha1(Sha1::new()),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            #[cfg(not(feature = "blake3"))]
+            Self::Blake3 => Hasher::Unsupported(self),
+            Self::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+}
+
+impl FromStr for DigestAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "sha1" => Ok(Self::Sha1),
+            "blake3" if cfg!(feature = "blake3") => Ok(Self::Blake3),
+            "blake3" => Err("blake3 digests are not built in (build with the blake3 feature)".to_string()),
+            "crc32" => Ok(Self::Crc32),
+            other => Err(format!("unknown digest {other:?} (expected sha256, sha1, blake3 or crc32)")),
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha256 => "sha256",
+            Self::Sha1 => "sha1",
+            Self::Blake3 => "blake3",
+            Self::Crc32 => "crc32",
+        })
+    }
+}
+
+/// A running digest; data is fed in by writing to it.
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+    /// An algorithm not built in; writing to it fails.
+    #[cfg(not(feature = "blake3"))]
+    Unsupported(DigestAlgo),
+}
+
+impl Hasher {
+    pub(crate) fn new(algo: DigestAlgo) -> Self {
+        algo.hasher()
+    }
+
+    /// The tagged digest of everything written.
+    pub(crate) fn finish(self) -> String {
+        let (algo, bytes) = match self {
+            Self::Sha256(h) => (DigestAlgo::Sha256, h.finalize().to_vec()),
+            Self::Sha1(h) => (DigestAlgo::Sha1, h.finalize().to_vec()),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(h) => (DigestAlgo::Blake3, h.finalize().as_bytes().to_vec()),
+            Self::Crc32(h) => (DigestAlgo::Crc32, h.finalize().to_be_bytes().to_vec()),
+            // Nothing was written to it; an empty digest matches no recorded one.
+            #[cfg(not(feature = "blake3"))]
+            Self::Unsupported(algo) => (algo, Vec::new()),
+        };
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{algo}:{hex}")
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Sha256(h) => h.update(buf),
+            Self::Sha1(h) => h.update(buf),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(h) => {
+                h.update(buf);
+            }
+            Self::Crc32(h) => h.update(buf),
+            #[cfg(not(feature = "blake3"))]
+            Self::Unsupported(algo) => {
+                let msg = format!("{algo} digests are not built in (build with the {algo} feature)");
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Tagged digest ("sha256:<hex>", ...) of the file at `path`, read in chunks.
+/// Errors carry no context.
+pub fn file_digest(path: impl AsRef<Path>, algo: DigestAlgo) -> io::Result<String> {
+    read_digest(File::open(path)?, algo)
+}
+
+/// Tagged digest of everything `r` yields, like [`file_digest`].
+pub(crate) fn read_digest(mut r: impl Read, algo: DigestAlgo) -> io::Result<String> {
+    let mut hasher = Hasher::new(algo);
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write_all(&buf[..n])?;
+    }
+    Ok(hasher.finish())
+}
+
+/// Copy everything `from` yields to `to`, hashing it on the way through the
+/// same buffer, so a copy and its digest take one read of the source.
+/// Returns the bytes copied and the tagged digest. Errors carry no context,
+/// but for `BackupError::Cancelled` naming `dest` once `cancel` is set, which
+/// is checked before every read.
+pub(crate) fn copy_hashed(
+    mut from: impl Read,
+    mut to: impl Write,
+    algo: DigestAlgo,
+    cancel: Cancel<'_>,
+    dest: &Path,
+) -> io::Result<(u64, String)> {
+    let mut hasher = Hasher::new(algo);
+    let mut buf = vec![0u8; 256 * 1024];
+    let mut n = 0;
+    loop {
+        cancel.check("copy", dest)?;
+        let read = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        to.write_all(&buf[..read])?;
+        hasher.write_all(&buf[..read])?;
+        cancel.count(read);
+        n += read as u64;
+    }
+    to.flush()?;
+    Ok((n, hasher.finish()))
+}
+
+/// `digest` with its algorithm tag, adding "sha256:" to an untagged legacy one.
+pub(crate) fn tagged(digest: &str) -> String {
+    if digest.contains(':') { digest.to_string() } else { format!("sha256:{digest}") }
+}
+
+/// The algorithms this build can hash with.
+#[cfg(test)]
+pub(crate) fn built_in() -> Vec<DigestAlgo> {
+    ["sha256", "sha1", "blake3", "crc32"].iter().filter_map(|name| name.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_algorithm_round_trips() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("abc");
+        std::fs::write(&path, "abc").unwrap();
+        let known = [
+            (DigestAlgo::Sha256, "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (DigestAlgo::Sha1, "sha1:a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (DigestAlgo::Blake3, "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+            (DigestAlgo::Crc32, "crc32:352441c2"),
+        ];
+        for (algo, expected) in known.into_iter().filter(|(algo, _)| built_in().contains(algo)) {
+            let digest = file_digest(&path, algo).unwrap();
+            assert_eq!(digest, expected);
+            assert_eq!(DigestAlgo::of(&digest), Ok(algo));
+            assert_eq!(algo.to_string().parse(), Ok(algo));
+            assert_eq!(serde_json::to_string(&algo).unwrap(), format!("\"{algo}\""));
+        }
+    }
+
+    #[test]
+    fn copying_hashes_what_it_copies() {
+        let data: Vec<u8> = (0..600_000u32).map(|i| (i % 251) as u8).collect();
+        for algo in built_in() {
+            let mut out = Vec::new();
+            let (n, digest) = copy_hashed(&data[..], &mut out, algo, Cancel::default(), Path::new("")).unwrap();
+            assert_eq!((n, out.as_slice()), (data.len() as u64, data.as_slice()));
+            assert_eq!(digest, read_digest(&data[..], algo).unwrap());
+        }
+    }
+
+    #[test]
+    fn untagged_digests_are_sha256() {
+        let legacy = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(DigestAlgo::of(legacy), Ok(DigestAlgo::Sha256));
+        assert_eq!(tagged(legacy), format!("sha256:{legacy}"));
+        assert!(DigestAlgo::of("md5:00").unwrap_err().contains("unknown digest"));
+        assert_eq!("SHA-256".parse(), Ok(DigestAlgo::Sha256));
+    }
+
+    #[cfg(not(feature = "blake3"))]
+    #[test]
+    fn blake3_is_refused_when_not_built_in() {
+        assert!("blake3".parse::<DigestAlgo>().unwrap_err().contains("not built in"));
+        assert_eq!(DigestAlgo::of("blake3:00"), Ok(DigestAlgo::Blake3));
+        assert!(read_digest(&b"abc"[..], DigestAlgo::Blake3).is_err());
+    }
+}
@@ -0,0 +1,748 @@
+//! Directory backups: `<name>.<ts>.bak/` trees and their restore. The tree
+//! is walked on the calling thread; its files are then copied and hashed by
+//! a pool of threads, while the journal, events and sidecar are written from
+//! the calling thread alone.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use crate::batch::{BatchEntry, BatchReport};
+use crate::cancel::{Cancel, CancellationToken};
+use crate::conflict::{ConflictStrategy, Resolution};
+use crate::error::{missing, no_backup, BackupError, Context};
+use crate::compress::Compression;
+use crate::exclude::{Excludes, PLATFORM_CASE_SENSITIVE};
+use crate::journal::{journal_for, Journal};
+use crate::meta::{record_files, sidecar_for, write_meta};
+use crate::naming::{logical_name, ts_backup_for};
+use crate::options::DirOptions;
+use crate::validate::check_backup_name;
+use crate::vfs::{FileKind, FileStat};
+use crate::{newest_ts_backup, BackupManager, Event, RestoreOutcome, Warning};
+
+/// Outcome of a successful directory backup. Each count of skipped entries
+/// counts a skipped directory once, whatever it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirReport {
+    /// The "<name>.<ts>.bak/" tree that was written.
+    pub path: PathBuf,
+    /// Files in the tree.
+    pub files: u64,
+    /// Entries left out by exclude patterns.
+    pub excluded: u64,
+    /// Dotfiles and dot-directories left out (see [`DirOptions::hidden`]).
+    pub hidden: u64,
+    /// Directories below [`DirOptions::max_depth`], not descended into.
+    pub too_deep: u64,
+    /// Symlinks not copied: all of them unless following, else broken ones and loops.
+    pub symlinks: u64,
+    /// Files modified less than `Config::min_age_secs` ago, left for the next backup.
+    pub too_recent: u64,
+    /// Secondary problems, e.g. symlink loops, that did not fail the backup.
+    pub warnings: Vec<Warning>,
+    /// Every file copied, or resumed as copied before, and every entry left
+    /// out with the rule that left it out, by path inside the tree.
+    pub batch: BatchReport,
+}
+
+/// Outcome of [`BackupManager::restore_dir_with`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirRestoreReport {
+    /// The directory restored into.
+    pub path: PathBuf,
+    /// Every file of the backup, by where it belongs, with what was done about it.
+    pub files: Vec<(PathBuf, RestoreOutcome)>,
+}
+
+impl DirReport {
+    /// Entries left out by any rule.
+    pub fn skipped(&self) -> u64 {
+        self.excluded + self.hidden + self.too_deep + self.symlinks + self.too_recent
+    }
+
+    /// "N files", then each nonzero skip count, as written to the log.
+    pub fn summary(&self) -> String {
+        let mut s = format!("{} files", self.files);
+        let counts = [
+            (self.excluded, "excluded"),
+            (self.hidden, "hidden"),
+            (self.too_deep, "too deep"),
+            (self.symlinks, "symlinks"),
+            (self.too_recent, "too recent"),
+        ];
+        for (n, what) in counts {
+            if n > 0 {
+                s.push_str(&format!(", {n} {what}"));
+            }
+        }
+        s
+    }
+}
+
+/// A file `walk_tree` found to copy.
+struct FileJob {
+    from: PathBuf,
+    to: PathBuf,
+    /// Its path inside the tree, as the manifest has it.
+    rel: String,
+}
+
+/// How `copy_tree` walks a tree, what it has left out so far and the files it found.
+struct Walk<'a> {
+    excludes: &'a Excludes,
+    hidden: bool,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    /// Whether files modified less than `Config::min_age_secs` ago are left out.
+    min_age: bool,
+    jobs: usize,
+    cancel: Option<CancellationToken>,
+    /// Directories from the root down to the one being walked, canonicalized
+    /// to notice a followed symlink leading back into one of them.
+    ancestors: Vec<PathBuf>,
+    files: Vec<FileJob>,
+    report: DirReport,
+}
+
+impl<'a> Walk<'a> {
+    fn new(excludes: &'a Excludes, opts: &DirOptions) -> Self {
+        Self {
+            excludes,
+            hidden: opts.hidden,
+            max_depth: opts.max_depth,
+            follow_symlinks: opts.follow_symlinks,
+            min_age: false,
+            jobs: opts.jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())),
+            cancel: opts.cancel.clone(),
+            ancestors: Vec::new(),
+            files: Vec::new(),
+            report: DirReport {
+                path: PathBuf::new(),
+                files: 0,
+                excluded: 0,
+                hidden: 0,
+                too_deep: 0,
+                symlinks: 0,
+                too_recent: 0,
+                warnings: Vec::new(),
+                batch: BatchReport::default(),
+            },
+        }
+    }
+
+    /// Record the entry at `rel` as left out, for `why`.
+    fn skip(&mut self, rel: &Path, why: &str) {
+        self.report.batch.entries.push(BatchEntry::skipped(logical_name(rel), why.to_string()));
+    }
+
+    /// Everything in the tree, as restore copies it.
+    fn everything(excludes: &'a Excludes) -> Self {
+        Self::new(excludes, &DirOptions::new().hidden(true))
+    }
+}
+
+impl BackupManager {
+    /// Recursively copy directory <name> to "<name>.<ts>.bak/", leaving out
+    /// `Config::exclude`, dotfiles and symlinks (see [`DirOptions`]). If the
+    /// copy fails or is cancelled (`Config::cancel`), the partial tree is removed.
+    pub fn backup_dir(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        self.backup_dir_with(name, &DirOptions::default()).map(|r| r.path)
+    }
+
+    /// [`backup_dir`](Self::backup_dir) that can pick up where an interrupted
+    /// run stopped. Progress goes to a journal, "<name>.resume.jsonl" in the
+    /// backup directory; a cancelled or failed run keeps its partial tree and
+    /// journal, and the next call continues that tree, skipping files whose
+    /// copies still match the digest journaled for them. The journal is
+    /// removed once the tree is complete.
+    pub fn backup_dir_resumable(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        self.backup_dir_with(name, &DirOptions::new().resume(true)).map(|r| r.path)
+    }
+
+    /// [`backup_dir`](Self::backup_dir) with per-call options: extra exclude
+    /// patterns and their case sensitivity, hidden files, depth, symlinks,
+    /// resuming and how many files are copied at once. Skipped directories are
+    /// not descended into. The report and the log result count what each rule
+    /// left out; the sidecar lists every file copied with its digest.
+    pub fn backup_dir_with(&self, name: impl AsRef<Path>, opts: &DirOptions) -> io::Result<DirReport> {
+        let (name, opts) = (name.as_ref().to_path_buf(), opts.clone());
+        self.with_timeout("backup_dir", name.clone(), move |mgr| mgr.backup_dir_now(&name, &opts))
+    }
+
+    /// [`backup_dir_with`](Self::backup_dir_with) on this thread, whatever `Config::timeout`.
+    fn backup_dir_now(&self, name: &Path, opts: &DirOptions) -> io::Result<DirReport> {
+        let started = Instant::now();
+        let root = self.root()?;
+        let src = self.resolve(&root, name)?;
+        // Named and logged after the canonical name, as file backups are.
+        let rel = src.strip_prefix(&root).unwrap_or(&src).to_path_buf();
+        let (name, logical) = (rel.as_path(), logical_name(&rel));
+        if !self.fs.metadata(&src).is_ok_and(|m| m.is_dir()) {
+            return Err(missing("backup_dir", "source directory does not exist", &src));
+        }
+        let patterns: Vec<&String> = self.config.exclude.iter().chain(&opts.exclude).collect();
+        let excludes = Excludes::new(&patterns, opts.case_sensitive.unwrap_or(PLATFORM_CASE_SENSITIVE))?;
+        let mut walk = Walk { min_age: true, ..Walk::new(&excludes, opts) };
+        let broot = self.ensure_backup_root("backup_dir", &root)?;
+        let fresh = ts_backup_for(&broot, name, self.now())?;
+        check_backup_name(&sidecar_for(&fresh), &self.config.limits)?;
+        let dest = if opts.resume {
+            let journal = Journal::open("backup_dir", &journal_for(&broot, name)?, &fresh)?;
+            let dest = journal.dest().to_path_buf();
+            let files = self.copy_tree("backup_dir", &src, &dest, Some(&journal), &mut walk)?;
+            let _committing = self.cancel_for(opts.cancel.as_ref()).commit("backup_dir", &dest)?;
+            write_meta(&*self.fs, "backup_dir", &dest, name, &src, Compression::None, self.config.digest)?;
+            record_files(&*self.fs, "backup_dir", &dest, files)?;
+            journal.finish("backup_dir")?;
+            dest
+        } else {
+            let cancel = self.cancel_for(opts.cancel.as_ref());
+            let copied = self.copy_tree("backup_dir", &src, &fresh, None, &mut walk);
+            let committing = copied.and_then(|files| Ok((files, cancel.commit("backup_dir", &fresh)?)));
+            let (files, _committing) = match committing {
+                Ok(done) => done,
+                Err(e) => {
+                    let _ = self.fs.remove_dir_all(&fresh);
+                    return Err(e);
+                }
+            };
+            write_meta(&*self.fs, "backup_dir", &fresh, name, &src, Compression::None, self.config.digest)?;
+            record_files(&*self.fs, "backup_dir", &fresh, files)?;
+            fresh
+        };
+        let mut report = DirReport { path: dest, ..walk.report };
+        report.batch.elapsed = started.elapsed();
+        self.log_action(&root, "backup_dir", Path::new(&logical), &format!("ok ({})", report.summary()))?;
+        Ok(report)
+    }
+
+    /// Restore the latest "<name>.<ts>.bak/" tree back into directory <name>.
+    /// Files already present in the destination are overwritten, as with `restore_file`;
+    /// files that exist only in the destination are left alone. A cancelled
+    /// restore keeps the files restored so far.
+    pub fn restore_dir(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        self.restore_dir_with(name, ConflictStrategy::Overwrite).map(|r| r.path)
+    }
+
+    /// [`restore_dir`](Self::restore_dir), treating each file already in the
+    /// destination with `strategy`, as `RestoreOptions::on_conflict` does for
+    /// one file. A `ConflictStrategy::Fail` conflict is found before any file
+    /// is written. The log records the files left alone.
+    pub fn restore_dir_with(&self, name: impl AsRef<Path>, strategy: ConflictStrategy) -> io::Result<DirRestoreReport> {
+        let name = name.as_ref().to_path_buf();
+        self.with_timeout("restore_dir", name.clone(), move |mgr| mgr.restore_dir_now(&name, strategy))
+    }
+
+    /// [`restore_dir_with`](Self::restore_dir_with) on this thread, whatever `Config::timeout`.
+    fn restore_dir_now(&self, name: &Path, strategy: ConflictStrategy) -> io::Result<DirRestoreReport> {
+        let root = self.root()?;
+        let dest = self.resolve(&root, name)?;
+        let rel = dest.strip_prefix(&root).unwrap_or(&dest).to_path_buf();
+        let (name, logical) = (rel.as_path(), logical_name(&rel));
+        let src = newest_ts_backup(&*self.fs, &self.backup_root(&root), name, false, FileStat::is_dir)?
+            .ok_or_else(|| no_backup("restore_dir", "no directory backup found for", &dest))?;
+        let excludes = Excludes::default();
+        let mut walk = Walk::everything(&excludes);
+        self.walk_tree("restore_dir", &src, &dest, Path::new(""), &mut walk)?;
+        let mut files = Vec::new();
+        let mut left = 0;
+        for job in std::mem::take(&mut walk.files) {
+            match self.resolve_conflict(strategy, &job.from, &job.to)? {
+                Resolution::Write(outcome) => {
+                    files.push((job.to.clone(), outcome));
+                    walk.files.push(job);
+                }
+                Resolution::Leave(outcome) => {
+                    files.push((job.to, outcome));
+                    left += 1;
+                }
+            }
+        }
+        for (to, _) in files.iter().filter(|(_, o)| *o == RestoreOutcome::RenamedExisting) {
+            self.move_aside(to)?;
+        }
+        self.copy_walked("restore_dir", None, &mut walk)?;
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        let result = match left {
+            0 => format!("ok ({} files)", walk.report.files),
+            n => format!("ok ({} files, left {n})", walk.report.files),
+        };
+        self.log_action(&root, "restore_dir", Path::new(&logical), &result)?;
+        Ok(DirRestoreReport { path: dest, files })
+    }
+
+    /// Copy the tree `from` into `to`: walk it, then copy its files with
+    /// `walk.jobs` threads. Returns the manifest of the files, each with the
+    /// digest taken while copying it, sorted by path whatever order they
+    /// finished in. With a `journal`, files it has as done are not copied
+    /// again and every file copied is recorded in it.
+    fn copy_tree(
+        &self,
+        op: &'static str,
+        from: &Path,
+        to: &Path,
+        journal: Option<&Journal>,
+        walk: &mut Walk,
+    ) -> io::Result<BTreeMap<String, String>> {
+        self.walk_tree(op, from, to, Path::new(""), walk)?;
+        self.copy_walked(op, journal, walk)
+    }
+
+    /// The copying half of [`copy_tree`](Self::copy_tree): copy the files
+    /// `walk` has collected.
+    fn copy_walked(
+        &self,
+        op: &'static str,
+        journal: Option<&Journal>,
+        walk: &mut Walk,
+    ) -> io::Result<BTreeMap<String, String>> {
+        let files = std::mem::take(&mut walk.files);
+        let cancel = self.cancel_for(walk.cancel.as_ref());
+        let manifest = self.copy_files(op, &files, journal, walk.jobs, cancel, &mut walk.report.batch)?;
+        walk.report.batch.entries.sort_by(|a, b| a.name.cmp(&b.name));
+        walk.report.files += manifest.len() as u64;
+        Ok(manifest)
+    }
+
+    /// Create `to` and the directories below it for `from` (at `rel` inside
+    /// the tree), collecting its files in `walk.files` and skipping and
+    /// counting in `walk.report` what its rules leave out. Unless following
+    /// them, symlinks are skipped so a tree can't escape its root; a followed
+    /// one leading back into an ancestor is skipped with a warning. The cancel
+    /// flag is checked before every entry.
+    fn walk_tree(&self, op: &'static str, from: &Path, to: &Path, rel: &Path, walk: &mut Walk) -> io::Result<()> {
+        self.fs.create_dir_all(to).at(op, "cannot create directory", to)?;
+        walk.ancestors.push(self.fs.canonicalize(from).at(op, "cannot stat", from)?);
+        for fname in self.fs.read_dir_names(from).at(op, "cannot list directory", from)? {
+            self.cancel_for(walk.cancel.as_ref()).check(op, to)?;
+            let fname = fname.at(op, "cannot list directory", from)?;
+            let path = from.join(&fname);
+            let mut ty = self.fs.symlink_metadata(&path).at(op, "cannot stat", &path)?;
+            let via_link = ty.kind == FileKind::Symlink;
+            if via_link {
+                match self.fs.metadata(&path) {
+                    Ok(md) if walk.follow_symlinks => ty = md,
+                    _ => {
+                        walk.report.symlinks += 1;
+                        walk.skip(&rel.join(&fname), "symlink");
+                        continue;
+                    }
+                }
+            }
+            if !ty.is_dir() && !ty.is_file() {
+                continue;
+            }
+            let target = to.join(&fname);
+            let entry_rel = rel.join(&fname);
+            if walk.excludes.is_excluded(&entry_rel, ty.is_dir()) {
+                walk.report.excluded += 1;
+                walk.skip(&entry_rel, "excluded");
+                continue;
+            }
+            if !walk.hidden && fname.as_encoded_bytes().starts_with(b".") {
+                walk.report.hidden += 1;
+                walk.skip(&entry_rel, "hidden");
+                continue;
+            }
+            if ty.is_dir() {
+                if walk.max_depth.is_some_and(|max| walk.ancestors.len() > max) {
+                    walk.report.too_deep += 1;
+                    walk.skip(&entry_rel, "too deep");
+                    continue;
+                }
+                if via_link && walk.ancestors.contains(&self.fs.canonicalize(&path).at(op, "cannot stat", &path)?) {
+                    walk.report.symlinks += 1;
+                    walk.skip(&entry_rel, "symlink loop");
+                    walk.report.warnings.push(Warning::SymlinkLoop { path });
+                    continue;
+                }
+                self.walk_tree(op, &path, &target, &entry_rel, walk)?;
+            } else if let Some(age) = self.too_recent(&ty).filter(|_| walk.min_age) {
+                walk.report.too_recent += 1;
+                walk.skip(&entry_rel, &format!("too recent (modified {age}s ago)"));
+            } else {
+                walk.files.push(FileJob { from: path, to: target, rel: logical_name(&entry_rel) });
+            }
+        }
+        walk.ancestors.pop();
+        Ok(())
+    }
+
+    /// Copy `files` with up to `jobs` threads, each taking the next file not
+    /// yet taken, and return their digests by path, adding an entry for each
+    /// file to `batch`. Only this thread writes
+    /// the journal and emits events, as each copy is reported back. A failed
+    /// copy stops the others taking new files; once they are done, the error
+    /// of the first such file in walk order is returned. Cancelling does the
+    /// same, and is an error unless every file was copied anyway.
+    fn copy_files(
+        &self,
+        op: &'static str,
+        files: &[FileJob],
+        journal: Option<&Journal>,
+        jobs: usize,
+        cancel: Cancel<'_>,
+        batch: &mut BatchReport,
+    ) -> io::Result<BTreeMap<String, String>> {
+        let algo = self.config.digest;
+        let (next, stop) = (AtomicUsize::new(0), AtomicBool::new(false));
+        let mut manifest = BTreeMap::new();
+        let mut failed: Option<(usize, io::Error)> = None;
+        let jobs = jobs.clamp(1, files.len().max(1));
+        thread::scope(|scope| {
+            // Bounded, so the threads can't get far ahead of the journal.
+            let (tx, rx) = mpsc::sync_channel(jobs);
+            for _ in 0..jobs {
+                let (tx, next, stop) = (tx.clone(), &next, &stop);
+                scope.spawn(move || {
+                    while !stop.load(Ordering::Relaxed) && !cancel.is_set() {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(file) = files.get(i) else { break };
+                        let result = match journal.and_then(|j| j.done_digest(&file.to)) {
+                            Some(digest) => Ok((digest, None)),
+                            None => self.copy_hashed(op, &file.from, &file.to, algo, cancel).map(|(n, d)| (d, Some(n))),
+                        };
+                        stop.fetch_or(result.is_err(), Ordering::Relaxed);
+                        if tx.send((i, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+            for (i, result) in rx {
+                let file = &files[i];
+                let recorded = result.and_then(|(digest, copied)| {
+                    if copied.is_some() {
+                        if let Some(j) = journal {
+                            j.mark_done(op, &file.to, &digest)?;
+                        }
+                        self.emit(Event::Copied { op: op.to_string(), path: file.to.clone() });
+                    }
+                    Ok((digest, copied))
+                });
+                match recorded {
+                    Ok((digest, copied)) => {
+                        batch.entries.push(match copied {
+                            Some(bytes) => BatchEntry::ok(file.rel.clone(), file.to.display().to_string(), bytes),
+                            None => BatchEntry::skipped(file.rel.clone(), "copied before resuming".to_string()),
+                        });
+                        manifest.insert(file.rel.clone(), digest);
+                    }
+                    Err(e) => {
+                        stop.store(true, Ordering::Relaxed);
+                        if failed.as_ref().is_none_or(|(first, _)| i < *first) {
+                            failed = Some((i, e));
+                        }
+                    }
+                }
+            }
+        });
+        if let Some((_, e)) = failed {
+            return Err(e);
+        }
+        match files.iter().find(|f| !manifest.contains_key(&f.rel)) {
+            Some(left) => Err(BackupError::Cancelled { op, path: left.to.clone() }.into()),
+            None => Ok(manifest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use crate::batch::BatchStatus;
+    use crate::Config;
+    use std::fs;
+
+    #[test]
+    fn directory_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("proj/src/nested")).unwrap();
+        fs::write(root.join("proj/README"), "top").unwrap();
+        fs::write(root.join("proj/src/main.rs"), "fn main() {}").unwrap();
+        fs::write(root.join("proj/src/nested/data.bin"), [0u8, 1, 2, 255]).unwrap();
+
+        let mgr = BackupManager::new(Config { root: Some(root.to_path_buf()), ..Config::default() });
+        let bak = mgr.backup_dir("proj").unwrap();
+        assert!(bak.is_dir());
+
+        fs::remove_dir_all(root.join("proj")).unwrap();
+        let restored = mgr.restore_dir("proj").unwrap();
+        assert_eq!(restored, root.join("proj"));
+        assert_eq!(fs::read_to_string(root.join("proj/README")).unwrap(), "top");
+        assert_eq!(fs::read_to_string(root.join("proj/src/main.rs")).unwrap(), "fn main() {}");
+        assert_eq!(fs::read(root.join("proj/src/nested/data.bin")).unwrap(), [0u8, 1, 2, 255]);
+
+        let log = fs::read_to_string(root.join("logfile.txt")).unwrap();
+        assert!(log.contains("\"action\":\"restore_dir\"") && log.contains("ok (3 files)"), "{log}");
+    }
+
+    #[test]
+    fn cancelled_backup_leaves_nothing_behind() {
+        use std::sync::Arc;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("big")).unwrap();
+        for i in 0..10 {
+            fs::write(root.join(format!("big/{i}.dat")), "x").unwrap();
+        }
+        let cancel = CancellationToken::new();
+        let flag = cancel.clone();
+        let copied = std::sync::Mutex::new(0);
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            // Pull the plug after the third file; with one thread only a few more are copied.
+            on_event: Some(Arc::new(move |e: &Event| {
+                if matches!(e, Event::Copied { .. }) {
+                    let mut n = copied.lock().unwrap();
+                    *n += 1;
+                    if *n == 3 {
+                        flag.cancel();
+                    }
+                }
+            })),
+            ..Config::default()
+        });
+        let e = mgr.backup_dir_with("big", &DirOptions::new().jobs(1).cancel(cancel)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::Cancelled { op: "backup_dir", .. })));
+        assert!(!crate::is_transient(&e));
+        let left: Vec<_> = fs::read_dir(root).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(left, ["big"]);
+    }
+
+    #[test]
+    fn resumed_backup_skips_finished_files() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("big/sub")).unwrap();
+        for i in 0..10 {
+            let dir = if i % 2 == 0 { "big" } else { "big/sub" };
+            fs::write(root.join(format!("{dir}/{i}.dat")), format!("data {i}")).unwrap();
+        }
+        let cancel = CancellationToken::new();
+        let copied = Arc::new(AtomicUsize::new(0));
+        let (flag, count) = (cancel.clone(), Arc::clone(&copied));
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            cancel: Some(cancel.clone()),
+            // Simulate an interruption after the fourth file of the first run.
+            on_event: Some(Arc::new(move |e: &Event| {
+                if matches!(e, Event::Copied { .. }) && count.fetch_add(1, Ordering::SeqCst) + 1 == 4 {
+                    flag.cancel();
+                }
+            })),
+            ..Config::default()
+        });
+        let e = mgr.backup_dir_with("big", &DirOptions::new().resume(true).jobs(1)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        let journal = root.join("big.resume.jsonl");
+        assert!(journal.is_file());
+
+        cancel.reset();
+        let report = mgr.backup_dir_with("big", &DirOptions::new().resume(true)).unwrap();
+        assert_eq!(copied.load(Ordering::SeqCst), 10, "finished files were not copied again");
+        // Files finished before the interruption are entries too, copied before resuming.
+        let (copied_now, resumed) = (report.batch.succeeded(), report.batch.skipped());
+        assert!(resumed >= 4 && copied_now + resumed == 10, "{}", report.batch.totals());
+        assert_eq!(report.batch.bytes(), copied_now as u64 * 6);
+        assert!(report.batch.entries.iter().any(|e| e.detail == "copied before resuming"));
+        let dest = report.path;
+        assert!(!journal.exists());
+        assert!(crate::meta::is_tool_backup(&crate::vfs::RealFs, &dest));
+        for i in 0..10 {
+            let dir = if i % 2 == 0 { "" } else { "sub/" };
+            assert_eq!(fs::read_to_string(dest.join(format!("{dir}{i}.dat"))).unwrap(), format!("data {i}"));
+        }
+        let log = fs::read_to_string(root.join("logfile.txt")).unwrap();
+        assert!(log.contains("ok (10 files)"), "{log}");
+    }
+
+    #[test]
+    fn excluded_subtrees_are_skipped_and_counted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let files = ["src/main.rs", "src/scratch.tmp", "target/debug/big", ".git/HEAD", "Notes.TMP"];
+        for f in files.map(|f| root.join("proj").join(f)) {
+            fs::create_dir_all(f.parent().unwrap()).unwrap();
+            fs::write(f, "x").unwrap();
+        }
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            exclude: vec!["target/".into(), ".git/".into()],
+            ..Config::default()
+        });
+
+        let report = mgr.backup_dir_with("proj", &DirOptions::new().exclude("*.tmp").case_sensitive(true)).unwrap();
+        assert_eq!((report.files, report.excluded), (2, 3));
+        let skipped: Vec<_> = report.batch.entries.iter().filter(|e| e.status == BatchStatus::Skipped).collect();
+        let names: Vec<_> = skipped.iter().map(|e| (e.name.as_str(), e.detail.as_str())).collect();
+        assert_eq!(names, [(".git", "excluded"), ("src/scratch.tmp", "excluded"), ("target", "excluded")]);
+        assert!(report.batch.all_ok() && report.batch.succeeded() == 2);
+        assert!(report.path.join("src/main.rs").is_file() && report.path.join("Notes.TMP").is_file());
+        assert!(!report.path.join("target").exists() && !report.path.join(".git").exists());
+        let log = fs::read_to_string(root.join("logfile.txt")).unwrap();
+        assert!(log.contains("ok (2 files, 3 excluded)"), "{log}");
+
+        let report = mgr.backup_dir_with("proj", &DirOptions::new().exclude("*.tmp").case_sensitive(false)).unwrap();
+        assert_eq!((report.files, report.excluded), (1, 4));
+    }
+
+    #[test]
+    fn hidden_entries_and_depth() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for f in [".env", ".cache/x", "a/.keep", "a/b/c/deep.txt", "top.txt"] {
+            let f = root.join("proj").join(f);
+            fs::create_dir_all(f.parent().unwrap()).unwrap();
+            fs::write(f, "x").unwrap();
+        }
+        // A new tree per call: same-second backups would share one.
+        let at = |ts| manager(root, ts);
+
+        let report = at(1).backup_dir_with("proj", &DirOptions::new()).unwrap();
+        assert_eq!((report.files, report.hidden), (2, 3));
+        assert!(!report.path.join(".env").exists() && report.path.join("a/b/c/deep.txt").is_file());
+        let log = fs::read_to_string(root.join("logfile.txt")).unwrap();
+        assert!(log.contains("ok (2 files, 3 hidden)"), "{log}");
+
+        let report = at(2).backup_dir_with("proj", &DirOptions::new().hidden(true).max_depth(1)).unwrap();
+        // "a" is one level down and copied; "a/b" would be two.
+        assert_eq!((report.files, report.hidden, report.too_deep), (4, 0, 1));
+        assert!(report.path.join(".cache/x").is_file() && report.path.join("a/.keep").is_file());
+        assert!(!report.path.join("a/b").exists());
+
+        let report = at(3).backup_dir_with("proj", &DirOptions::new().max_depth(0)).unwrap();
+        assert_eq!((report.files, report.too_deep, report.summary().as_str()), (1, 1, "1 files, 2 hidden, 1 too deep"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_skipped_or_followed_without_looping() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("proj/sub")).unwrap();
+        fs::create_dir(root.join("shared")).unwrap();
+        fs::write(root.join("shared/lib.txt"), "lib").unwrap();
+        fs::write(root.join("proj/sub/a.txt"), "a").unwrap();
+        symlink(root.join("shared"), root.join("proj/shared")).unwrap();
+        symlink(root.join("proj"), root.join("proj/sub/loop")).unwrap();
+        symlink(root.join("gone"), root.join("proj/dangling")).unwrap();
+        let at = |ts| manager(root, ts);
+
+        let report = at(1).backup_dir_with("proj", &DirOptions::new()).unwrap();
+        assert_eq!((report.files, report.symlinks), (1, 3));
+        assert_eq!(report.warnings, []);
+
+        let report = at(2).backup_dir_with("proj", &DirOptions::new().follow_symlinks(true)).unwrap();
+        assert_eq!((report.files, report.symlinks), (2, 2));
+        assert_eq!(fs::read_to_string(report.path.join("shared/lib.txt")).unwrap(), "lib");
+        assert!(!report.path.join("sub/loop").exists());
+        assert_eq!(report.warnings, [Warning::SymlinkLoop { path: root.join("proj/sub/loop") }]);
+    }
+
+    #[test]
+    fn files_are_copied_in_parallel_into_a_sorted_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mut names: Vec<String> = (0..300).map(|i| format!("d{}/f{i:03}.txt", i % 3)).collect();
+        for (i, name) in names.iter().enumerate() {
+            let f = root.join("proj").join(name);
+            fs::create_dir_all(f.parent().unwrap()).unwrap();
+            fs::write(f, format!("file {i}")).unwrap();
+        }
+        names.sort();
+        let at = |ts| manager(root, ts);
+
+        let report = at(100).backup_dir_with("proj", &DirOptions::new().jobs(8)).unwrap();
+        assert_eq!(report.files, 300);
+        let meta = crate::read_backup_meta(&report.path).unwrap();
+        assert_eq!(meta.files.keys().collect::<Vec<_>>(), names.iter().collect::<Vec<_>>());
+        let copy = report.path.join("d1/f151.txt");
+        assert_eq!(meta.files["d1/f151.txt"], crate::digest::file_digest(&copy, crate::DigestAlgo::Sha256).unwrap());
+        // Sorted in the sidecar itself, not only once read back.
+        let text = fs::read_to_string(sidecar_for(&report.path)).unwrap();
+        let offsets: Vec<usize> = names.iter().map(|n| text.find(&format!("\"{n}\"")).unwrap()).collect();
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+
+        // A file that can't be written fails the backup with its own error, on whichever thread.
+        fs::create_dir_all(root.join("proj.200.bak/d1/f151.txt")).unwrap();
+        let e = at(200).backup_dir_with("proj", &DirOptions::new().jobs(8)).unwrap_err();
+        assert!(crate::error_chain(&e).contains("f151.txt"), "{e}");
+        assert!(!root.join("proj.200.bak").exists());
+        assert_eq!(crate::read_log(root).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn padded_names_are_backed_up_and_logged_canonically() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("proj")).unwrap();
+        fs::write(root.join("proj/README"), "top").unwrap();
+        let mgr = crate::test_support::manager(root, 100);
+        assert_eq!(mgr.backup_dir(" ./proj/ ").unwrap(), root.join("proj.100.bak"));
+        fs::remove_dir_all(root.join("proj")).unwrap();
+        assert_eq!(mgr.restore_dir("./proj/").unwrap(), root.join("proj"));
+        let entries: Vec<_> = mgr.read_log().unwrap().into_iter().map(|e| (e.action, e.file)).collect();
+        assert_eq!(entries, [("backup_dir".into(), "proj".into()), ("restore_dir".into(), "proj".into())]);
+    }
+
+    #[test]
+    fn restore_dir_treats_files_already_there_with_the_strategy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("proj/sub")).unwrap();
+        fs::write(root.join("proj/a.txt"), "a").unwrap();
+        fs::write(root.join("proj/sub/b.txt"), "b").unwrap();
+        manager(root, 100).backup_dir("proj").unwrap();
+        let (a, b) = (root.join("proj/a.txt"), root.join("proj/sub/b.txt"));
+        let edit = || {
+            fs::write(&a, "mine").unwrap();
+            let _ = fs::remove_file(&b);
+        };
+
+        // A conflict to fail on stops the restore before any file is written.
+        edit();
+        let e = manager(root, 200).restore_dir_with("proj", ConflictStrategy::Fail).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::DestinationExists { .. })), "{e}");
+        assert!(!b.exists());
+
+        let report = manager(root, 300).restore_dir_with("proj", ConflictStrategy::Skip).unwrap();
+        assert_eq!(report.path, root.join("proj"));
+        assert_eq!(report.files, [(a.clone(), RestoreOutcome::Skipped), (b.clone(), RestoreOutcome::Restored)]);
+        assert_eq!((fs::read_to_string(&a).unwrap(), fs::read_to_string(&b).unwrap()), ("mine".into(), "b".into()));
+        let last = manager(root, 300).read_log().unwrap().pop().unwrap();
+        assert_eq!((last.file.as_str(), last.result.as_str()), ("proj", "ok (1 files, left 1)"));
+
+        edit();
+        let report = manager(root, 400).restore_dir_with("proj", ConflictStrategy::RenameExisting).unwrap();
+        assert_eq!(report.files, [(a.clone(), RestoreOutcome::RenamedExisting), (b.clone(), RestoreOutcome::Restored)]);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a");
+        assert_eq!(fs::read_to_string(root.join("proj/a.txt.pre-restore.400")).unwrap(), "mine");
+
+        // What already matches the backup is no conflict.
+        let report = manager(root, 500).restore_dir_with("proj", ConflictStrategy::Fail).unwrap();
+        assert_eq!(report.files, [(a, RestoreOutcome::Unchanged), (b, RestoreOutcome::Unchanged)]);
+    }
+
+    #[test]
+    fn restore_dir_without_backup_is_not_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mgr = BackupManager::new(Config { root: Some(tmp.path().to_path_buf()), ..Config::default() });
+        assert_eq!(mgr.restore_dir("missing").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
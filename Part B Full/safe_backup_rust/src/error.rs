@@ -0,0 +1,493 @@
+//! Typed errors carrying the operation and the path involved.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What went wrong, and where. Public functions still return `io::Result`;
+/// the `io::Error` wraps a `BackupError` (see [`BackupError::from_io`]) and keeps its kind.
+#[derive(Debug)]
+pub enum BackupError {
+    /// A filesystem step on `path` failed.
+    Io { op: &'static str, step: &'static str, path: PathBuf, source: io::Error },
+    /// Copying `from` to `to` failed.
+    Copy { op: &'static str, from: PathBuf, to: PathBuf, source: io::Error },
+    /// `path` is locked by another program (Windows sharing violation).
+    FileBusy { op: &'static str, path: PathBuf, source: io::Error },
+    /// Something required was not there.
+    Missing { op: &'static str, what: &'static str, path: PathBuf },
+    /// The backup needed, or any backup of `path`, was not there.
+    NoBackup { op: &'static str, what: &'static str, path: PathBuf },
+    /// A name (or a backup name derived from it) exceeds the configured limit.
+    NameTooLong { what: &'static str, input: String, len: usize, max: usize },
+    /// The user-supplied name was rejected.
+    InvalidPath { input: String, reason: &'static str },
+    /// A setting (environment variable or config key `name`) has an unusable value.
+    InvalidSetting { name: String, value: String, reason: String },
+    /// The backup at `path` no longer matches the digest recorded when it was made.
+    Corrupt { path: PathBuf, expected: String, actual: String },
+    /// `op` was stopped through a `CancellationToken` while writing into `path`.
+    Cancelled { op: &'static str, path: PathBuf },
+    /// `op` on `path` was given up on after `after` (`Config::timeout`), with
+    /// `copied` bytes copied.
+    TimedOut { op: &'static str, path: PathBuf, after: Duration, copied: u64 },
+    /// `op` on `path` ran out of time after `after` while putting its result
+    /// in place, and had not finished when given up on: it may yet.
+    OutcomeUnknown { op: &'static str, path: PathBuf, after: Duration },
+    /// The hash chain of the log at `path` is broken at `line` (1-based).
+    LogTampered { path: PathBuf, line: usize, reason: &'static str },
+    /// The log at `path` is `found` bytes, shorter than the `recorded` it had
+    /// after the last write (`Config::log_audit`).
+    LogTruncated { path: PathBuf, recorded: u64, found: u64 },
+    /// Line `line` (1-based) of the script at `path` can't be run.
+    ScriptSyntax { path: PathBuf, line: usize, reason: &'static str },
+    /// `path` is a FIFO, socket or device (`kind`), not a regular file.
+    UnsupportedFileType { op: &'static str, path: PathBuf, kind: &'static str },
+    /// The `offset`-th newest backup of `name` was asked for, but only `available` exist.
+    NoSuchVersion { name: String, offset: usize, available: usize },
+    /// The backup at `local` was made but could not be copied to `target`; it is kept.
+    Upload { local: PathBuf, target: String, source: io::Error },
+    /// A file `op` would write over is there already and wasn't made by this tool.
+    Foreign { op: &'static str, path: PathBuf },
+    /// `path` was asked to be backed up but is a backup itself.
+    BackupOfBackup { path: PathBuf },
+    /// Restoring the checkpoint `name` replaced the files in `restored`, then
+    /// failed; those in `not_restored` are as they were.
+    CheckpointPartial { name: String, restored: Vec<PathBuf>, not_restored: Vec<PathBuf>, source: io::Error },
+    /// Purging the backups of `file` removed those in `purged`, then failed;
+    /// those in `not_purged` are still there.
+    PurgePartial { file: PathBuf, purged: Vec<PathBuf>, not_purged: Vec<PathBuf>, source: io::Error },
+    /// A restore found `path` already there, holding something else
+    /// (`ConflictStrategy::Fail`).
+    DestinationExists { path: PathBuf },
+    /// The plain copy at `path` is the backup of another file, `original`,
+    /// whose name differs only in its extension ("a.txt" and "a.md" share "a.bak").
+    PlainCollision { path: PathBuf, original: String },
+    /// `Config::pre_hook`, the program at `program`, failed before `action`, which wasn't done.
+    HookFailed { action: &'static str, program: PathBuf, reason: String },
+    /// The file restored to `path` from `backup` does not match the digest
+    /// recorded for it; it was not put in place.
+    ChecksumMismatch { path: PathBuf, backup: PathBuf, expected: String, actual: String },
+    /// `path` was modified `age` seconds ago, less than `Config::min_age_secs`
+    /// (`min_age`); it may still be being written.
+    TooRecent { path: PathBuf, age: u64, min_age: u64 },
+    /// The file at `path` describing a backup is in format `found`, newer
+    /// than the `supported` this version reads.
+    NewerFormat { path: PathBuf, found: u32, supported: u32 },
+    /// A note, starting `preview`, is `len` bytes once escaped, more than `max`.
+    NoteTooLong { preview: String, len: usize, max: usize },
+}
+
+impl BackupError {
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::Io { source, .. }
+            | Self::Copy { source, .. }
+            | Self::Upload { source, .. }
+            | Self::CheckpointPartial { source, .. }
+            | Self::PurgePartial { source, .. } => source.kind(),
+            Self::FileBusy { .. } | Self::TooRecent { .. } => io::ErrorKind::ResourceBusy,
+            Self::Missing { .. } | Self::NoBackup { .. } | Self::NoSuchVersion { .. } => io::ErrorKind::NotFound,
+            Self::NameTooLong { .. }
+            | Self::NoteTooLong { .. }
+            | Self::InvalidPath { .. }
+            | Self::InvalidSetting { .. }
+            | Self::ScriptSyntax { .. }
+            | Self::BackupOfBackup { .. } => {
+                io::ErrorKind::InvalidInput
+            }
+            Self::Corrupt { .. }
+            | Self::ChecksumMismatch { .. }
+            | Self::LogTampered { .. }
+            | Self::LogTruncated { .. } => io::ErrorKind::InvalidData,
+            Self::UnsupportedFileType { .. } | Self::NewerFormat { .. } => io::ErrorKind::Unsupported,
+            Self::Cancelled { .. } => io::ErrorKind::Interrupted,
+            Self::TimedOut { .. } | Self::OutcomeUnknown { .. } => io::ErrorKind::TimedOut,
+            Self::Foreign { .. } | Self::DestinationExists { .. } | Self::PlainCollision { .. } => {
+                io::ErrorKind::AlreadyExists
+            }
+            Self::HookFailed { .. } => io::ErrorKind::Other,
+        }
+    }
+
+    /// A short code naming what went wrong, for scripts: "invalid_path",
+    /// "no_backup_found", "backup_corrupt" and so on. Codes don't change
+    /// between releases; a failed filesystem step is named after its cause.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io { source, .. } | Self::Copy { source, .. } => kind_code(source.kind()),
+            Self::FileBusy { .. } => "file_busy",
+            Self::NoBackup { .. } => "no_backup_found",
+            Self::Missing { .. } => "not_found",
+            Self::NameTooLong { .. } => "name_too_long",
+            Self::InvalidPath { .. } => "invalid_path",
+            Self::InvalidSetting { .. } => "invalid_setting",
+            Self::Corrupt { .. } => "backup_corrupt",
+            Self::Cancelled { .. } => "cancelled",
+            Self::TimedOut { .. } => "timed_out",
+            Self::OutcomeUnknown { .. } => "outcome_unknown",
+            Self::LogTampered { .. } => "log_tampered",
+            Self::LogTruncated { .. } => "log_truncated",
+            Self::ScriptSyntax { .. } => "script_syntax",
+            Self::UnsupportedFileType { .. } => "unsupported_file_type",
+            Self::NoSuchVersion { .. } => "no_such_version",
+            Self::Upload { .. } => "upload_failed",
+            Self::Foreign { .. } => "foreign_file",
+            Self::BackupOfBackup { .. } => "backup_of_backup",
+            Self::CheckpointPartial { .. } => "checkpoint_partial",
+            Self::PurgePartial { .. } => "purge_partial",
+            Self::DestinationExists { .. } => "destination_exists",
+            Self::PlainCollision { .. } => "plain_collision",
+            Self::HookFailed { .. } => "hook_failed",
+            Self::ChecksumMismatch { .. } => "checksum_mismatch",
+            Self::TooRecent { .. } => "too_recent",
+            Self::NewerFormat { .. } => "newer_format",
+            Self::NoteTooLong { .. } => "note_too_long",
+        }
+    }
+
+    /// The `BackupError` inside an `io::Error` produced by this crate, if any.
+    pub fn from_io(e: &io::Error) -> Option<&BackupError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { op, step, path, .. } => write!(f, "{op}: {step} {}", path.display()),
+            Self::Copy { op, from, to, .. } => {
+                write!(f, "{op}: cannot copy {} to {}", from.display(), to.display())
+            }
+            Self::FileBusy { op, path, .. } => write!(
+                f,
+                "{op}: {} is in use; it may be open in another program (close it and try again)",
+                path.display()
+            ),
+            Self::Missing { op, what, path } | Self::NoBackup { op, what, path } => {
+                write!(f, "{op}: {what}: {}", path.display())
+            }
+            Self::NameTooLong { what, input, len, max } => {
+                write!(f, "{what} too long ({len} > {max} bytes): {input}")?;
+                if what.starts_with("backup") {
+                    write!(f, "; shorten the file name by at least {} bytes", len - max)?;
+                }
+                Ok(())
+            }
+            Self::InvalidPath { input, reason } => write!(f, "invalid file name {input:?}: {reason}"),
+            Self::InvalidSetting { name, value, reason } => write!(f, "{name}={value:?}: {reason}"),
+            Self::Corrupt { path, expected, actual } => {
+                write!(f, "verify: {} is corrupt ({actual}, expected {expected})", path.display())
+            }
+            Self::Cancelled { op, path } => write!(f, "{op}: cancelled: {}", path.display()),
+            Self::TimedOut { op, path, after, copied } => {
+                write!(f, "{op}: timed out after {after:?} with {copied} bytes copied: {}", path.display())
+            }
+            Self::OutcomeUnknown { op, path, after } => {
+                write!(f, "{op}: timed out after {after:?} while finishing; it may still complete: {}", path.display())
+            }
+            Self::LogTampered { path, line, reason } => {
+                write!(f, "log: {} fails verification at line {line}: {reason}", path.display())
+            }
+            Self::LogTruncated { path, recorded, found } => write!(
+                f,
+                "log: {} was cut short: {found} bytes, {recorded} recorded after the last write; not writing to it",
+                path.display()
+            ),
+            Self::ScriptSyntax { path, line, reason } => write!(f, "run: {}:{line}: {reason}", path.display()),
+            Self::UnsupportedFileType { op, path, kind } => {
+                write!(f, "{op}: {} is a {kind}, not a regular file", path.display())
+            }
+            Self::NoSuchVersion { name, offset, available } => write!(
+                f,
+                "restore: no backup {offset} versions back for {name}: it has {available} (0 to {})",
+                available.saturating_sub(1)
+            ),
+            Self::Upload { local, target, .. } => {
+                write!(f, "upload: cannot copy {} to {target}; the local backup is kept", local.display())
+            }
+            Self::Foreign { op, path } => {
+                write!(f, "{op}: {} exists and was not made by safe_backup; leaving it alone", path.display())
+            }
+            Self::BackupOfBackup { path } => {
+                write!(f, "backup: {}: refusing to back up a backup file (use --force)", path.display())
+            }
+            Self::CheckpointPartial { name, restored, not_restored, .. } => {
+                write!(f, "checkpoint {name}: restored {}; not restored {}", list(restored), list(not_restored))
+            }
+            Self::PurgePartial { file, purged, not_purged, .. } => {
+                write!(f, "delete: {}: purged {}; not purged {}", file.display(), list(purged), list(not_purged))
+            }
+            Self::DestinationExists { path } => {
+                write!(f, "restore: {} exists and differs from the backup; not replacing it", path.display())
+            }
+            Self::PlainCollision { path, original } => {
+                write!(f, "backup: {} is the plain backup of {original}; not overwriting it", path.display())
+            }
+            Self::HookFailed { action, program, reason } => {
+                write!(f, "{action}: pre-hook {} failed ({reason}); not done", program.display())
+            }
+            Self::ChecksumMismatch { path, backup, expected, actual } => write!(
+                f,
+                "restore: data restored to {} from {} does not match its digest (expected {expected}, got {actual}); \
+                 not restored",
+                path.display(),
+                backup.display()
+            ),
+            Self::TooRecent { path, age, min_age } => write!(
+                f,
+                "backup: {} was modified {age}s ago, less than the minimum age of {min_age}s; try again later",
+                path.display()
+            ),
+            Self::NewerFormat { path, found, .. } => write!(
+                f,
+                "{}: backup was created by a newer safe_backup (format {found}); please upgrade",
+                path.display()
+            ),
+            Self::NoteTooLong { preview, len, max } => write!(f, "note too long ({len} > {max} bytes): {preview}"),
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io { source, .. }
+            | Self::Copy { source, .. }
+            | Self::FileBusy { source, .. }
+            | Self::Upload { source, .. }
+            | Self::CheckpointPartial { source, .. }
+            | Self::PurgePartial { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<BackupError> for io::Error {
+    fn from(e: BackupError) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// Attach operation/path context to a raw `io::Result`.
+pub(crate) trait Context<T> {
+    fn at(self, op: &'static str, step: &'static str, path: &Path) -> io::Result<T>;
+    fn copying(self, op: &'static str, from: &Path, to: &Path) -> io::Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn at(self, op: &'static str, step: &'static str, path: &Path) -> io::Result<T> {
+        self.map_err(|source| BackupError::Io { op, step, path: path.to_path_buf(), source }.into())
+    }
+
+    fn copying(self, op: &'static str, from: &Path, to: &Path) -> io::Result<T> {
+        self.map_err(|source| {
+            BackupError::Copy { op, from: from.to_path_buf(), to: to.to_path_buf(), source }.into()
+        })
+    }
+}
+
+/// `paths` for a message, or "none".
+fn list(paths: &[PathBuf]) -> String {
+    if paths.is_empty() {
+        return "none".to_string();
+    }
+    paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// `Missing` as an `io::Error`.
+pub(crate) fn missing(op: &'static str, what: &'static str, path: &Path) -> io::Error {
+    BackupError::Missing { op, what, path: path.to_path_buf() }.into()
+}
+
+/// `NoBackup` as an `io::Error`.
+pub(crate) fn no_backup(op: &'static str, what: &'static str, path: &Path) -> io::Error {
+    BackupError::NoBackup { op, what, path: path.to_path_buf() }.into()
+}
+
+/// `InvalidPath` as an `io::Error`.
+pub(crate) fn invalid(input: &Path, reason: &'static str) -> io::Error {
+    BackupError::InvalidPath { input: crate::naming::display_name(input.as_os_str()).into_owned(), reason }.into()
+}
+
+/// The code of a failed filesystem step, from the kind of its error.
+fn kind_code(kind: io::ErrorKind) -> &'static str {
+    match kind {
+        io::ErrorKind::NotFound => "not_found",
+        io::ErrorKind::PermissionDenied => "permission_denied",
+        io::ErrorKind::AlreadyExists => "already_exists",
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => "insufficient_space",
+        io::ErrorKind::ResourceBusy => "file_busy",
+        _ => "io_error",
+    }
+}
+
+/// The [`BackupError::code`] of `e`; for an error not from this crate, the
+/// code of a filesystem step failing with it.
+pub fn error_code(e: &io::Error) -> &'static str {
+    BackupError::from_io(e).map_or_else(|| kind_code(e.kind()), BackupError::code)
+}
+
+/// The process exit code the command line exits with after `e`:
+///
+/// | code | errors |
+/// |------|--------|
+/// | 1 | anything not below |
+/// | 2 | bad input: `invalid_path`, `invalid_setting`, `name_too_long`, `script_syntax`, `backup_of_backup` |
+/// | 3 | nothing to act on: `no_backup_found`, `not_found`, `no_such_version` |
+/// | 4 | damage: `backup_corrupt`, `checksum_mismatch`, `log_tampered`, `log_truncated` |
+/// | 5 | worth retrying: `file_busy`, `timed_out`, `outcome_unknown`, `insufficient_space`, `too_recent` |
+/// | 130 | `cancelled` |
+pub fn exit_code(e: &io::Error) -> u8 {
+    match error_code(e) {
+        "invalid_path" | "invalid_setting" | "name_too_long" | "note_too_long" | "script_syntax" | "backup_of_backup" => {
+            2
+        }
+        "no_backup_found" | "not_found" | "no_such_version" => 3,
+        "backup_corrupt" | "checksum_mismatch" | "log_tampered" | "log_truncated" => 4,
+        "file_busy" | "timed_out" | "outcome_unknown" | "insufficient_space" | "too_recent" => 5,
+        "cancelled" => 130,
+        _ => 1,
+    }
+}
+
+/// Render an error and all of its causes as "outer: cause: root cause".
+pub fn error_chain(e: &(dyn Error + 'static)) -> String {
+    let mut out = e.to_string();
+    let mut cur = e.source();
+    while let Some(c) = cur {
+        out.push_str(": ");
+        out.push_str(&c.to_string());
+        cur = c.source();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_includes_path_and_cause() {
+        let e: io::Error = BackupError::Io {
+            op: "backup",
+            step: "cannot read source",
+            path: PathBuf::from("/home/me/proj/notes.txt"),
+            source: io::Error::from(io::ErrorKind::PermissionDenied),
+        }
+        .into();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        let rendered = error_chain(&e);
+        assert!(rendered.starts_with("backup: cannot read source /home/me/proj/notes.txt: "), "{rendered}");
+        assert!(rendered.to_lowercase().contains("permission denied"), "{rendered}");
+    }
+
+    /// Scripts depend on these; a change here is a breaking change.
+    #[test]
+    fn error_codes_are_stable() {
+        let p = || PathBuf::from("f");
+        let io = |kind| io::Error::from(kind);
+        let errors = [
+            BackupError::Io { op: "backup", step: "cannot read", path: p(), source: io(io::ErrorKind::Other) },
+            BackupError::Io { op: "backup", step: "cannot read", path: p(), source: io(io::ErrorKind::NotFound) },
+            BackupError::Io {
+                op: "backup",
+                step: "cannot read",
+                path: p(),
+                source: io(io::ErrorKind::PermissionDenied),
+            },
+            BackupError::Copy { op: "backup", from: p(), to: p(), source: io(io::ErrorKind::AlreadyExists) },
+            BackupError::Copy { op: "backup", from: p(), to: p(), source: io(io::ErrorKind::StorageFull) },
+            BackupError::FileBusy { op: "backup", path: p(), source: io(io::ErrorKind::Other) },
+            BackupError::NoBackup { op: "restore", what: "no backup file found for", path: p() },
+            BackupError::Missing { op: "delete", what: "file does not exist", path: p() },
+            BackupError::Missing { op: "backup", what: "source file does not exist", path: p() },
+            BackupError::NameTooLong { what: "file name", input: "f".into(), len: 2, max: 1 },
+            BackupError::InvalidPath { input: "f".into(), reason: "empty" },
+            BackupError::InvalidSetting { name: "n".into(), value: "v".into(), reason: "bad".into() },
+            BackupError::Corrupt { path: p(), expected: "a".into(), actual: "b".into() },
+            BackupError::Cancelled { op: "backup", path: p() },
+            BackupError::TimedOut { op: "backup", path: p(), after: Duration::from_secs(1), copied: 0 },
+            BackupError::OutcomeUnknown { op: "backup", path: p(), after: Duration::from_secs(1) },
+            BackupError::LogTampered { path: p(), line: 1, reason: "altered" },
+            BackupError::LogTruncated { path: p(), recorded: 2, found: 1 },
+            BackupError::ScriptSyntax { path: p(), line: 1, reason: "bad" },
+            BackupError::UnsupportedFileType { op: "backup", path: p(), kind: "FIFO" },
+            BackupError::NoSuchVersion { name: "f".into(), offset: 1, available: 1 },
+            BackupError::Upload { local: p(), target: "t".into(), source: io(io::ErrorKind::Other) },
+            BackupError::Foreign { op: "restore", path: p() },
+            BackupError::BackupOfBackup { path: p() },
+            BackupError::CheckpointPartial {
+                name: "c".into(),
+                restored: vec![],
+                not_restored: vec![],
+                source: io(io::ErrorKind::Other),
+            },
+            BackupError::PurgePartial {
+                file: p(),
+                purged: vec![],
+                not_purged: vec![],
+                source: io(io::ErrorKind::Other),
+            },
+            BackupError::DestinationExists { path: p() },
+            BackupError::PlainCollision { path: p(), original: "a.md".into() },
+            BackupError::HookFailed { action: "backup", program: p(), reason: "exit status: 1".into() },
+            BackupError::ChecksumMismatch { path: p(), backup: p(), expected: "a".into(), actual: "b".into() },
+            BackupError::TooRecent { path: p(), age: 1, min_age: 60 },
+            BackupError::NewerFormat { path: p(), found: 2, supported: 1 },
+            BackupError::NoteTooLong { preview: "n".into(), len: 2, max: 1 },
+        ];
+        let codes: Vec<(&str, u8)> = errors
+            .into_iter()
+            .map(io::Error::from)
+            .map(|e| (error_code(&e), exit_code(&e)))
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                ("io_error", 1),
+                ("not_found", 3),
+                ("permission_denied", 1),
+                ("already_exists", 1),
+                ("insufficient_space", 5),
+                ("file_busy", 5),
+                ("no_backup_found", 3),
+                ("not_found", 3),
+                ("not_found", 3),
+                ("name_too_long", 2),
+                ("invalid_path", 2),
+                ("invalid_setting", 2),
+                ("backup_corrupt", 4),
+                ("cancelled", 130),
+                ("timed_out", 5),
+                ("outcome_unknown", 5),
+                ("log_tampered", 4),
+                ("log_truncated", 4),
+                ("script_syntax", 2),
+                ("unsupported_file_type", 1),
+                ("no_such_version", 3),
+                ("upload_failed", 1),
+                ("foreign_file", 1),
+                ("backup_of_backup", 2),
+                ("checkpoint_partial", 1),
+                ("purge_partial", 1),
+                ("destination_exists", 1),
+                ("plain_collision", 1),
+                ("hook_failed", 1),
+                ("checksum_mismatch", 4),
+                ("too_recent", 5),
+                ("newer_format", 1),
+                ("note_too_long", 2),
+            ]
+        );
+        assert_eq!(error_code(&io::Error::other("boom")), "io_error");
+    }
+
+    #[test]
+    fn plain_io_errors_render_unchanged() {
+        let e = io::Error::other("boom");
+        assert_eq!(error_chain(&e), "boom");
+    }
+}
@@ -0,0 +1,26 @@
+//! Progress/diagnostic events emitted by library operations.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Something noteworthy that happened during an operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A transient failure in `op` is about to be retried after `delay`.
+    Retry { op: String, attempt: u32, delay: Duration, error: String },
+    /// `name` would be the Windows device `device`; allowed here, but the
+    /// backup won't survive a trip to a Windows machine.
+    ReservedName { name: String, device: &'static str },
+    /// A file inside a directory backup/restore was copied to `path`.
+    Copied { op: String, path: PathBuf },
+    /// A line the hook run before or after `action` wrote, to stderr if
+    /// `stderr`, else to stdout; with `HookOutput::Events` only.
+    HookOutput { action: String, stderr: bool, line: String },
+    /// The log archive at `path` is damaged (`error`), e.g. a truncated
+    /// gzip file; the log was read without its entries.
+    LogArchiveSkipped { path: PathBuf, error: String },
+}
+
+/// Callback receiving events; shared so configs stay cheap to clone.
+pub type EventSink = Arc<dyn Fn(&Event) + Send + Sync>;
@@ -0,0 +1,151 @@
+//! Gitignore-style exclude patterns for directory backups.
+//!
+//! Patterns are matched against paths relative to the directory being backed
+//! up, with '/' as separator:
+//! - `*.tmp` (no slash) matches a name at any depth;
+//! - `/build` or `docs/*.pdf` (a slash before the end) are anchored to the root;
+//! - a trailing slash (`target/`) matches only directories;
+//! - `**` matches any number of directories (`**/cache`, `logs/**`);
+//! - `!pattern` re-includes what an earlier pattern excluded. As with git, a
+//!   file inside an excluded directory can't be re-included: excluded
+//!   directories are not descended into.
+//!
+//! Blank lines and lines starting with `#` are ignored.
+
+use std::io;
+use std::path::{Component, Path};
+
+use glob::{MatchOptions, Pattern};
+
+use crate::error::BackupError;
+
+/// Whether names differ by case on this platform: not on Windows or macOS.
+pub(crate) const PLATFORM_CASE_SENSITIVE: bool = !cfg!(any(windows, target_os = "macos"));
+
+struct Rule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    /// No slash in the pattern: match the entry's name at any depth.
+    any_depth: bool,
+}
+
+/// A compiled exclude list; empty excludes nothing.
+#[derive(Default)]
+pub(crate) struct Excludes {
+    rules: Vec<Rule>,
+    options: MatchOptions,
+}
+
+impl Excludes {
+    /// Compile `patterns`. A malformed one is an `InvalidSetting` error naming it.
+    pub(crate) fn new<S: AsRef<str>>(patterns: &[S], case_sensitive: bool) -> io::Result<Self> {
+        let mut rules = Vec::new();
+        for raw in patterns {
+            let raw = raw.as_ref();
+            let line = raw.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let any_depth = !line.contains('/');
+            let line = line.strip_prefix('/').unwrap_or(line);
+            let pattern = Pattern::new(line).map_err(|e| BackupError::InvalidSetting {
+                name: "exclude".to_string(),
+                value: raw.to_string(),
+                reason: e.msg.to_string(),
+            })?;
+            rules.push(Rule { pattern, negated, dir_only, any_depth });
+        }
+        let options =
+            MatchOptions { case_sensitive, require_literal_separator: true, require_literal_leading_dot: false };
+        Ok(Self { rules, options })
+    }
+
+    /// Whether the entry at `rel` (relative to the tree's root) is left out.
+    /// The last pattern that matches decides.
+    pub(crate) fn is_excluded(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let rel = slash_path(rel);
+        let name = rel.rsplit('/').next().unwrap_or(&rel);
+        let mut excluded = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let subject = if rule.any_depth { name } else { rel.as_str() };
+            if rule.pattern.matches_with(subject, self.options) {
+                excluded = !rule.negated;
+            }
+        }
+        excluded
+    }
+}
+
+fn slash_path(rel: &Path) -> String {
+    let parts: Vec<_> = rel
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excluded(patterns: &[&str], rel: &str, is_dir: bool) -> bool {
+        Excludes::new(patterns, true).unwrap().is_excluded(Path::new(rel), is_dir)
+    }
+
+    #[test]
+    fn gitignore_rules() {
+        // No slash: any depth.
+        assert!(excluded(&["*.tmp"], "a.tmp", false));
+        assert!(excluded(&["*.tmp"], "deep/er/a.tmp", false));
+        assert!(!excluded(&["*.tmp"], "a.tmpl", false));
+        // Anchored to the root.
+        assert!(excluded(&["/build"], "build", true));
+        assert!(!excluded(&["/build"], "src/build", true));
+        assert!(excluded(&["docs/*.pdf"], "docs/a.pdf", false));
+        assert!(!excluded(&["docs/*.pdf"], "sub/docs/a.pdf", false));
+        assert!(!excluded(&["docs/*.pdf"], "docs/old/a.pdf", false));
+        // Directories only.
+        assert!(excluded(&["target/"], "crate/target", true));
+        assert!(!excluded(&["target/"], "target", false));
+        // Double star.
+        assert!(excluded(&["**/cache"], "a/b/cache", true));
+        assert!(excluded(&["logs/**"], "logs/2024/x.log", false));
+        // Negation: the last match wins.
+        assert!(!excluded(&["*.log", "!keep.log"], "keep.log", false));
+        assert!(excluded(&["*.log", "!keep.log"], "other.log", false));
+        // Comments and blanks.
+        assert!(!excluded(&["# *.rs", "", "  "], "main.rs", false));
+    }
+
+    #[test]
+    fn case_sensitivity_is_configurable() {
+        let rel = Path::new("IMG.JPG");
+        assert!(!Excludes::new(&["*.jpg"], true).unwrap().is_excluded(rel, false));
+        assert!(Excludes::new(&["*.jpg"], false).unwrap().is_excluded(rel, false));
+    }
+
+    #[test]
+    fn malformed_pattern_is_named() {
+        let e = Excludes::new(&["[a"], true).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().starts_with("exclude=\"[a\":"), "{e}");
+    }
+}
@@ -0,0 +1,165 @@
+//! Format versions of the files that describe backups: sidecars, chunk
+//! indexes, dedup manifests, checkpoints, the aliases of renamed files,
+//! resume journals and the digest cache each record the `format_version`
+//! they were written in. Files from before the field are version 1. This
+//! version reads any format up to [`FORMAT_VERSION`] as it always did; a
+//! newer one is refused with `BackupError::NewerFormat` before anything else
+//! in it is looked at, so an old binary never acts on what it cannot
+//! understand. The digest cache is the exception: a newer one is ignored.
+
+use std::io;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::error::{BackupError, Context};
+
+/// Format version written by this version of the tool.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The version of files written before they recorded one.
+pub(crate) fn unversioned() -> u32 {
+    1
+}
+
+/// Just the version, whatever else a file holds.
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(default = "unversioned")]
+    format_version: u32,
+}
+
+/// Parse `text`, the JSON at `path`, as a `T` if its format is one this
+/// version reads. Malformed JSON fails as `step` ("malformed chunk index", ...).
+pub(crate) fn parse_versioned<T: DeserializeOwned>(
+    op: &'static str,
+    step: &'static str,
+    path: &Path,
+    text: &str,
+) -> io::Result<T> {
+    let malformed = |e: serde_json::Error| -> io::Result<T> {
+        Err(io::Error::new(io::ErrorKind::InvalidData, e)).at(op, step, path)
+    };
+    let found = match serde_json::from_str::<Versioned>(text) {
+        Ok(v) => v.format_version,
+        Err(e) => return malformed(e),
+    };
+    if found > FORMAT_VERSION {
+        return Err(BackupError::NewerFormat { path: path.to_path_buf(), found, supported: FORMAT_VERSION }.into());
+    }
+    serde_json::from_str(text).or_else(malformed)
+}
+
+/// Whether `e` is a file in a format newer than this version reads.
+pub(crate) fn is_newer_format(e: &io::Error) -> bool {
+    matches!(BackupError::from_io(e), Some(BackupError::NewerFormat { .. }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use crate::{read_backup_meta, RestoreOptions};
+    use std::fs;
+
+    fn is_newer(e: &io::Error, format: u32) -> bool {
+        matches!(BackupError::from_io(e), Some(BackupError::NewerFormat { found, .. }) if *found == format)
+    }
+
+    #[test]
+    fn files_from_a_newer_version_are_refused_not_misread() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = manager(root, 300);
+        fs::write(root.join("notes.txt"), "now").unwrap();
+        let ours = mgr.backup_file("notes.txt").unwrap();
+        assert_eq!(read_backup_meta(&ours).unwrap().format_version, FORMAT_VERSION);
+
+        // A sidecar from before the field reads as version 1.
+        fs::write(root.join("notes.txt.100.bak"), "old").unwrap();
+        let old = include_str!("../tests/fixtures/format/unversioned_meta.json");
+        fs::write(root.join("notes.txt.100.bak.meta.json"), old).unwrap();
+        assert_eq!(read_backup_meta(root.join("notes.txt.100.bak")).unwrap().format_version, 1);
+
+        // One from the future, with fields this version would misread, is refused by everything that needs it.
+        fs::write(root.join("notes.txt.200.bak"), "future").unwrap();
+        let future = include_str!("../tests/fixtures/format/future_meta.json");
+        fs::write(root.join("notes.txt.200.bak.meta.json"), future).unwrap();
+        let e = read_backup_meta(root.join("notes.txt.200.bak")).unwrap_err();
+        assert!(is_newer(&e, 99), "{e}");
+        let sidecar = root.join("notes.txt.200.bak.meta.json");
+        let message = "backup was created by a newer safe_backup (format 99); please upgrade";
+        assert_eq!(e.to_string(), format!("{}: {message}", sidecar.display()));
+        let e = mgr.restore_file("notes.txt.200.bak").unwrap_err();
+        assert!(is_newer(&e, 99), "{e}");
+        let e = mgr.restore_file_with("notes.txt", &RestoreOptions::new().version(200)).unwrap_err();
+        assert!(is_newer(&e, 99), "{e}");
+        assert!(is_newer(&mgr.cat_backup("notes.txt.200.bak", None, &mut Vec::new()).unwrap_err(), 99));
+        assert!(is_newer(&mgr.verify_backup("notes.txt.200.bak").unwrap_err(), 99));
+        assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "now");
+        // It is still ours, and listed.
+        assert_eq!(mgr.list_backups("notes.txt").unwrap().len(), 3);
+        mgr.restore_file("notes.txt.100.bak").unwrap();
+        assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "old");
+    }
+
+    #[test]
+    fn chunk_indexes_and_manifests_check_their_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("big.bin.100.bak");
+        fs::write(&path, include_str!("../tests/fixtures/format/future_chunk_index.json")).unwrap();
+        assert!(is_newer(&crate::chunk::read_index("restore", &path).unwrap_err(), 2));
+        fs::write(&path, include_str!("../tests/fixtures/format/future_manifest.json")).unwrap();
+        assert!(is_newer(&crate::dedup::read_manifest("restore", &path).unwrap_err(), 2));
+        // Malformed is still malformed.
+        fs::write(&path, "{not json").unwrap();
+        let e = crate::dedup::read_manifest("restore", &path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(!is_newer_format(&e));
+    }
+
+    #[test]
+    fn checkpoints_aliases_journals_and_the_digest_cache_check_their_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = manager(root, 300);
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let checkpoint = mgr.create_checkpoint("before", &["a.txt"]).unwrap();
+        assert_eq!(checkpoint.format_version, FORMAT_VERSION);
+        let path = root.join(".safe_backup/checkpoints/before.json");
+        let text = fs::read_to_string(&path).unwrap().replace("\"format_version\": 1", "\"format_version\": 2");
+        fs::write(&path, text).unwrap();
+        assert!(is_newer(&mgr.read_checkpoint("before").unwrap_err(), 2));
+        assert!(is_newer(&mgr.restore_checkpoint("before").unwrap_err(), 2));
+
+        // Newer aliases stop a rename before the file moves.
+        fs::write(root.join(".safe_backup.aliases.json"), r#"{"format_version":2,"earlier":{}}"#).unwrap();
+        assert!(is_newer(&mgr.rename_tracked("a.txt", "b.txt", false).unwrap_err(), 2));
+        assert!(root.join("a.txt").exists());
+        assert!(is_newer(&mgr.list_backups("a.txt").unwrap_err(), 2));
+        fs::write(root.join(".safe_backup.aliases.json"), r#"{"earlier":{}}"#).unwrap();
+        mgr.rename_tracked("a.txt", "b.txt", false).unwrap();
+        let text = fs::read_to_string(root.join(".safe_backup.aliases.json")).unwrap();
+        assert!(text.starts_with(r#"{"format_version":1,"#), "{text}");
+
+        // A newer journal is not resumed, nor started over.
+        fs::create_dir(root.join("tree")).unwrap();
+        let journal = root.join("tree.resume.jsonl");
+        fs::write(&journal, "{\"format_version\":2,\"dest\":\"tree.1.bak\"}\n").unwrap();
+        assert!(is_newer(&mgr.backup_dir_resumable("tree").unwrap_err(), 2));
+        assert!(fs::read_to_string(&journal).unwrap().starts_with("{\"format_version\":2"));
+
+        // The digest cache is only a cache: a newer one is ignored and replaced.
+        let cache = root.join(crate::cache::CACHE_FILE);
+        let entry = r#"{"size":1,"mtime":1,"mtime_nanos":0,"digest":"sha256:00","used":1}"#;
+        fs::write(&cache, format!(r#"{{"format_version":2,"/x":{entry}}}"#)).unwrap();
+        let loaded = crate::cache::DigestCache::load(&cache);
+        loaded.save("verify").unwrap();
+        assert_eq!(fs::read_to_string(&cache).unwrap(), r#"{"format_version":1}"#);
+        // One from before the field keeps its entries.
+        fs::write(&cache, format!(r#"{{"/x":{entry}}}"#)).unwrap();
+        crate::cache::DigestCache::load(&cache).save("verify").unwrap();
+        assert_eq!(fs::read_to_string(&cache).unwrap(), format!(r#"{{"format_version":1,"/x":{entry}}}"#));
+    }
+}
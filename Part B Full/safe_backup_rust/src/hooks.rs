@@ -0,0 +1,206 @@
+//! Programs run around file backups, restores and deletes
+//! (`Config::pre_hook`, `Config::post_hook`), e.g. to flush a database before
+//! it is backed up or to upload the backup after. Each gets the action
+//! ("backup", "restore" or "delete") and the file's resolved path as its two
+//! arguments. By default its output goes to `Config::on_event`, so the
+//! library writes nothing to stdout or stderr; `Config::hook_output` lets it
+//! share the tool's stdin, stdout and stderr instead, as the command line does.
+
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::error::BackupError;
+use crate::event::Event;
+use crate::warning::Warning;
+use crate::BackupManager;
+
+/// Where the output of the hooks goes (`Config::hook_output`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HookOutput {
+    /// The tool's own stdout and stderr; the hook can read its stdin.
+    Inherit,
+    /// Each line to `Config::on_event` as `Event::HookOutput` once the hook
+    /// exits, stdout before stderr; its stdin is empty.
+    #[default]
+    Events,
+    /// Nowhere; its stdin is empty.
+    Discard,
+}
+
+impl BackupManager {
+    /// Run `program` with `action` and `path`; why it failed, if it did. With
+    /// its output captured, that includes the last line it wrote to stderr.
+    fn run_hook(&self, program: &Path, action: &str, path: &Path) -> Result<(), String> {
+        let mut command = Command::new(program);
+        command.arg(action).arg(path);
+        if self.config.hook_output == HookOutput::Inherit {
+            return match command.status() {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(status.to_string()),
+                Err(e) => Err(format!("cannot run it: {e}")),
+            };
+        }
+        let output = command.stdin(Stdio::null()).output().map_err(|e| format!("cannot run it: {e}"))?;
+        let (stdout, stderr) = (String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        if self.config.hook_output == HookOutput::Events {
+            let lines = stdout.lines().map(|l| (false, l)).chain(stderr.lines().map(|l| (true, l)));
+            for (stderr, line) in lines {
+                self.emit(Event::HookOutput { action: action.to_string(), stderr, line: line.to_string() });
+            }
+        }
+        match stderr.lines().rev().map(str::trim).find(|l| !l.is_empty()) {
+            _ if output.status.success() => Ok(()),
+            Some(last) => Err(format!("{}: {last}", output.status)),
+            None => Err(output.status.to_string()),
+        }
+    }
+
+    /// Run `Config::pre_hook`, if set, before `action` on `path`; its failure
+    /// is `BackupError::HookFailed`.
+    pub(crate) fn pre_hook(&self, action: &'static str, path: &Path) -> io::Result<()> {
+        let Some(program) = &self.config.pre_hook else { return Ok(()) };
+        self.run_hook(program, action, path)
+            .map_err(|reason| BackupError::HookFailed { action, program: program.clone(), reason }.into())
+    }
+
+    /// Run `Config::post_hook`, if set, after `action` on `path` succeeded. A
+    /// failure is logged as a "post_hook" entry and returned as a warning.
+    pub(crate) fn post_hook(&self, root: &Path, action: &str, path: &Path) -> Option<Warning> {
+        let program = self.config.post_hook.as_ref()?;
+        let error = self.run_hook(program, action, path).err()?;
+        let name = path.strip_prefix(root).unwrap_or(path);
+        let _ = self.log_action(root, "post_hook", name, &format!("failed ({action}: {error})"));
+        Some(Warning::PostHook { path: path.to_path_buf(), error: format!("{}: {error}", program.display()) })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::{BackupError, Config, ConflictStrategy, DeleteOptions};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    /// A shell script at `root/name` that echoes its arguments into `root/hooks.txt`,
+    /// then exits with `status`.
+    fn script(root: &Path, name: &str, status: u8) -> std::path::PathBuf {
+        let path = root.join(name);
+        let out = root.join("hooks.txt");
+        fs::write(&path, format!("#!/bin/sh\necho \"{name} $1 $2\" >> '{}'\nexit {status}\n", out.display())).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn hooks_run_around_each_action() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("notes.txt"), "hi").unwrap();
+        let config = Config {
+            pre_hook: Some(script(root, "pre", 0)),
+            post_hook: Some(script(root, "post", 0)),
+            ..config(root, 100)
+        };
+        let mgr = BackupManager::new(config.clone());
+        let report = mgr.backup_file_report("notes.txt").unwrap();
+        assert_eq!(report.warnings, []);
+        mgr.delete_file("notes.txt").unwrap();
+        mgr.restore_file("notes.txt").unwrap();
+        let file = root.join("notes.txt");
+        let calls: Vec<String> = ["pre backup", "post backup", "pre delete", "post delete", "pre restore", "post restore"]
+            .iter()
+            .map(|c| format!("{c} {}", file.display()))
+            .collect();
+        assert_eq!(fs::read_to_string(root.join("hooks.txt")).unwrap().lines().collect::<Vec<_>>(), calls);
+
+        // A failing pre-hook stops the action; a failing post-hook only warns.
+        let failing = BackupManager::new(Config { pre_hook: Some(script(root, "veto", 1)), ..config.clone() });
+        let e = failing.delete_file("notes.txt").unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::HookFailed { action: "delete", .. })), "{e}");
+        assert!(file.exists());
+        let warned = BackupManager::new(Config { post_hook: Some(script(root, "upload", 2)), ..config });
+        let report = warned.backup_file_report("notes.txt").unwrap();
+        assert!(matches!(&report.warnings[..], [Warning::PostHook { .. }]), "{report:?}");
+        let last = mgr.read_log().unwrap().pop().unwrap();
+        assert_eq!((last.action.as_str(), last.file.as_str()), ("post_hook", "notes.txt"));
+        assert!(last.result.starts_with("failed (backup: "), "{}", last.result);
+        let report = warned.delete_file_with("notes.txt", &DeleteOptions::new()).unwrap();
+        assert!(matches!(&report.warnings[..], [Warning::PostHook { .. }]), "{report:?}");
+    }
+
+    #[test]
+    fn checkpoint_hooks_see_each_destination_and_all_pre_hooks_run_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for f in ["a.txt", "b.txt"] {
+            fs::write(root.join(f), format!("{f} before")).unwrap();
+        }
+        let config = config(root, 100);
+        BackupManager::new(config.clone()).create_checkpoint("cp", &["a.txt", "b.txt"]).unwrap();
+        for f in ["a.txt", "b.txt"] {
+            fs::write(root.join(f), format!("{f} after")).unwrap();
+        }
+
+        // Refused for the second file: nothing is staged or replaced.
+        let veto = root.join("veto");
+        let out = root.join("hooks.txt");
+        let log = format!("echo \"veto $1 $2\" >> '{}'", out.display());
+        let text = format!("#!/bin/sh\n{log}\ncase \"$2\" in *b.txt) exit 1;; esac\n");
+        fs::write(&veto, text).unwrap();
+        fs::set_permissions(&veto, fs::Permissions::from_mode(0o755)).unwrap();
+        let vetoed = BackupManager::new(Config { pre_hook: Some(veto), ..config.clone() });
+        let e = vetoed.restore_checkpoint("cp").unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::HookFailed { action: "restore", .. })), "{e}");
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "a.txt after");
+        let names = fs::read_dir(root).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap());
+        assert_eq!(names.filter(|n| n.ends_with(".tmp")).count(), 0);
+        fs::remove_file(&out).unwrap();
+
+        let (pre, post) = (Some(script(root, "pre", 0)), Some(script(root, "post", 2)));
+        let config = Config { pre_hook: pre, post_hook: post, ..config };
+        let report = BackupManager::new(config).restore_checkpoint_with("cp", ConflictStrategy::Overwrite).unwrap();
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "b.txt before");
+        let (a, b) = (root.join("a.txt").display().to_string(), root.join("b.txt").display().to_string());
+        let calls = [
+            format!("pre restore {a}"),
+            format!("pre restore {b}"),
+            format!("post restore {a}"),
+            format!("post restore {b}"),
+        ];
+        assert_eq!(fs::read_to_string(&out).unwrap().lines().collect::<Vec<_>>(), calls);
+        assert!(matches!(&report.warnings[..], [Warning::PostHook { .. }, Warning::PostHook { .. }]), "{report:?}");
+    }
+
+    #[test]
+    fn captured_hook_output_reaches_the_event_sink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("notes.txt"), "hi").unwrap();
+        let hook = root.join("chatty");
+        fs::write(&hook, "#!/bin/sh\necho \"checking $1\"\necho 'disk full' >&2\nexit 3\n").unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let config = Config {
+            post_hook: Some(hook.clone()),
+            hook_output: HookOutput::Events,
+            on_event: Some(Arc::new(move |e: &Event| sink.lock().unwrap().push(e.clone()))),
+            ..config(root, 100)
+        };
+        let report = BackupManager::new(config.clone()).backup_file_report("notes.txt").unwrap();
+        let line = |stderr, line: &str| Event::HookOutput { action: "backup".into(), stderr, line: line.into() };
+        assert_eq!(*events.lock().unwrap(), [line(false, "checking backup"), line(true, "disk full")]);
+        let [Warning::PostHook { error, .. }] = &report.warnings[..] else { panic!("{report:?}") };
+        assert!(error.ends_with("exit status: 3: disk full"), "{error}");
+
+        // Discarded, it goes nowhere, but still explains a failing pre-hook.
+        events.lock().unwrap().clear();
+        let discard = Config { pre_hook: Some(hook), post_hook: None, hook_output: HookOutput::Discard, ..config };
+        let e = BackupManager::new(discard).delete_file("notes.txt").unwrap_err();
+        assert!(e.to_string().contains("failed (exit status: 3: disk full)"), "{e}");
+        assert_eq!(*events.lock().unwrap(), []);
+    }
+}
@@ -0,0 +1,123 @@
+//! Progress journal of a resumable directory backup, "<base>.resume.jsonl"
+//! next to the backups. The first line names the tree being written; each
+//! further line is a file that was copied completely, with its tagged digest.
+//! The first line also records the journal's `format_version`; a journal in a
+//! newer format is refused rather than resumed.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::digest::{file_digest, tagged, DigestAlgo};
+use crate::error::Context;
+use crate::format::{is_newer_format, parse_versioned, unversioned, FORMAT_VERSION};
+use crate::naming::base_name;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    #[serde(default = "unversioned")]
+    format_version: u32,
+    /// File name of the "<name>.<ts>.bak" tree in the backup directory.
+    dest: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Done {
+    /// Path relative to the tree, '/'-separated.
+    file: String,
+    #[serde(alias = "sha256")]
+    digest: String,
+}
+
+/// An open journal, appended to as files are finished. Shared by the threads
+/// copying a tree: they look up finished files while one of them records more.
+pub(crate) struct Journal {
+    path: PathBuf,
+    dest: PathBuf,
+    done: HashMap<String, String>,
+    out: Mutex<fs::File>,
+}
+
+/// "<broot>/<base>.resume.jsonl" for the directory `name`.
+pub(crate) fn journal_for(broot: &Path, name: &Path) -> io::Result<PathBuf> {
+    let mut fname = OsString::from(base_name(name)?);
+    fname.push(".resume.jsonl");
+    Ok(broot.join(fname))
+}
+
+impl Journal {
+    /// Pick up the journal at `path` if it names a tree that still exists,
+    /// else start a new one for `fresh`. Unreadable lines (e.g. one cut short
+    /// by a crash) are ignored; their files are simply copied again. Files
+    /// already recorded are checked with the algorithm they were recorded with.
+    pub(crate) fn open(op: &'static str, path: &Path, fresh: &Path) -> io::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).at(op, "cannot read journal", path),
+        };
+        let mut lines = text.lines();
+        let broot = path.parent().unwrap_or(Path::new(""));
+        let header = match lines.next().map(|l| parse_versioned::<Header>(op, "malformed journal", path, l)) {
+            Some(Err(e)) if is_newer_format(&e) => return Err(e),
+            header => header.and_then(Result::ok),
+        };
+        let resumed = header
+            .map(|h| broot.join(h.dest))
+            .filter(|d| d.is_dir());
+        if let Some(dest) = resumed {
+            let done = lines
+                .filter_map(|l| serde_json::from_str::<Done>(l).ok())
+                .map(|d| (d.file, d.digest))
+                .collect();
+            let out = OpenOptions::new().append(true).open(path).at(op, "cannot open journal", path)?;
+            return Ok(Self { path: path.to_path_buf(), dest, done, out: Mutex::new(out) });
+        }
+        let mut out = fs::File::create(path).at(op, "cannot create journal", path)?;
+        let dest = fresh.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        serde_json::to_string(&Header { format_version: FORMAT_VERSION, dest })
+            .map_err(io::Error::other)
+            .and_then(|line| writeln!(out, "{line}"))
+            .at(op, "cannot write journal", path)?;
+        let out = Mutex::new(out);
+        Ok(Self { path: path.to_path_buf(), dest: fresh.to_path_buf(), done: HashMap::new(), out })
+    }
+
+    /// The tree being written.
+    pub(crate) fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    /// The recorded digest of `target` inside the tree, if it was finished
+    /// before and still matches it.
+    pub(crate) fn done_digest(&self, target: &Path) -> Option<String> {
+        let recorded = tagged(self.done.get(&self.key(target))?);
+        let algo = DigestAlgo::of(&recorded).ok()?;
+        file_digest(target, algo).is_ok_and(|d| d == recorded).then_some(recorded)
+    }
+
+    /// Record `target` inside the tree as finished, with the `digest` taken while copying it.
+    pub(crate) fn mark_done(&self, op: &'static str, target: &Path, digest: &str) -> io::Result<()> {
+        let line = serde_json::to_string(&Done { file: self.key(target), digest: digest.to_string() })
+            .map_err(io::Error::other)
+            .at(op, "cannot write journal", &self.path)?;
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(out, "{line}").and_then(|_| out.sync_data()).at(op, "cannot write journal", &self.path)
+    }
+
+    /// Delete the journal once the tree is complete.
+    pub(crate) fn finish(self, op: &'static str) -> io::Result<()> {
+        drop(self.out);
+        fs::remove_file(&self.path).at(op, "cannot remove journal", &self.path)
+    }
+
+    fn key(&self, target: &Path) -> String {
+        let rel = target.strip_prefix(&self.dest).unwrap_or(target);
+        rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+    }
+}
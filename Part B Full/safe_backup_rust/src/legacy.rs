@@ -0,0 +1,147 @@
+//! Moving backups of the old tool ("<name>.YYYYMMDDHHMMSS.bk", "<name>.bk")
+//! over to the native "<name>.<ts>.bak" scheme.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::error::Context;
+use crate::meta::{sidecar_for, write_meta};
+use crate::naming::{split_legacy_name, ts_backup_for};
+use crate::{BackupManager, Compression};
+
+/// One legacy backup renamed (or, in a dry run, to be renamed) by [`BackupManager::migrate_legacy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl BackupManager {
+    /// Rename every legacy ".bk" backup in the backup directory to
+    /// "<name>.<ts>.bak" and give it a sidecar, so it is a native backup from
+    /// then on. `ts` is the time in its name, or its mtime for "<name>.bk";
+    /// when that second is taken, the next free one, so the order of all
+    /// backups of a file is kept. With `dry_run`, only report the renames.
+    /// Works whether or not `Config::legacy_bk` is set.
+    pub fn migrate_legacy(&self, dry_run: bool) -> io::Result<Vec<Migration>> {
+        let root = self.root()?;
+        let broot = self.backup_root(&root);
+        if !self.fs.metadata(&broot).is_ok_and(|m| m.is_dir()) {
+            return Ok(Vec::new());
+        }
+        let mut legacy: Vec<(OsString, u64, PathBuf)> = Vec::new();
+        for fname in self.fs.read_dir_names(&broot).at("migrate", "cannot list directory", &broot)? {
+            let fname = fname.at("migrate", "cannot list directory", &broot)?;
+            let Some((logical, ts)) = split_legacy_name(&fname) else { continue };
+            let path = broot.join(&fname);
+            let md = self.fs.symlink_metadata(&path).at("migrate", "cannot stat", &path)?;
+            if !md.is_file() {
+                continue;
+            }
+            let mtime = md.modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+            legacy.push((logical.to_os_string(), ts.unwrap_or(mtime.as_secs()), path));
+        }
+        // Oldest first per name, so a bumped timestamp never overtakes a later legacy backup.
+        legacy.sort();
+        let mut taken = HashSet::new();
+        let mut moves = Vec::new();
+        for (logical, mut ts, from) in legacy {
+            let name = Path::new(&logical);
+            let mut to = ts_backup_for(&broot, name, ts)?;
+            while taken.contains(&to) || self.fs.exists(&to) || self.fs.exists(&sidecar_for(&to)) {
+                ts += 1;
+                to = ts_backup_for(&broot, name, ts)?;
+            }
+            taken.insert(to.clone());
+            if !dry_run {
+                self.fs.rename(&from, &to).copying("migrate", &from, &to)?;
+                write_meta(&*self.fs, "migrate", &to, name, &to, Compression::None, self.config.digest)?;
+                let old = from.file_name().unwrap_or(from.as_os_str()).to_string_lossy();
+                self.log_action(&root, "migrate", name, &format!("ok (from {old})"))?;
+            }
+            moves.push(Migration { from, to });
+        }
+        Ok(moves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::Config;
+    use std::ffi::OsStr;
+    use std::fs;
+
+    fn manager(root: &Path, legacy_bk: bool, now: u64) -> BackupManager {
+        BackupManager::new(Config { legacy_bk, ..config(root, now) })
+    }
+
+    /// Unix time of a legacy file name, as the tool reads it.
+    fn legacy_ts(fname: &str) -> u64 {
+        split_legacy_name(OsStr::new(fname)).unwrap().1.unwrap()
+    }
+
+    #[test]
+    fn mixed_schemes_sort_by_time_and_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let (old, new) = ("notes.txt.20200101120000.bk", "notes.txt.20300101120000.bk");
+        let mid = legacy_ts(old) + 86_400 * 365;
+        fs::write(root.join("notes.txt"), "native").unwrap();
+        manager(root, false, mid).backup_file("notes.txt").unwrap();
+        fs::write(root.join(old), "legacy old").unwrap();
+        fs::write(root.join(new), "legacy new").unwrap();
+
+        // Off by default: only the native backup exists.
+        let off = manager(root, false, mid + 1);
+        assert_eq!(off.list_backups("notes.txt").unwrap().len(), 1);
+
+        let on = manager(root, true, mid + 1);
+        let ts: Vec<u64> = on.list_backups("notes.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(ts, [legacy_ts(new), mid, legacy_ts(old)]);
+        assert_eq!(on.find_latest_backup("notes.txt").unwrap(), root.join(new));
+
+        on.restore_file("notes.txt").unwrap();
+        assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "legacy new");
+        on.restore_file(old).unwrap();
+        assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "legacy old");
+    }
+
+    #[test]
+    fn migrate_renames_in_order_and_adds_sidecars() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let dated = "a.txt.20240301080000.bk";
+        let ts = legacy_ts(dated);
+        fs::write(root.join("a.txt"), "x").unwrap();
+        // A native backup already holds that second.
+        manager(root, false, ts).backup_file("a.txt").unwrap();
+        fs::write(root.join(dated), "dated").unwrap();
+        fs::write(root.join("a.txt.bk"), "undated").unwrap();
+        let undated_mtime = fs::metadata(root.join("a.txt.bk")).unwrap().modified().unwrap();
+        let undated = undated_mtime.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        let mgr = manager(root, false, undated + 10);
+        let planned = mgr.migrate_legacy(true).unwrap();
+        assert!(root.join(dated).exists());
+        let done = mgr.migrate_legacy(false).unwrap();
+        assert_eq!(done, planned);
+        assert_eq!(
+            done,
+            [
+                Migration { from: root.join(dated), to: root.join(format!("a.txt.{}.bak", ts + 1)) },
+                Migration { from: root.join("a.txt.bk"), to: root.join(format!("a.txt.{undated}.bak")) },
+            ]
+        );
+        let list = mgr.list_backups("a.txt").unwrap();
+        let ts_list: Vec<u64> = list.iter().map(|e| e.ts).collect();
+        assert_eq!(ts_list, [undated, ts + 1, ts]);
+        mgr.verify_backup(format!("a.txt.{}.bak", ts + 1)).unwrap();
+        assert_eq!(mgr.read_log().unwrap().iter().filter(|e| e.action == "migrate").count(), 2);
+        assert!(mgr.migrate_legacy(false).unwrap().is_empty());
+    }
+}
@@ -1,167 +1,2859 @@
 //! Core library for safe_backup.
 //! Secure file operations: backup, restore, delete, with validation & simple logging.
 
-use std::fs::{self, OpenOptions};
-use std::io::{self, Write};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-fn now_unix() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
+use error::{missing, no_backup, Context};
+use meta::{
+    apply_meta, is_tool_backup, is_tool_backup_of, read_meta, record_acl, record_note, record_xattrs, sidecar_for,
+    sidecar_if_any, tool_backup_original, write_meta, write_meta_for, write_stream_meta, BackupDigest,
+};
+use naming::{
+    base_name, display_name, logical_name, name_from_display, parse_legacy_backup, parse_ts_backup, plain_backup_for,
+    split_backup_name, split_legacy_name, trim_name, ts_backup_for,
+};
+use validate::{check_backup_name, find_reserved, special_file_kind, validate_path_in};
+
+mod acls;
+mod alias;
+mod audit;
+mod batch;
+mod busy;
+mod cache;
+mod cancel;
+mod check;
+mod checkpoint;
+mod chunk;
+mod clean;
+mod clock;
+mod compact;
+mod compress;
+mod config;
+mod conflict;
+mod dedup;
+mod delta;
+mod digest;
+mod dir;
+mod error;
+mod event;
+mod exclude;
+mod format;
+mod hooks;
+mod journal;
+mod legacy;
+mod log;
+mod logger;
+mod meta;
+mod naming;
+mod note;
+mod options;
+mod perms;
+mod remote;
+mod retention;
+mod retry;
+mod rotate;
+mod schedule;
+mod script;
+mod settings;
+mod tidy;
+mod timeout;
+mod trash;
+mod validate;
+mod versions;
+mod vfs;
+mod warning;
+mod watch;
+mod xattrs;
+
+pub use alias::RenameReport;
+pub use batch::{BackupSet, BatchEntry, BatchReport, BatchStatus, RepoStats, RECENT_WINDOW};
+pub use cancel::{Cancel, CancellationToken};
+pub use check::{CheckStatus, RestoreCheck, RestoreConflict};
+pub use checkpoint::{Checkpoint, CheckpointFile, CheckpointReport};
+pub use chunk::DEFAULT_CHUNK_SIZE;
+pub use clean::{CleanReport, StrayFile, StrayKind};
+pub use clock::{Clock, FixedClock, ManualClock, SystemClock, TimeZone};
+pub use compact::CompactReport;
+pub use compress::Compression;
+pub use config::Config;
+pub use dedup::GcReport;
+pub use digest::{file_digest, DigestAlgo};
+pub use dir::{DirReport, DirRestoreReport};
+pub use error::{error_chain, error_code, exit_code, BackupError};
+pub use event::{Event, EventSink};
+pub use format::FORMAT_VERSION;
+pub use hooks::HookOutput;
+pub use legacy::Migration;
+pub use logger::{ActionLogger, BufferedLogger};
+pub use log::{
+    parse_log_line, read_log, verify_log_chain, CorruptLine, LogEntry, LogFilter, LogRepair, LogScan, LogUser,
+    ParsedLine, RecoveredLine, SkipReason, GENESIS_HASH, LOG_VERSION,
+};
+pub use meta::{read_backup_meta, BackupMeta, Layout};
+pub use note::MAX_NOTE_BYTES;
+pub use conflict::ConflictStrategy;
+pub use options::{BackupOptions, DeleteOptions, DirOptions, RestoreOptions, WatchOptions};
+pub use remote::{
+    RemoteReport, RemoteTarget, SftpConfig, ENV_SFTP_DIR, ENV_SFTP_HOST, ENV_SFTP_KEY, ENV_SFTP_PASSWORD, ENV_SFTP_PORT,
+    ENV_SFTP_USER,
+};
+pub use retention::{RetentionPolicy, RetentionReport};
+pub use retry::{is_transient, RetryPolicy};
+pub use rotate::LogPrune;
+pub use schedule::{ChangeCheck, CycleReport};
+pub use script::{parse_script, ScriptAction, ScriptReport, ScriptStep};
+pub use settings::{resolve_settings, Settings, ENV_BACKUP_DIR, ENV_COMPRESS, ENV_KEEP, ENV_LOG};
+pub use trash::TrashReport;
+pub use validate::NameLimits;
+pub use versions::BackupEntry;
+pub use vfs::{DirNames, FileKind, FileStat, FileSystem, RealFs};
+pub use warning::Warning;
+
+/// The version of this crate, e.g. "0.1.0".
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The optional Cargo features this build has, of "blake3", "reflink",
+/// "xattrs", "acls" and "sftp". One enabled on a platform it does nothing on
+/// (xattrs off Unix, acls off Linux) is left out.
+pub fn capabilities() -> Vec<&'static str> {
+    [
+        ("blake3", cfg!(feature = "blake3")),
+        ("reflink", cfg!(feature = "reflink")),
+        ("xattrs", cfg!(all(unix, feature = "xattrs"))),
+        ("acls", cfg!(all(target_os = "linux", feature = "acls"))),
+        ("sftp", cfg!(feature = "sftp")),
+    ]
+    .into_iter()
+    .filter_map(|(name, on)| on.then_some(name))
+    .collect()
 }
 
 /// Validate a filename: not empty, not absolute, no parent traversal.
-pub fn validate_path(name: &str) -> io::Result<PathBuf> {
-    let trimmed = name.trim();
-    if trimmed.is_empty() {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty file name"));
-    }
-    let p = Path::new(trimmed);
-    if p.is_absolute() {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "absolute paths not allowed"));
-    }
-    let s = trimmed.replace('\\', "/");
-    if s.starts_with("../") || s.contains("/../") || s.starts_with("./../") {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "parent traversal not allowed"));
-    }
-    let mut cwd = std::env::current_dir()?;
-    cwd.push(trimmed);
-    Ok(cwd)
-}
-
-/// Build timestamped "<name>.<ts>.bak" in CWD.
-fn ts_backup_for(original_name: &str, ts: u64) -> io::Result<PathBuf> {
-    let base = Path::new(original_name)
-        .file_name()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))?
-        .to_string_lossy()
-        .to_string();
-    let mut cwd = std::env::current_dir()?;
-    cwd.push(format!("{base}.{ts}.bak"));
-    Ok(cwd)
-}
-
-/// Build convenience "name.bak" (just the stem + .bak) in CWD.
-fn plain_backup_for(original_name: &str) -> io::Result<PathBuf> {
-    let base = Path::new(original_name)
-        .file_stem()
-        .unwrap_or_else(|| Path::new(original_name).as_os_str())
-        .to_string_lossy()
-        .to_string();
-    let mut cwd = std::env::current_dir()?;
-    cwd.push(format!("{base}.bak"));
-    Ok(cwd)
-}
-
-/// Find latest "<base>.<ts>.bak" for original; fall back to "name.bak".
-pub fn find_latest_backup(original_name: &str) -> io::Result<PathBuf> {
-    let mut newest: Option<(u64, PathBuf)> = None;
-    let base = Path::new(original_name)
-        .file_name()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))?
-        .to_string_lossy()
-        .to_string();
-
-    for entry in fs::read_dir(std::env::current_dir()?)? {
-        let entry = entry?;
-        let path = entry.path();
-        if !path.is_file() { continue; }
-        let Some(fname) = path.file_name().and_then(|s| s.to_str()) else { continue };
-        if fname.starts_with(&(base.clone() + ".")) and fname.ends_with(".bak") {
-            if let Some(ts) = fname.trim_end_matches(".bak").rsplit('.').next().and_then(|n| n.parse::<u64>().ok()) {
-                if newest.as_ref().map(|(t, _)| ts > *t).unwrap_or(true) {
-                    newest = Some((ts, path.clone()));
+/// Returns the normalized path under the CWD; see `validate_path_in` for the exact rules.
+pub fn validate_path(name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().validate_path(name)
+}
+
+/// [`validate_path`] that also returns the canonical name; see [`BackupManager::validate_path_parts`].
+pub fn validate_path_parts(name: impl AsRef<Path>) -> io::Result<(PathBuf, String)> {
+    BackupManager::default().validate_path_parts(name)
+}
+
+/// Find latest "<base>.<ts>.bak" made by this tool for original; fall back to "name.bak".
+/// Same-second backups are ordered by mtime, then file name (see `newest_ts_backup`).
+pub fn find_latest_backup(original_name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().find_latest_backup(original_name)
+}
+
+/// Newest "<base>.<ts>.bak" entry in `root` accepted by `keep` (files or directories).
+/// See [`ts_backups`] for what counts and how ties are broken.
+fn newest_ts_backup(
+    fs: &dyn FileSystem,
+    root: &Path,
+    original_name: &Path,
+    legacy: bool,
+    keep: impl Fn(&FileStat) -> bool,
+) -> io::Result<Option<PathBuf>> {
+    Ok(newest_ts_backup_in(fs, &[root], &[(original_name, ANY_TIME)], legacy, keep)?.map(|(_, p)| p))
+}
+
+/// The timestamps of backups under a name that count for it; for most names, all.
+pub(crate) type Window = std::ops::RangeInclusive<u64>;
+
+pub(crate) const ANY_TIME: Window = 0..=u64::MAX;
+
+/// [`newest_ts_backup`] over several directories and names, with its
+/// timestamp. Only the newest so far is kept, and a name dated before it
+/// costs no stat or sidecar read.
+fn newest_ts_backup_in(
+    fs: &dyn FileSystem,
+    roots: &[&Path],
+    original_names: &[(&Path, Window)],
+    legacy: bool,
+    keep: impl Fn(&FileStat) -> bool,
+) -> io::Result<Option<(u64, PathBuf)>> {
+    let mut newest: Option<(u64, SystemTime, PathBuf)> = None;
+    let floor = std::cell::Cell::new(0);
+    scan_ts_backups(fs, roots, original_names, legacy, keep, |ts| ts >= floor.get(), |found| {
+        if newest.as_ref().is_none_or(|n| backup_order(&found, n).is_lt()) {
+            floor.set(found.0);
+            newest = Some(found);
+        }
+    })?;
+    Ok(newest.map(|(ts, _, p)| (ts, p)))
+}
+
+/// All "<base>.<ts>.bak" entries in `root` whose stats `keep` accepts, newest first, with
+/// their timestamps. A missing `root` has none.
+/// Only entries with a `.meta.json` marker recording `original_name`'s file name
+/// count: a user file that happens to be called "data.999.bak" is not a backup
+/// of "data", nor is a backup of "data.bak" called "data.bak.999.bak".
+/// With `legacy`, the old tool's "<base>.<datetime>.bk" and "<base>.bk" count
+/// too, the undated one stamped with its mtime, so both schemes sort by time.
+///
+/// Ordering is deterministic regardless of directory iteration order: highest
+/// timestamp first; on a tie the later mtime first; if those tie too, the
+/// greatest file name (by bytes) first.
+fn ts_backups(
+    fs: &dyn FileSystem,
+    root: &Path,
+    original_name: &Path,
+    legacy: bool,
+    keep: impl Fn(&FileStat) -> bool,
+) -> io::Result<Vec<(u64, PathBuf)>> {
+    ts_backups_in(fs, &[root], &[(original_name, ANY_TIME)], legacy, keep)
+}
+
+/// [`ts_backups`] over several directories and names, each with the
+/// timestamps that count for it, ordered together.
+fn ts_backups_in(
+    fs: &dyn FileSystem,
+    roots: &[&Path],
+    original_names: &[(&Path, Window)],
+    legacy: bool,
+    keep: impl Fn(&FileStat) -> bool,
+) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut found = Vec::new();
+    scan_ts_backups(fs, roots, original_names, legacy, keep, |_| true, |entry| found.push(entry))?;
+    found.sort_by(backup_order);
+    Ok(found.into_iter().map(|(ts, _, p)| (ts, p)).collect())
+}
+
+/// The order of [`ts_backups`] for backups with their timestamps and mtimes: newest first.
+fn backup_order(a: &(u64, SystemTime, PathBuf), b: &(u64, SystemTime, PathBuf)) -> std::cmp::Ordering {
+    (b.0, b.1, b.2.file_name()).cmp(&(a.0, a.1, a.2.file_name()))
+}
+
+/// Hand each backup [`ts_backups_in`] counts to `found`, with its
+/// timestamp and mtime, reading each directory once and entry by entry. The
+/// timestamp in a dated name is put to `wanted` first, before anything is
+/// read for it; one it turns down is skipped.
+///
+/// Against reading each directory whole, once per name, and sorting every
+/// backup, finding the newest of 20 backups among 10,000 other files (ext4,
+/// warm cache, release build) went from 40,337 allocations and 4.3 ms to
+/// 20,116 and 3.0 ms; of 2,000 backups, from 70,044 and 19.7 ms to 28,092
+/// and 4.6 ms, as the older ones have no sidecar read (mean of three runs).
+fn scan_ts_backups(
+    fs: &dyn FileSystem,
+    roots: &[&Path],
+    original_names: &[(&Path, Window)],
+    legacy: bool,
+    keep: impl Fn(&FileStat) -> bool,
+    wanted: impl Fn(u64) -> bool,
+    mut found: impl FnMut((u64, SystemTime, PathBuf)),
+) -> io::Result<()> {
+    let bases = original_names.iter().map(|(n, w)| Ok((base_name(n)?, w))).collect::<io::Result<Vec<_>>>()?;
+    for root in roots.iter().filter(|r| fs.metadata(r).is_ok_and(|m| m.is_dir())) {
+        for fname in fs.read_dir_names(root).at("find", "cannot list directory", root)? {
+            let fname = fname.at("find", "cannot list directory", root)?;
+            for &(base, window) in &bases {
+                let (ts, marked) = match parse_ts_backup(&fname, base) {
+                    Some(ts) => (Some(ts), true),
+                    None if legacy => match parse_legacy_backup(&fname, base) {
+                        Some(ts) => (ts, false),
+                        None => continue,
+                    },
+                    None => continue,
+                };
+                if ts.is_some_and(|ts| !wanted(ts) || !window.contains(&ts)) {
+                    continue;
+                }
+                let path = root.join(&fname);
+                if marked && !is_tool_backup_of(fs, &path, base) {
+                    continue;
+                }
+                if !fs.metadata(&path).is_ok_and(|m| keep(&m)) {
+                    continue;
+                }
+                let mtime = fs.symlink_metadata(&path).ok().and_then(|m| m.modified).unwrap_or(UNIX_EPOCH);
+                let ts = ts.unwrap_or_else(|| mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+                if window.contains(&ts) {
+                    found((ts, mtime, path));
                 }
             }
         }
     }
+    Ok(())
+}
+
+/// Backup unless there is one with the default configuration; see [`BackupManager::ensure_backup`].
+pub fn ensure_backup(name: impl AsRef<Path>) -> io::Result<(PathBuf, bool)> {
+    BackupManager::default().ensure_backup(name)
+}
+
+/// Backup with the default configuration; see [`BackupManager::backup_file`].
+pub fn backup_file(name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().backup_file(name)
+}
+
+/// Backup with the default configuration and `opts`; see [`BackupManager::backup_file_with`].
+pub fn backup_file_with(name: impl AsRef<Path>, opts: &BackupOptions) -> io::Result<BackupReport> {
+    BackupManager::default().backup_file_with(name, opts)
+}
+
+/// Append-only backup with the default configuration; see [`BackupManager::backup_append_delta`].
+pub fn backup_append_delta(name: impl AsRef<Path>) -> io::Result<BackupReport> {
+    BackupManager::default().backup_append_delta(name)
+}
+
+/// Stream backup with the default configuration; see [`BackupManager::backup_from_reader`].
+pub fn backup_from_reader(name: impl AsRef<Path>, reader: impl Read) -> io::Result<BackupReport> {
+    BackupManager::default().backup_from_reader(name, reader)
+}
+
+/// Restore with the default configuration; see [`BackupManager::restore_file`].
+pub fn restore_file(name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().restore_file(name)
+}
+
+/// Restore with the default configuration and `opts`; see [`BackupManager::restore_file_with`].
+pub fn restore_file_with(name: impl AsRef<Path>, opts: &RestoreOptions) -> io::Result<RestoreReport> {
+    BackupManager::default().restore_file_with(name, opts)
+}
+
+/// Restore dry run with the default configuration; see [`BackupManager::check_restore`].
+pub fn check_restore(name: impl AsRef<Path>, opts: &RestoreOptions) -> io::Result<RestoreCheck> {
+    BackupManager::default().check_restore(name, opts)
+}
+
+/// Backup, then upload it over SFTP; see [`BackupManager::backup_file_remote`].
+pub fn backup_file_remote(name: impl AsRef<Path>, sftp: &SftpConfig) -> io::Result<RemoteReport> {
+    BackupManager::default().backup_file_remote(name, sftp)
+}
+
+/// Originals known from the log or from existing backups; see [`BackupManager::tracked_originals`].
+pub fn tracked_originals() -> io::Result<Vec<OsString>> {
+    BackupManager::default().tracked_originals()
+}
+
+/// Rename a file, its backups following it; see [`BackupManager::rename_tracked`].
+pub fn rename_tracked(old: impl AsRef<Path>, new: impl AsRef<Path>, migrate_backups: bool) -> io::Result<RenameReport> {
+    BackupManager::default().rename_tracked(old, new, migrate_backups)
+}
+
+/// Backups of the originals whose names contain `substring`; see [`BackupManager::find_backups`].
+pub fn find_backups(substring: &str) -> io::Result<Vec<BackupEntry>> {
+    BackupManager::default().find_backups(substring)
+}
+
+/// Back up each of `names`; see [`BackupManager::backup_many`].
+pub fn backup_many(names: &[PathBuf], opts: &BackupOptions) -> BatchReport {
+    BackupManager::default().backup_many(names, opts)
+}
+
+/// Restore the latest backup of everything ever backed up; see [`BackupManager::restore_all`].
+/// If the originals can't even be enumerated, the single entry `("*", Err(_))` says why.
+pub fn restore_all() -> Vec<(String, io::Result<PathBuf>)> {
+    BackupManager::default().restore_all().unwrap_or_else(|e| vec![("*".to_string(), Err(e))])
+}
+
+/// Verify every backup of everything ever backed up; see [`BackupManager::verify_all`].
+/// If the originals can't even be enumerated, the single entry `("*", Err(_))` says why.
+pub fn verify_all() -> Vec<(String, io::Result<()>)> {
+    BackupManager::default().verify_all().unwrap_or_else(|e| vec![("*".to_string(), Err(e))])
+}
+
+/// Forget the digests remembered by [`verify_all`]; see [`BackupManager::clear_digest_cache`].
+pub fn clear_digest_cache() -> io::Result<()> {
+    BackupManager::default().clear_digest_cache()
+}
+
+/// Run a script of operations with the default configuration; see [`BackupManager::run_script`].
+pub fn run_script(path: impl AsRef<Path>, keep_going: bool) -> io::Result<ScriptReport> {
+    BackupManager::default().run_script(path, keep_going)
+}
+
+/// Totals over the whole backup directory; see [`BackupManager::stats`].
+pub fn stats() -> io::Result<RepoStats> {
+    BackupManager::default().stats()
+}
+
+/// Drop the lines of the log that aren't entries; see [`BackupManager::repair_log`].
+pub fn repair_log() -> io::Result<LogRepair> {
+    BackupManager::default().repair_log()
+}
+
+/// Rename legacy ".bk" backups to native ones; see [`BackupManager::migrate_legacy`].
+pub fn migrate_legacy(dry_run: bool) -> io::Result<Vec<Migration>> {
+    BackupManager::default().migrate_legacy(dry_run)
+}
+
+/// Directory backup with the default configuration; see [`BackupManager::backup_dir`].
+pub fn backup_dir(name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().backup_dir(name)
+}
+
+/// Resumable directory backup with the default configuration; see [`BackupManager::backup_dir_resumable`].
+pub fn backup_dir_resumable(name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().backup_dir_resumable(name)
+}
+
+/// Directory backup with the default configuration and `opts`; see [`BackupManager::backup_dir_with`].
+pub fn backup_dir_with(name: impl AsRef<Path>, opts: &DirOptions) -> io::Result<DirReport> {
+    BackupManager::default().backup_dir_with(name, opts)
+}
+
+/// Directory restore with the default configuration; see [`BackupManager::restore_dir`].
+pub fn restore_dir(name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().restore_dir(name)
+}
+
+/// Whether a backup call made a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupOutcome {
+    /// A new backup was made.
+    Created,
+    /// The file matches its latest backup, so none was made
+    /// ([`BackupOptions::if_changed`]).
+    Unchanged,
+    /// The file does not exist, so none was made ([`BackupOptions::skip_missing`]).
+    Skipped,
+}
+
+impl fmt::Display for BackupOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Created => "created",
+            Self::Unchanged => "unchanged",
+            Self::Skipped => "skipped",
+        })
+    }
+}
+
+/// Outcome of a successful file backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupReport {
+    /// The timestamped backup that was created; when `Unchanged`, the latest
+    /// backup the file matches; when `Skipped`, the file that was missing.
+    pub path: PathBuf,
+    /// Whether a backup was made, and if not why.
+    pub outcome: BackupOutcome,
+    /// Whether it was made as a copy-on-write reflink rather than a byte copy.
+    pub reflinked: bool,
+    /// Size of what was backed up, before compression.
+    pub bytes: u64,
+    /// The plain "<stem>.bak" copy, if it was refreshed too.
+    pub plain: Option<PathBuf>,
+    /// Secondary steps that failed without failing the backup.
+    pub warnings: Vec<Warning>,
+}
+
+impl BackupReport {
+    /// The report of a call that made no backup.
+    fn not_made(path: PathBuf, outcome: BackupOutcome, bytes: u64) -> Self {
+        Self { path, outcome, reflinked: false, bytes, plain: None, warnings: Vec::new() }
+    }
+}
+
+/// What a successful restore did about its destination
+/// ([`RestoreOptions::on_conflict`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    /// There was no file there; it was written.
+    Restored,
+    /// The file there was replaced.
+    Overwritten,
+    /// The file there was moved aside (to [`RestoreReport::moved`]), then written.
+    RenamedExisting,
+    /// The file there was left alone, and nothing restored.
+    Skipped,
+    /// The file there already held what the backup does, so it was left alone.
+    Unchanged,
+}
 
-    if let Some((_, p)) = newest { return Ok(p); }
+impl fmt::Display for RestoreOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Restored => "restored",
+            Self::Overwritten => "overwritten",
+            Self::RenamedExisting => "renamed existing",
+            Self::Skipped => "skipped",
+            Self::Unchanged => "unchanged",
+        })
+    }
+}
+
+/// Outcome of a successful file restore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// The file that was written, or left alone.
+    pub path: PathBuf,
+    /// Whether it was written, and what became of the file that was there.
+    pub outcome: RestoreOutcome,
+    /// Where the file that was there was moved, for `RenamedExisting`.
+    pub moved: Option<PathBuf>,
+    /// Secondary steps that failed without failing the restore.
+    pub warnings: Vec<Warning>,
+}
+
+/// Outcome of [`BackupManager::delete_file_with`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeleteReport {
+    /// The file that was deleted (or would be, with `dry_run`); `None` if it wasn't there.
+    pub deleted: Option<PathBuf>,
+    /// Where it went, with `trash`; `None` with `dry_run`.
+    pub trashed: Option<PathBuf>,
+    /// Its backups that were removed (or would be), with `purge_backups`.
+    pub purged: Vec<PathBuf>,
+    /// Non-fatal problems: a failed post-hook.
+    pub warnings: Vec<Warning>,
+}
+
+/// What a restore reads and writes, worked out before touching anything.
+struct RestorePlan {
+    source: PathBuf,
+    dest: PathBuf,
+    compression: Compression,
+    /// Recorded digest of `source`, from its sidecar.
+    digest: Option<String>,
+    /// Where the contents of `source` are.
+    layout: Layout,
+    /// The sidecar, if it names an original matching the backup.
+    meta: Option<BackupMeta>,
+}
 
-    let plain = plain_backup_for(original_name)?;
-    if plain.exists() { return Ok(plain); }
+/// Runs operations under a given [`Config`].
+#[derive(Clone)]
+pub struct BackupManager {
+    config: Config,
+    /// Where files are read and written; see [`vfs`].
+    fs: Arc<dyn FileSystem>,
+    /// The deadline of the operation this manager was cloned to run under
+    /// `Config::timeout`; see [`timeout`].
+    timer: Option<cancel::Timer>,
+    /// Where log entries go instead of straight to the log; see [`logger`].
+    logger: Option<Arc<Mutex<Box<dyn ActionLogger>>>>,
+}
 
-    Err(io::Error::new(io::ErrorKind::NotFound, "no backup file found"))
+impl Default for BackupManager {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
 }
 
-/// Backup: copies <name> to timestamped and also updates plain "<stem>.bak".
-pub fn backup_file(name: &str) -> io::Result<PathBuf> {
-    let src = validate_path(name)?;
-    if !src.exists() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "source file does not exist"));
+impl fmt::Debug for BackupManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackupManager").field("config", &self.config).finish_non_exhaustive()
     }
-    let ts = now_unix();
-    let ts_bak = ts_backup_for(name, ts)?;
-    fs::copy(&src, &ts_bak)?;
-    let plain_bak = plain_backup_for(name)?;
-    fs::copy(&src, &plain_bak)?;
-    log_action("backup", name, "ok")?;
-    Ok(ts_bak)
 }
 
-/// Restore:
-/// - If `name` ends with ".bak": restore from that file to a sensible target.
-/// - If `name` is original (e.g., "test.txt"): restore from latest backup to "name".
-pub fn restore_file(name: &str) -> io::Result<PathBuf> {
-    let trimmed = name.trim();
-    let cwd = std::env::current_dir()?;
-    let dest: PathBuf;
-    let src_bak: PathBuf;
+impl BackupManager {
+    pub fn new(config: Config) -> Self {
+        Self { config, fs: Arc::new(RealFs), timer: None, logger: None }
+    }
+
+    /// A manager working on `fs` instead of the real file system, such as
+    /// one in memory for tests. Chunked, deduplicated and remote backups,
+    /// and the journal of a resumable directory backup, still use the real one.
+    pub fn with_fs(config: Config, fs: Arc<dyn FileSystem>) -> Self {
+        Self { config, fs, timer: None, logger: None }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 
-    if trimmed.ends_with(".bak") {
-        src_bak = validate_path(trimmed)?;
-        if !src_bak.exists() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "backup file not found"));
+    /// Directory all names are resolved against: `config.root` or the CWD.
+    fn root(&self) -> io::Result<PathBuf> {
+        match &self.config.root {
+            Some(r) => Ok(r.clone()),
+            None => self.fs.current_dir(),
         }
-        let fname = Path::new(trimmed).file_name().and_then(|s| s.to_str()).unwrap_or(trimmed);
-        let maybe_ts = fname.trim_end_matches(".bak").rsplit('.').next();
-        let ts_is_num = maybe_ts.and_then(|n| n.parse::<u64>().ok()).is_some();
+    }
 
-        if ts_is_num {
-            // "<orig>.<ts>.bak" → restore to "<orig>"
-            let logical = fname.trim_end_matches(".bak").rsplitn(2, '.').last().unwrap_or("restored.out");
-            dest = cwd.join(logical);
-        } else {
-            // "<stem>.bak" → restore to "<stem>.restored.<now>"
-            let stem = Path::new(fname).file_stem().and_then(|s| s.to_str()).unwrap_or("restored");
-            dest = cwd.join(format!("{stem}.restored.{}", now_unix()));
+    /// Directory backups live in: `config.backup_dir` under `root`, or `root` itself.
+    fn backup_root(&self, root: &Path) -> PathBuf {
+        match &self.config.backup_dir {
+            Some(dir) => root.join(dir),
+            None if self.config.tidy_backups => root.join(tidy::TIDY_BACKUPS),
+            None => root.to_path_buf(),
         }
-    } else {
-        // Original name passed → pick latest backup automatically
-        src_bak = find_latest_backup(trimmed)?;
-        dest = cwd.join(Path::new(trimmed).file_name().unwrap());
     }
 
-    fs::copy(&src_bak, &dest)?;
-    log_action("restore", name, "ok")?;
-    Ok(dest)
-}
+    /// [`backup_root`](Self::backup_root), created if it doesn't exist yet.
+    fn ensure_backup_root(&self, op: &'static str, root: &Path) -> io::Result<PathBuf> {
+        let dir = self.backup_root(root);
+        self.create_dirs(op, &dir, "cannot create backup directory")?;
+        Ok(dir)
+    }
 
-/// Delete a given file (validated).
-pub fn delete_file(name: &str) -> io::Result<()> {
-    let p = validate_path(name)?;
-    if p.exists() {
-        fs::remove_file(p)?;
-        log_action("delete", name, "ok")?;
+    pub fn validate_path(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        self.resolve(&self.root()?, name.as_ref())
+    }
+
+    /// [`validate_path`](Self::validate_path), also returning the name in the
+    /// canonical form the log and sidecars record: trimmed, without "."
+    /// components, and joined by "/" (so " ./docs//a.txt" is "docs/a.txt").
+    pub fn validate_path_parts(&self, name: impl AsRef<Path>) -> io::Result<(PathBuf, String)> {
+        let root = self.root()?;
+        let path = self.resolve(&root, name.as_ref())?;
+        let logical = logical_name(path.strip_prefix(&root).unwrap_or(&path));
+        Ok((path, logical))
+    }
+
+    /// Validate `name` and resolve it under `root` with the configured limits.
+    /// Windows device names are rejected there; elsewhere they are reported as
+    /// an event, or rejected with `config.reject_reserved_names`.
+    fn resolve(&self, root: &Path, name: &Path) -> io::Result<PathBuf> {
+        let path = validate_path_in(root, name, &self.config.limits)?;
+        if let Some(device) = find_reserved(trim_name(name)) {
+            if self.config.reject_reserved_names {
+                return Err(error::invalid(name, "reserved Windows device name"));
+            }
+            self.emit(Event::ReservedName { name: display_name(name.as_os_str()).into_owned(), device });
+        }
+        Ok(path)
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(sink) = &self.config.on_event {
+            sink(&event);
+        }
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.config.clock.now_unix()
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel_for(None).is_set()
+    }
+
+    /// What stops an operation given `per_call` in its options: that,
+    /// `Config::cancel` or its timer.
+    fn cancel_for<'a>(&'a self, per_call: Option<&'a CancellationToken>) -> cancel::Cancel<'a> {
+        cancel::Cancel::new(self.config.cancel.as_ref(), per_call, self.timer.as_ref())
+    }
+
+    pub fn find_latest_backup(&self, original_name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        self.latest_backup_in(&self.root()?, original_name.as_ref())
+    }
+
+    fn latest_backup_in(&self, root: &Path, original_name: &Path) -> io::Result<PathBuf> {
+        let legacy = self.config.legacy_bk;
+        let newest = self.scan_backup_dirs(root, &[original_name], |roots, names| {
+            newest_ts_backup_in(&*self.fs, roots, names, legacy, FileStat::is_file)
+        })?;
+        if let Some((_, p)) = newest {
+            return Ok(p);
+        }
+
+        let plain_root = self.plain_root(root);
+        let plain = self.plain_backup_for(&plain_root, original_name)?;
+        if self.fs.exists(&plain) {
+            return Ok(plain);
+        }
+        // A plain copy from before names with several extensions were kept whole.
+        if let Some(old) = naming::old_plain_backup_for(&plain_root, original_name) {
+            if is_tool_backup_of(&*self.fs, &old, base_name(original_name)?) {
+                return Ok(old);
+            }
+        }
+
+        Err(no_backup("restore", "no backup file found for", &self.backup_root(root).join(original_name)))
+    }
+
+    /// [`ts_backups`] of `original_name` in the backup directory of `root`, and
+    /// in `root` itself for backups from before `Config::tidy_backups`.
+    fn find_ts_backups(
+        &self,
+        root: &Path,
+        original_name: &Path,
+        keep: impl Fn(&FileStat) -> bool,
+    ) -> io::Result<Vec<(u64, PathBuf)>> {
+        let legacy = self.config.legacy_bk;
+        self.scan_backup_dirs(root, &[original_name], |roots, names| {
+            ts_backups_in(&*self.fs, roots, names, legacy, keep)
+        })
+    }
+
+    /// Run `scan` over the directories [`find_ts_backups`](Self::find_ts_backups)
+    /// looks in and the names it looks for, for each of `original_names`.
+    pub(crate) fn scan_backup_dirs<T>(
+        &self,
+        root: &Path,
+        original_names: &[&Path],
+        scan: impl FnOnce(&[&Path], &[(&Path, Window)]) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let broot = self.backup_root(root);
+        let flat = self.flat_root(root);
+        let roots: Vec<&Path> = std::iter::once(broot.as_path()).chain(flat.as_deref()).collect();
+        let mut named = Vec::new();
+        for name in original_names {
+            named.extend(self.backup_names(root, name)?);
+        }
+        let names: Vec<(&Path, Window)> = named.iter().map(|(n, w)| (n.as_path(), w.clone())).collect();
+        scan(&roots, &names)
+    }
+
+    /// The backup file `name` in the backup directory of `root`, or in `root`
+    /// itself when only the flat layout has it (see `Config::tidy_backups`).
+    fn backup_named(&self, root: &Path, name: &Path) -> io::Result<PathBuf> {
+        let path = self.resolve(&self.backup_root(root), name)?;
+        match self.flat_root(root) {
+            Some(flat) if !self.fs.exists(&path) => {
+                let old = self.resolve(&flat, name)?;
+                Ok(if self.fs.exists(&old) { old } else { path })
+            }
+            _ => Ok(path),
+        }
+    }
+
+    /// The convenience backup path, named per `config.plain_keep_extension`.
+    fn plain_backup_for(&self, root: &Path, original_name: &Path) -> io::Result<PathBuf> {
+        plain_backup_for(root, original_name, self.config.plain_keep_extension)
+    }
+
+    /// Refuse with `TooRecent` to back up `src` if it was modified less than
+    /// `Config::min_age_secs` ago.
+    fn check_min_age(&self, src: &Path) -> io::Result<()> {
+        let Some(min_age) = self.config.min_age_secs else { return Ok(()) };
+        let stat = self.fs.metadata(src).at("backup", "cannot stat", src)?;
+        match self.too_recent(&stat) {
+            Some(age) => Err(BackupError::TooRecent { path: src.to_path_buf(), age, min_age }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// How long ago the file with `stat` was modified, if that is less than
+    /// `Config::min_age_secs`; a modification time in the future counts as now.
+    pub(crate) fn too_recent(&self, stat: &FileStat) -> Option<u64> {
+        let min_age = self.config.min_age_secs?;
+        let modified = stat.modified?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let age = self.now().saturating_sub(modified);
+        (age < min_age).then_some(age)
+    }
+
+    /// Refuse with `Foreign` to write a backup of `name` over `path` when a file
+    /// is there that this tool didn't make: one without our sidecar, unless the
+    /// log records a backup of `name` made no earlier than the file was last
+    /// written (a plain copy from before sidecars existed). Refuse with
+    /// `PlainCollision` when it is the backup of a file with another name.
+    fn check_overwrite(&self, op: &'static str, root: &Path, name: &Path, path: &Path) -> io::Result<()> {
+        let Ok(md) = self.fs.symlink_metadata(path) else { return Ok(()) };
+        // Nothing is written over a directory; the write itself fails.
+        if md.is_dir() {
+            return Ok(());
+        }
+        if let Some(original) = tool_backup_original(&*self.fs, path) {
+            if is_tool_backup_of(&*self.fs, path, base_name(name)?) {
+                return Ok(());
+            }
+            return Err(BackupError::PlainCollision { path: path.to_path_buf(), original }.into());
+        }
+        self.flush_log()?;
+        let mtime = md.modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+        let file = display_name(name.as_os_str());
+        let logged = mtime.is_some_and(|mtime| {
+            log::read_log_file(&*self.fs, &self.log_path(root))
+                .is_ok_and(|log| log.iter().any(|e| e.action == "backup" && e.file == file && e.ts >= mtime))
+        });
+        if md.is_file() && logged {
+            return Ok(());
+        }
+        Err(BackupError::Foreign { op, path: path.to_path_buf() }.into())
+    }
+
+    /// [`FileSystem::copy`] under the configured retry policy. A source still locked after
+    /// retrying gets one more try with a share-friendly read, then `FileBusy`.
+    fn copy(&self, op: &'static str, from: &Path, to: &Path) -> io::Result<u64> {
+        // The retry wrapper rewrites the final error, so remember whether the last attempt hit a lock.
+        let mut busy = false;
+        let attempt = || self.fs.copy(from, to).inspect_err(|e| busy = busy::is_file_busy(e));
+        match retry::with_retry(&self.config.retry, "copy", self.config.on_event.as_ref(), attempt) {
+            Err(e) if busy => busy::shared_read_copy(from, to).map_err(|_| {
+                BackupError::FileBusy { op, path: busy::locked_side(from, to).to_path_buf(), source: e }.into()
+            }),
+            r => r.copying(op, from, to),
+        }
+    }
+
+    /// [`copy`](Self::copy) taking the digest of the data on the way, in one
+    /// read of `from`, until `cancel` is set. Only the share-friendly fallback
+    /// for a locked source reads the copy again for it.
+    fn copy_hashed(
+        &self,
+        op: &'static str,
+        from: &Path,
+        to: &Path,
+        algo: DigestAlgo,
+        cancel: cancel::Cancel<'_>,
+    ) -> io::Result<(u64, String)> {
+        let mut busy = false;
+        let attempt = || self.fs.copy_hashed(from, to, algo, cancel).inspect_err(|e| busy = busy::is_file_busy(e));
+        match retry::with_retry(&self.config.retry, "copy", self.config.on_event.as_ref(), attempt) {
+            Err(e) if cancel::is_cancelled(&e) => Err(BackupError::Cancelled { op, path: to.to_path_buf() }.into()),
+            Err(e) if busy => busy::shared_read_copy(from, to)
+                .and_then(|n| Ok((n, file_digest(to, algo)?)))
+                .map_err(|_| {
+                    BackupError::FileBusy { op, path: busy::locked_side(from, to).to_path_buf(), source: e }.into()
+                }),
+            r => r.copying(op, from, to),
+        }
+    }
+
+    /// With `Config::verify_writes`, read `backup` back and check it against
+    /// the `digest` taken while writing it; a mismatch removes it and fails
+    /// with `BackupError::Corrupt`.
+    fn verify_written(&self, op: &'static str, backup: &Path, digest: &str) -> io::Result<()> {
+        if !self.config.verify_writes {
+            return Ok(());
+        }
+        let algo = DigestAlgo::of(digest).map_err(io::Error::other).at(op, "malformed digest for", backup)?;
+        let actual = self.fs.open(backup).and_then(|f| digest::read_digest(f, algo)).at(op, "cannot read", backup)?;
+        if actual != digest {
+            let _ = self.fs.remove_file(backup);
+            let expected = digest.to_string();
+            return Err(BackupError::Corrupt { path: backup.to_path_buf(), expected, actual }.into());
+        }
         Ok(())
-    } else {
-        Err(io::Error::new(io::ErrorKind::NotFound, "file does not exist"))
-    }
-}
-
-/// Minimal JSONL logger in ./logfile.txt
-fn log_action(action: &str, file: &str, result: &str) -> io::Result<()> {
-    let mut path = std::env::current_dir()?;
-    path.push("logfile.txt");
-    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
-    let user = whoami::username();
-    let ts = now_unix();
-    writeln!(
-        f,
-        "{{\"ts\":{ts},\"user\":\"{user}\",\"action\":\"{action}\",\"file\":\"{file}\",\"result\":\"{result}\"}}"
-    )?;
-    Ok(())
+    }
+
+    /// Backup: copies <name> to timestamped and also updates plain "<stem>.bak"
+    /// ("<name>.bak" for names with several extensions or none, or with
+    /// `config.plain_keep_extension`).
+    pub fn backup_file(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        self.backup_file_report(name).map(|r| r.path)
+    }
+
+    /// The latest backup of `name` with `false` if it has one (timestamped,
+    /// or a plain copy); otherwise [`backup_file`](Self::backup_file) with
+    /// `true`. A baseline is guaranteed without a copy per call.
+    pub fn ensure_backup(&self, name: impl AsRef<Path>) -> io::Result<(PathBuf, bool)> {
+        let root = self.root()?;
+        let (path, _) = self.validate_path_parts(name)?;
+        let rel = path.strip_prefix(&root).unwrap_or(&path);
+        match self.latest_backup_in(&root, rel) {
+            Ok(latest) => Ok((latest, false)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((self.backup_file(rel)?, true)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// [`backup_file`](Self::backup_file), reporting how the backup was made.
+    pub fn backup_file_report(&self, name: impl AsRef<Path>) -> io::Result<BackupReport> {
+        self.backup_file_with(name, &BackupOptions::default())
+    }
+
+    /// [`backup_file_report`](Self::backup_file_report) with per-call options.
+    pub fn backup_file_with(&self, name: impl AsRef<Path>, opts: &BackupOptions) -> io::Result<BackupReport> {
+        let (name, opts) = (name.as_ref().to_path_buf(), opts.clone());
+        self.with_timeout("backup", name.clone(), move |mgr| mgr.backup_file_now(&name, &opts))
+    }
+
+    /// [`backup_file_with`](Self::backup_file_with) on this thread, whatever `Config::timeout`.
+    fn backup_file_now(&self, name: &Path, opts: &BackupOptions) -> io::Result<BackupReport> {
+        let root = self.root()?;
+        let src = self.resolve(&root, name)?;
+        self.pre_hook("backup", &src)?;
+        let mut report = self.backup_source(&root, src.clone(), opts)?;
+        report.warnings.extend(self.post_hook(&root, "backup", &src));
+        Ok(report)
+    }
+
+    /// Back up `src`, resolved in `root`.
+    fn backup_source(&self, root: &Path, src: PathBuf, opts: &BackupOptions) -> io::Result<BackupReport> {
+        let note = opts.note.as_deref().map(note::escape_note).transpose()?;
+        // Backups are named and logged after the canonical name, not the spelling it was given in.
+        let rel = src.strip_prefix(root).unwrap_or(&src).to_path_buf();
+        let (name, logical) = (rel.as_path(), logical_name(&rel));
+        if !self.fs.exists(&src) {
+            if opts.skip_missing {
+                return Ok(BackupReport::not_made(src, BackupOutcome::Skipped, 0));
+            }
+            return Err(missing("backup", "source file does not exist", &src));
+        }
+        let special = special_file_kind(&*self.fs, "backup", &src)?;
+        if let (Some(kind), false) = (special, self.config.allow_special_files) {
+            return Err(BackupError::UnsupportedFileType { op: "backup", path: src, kind }.into());
+        }
+        // Its backups and plain copy would pass for backups of the original it came from.
+        if !opts.force && is_backup_file(&*self.fs, &src) {
+            return Err(BackupError::BackupOfBackup { path: src }.into());
+        }
+        self.check_min_age(&src)?;
+        if opts.if_changed && !self.check_changed(name)?.changed() {
+            let broot = self.backup_root(root);
+            if let Some(bak) = newest_ts_backup(&*self.fs, &broot, name, self.config.legacy_bk, FileStat::is_file)? {
+                let bytes = self.fs.metadata(&src).at("backup", "cannot stat", &src)?.len;
+                return Ok(BackupReport::not_made(bak, BackupOutcome::Unchanged, bytes));
+            }
+        }
+        let cancel = self.cancel_for(opts.cancel.as_ref());
+        cancel.check("backup", &src)?;
+        // Recorded rather than the stat after copying, which may include a change the copy missed.
+        let before = self.fs.metadata(&src).at("backup", "cannot stat", &src)?;
+        let ts = self.now();
+        let broot = self.ensure_backup_root("backup", root)?;
+        let ts_bak = ts_backup_for(&broot, name, ts)?;
+        // The sidecar is the longest name written.
+        check_backup_name(&sidecar_for(&ts_bak), &self.config.limits)?;
+        self.check_overwrite("backup", root, name, &ts_bak)?;
+        let mut compression = opts.compress.unwrap_or(self.config.compression);
+        // Only regular files are deduplicated, and only those larger than one piece chunked.
+        let layout = match (special, self.config.dedup, self.config.chunk_size) {
+            (None, true, _) => Layout::Deduped,
+            (None, false, Some(size)) if before.len > size => Layout::Chunked,
+            _ => Layout::Whole,
+        };
+        // A plain copy has its digest taken as it is written; everything else is read back for it.
+        let (reflinked, copied) = match (layout, compression, special) {
+            (Layout::Deduped, _, _) => {
+                dedup::write_deduped("backup", &src, &ts_bak, self.config.digest)?;
+                compression = Compression::None;
+                (false, None)
+            }
+            (Layout::Chunked, _, _) => {
+                let size = self.config.chunk_size.unwrap_or(chunk::DEFAULT_CHUNK_SIZE);
+                chunk::write_chunked("backup", &src, &ts_bak, size, compression, self.config.digest, cancel)?;
+                (false, None)
+            }
+            (Layout::Whole, Compression::None, None) => {
+                let reflink = opts.reflink.unwrap_or(self.config.reflink);
+                self.clone_or_copy("backup", &src, &ts_bak, reflink, cancel).inspect_err(|e| {
+                    if cancel::is_cancelled(e) {
+                        self.discard_backup(&ts_bak, layout);
+                    }
+                })?
+            }
+            (Layout::Whole, Compression::None, Some(_)) => {
+                stream_copy(&*self.fs, "backup", &src, &ts_bak)?;
+                (false, None)
+            }
+            (Layout::Whole, Compression::Gzip, _) => {
+                compress::gzip_copy("backup", &src, &ts_bak)?;
+                (false, None)
+            }
+            (Layout::Delta, _, _) => {
+                let e = io::Error::new(io::ErrorKind::Unsupported, "deltas are only made by backup_append_delta");
+                return Err(e).at("backup", "cannot back up", &src);
+            }
+        };
+        // The last point to stop at: past it the backup is committed and completes.
+        let _committing = match cancel.commit("backup", &ts_bak) {
+            Ok(guard) => guard,
+            Err(e) => {
+                self.discard_backup(&ts_bak, layout);
+                return Err(e);
+            }
+        };
+        // No more open than `Config::file_mode` and the source allow.
+        let src_mode = self.fs.metadata(&src).at("backup", "cannot stat", &src)?.mode;
+        if let Err(e) = self.restrict("backup", &ts_bak, Some(src_mode)) {
+            self.discard_backup(&ts_bak, layout);
+            return Err(e);
+        }
+        let digest = match copied {
+            Some(digest) => {
+                self.verify_written("backup", &ts_bak, &digest)?;
+                BackupDigest::Known(digest)
+            }
+            None => BackupDigest::Read(self.config.digest),
+        };
+        let bytes =
+            write_meta_for(&*self.fs, "backup", &ts_bak, name, &src, compression, digest, layout, Some(&before))?;
+        if let Some(note) = note {
+            if let Err(e) = record_note(&*self.fs, "backup", &ts_bak, Some(note)) {
+                self.discard_backup(&ts_bak, layout);
+                return Err(e);
+            }
+        }
+        // The timestamped backup is safe from here on; later failures only warn.
+        let mut warnings = Vec::new();
+        let xattrs = match self.config.preserve_xattrs.then(|| xattrs::read("backup", &src)) {
+            Some(Ok(attrs)) => attrs,
+            Some(Err(e)) => {
+                warnings.push(Warning::Xattrs { path: src.clone(), error: error_chain(&e) });
+                xattrs::Xattrs::new()
+            }
+            None => xattrs::Xattrs::new(),
+        };
+        keep_xattrs(&*self.fs, &xattrs, &ts_bak, &mut warnings);
+        let acl = match self.config.preserve_acls.then(|| acls::read("backup", &src)) {
+            Some(Ok(acl)) => acl,
+            Some(Err(e)) => {
+                warnings.push(Warning::Acl { path: src.clone(), error: error_chain(&e) });
+                acls::Acl::new()
+            }
+            None => acls::Acl::new(),
+        };
+        keep_acl(&*self.fs, &acl, &ts_bak, &mut warnings);
+        // Forced backups of a ".bak" must not copy it onto itself.
+        let plain_bak = (opts.plain_copy && self.config.plain_copy)
+            .then(|| self.plain_backup_for(&self.plain_root(root), name))
+            .transpose()?
+            .filter(|p| *p != src);
+        let mut plain = None;
+        if let Some(plain_bak) = plain_bak {
+            // A special file can only be read once: take the plain copy from the backup.
+            let algo = self.config.digest;
+            let written = self.check_overwrite("backup", root, name, &plain_bak).and_then(|_| {
+                let written = match (special, compression) {
+                    (None, _) => {
+                        self.copy_hashed("backup", &src, &plain_bak, algo, cancel).map(|(_, d)| BackupDigest::Known(d))
+                    }
+                    (Some(_), Compression::None) => self.copy("backup", &ts_bak, &plain_bak).map(|_| algo.into()),
+                    (Some(_), Compression::Gzip) => {
+                        compress::gunzip_copy("backup", &ts_bak, &plain_bak).map(|_| algo.into())
+                    }
+                }
+                .and_then(|digest| self.restrict("backup", &plain_bak, Some(src_mode)).map(|_| digest))
+                .and_then(|digest| write_meta(&*self.fs, "backup", &plain_bak, name, &src, Compression::None, digest));
+                // Past the overwrite check the file is ours, and half-written it is no copy at all.
+                if written.is_err() && !opts.best_effort {
+                    self.discard_backup(&plain_bak, Layout::Whole);
+                }
+                written
+            });
+            match written {
+                Ok(_) => {
+                    keep_xattrs(&*self.fs, &xattrs, &plain_bak, &mut warnings);
+                    keep_acl(&*self.fs, &acl, &plain_bak, &mut warnings);
+                    plain = Some(plain_bak);
+                }
+                Err(e) if !opts.best_effort => {
+                    self.discard_backup(&ts_bak, layout);
+                    return Err(e);
+                }
+                Err(e) => warnings.push(Warning::PlainBackup { path: plain_bak, error: error_chain(&e) }),
+            }
+        }
+        let result = if opts.auto { "auto" } else { "ok" };
+        self.log_or_warn(root, "backup", Path::new(&logical), result, &mut warnings);
+        if self.config.max_age_secs.is_some() || self.config.keep_last.is_some() {
+            self.prune_after_backup(root, name, ts, &ts_bak, &mut warnings);
+        }
+        Ok(BackupReport { path: ts_bak, outcome: BackupOutcome::Created, reflinked, bytes, plain, warnings })
+    }
+
+    /// Remove a backup this call made, with its sidecar and pieces, when the
+    /// backup is given up on. Best effort: the error that caused it matters more.
+    fn discard_backup(&self, backup: &Path, layout: Layout) {
+        if layout == Layout::Whole {
+            let _ = self.fs.remove_file(backup);
+            let _ = self.fs.remove_file(&sidecar_for(backup));
+        } else {
+            let _ = versions::remove_backup(&*self.fs, backup);
+        }
+    }
+
+    /// Back up everything `reader` yields as a new timestamped backup of the
+    /// logical file `name`, which need not exist. The plain "<stem>.bak" copy,
+    /// the log, compression and pruning work as for [`backup_file`](Self::backup_file),
+    /// and restoring `name` afterwards writes the data to `name` in the root.
+    /// A failed read leaves no backup behind.
+    pub fn backup_from_reader(&self, name: impl AsRef<Path>, mut reader: impl Read) -> io::Result<BackupReport> {
+        let name = name.as_ref();
+        let root = self.root()?;
+        let dest = self.resolve(&root, name)?;
+        let rel = dest.strip_prefix(&root).unwrap_or(&dest).to_path_buf();
+        let (name, logical) = (rel.as_path(), logical_name(&rel));
+        let ts = self.now();
+        let broot = self.ensure_backup_root("backup", &root)?;
+        let ts_bak = ts_backup_for(&broot, name, ts)?;
+        check_backup_name(&sidecar_for(&ts_bak), &self.config.limits)?;
+        self.check_overwrite("backup", &root, name, &ts_bak)?;
+        let compression = self.config.compression;
+        let written = match compression {
+            Compression::None => self.fs.create(&ts_bak).and_then(|mut f| io::copy(&mut reader, &mut f)),
+            Compression::Gzip => compress::gzip_from(&mut reader, &ts_bak),
+        };
+        let bytes = match written {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = self.fs.remove_file(&ts_bak);
+                return Err(e).at("backup", "cannot write stream into", &ts_bak);
+            }
+        };
+        self.restrict("backup", &ts_bak, None)?;
+        let digest = self.config.digest;
+        write_stream_meta(&*self.fs, &ts_bak, name, &dest, bytes, ts, compression, digest)?;
+        let mut warnings = Vec::new();
+        let plain_bak = self.plain_backup_for(&self.plain_root(&root), name)?;
+        let plain = self.check_overwrite("backup", &root, name, &plain_bak).and_then(|_| match compression {
+            Compression::None => self.copy("backup", &ts_bak, &plain_bak),
+            Compression::Gzip => compress::gunzip_copy("backup", &ts_bak, &plain_bak),
+        })
+        .and_then(|_| self.restrict("backup", &plain_bak, None))
+        .and_then(|_| write_stream_meta(&*self.fs, &plain_bak, name, &dest, bytes, ts, Compression::None, digest));
+        let plain = match plain {
+            Ok(_) => Some(plain_bak),
+            Err(e) => {
+                warnings.push(Warning::PlainBackup { path: plain_bak, error: error_chain(&e) });
+                None
+            }
+        };
+        self.log_or_warn(&root, "backup", Path::new(&logical), "ok", &mut warnings);
+        if self.config.max_age_secs.is_some() || self.config.keep_last.is_some() {
+            self.prune_after_backup(&root, name, ts, &ts_bak, &mut warnings);
+        }
+        Ok(BackupReport { path: ts_bak, outcome: BackupOutcome::Created, reflinked: false, bytes, plain, warnings })
+    }
+
+    /// Reflink `from` to the new file `to` when `reflink` is set and supported,
+    /// else copy it taking its digest until `cancel` is set. Returns whether a
+    /// reflink was made, and the digest if it was copied.
+    fn clone_or_copy(
+        &self,
+        op: &'static str,
+        from: &Path,
+        to: &Path,
+        reflink: bool,
+        cancel: cancel::Cancel<'_>,
+    ) -> io::Result<(bool, Option<String>)> {
+        #[cfg(feature = "reflink")]
+        if reflink && reflink::reflink(from, to).is_ok() {
+            return Ok((true, None));
+        }
+        #[cfg(not(feature = "reflink"))]
+        let _ = reflink;
+        let (_, digest) = self.copy_hashed(op, from, to, self.config.digest, cancel)?;
+        Ok((false, Some(digest)))
+    }
+
+    /// Restore:
+    /// - If `name` ends with ".bak": restore from that file to a sensible target.
+    /// - If `name` is original (e.g., "test.txt"): restore from latest backup to "name".
+    ///
+    /// When the backup has a metadata sidecar naming a matching original, the
+    /// file goes back to that recorded name (e.g. "docs/test.txt") and gets its
+    /// recorded permissions and mtime back.
+    pub fn restore_file(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        self.restore_file_with(name, &RestoreOptions::default()).map(|r| r.path)
+    }
+
+    /// [`restore_file`](Self::restore_file) with per-call options, reporting warnings.
+    pub fn restore_file_with(&self, name: impl AsRef<Path>, opts: &RestoreOptions) -> io::Result<RestoreReport> {
+        let (name, opts) = (name.as_ref().to_path_buf(), opts.clone());
+        self.with_timeout("restore", name.clone(), move |mgr| mgr.restore_file_now(&name, &opts))
+    }
+
+    /// [`restore_file_with`](Self::restore_file_with) on this thread, whatever `Config::timeout`.
+    fn restore_file_now(&self, name: &Path, opts: &RestoreOptions) -> io::Result<RestoreReport> {
+        let plan = self.restore_plan(name, opts)?;
+        // Logged under the canonical spelling of the name given: " ./notes.txt " as "notes.txt".
+        let (_, logical) = self.validate_path_parts(name)?;
+        let name = Path::new(&logical);
+        let dest = plan.dest.clone();
+        let outcome = match self.resolve_conflict(opts.on_conflict, &plan.source, &dest)? {
+            conflict::Resolution::Write(outcome) => outcome,
+            conflict::Resolution::Leave(outcome) => {
+                self.log_action(&self.root()?, "restore", name, &outcome.to_string())?;
+                return Ok(RestoreReport { path: dest, outcome, moved: None, warnings: Vec::new() });
+            }
+        };
+        self.pre_hook("restore", &dest)?;
+        let mut warnings = Vec::new();
+        let cancel = self.cancel_for(None);
+        let (tmp, verified) = match self.stage_restore(&plan, opts.allow_mismatch, cancel, &mut warnings) {
+            Ok(staged) => staged,
+            Err(e) => {
+                if matches!(BackupError::from_io(&e), Some(BackupError::ChecksumMismatch { .. })) {
+                    self.log_action(&self.root()?, "restore", name, "failed (checksum mismatch)")?;
+                }
+                return Err(e);
+            }
+        };
+        let mut moved = None;
+        let mut swap = || -> io::Result<()> {
+            let _committing = cancel.commit("restore", &dest)?;
+            if outcome == RestoreOutcome::RenamedExisting {
+                moved = Some(self.move_aside(&dest)?);
+            }
+            self.fs.rename(&tmp, &dest).copying("restore", &tmp, &dest).inspect_err(|_| {
+                if let Some(aside) = &moved {
+                    let _ = self.fs.rename(aside, &dest);
+                }
+            })
+        };
+        if let Err(e) = swap() {
+            let _ = self.fs.remove_file(&tmp);
+            return Err(e);
+        }
+        self.apply_restored_meta(plan.meta.as_ref(), &dest, &mut warnings);
+        let root = self.root()?;
+        self.log_or_warn(&root, "restore", name, restored_result(verified), &mut warnings);
+        warnings.extend(self.post_hook(&root, "restore", &dest));
+        Ok(RestoreReport { path: dest, outcome, moved, warnings })
+    }
+
+    /// Write the contents of `plan.source` to a new temporary file beside
+    /// `plan.dest`, synced, and return it with whether it matched the recorded
+    /// digest (`None`: none recorded). A mismatch is an error unless
+    /// `allow_mismatch`, when it is a warning. Nothing else is touched, and
+    /// on failure the temporary file is removed.
+    fn stage_restore(
+        &self,
+        plan: &RestorePlan,
+        allow_mismatch: bool,
+        cancel: cancel::Cancel<'_>,
+        warnings: &mut Vec<Warning>,
+    ) -> io::Result<(PathBuf, Option<bool>)> {
+        let RestorePlan { source: src_bak, dest, compression, digest, layout, .. } = plan;
+        let (compression, layout) = (*compression, *layout);
+        // A plain backup with a recorded digest is checked against it as it is copied, and the copy read back.
+        // Any other is checked as stored (the compressed file, index, manifest or delta tail the digest is of),
+        // and unpacking it checks its pieces or the links of its chain.
+        let plain = layout == Layout::Whole && compression == Compression::None;
+        let recorded = digest.as_deref().map(digest::tagged);
+        let recorded = recorded.and_then(|d| DigestAlgo::of(&d).ok().map(|algo| (d, algo)));
+        cancel.check("restore", dest)?;
+        // Written beside `dest` and renamed over it, so an interrupted restore
+        // leaves the file as it was rather than half restored.
+        let tmp = vfs::unique_temp(dest);
+        let mut verified = None;
+        let mut write = || -> io::Result<()> {
+            let mut streamed = None;
+            if let (false, Some((_, algo))) = (plain, &recorded) {
+                let stored = self.fs.open(src_bak).and_then(|f| digest::read_digest(f, *algo));
+                streamed = Some(stored.at("restore", "cannot read", src_bak)?);
+            }
+            match (layout, compression, &recorded) {
+                (Layout::Deduped, _, _) => dedup::restore_to("restore", src_bak, &tmp)?,
+                (Layout::Chunked, _, _) => chunk::restore_to("restore", src_bak, compression, &tmp)?,
+                (Layout::Delta, _, _) => delta::restore_to("restore", src_bak, &tmp)?,
+                (Layout::Whole, Compression::None, Some((_, algo))) => {
+                    let (n, read) = self.copy_hashed("restore", src_bak, &tmp, *algo, cancel)?;
+                    streamed = Some(read);
+                    n
+                }
+                (Layout::Whole, Compression::None, None) => self.copy("restore", src_bak, &tmp)?,
+                (Layout::Whole, Compression::Gzip, _) => compress::gunzip_copy("restore", src_bak, &tmp)?,
+            };
+            self.fs.sync(&tmp).at("restore", "cannot sync", &tmp)?;
+            if let Some((expected, algo)) = &recorded {
+                let actual = match streamed {
+                    // The backup read differs already, or is not plain; the copy needn't be read back.
+                    Some(read) if read != *expected || !plain => read,
+                    _ => {
+                        let read_back = self.fs.open(&tmp).and_then(|f| digest::read_digest(f, *algo));
+                        read_back.at("restore", "cannot read", &tmp)?
+                    }
+                };
+                verified = Some(actual == *expected);
+                if actual != *expected {
+                    let (expected, path, backup) = (expected.clone(), dest.clone(), src_bak.clone());
+                    if !allow_mismatch {
+                        return Err(BackupError::ChecksumMismatch { path, backup, expected, actual }.into());
+                    }
+                    warnings.push(Warning::DigestMismatch { path, expected, actual });
+                }
+            }
+            Ok(())
+        };
+        match write() {
+            Ok(()) => Ok((tmp, verified)),
+            Err(e) => {
+                let _ = self.fs.remove_file(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    /// Put the permissions, mtime, extended attributes and ACL recorded in
+    /// `meta` back on the restored `dest`; what fails becomes a warning.
+    fn apply_restored_meta(&self, meta: Option<&BackupMeta>, dest: &Path, warnings: &mut Vec<Warning>) {
+        let Some(meta) = meta else { return };
+        if let Err(e) = apply_meta(&*self.fs, "restore", meta, dest) {
+            warnings.push(Warning::Metadata { path: dest.to_path_buf(), error: error_chain(&e) });
+        }
+        if !meta.xattrs.is_empty() {
+            if let Err(e) = xattrs::apply("restore", &meta.xattrs, dest) {
+                warnings.push(Warning::Xattrs { path: dest.to_path_buf(), error: error_chain(&e) });
+            }
+        }
+        // After the mode bits, which would otherwise change the ACL's mask.
+        if !meta.acl.is_empty() {
+            if let Err(e) = acls::apply("restore", &meta.acl, dest) {
+                warnings.push(Warning::Acl { path: dest.to_path_buf(), error: error_chain(&e) });
+            }
+        }
+    }
+
+    /// Which backup restoring `name` reads, and where it writes: to the
+    /// original its sidecar records, or where none fits, to what the backup's
+    /// file name suggests.
+    fn restore_plan(&self, name: &Path, opts: &RestoreOptions) -> io::Result<RestorePlan> {
+        let trimmed = trim_name(name);
+        let cwd = self.root()?;
+        // Restoring an original by name, which may have had other names.
+        let mut by_name = None;
+
+        let fname = trimmed.file_name().unwrap_or(trimmed.as_os_str());
+        let (src_bak, guessed) = if let Some((logical, ts)) = split_backup_name(fname) {
+            let src_bak = self.backup_named(&cwd, trimmed)?;
+            if !self.fs.exists(&src_bak) {
+                return Err(no_backup("restore", "backup file not found", &src_bak));
+            }
+            if ts.is_some() {
+                // "<orig>.<ts>.bak" → restore to "<orig>"
+                (src_bak, cwd.join(logical))
+            } else {
+                // "<stem>.bak" → restore to "<stem>.restored.<now>"
+                let mut restored = logical.to_os_string();
+                restored.push(format!(".restored.{}", self.now()));
+                (src_bak, cwd.join(restored))
+            }
+        } else if let Some((logical, _)) = split_legacy_name(fname).filter(|_| self.config.legacy_bk) {
+            // Legacy "<orig>.<datetime>.bk" or "<orig>.bk" → restore to "<orig>"
+            let src_bak = self.backup_named(&cwd, trimmed)?;
+            if !self.fs.exists(&src_bak) {
+                return Err(no_backup("restore", "backup file not found", &src_bak));
+            }
+            (src_bak, cwd.join(logical))
+        } else {
+            // Original name passed → pick the requested version, else the latest backup
+            by_name = Some(base_name(trimmed)?);
+            (self.select_backup("restore", &cwd, trimmed, opts)?, cwd.join(base_name(trimmed)?))
+        };
+        let sidecar = sidecar_if_any(&*self.fs, &src_bak)?;
+        let compression = sidecar.as_ref().map_or(Compression::None, |m| m.compression);
+        let digest = sidecar.as_ref().and_then(|m| m.digest.clone());
+        let layout = sidecar.as_ref().map_or(Layout::Whole, |m| m.layout);
+        let recorded = sidecar.as_ref().and_then(|m| recorded_original(m, &src_bak));
+        let mut dest = match (&recorded, by_name) {
+            // A backup made under an earlier name goes back under the name asked for.
+            (Some(original), Some(base)) if original.file_name() != Some(base) => self.resolve(&cwd, trimmed)?,
+            (Some(original), _) => self.resolve(&cwd, original)?,
+            (None, _) => guessed,
+        };
+        let meta = sidecar.filter(|_| recorded.is_some());
+        if let Some(to) = &opts.to {
+            dest = self.resolve(&cwd, to)?;
+        }
+        Ok(RestorePlan { source: src_bak, dest, compression, digest, layout, meta })
+    }
+
+    /// The backup of the original `name` chosen by `opts.version` / `opts.previous`,
+    /// else the latest one.
+    fn select_backup(&self, op: &'static str, root: &Path, name: &Path, opts: &RestoreOptions) -> io::Result<PathBuf> {
+        match (opts.version, opts.previous) {
+            (Some(v), _) => self
+                .find_ts_backups(root, name, FileStat::is_file)?
+                .into_iter()
+                .find(|(ts, _)| *ts == v)
+                .map(|(_, p)| p)
+                .ok_or_else(|| no_backup(op, "no backup with that version for", &self.backup_root(root).join(name))),
+            (None, Some(n)) => {
+                let entries = self.list_backups(name)?;
+                let available = entries.len();
+                entries.into_iter().nth(n).map(|e| e.path).ok_or_else(|| {
+                    let name = display_name(name.as_os_str()).into_owned();
+                    BackupError::NoSuchVersion { name, offset: n, available }.into()
+                })
+            }
+            (None, None) => match &opts.note_match {
+                Some(text) => {
+                    let entries = self.list_backups(name)?;
+                    let found = entries.into_iter().find(|e| note::note_matches(e.note.as_deref(), text));
+                    let path = self.backup_root(root).join(name);
+                    found.map(|e| e.path).ok_or_else(|| no_backup(op, "no backup with a matching note for", &path))
+                }
+                None => self.latest_backup_in(root, name),
+            },
+        }
+    }
+
+    /// Write the contents of a backup to `out`, decompressed, without touching
+    /// any file. `name` is an original (its backup with timestamp `version`, else
+    /// the latest) or a backup file name. Returns the number of bytes written.
+    pub fn cat_backup(&self, name: impl AsRef<Path>, version: Option<u64>, out: &mut dyn Write) -> io::Result<u64> {
+        let trimmed = trim_name(name.as_ref());
+        let root = self.root()?;
+        let fname = trimmed.file_name().unwrap_or(trimmed.as_os_str());
+        let src_bak = if split_backup_name(fname).is_some() {
+            let p = self.backup_named(&root, trimmed)?;
+            if !self.fs.metadata(&p).is_ok_and(|m| m.is_file()) {
+                return Err(no_backup("cat", "backup file not found", &p));
+            }
+            p
+        } else {
+            let opts = RestoreOptions { version, ..RestoreOptions::default() };
+            self.select_backup("cat", &root, trimmed, &opts)?
+        };
+        let (compression, layout) = sidecar_if_any(&*self.fs, &src_bak)?
+            .map_or((Compression::None, Layout::Whole), |m| (m.compression, m.layout));
+        match (layout, compression) {
+            (Layout::Deduped, _) => dedup::read_to("cat", &src_bak, out),
+            (Layout::Chunked, _) => chunk::read_to("cat", &src_bak, compression, out),
+            (Layout::Delta, _) => delta::read_to("cat", &src_bak, out),
+            (Layout::Whole, Compression::None) => {
+                let mut f = self.fs.open(&src_bak).at("cat", "cannot open", &src_bak)?;
+                io::copy(&mut f, out).at("cat", "cannot stream", &src_bak)
+            }
+            (Layout::Whole, Compression::Gzip) => compress::gunzip_to("cat", &src_bak, out),
+        }
+    }
+
+    /// The first `max_bytes` bytes of a backup's contents, decompressed, found
+    /// as by [`cat_backup`](Self::cat_backup). Reading stops there, so peeking
+    /// at a large backup is cheap; the digest is not checked.
+    pub fn peek_backup(&self, name: impl AsRef<Path>, version: Option<u64>, max_bytes: usize) -> io::Result<Vec<u8>> {
+        let mut head = Head { buf: Vec::with_capacity(max_bytes.min(1 << 20)), max: max_bytes, full: false };
+        match self.cat_backup(name, version, &mut head) {
+            Err(_) if head.full => {}
+            r => drop(r?),
+        }
+        Ok(head.buf)
+    }
+
+    /// Log a successful `action` with `result`; a failure to log becomes a warning.
+    fn log_or_warn(&self, root: &Path, action: &str, name: &Path, result: &str, warnings: &mut Vec<Warning>) {
+        if let Err(e) = self.log_action(root, action, name, result) {
+            warnings.push(Warning::Log { error: error_chain(&e) });
+        }
+    }
+}
+
+/// A writer keeping the first `max` bytes, then failing so the stream stops.
+struct Head {
+    buf: Vec<u8>,
+    max: usize,
+    full: bool,
+}
+
+impl Write for Head {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let take = data.len().min(self.max - self.buf.len());
+        self.buf.extend_from_slice(&data[..take]);
+        if self.buf.len() == self.max && !data.is_empty() {
+            self.full = true;
+            return Err(io::Error::other("peeked enough"));
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The log result of a restore whose digest `verified` as given.
+fn restored_result(verified: Option<bool>) -> &'static str {
+    match verified {
+        Some(true) => "ok (verified)",
+        Some(false) => "ok (checksum mismatch)",
+        None => "ok",
+    }
+}
+
+/// Whether the file at `path` is a backup: a ".bak" file, or one this tool made.
+fn is_backup_file(fs: &dyn FileSystem, path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("bak")) || is_tool_backup(fs, path)
+}
+
+/// Read `from` to its end into the new file `to`. Unlike `fs::copy` this works
+/// for FIFOs and devices; it is not retried, since a pipe can't be read twice.
+fn stream_copy(fs: &dyn FileSystem, op: &'static str, from: &Path, to: &Path) -> io::Result<u64> {
+    let mut input = fs.open(from).at(op, "cannot open", from)?;
+    let mut output = fs.create(to).at(op, "cannot create", to)?;
+    io::copy(&mut input, &mut output).copying(op, from, to)
+}
+
+/// Record `xattrs` in the sidecar of `backup`, then set them on it. Failures only warn.
+fn keep_xattrs(fs: &dyn FileSystem, xattrs: &xattrs::Xattrs, backup: &Path, warnings: &mut Vec<Warning>) {
+    if xattrs.is_empty() {
+        return;
+    }
+    if let Err(e) = record_xattrs(fs, "backup", backup, xattrs).and_then(|_| xattrs::apply("backup", xattrs, backup)) {
+        warnings.push(Warning::Xattrs { path: backup.to_path_buf(), error: error_chain(&e) });
+    }
+}
+
+/// Record `acl` in the sidecar of `backup`. The backup itself keeps the
+/// tool's permissions, so the ACL grants no one access to it. Failures only warn.
+fn keep_acl(fs: &dyn FileSystem, acl: &acls::Acl, backup: &Path, warnings: &mut Vec<Warning>) {
+    if acl.is_empty() {
+        return;
+    }
+    if let Err(e) = record_acl(fs, "backup", backup, acl) {
+        warnings.push(Warning::Acl { path: backup.to_path_buf(), error: error_chain(&e) });
+    }
+}
+
+/// The original recorded in `meta`, relative to the root, if it plausibly
+/// produced the backup name: its file name is the name the backup is named
+/// after, or that name plus one extension (a plain "<stem>.bak"). If not,
+/// restore falls back to the naming heuristics. The record is text, with
+/// bytes that aren't UTF-8 shown as `\xNN`: the directories are turned back
+/// into the names they stand for, and the file name is rebuilt from the
+/// backup's own name, which has them as they were.
+fn recorded_original(meta: &BackupMeta, backup: &Path) -> Option<PathBuf> {
+    let (logical, _) = backup.file_name().and_then(split_backup_name)?;
+    let original = Path::new(&meta.original);
+    let rest = original.file_name()?.to_str()?.strip_prefix(&*display_name(logical))?;
+    let extension = rest.strip_prefix('.').is_some_and(|ext| !ext.is_empty() && !ext.contains('.'));
+    if !rest.is_empty() && !extension {
+        return None;
+    }
+    let mut file_name = logical.to_os_string();
+    file_name.push(rest);
+    let dir: PathBuf =
+        original.parent()?.iter().map(|c| c.to_str().map_or_else(|| c.to_os_string(), name_from_display)).collect();
+    Some(dir.join(file_name))
+}
+
+/// Stream a backup to `out`; see [`BackupManager::cat_backup`].
+pub fn cat_backup(name: impl AsRef<Path>, version: Option<u64>, out: &mut dyn Write) -> io::Result<u64> {
+    BackupManager::default().cat_backup(name, version, out)
+}
+
+/// The start of a backup's contents; see [`BackupManager::peek_backup`].
+pub fn peek_backup(name: impl AsRef<Path>, version: Option<u64>, max_bytes: usize) -> io::Result<Vec<u8>> {
+    BackupManager::default().peek_backup(name, version, max_bytes)
+}
+
+/// Back up `files` as the checkpoint `name`; see [`BackupManager::create_checkpoint`].
+pub fn create_checkpoint(name: &str, files: &[impl AsRef<Path>]) -> io::Result<Checkpoint> {
+    BackupManager::default().create_checkpoint(name, files)
+}
+
+/// Put the files of the checkpoint `name` back; see [`BackupManager::restore_checkpoint`].
+pub fn restore_checkpoint(name: &str) -> io::Result<Vec<PathBuf>> {
+    BackupManager::default().restore_checkpoint(name)
+}
+
+/// Remove restore leftovers, empty backups and temporary files; see [`BackupManager::clean`].
+pub fn clean(dry_run: bool) -> io::Result<CleanReport> {
+    BackupManager::default().clean(dry_run)
+}
+
+/// Archive old backups with the default configuration; see [`BackupManager::compact_backups`].
+pub fn compact_backups(name: impl AsRef<Path>, older_than: u64) -> io::Result<CompactReport> {
+    BackupManager::default().compact_backups(name, older_than)
+}
+
+/// Extract an archive of old backups; see [`BackupManager::extract_archive`].
+pub fn extract_archive(archive: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    BackupManager::default().extract_archive(archive)
+}
+
+/// Timestamped backups of `name`, newest first; see [`BackupManager::list_backups`].
+pub fn list_backups(name: impl AsRef<Path>) -> io::Result<Vec<BackupEntry>> {
+    BackupManager::default().list_backups(name)
+}
+
+/// Keep only the newest `keep` backups of `name`; see [`BackupManager::prune_backups`].
+pub fn prune_backups(name: impl AsRef<Path>, keep: usize, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+    BackupManager::default().prune_backups(name, keep, dry_run)
+}
+
+/// Thin out the backups of `name` by age; see [`BackupManager::apply_retention`].
+pub fn apply_retention(name: impl AsRef<Path>, policy: &RetentionPolicy) -> io::Result<RetentionReport> {
+    BackupManager::default().apply_retention(name, policy)
+}
+
+/// Check a backup against its recorded digest; see [`BackupManager::verify_backup`].
+pub fn verify_backup(backup: impl AsRef<Path>) -> io::Result<()> {
+    BackupManager::default().verify_backup(backup)
+}
+
+/// Replace the note of a backup; see [`BackupManager::annotate`].
+pub fn annotate(backup: impl AsRef<Path>, note: &str) -> io::Result<()> {
+    BackupManager::default().annotate(backup, note)
+}
+
+/// Delete a given file (validated).
+pub fn delete_file(name: impl AsRef<Path>) -> io::Result<()> {
+    BackupManager::default().delete_file(name)
+}
+
+/// Delete a file, and with `purge_backups` its backups; see [`BackupManager::delete_file_with`].
+pub fn delete_file_with(name: impl AsRef<Path>, opts: &DeleteOptions) -> io::Result<DeleteReport> {
+    BackupManager::default().delete_file_with(name, opts)
+}
+
+/// Move a file to the trash instead of deleting it; see [`BackupManager::trash_file`].
+pub fn trash_file(name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    BackupManager::default().trash_file(name)
+}
+
+/// Remove trashed files older than `older_than` seconds, or all; see [`BackupManager::empty_trash`].
+pub fn empty_trash(older_than: Option<u64>) -> io::Result<TrashReport> {
+    BackupManager::default().empty_trash(older_than)
+}
+
+impl BackupManager {
+    /// Delete a given file (validated).
+    pub fn delete_file(&self, name: impl AsRef<Path>) -> io::Result<()> {
+        self.delete_file_with(name, &DeleteOptions::new()).map(drop)
+    }
+
+    /// Delete a given file (validated), with `opts`. What goes is worked out
+    /// before anything is removed; then the file, then each backup, each
+    /// removal logged ("delete", then "purge" per backup). Purging the
+    /// backups of a file that is gone already is allowed; with neither file
+    /// nor backup, it is `NotFound`. Objects in the dedup store that only
+    /// purged backups used are removed with them. Should removing a backup
+    /// fail, the error is [`BackupError::PurgePartial`], naming the backups
+    /// that were and weren't removed.
+    pub fn delete_file_with(&self, name: impl AsRef<Path>, opts: &DeleteOptions) -> io::Result<DeleteReport> {
+        let (p, logical) = self.validate_path_parts(name)?;
+        let root = self.root()?;
+        let purged = if opts.purge_backups { self.backups_to_purge(&root, Path::new(&logical))? } else { Vec::new() };
+        let deleted = self.fs.exists(&p).then(|| p.clone());
+        if deleted.is_none() && purged.is_empty() {
+            return Err(missing("delete", "file does not exist", &p));
+        }
+        let plan = DeleteReport { deleted, trashed: None, purged, warnings: Vec::new() };
+        if opts.dry_run {
+            return Ok(plan);
+        }
+        self.carry_out_delete(&root, &p, &logical, plan, opts.trash)
+    }
+
+    /// Remove what `plan`, from [`delete_file_with`](Self::delete_file_with)
+    /// with `dry_run`, says for `name` (once someone confirmed it), and
+    /// nothing found since. What is gone already, or is no longer a backup
+    /// of `name`, is left out of the report.
+    pub fn delete_planned(&self, name: impl AsRef<Path>, plan: DeleteReport) -> io::Result<DeleteReport> {
+        self.delete_planned_with(name, plan, &DeleteOptions::new())
+    }
+
+    /// [`delete_planned`](Self::delete_planned), moving the file to the trash with `opts.trash`.
+    pub fn delete_planned_with(
+        &self,
+        name: impl AsRef<Path>,
+        plan: DeleteReport,
+        opts: &DeleteOptions,
+    ) -> io::Result<DeleteReport> {
+        let (p, logical) = self.validate_path_parts(name)?;
+        let root = self.root()?;
+        let still = match plan.purged.is_empty() {
+            true => Vec::new(),
+            false => self.backups_to_purge(&root, Path::new(&logical))?,
+        };
+        let deleted = plan.deleted.filter(|d| *d == p && self.fs.exists(d));
+        let purged = plan.purged.into_iter().filter(|b| still.contains(b)).collect();
+        let plan = DeleteReport { deleted, trashed: None, purged, warnings: Vec::new() };
+        self.carry_out_delete(&root, &p, &logical, plan, opts.trash)
+    }
+
+    /// Remove the file and backups of `plan`, logging each removal; the
+    /// file goes to the trash with `trash`.
+    fn carry_out_delete(
+        &self,
+        root: &Path,
+        p: &Path,
+        logical: &str,
+        mut plan: DeleteReport,
+        trash: bool,
+    ) -> io::Result<DeleteReport> {
+        self.pre_hook("delete", p)?;
+        if plan.deleted.is_some() && trash {
+            let trashed = self.move_to_trash(root, p)?;
+            let shown = trashed.strip_prefix(root).unwrap_or(&trashed);
+            let result = format!("ok (trashed as {})", display_name(shown.as_os_str()));
+            self.log_action(root, "delete", Path::new(logical), &result)?;
+            plan.trashed = Some(trashed);
+        } else if plan.deleted.is_some() {
+            self.fs.remove_file(p).at("delete", "cannot remove", p)?;
+            self.log_action(root, "delete", Path::new(logical), "ok")?;
+        }
+        let mut deduped = false;
+        for (i, backup) in plan.purged.iter().enumerate() {
+            deduped |= read_meta(&*self.fs, backup).is_ok_and(|m| m.layout == Layout::Deduped);
+            let removed = versions::remove_backup(&*self.fs, backup)
+                .and_then(|()| self.log_action(root, "purge", backup.strip_prefix(root).unwrap_or(backup), "ok"));
+            if let Err(source) = removed {
+                let (purged, not_purged) = (plan.purged[..i].to_vec(), plan.purged[i..].to_vec());
+                return Err(BackupError::PurgePartial { file: p.to_path_buf(), purged, not_purged, source }.into());
+            }
+        }
+        if deduped {
+            self.gc(false)?;
+        }
+        plan.warnings.extend(self.post_hook(root, "delete", p));
+        Ok(plan)
+    }
+
+    /// The backups of `name` a purge removes: its timestamped backups, also
+    /// under earlier names, and its plain copy if the sidecar says it is its.
+    fn backups_to_purge(&self, root: &Path, name: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut found: Vec<PathBuf> =
+            self.find_ts_backups(root, name, FileStat::is_file)?.into_iter().map(|(_, p)| p).collect();
+        let plain_root = self.plain_root(root);
+        let plain = self.plain_backup_for(&plain_root, name)?;
+        let base = base_name(name)?;
+        for p in std::iter::once(plain).chain(naming::old_plain_backup_for(&plain_root, name)) {
+            if is_tool_backup_of(&*self.fs, &p, base) && !found.contains(&p) {
+                found.push(p);
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Managers for the tests of every module.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    /// The configuration of a manager working in `root` whose clock stands at `now`.
+    pub(crate) fn config(root: &Path, now: u64) -> Config {
+        Config { root: Some(root.to_path_buf()), clock: Arc::new(FixedClock(now)), ..Config::default() }
+    }
+
+    /// A manager working in `root` whose clock stands at `now`.
+    pub(crate) fn manager(root: &Path, now: u64) -> BackupManager {
+        BackupManager::new(config(root, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use test_support::{config, manager};
+
+    #[test]
+    fn capabilities_are_the_features_built_in() {
+        let caps = capabilities();
+        assert_eq!(caps.contains(&"sftp"), cfg!(feature = "sftp"));
+        assert_eq!(caps.contains(&"reflink"), cfg!(feature = "reflink"));
+        assert_eq!(caps.contains(&"blake3"), cfg!(feature = "blake3"));
+        assert!(caps.iter().all(|c| ["blake3", "reflink", "xattrs", "acls", "sftp"].contains(c)), "{caps:?}");
+        assert_eq!(VERSION, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn missing_source_names_the_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let e = manager(tmp.path(), 100).backup_file("notes.txt").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        let msg = error_chain(&e);
+        assert!(msg.starts_with("backup: source file does not exist"), "{msg}");
+        assert!(msg.contains(&tmp.path().join("notes.txt").display().to_string()), "{msg}");
+    }
+
+    #[test]
+    fn missing_backup_names_the_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let msg = error_chain(&manager(tmp.path(), 100).restore_file("notes.txt").unwrap_err());
+        assert!(msg.contains(&tmp.path().join("notes.txt").display().to_string()), "{msg}");
+    }
+
+    #[test]
+    fn delete_missing_names_the_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let msg = error_chain(&manager(tmp.path(), 100).delete_file("gone.txt").unwrap_err());
+        assert!(msg.contains(&tmp.path().join("gone.txt").display().to_string()), "{msg}");
+    }
+
+    #[test]
+    fn failed_copy_names_both_paths_and_cause() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("notes.txt"), "hi").unwrap();
+        let mgr = manager(tmp.path(), 100);
+        let bak = mgr.backup_file("notes.txt").unwrap();
+        // A directory where the backup was makes the copy fail.
+        fs::remove_file(&bak).unwrap();
+        fs::create_dir(&bak).unwrap();
+        let e = mgr.restore_file(bak.file_name().unwrap()).unwrap_err();
+        let inner = BackupError::from_io(&e).expect("typed error");
+        assert!(matches!(inner, BackupError::Copy { op: "restore", .. }));
+        let msg = error_chain(&e);
+        assert!(msg.contains(&bak.display().to_string()), "{msg}");
+        assert!(msg.contains(&tmp.path().join("notes.txt").display().to_string()), "{msg}");
+        assert!(msg.matches(": ").count() >= 2, "cause missing: {msg}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifo_is_rejected_unless_allowed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let fifo = root.join("pipe");
+        assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+
+        let e = manager(root, 100).backup_file("pipe").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::UnsupportedFileType { kind: "FIFO", .. })));
+        assert_eq!(fs::read_dir(root).unwrap().count(), 1, "nothing written");
+
+        let writer = std::thread::spawn(move || fs::write(fifo, "through the pipe").unwrap());
+        let mgr =
+            BackupManager::new(Config { root: Some(root.to_path_buf()), allow_special_files: true, ..Config::default() });
+        let bak = mgr.backup_file("pipe").unwrap();
+        writer.join().unwrap();
+        assert_eq!(fs::read_to_string(bak).unwrap(), "through the pipe");
+        assert_eq!(fs::read_to_string(root.join("pipe.bak")).unwrap(), "through the pipe");
+    }
+
+    #[test]
+    fn secondary_failures_are_warnings() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("notes.txt"), "hi").unwrap();
+        // A directory where the plain backup and the log should go.
+        fs::create_dir(tmp.path().join("notes.bak")).unwrap();
+        fs::create_dir(tmp.path().join("logfile.txt")).unwrap();
+        let report = manager(tmp.path(), 100).backup_file_report("notes.txt").unwrap();
+        assert_eq!(fs::read_to_string(&report.path).unwrap(), "hi");
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [Warning::PlainBackup { path, .. }, Warning::Log { .. }] if *path == tmp.path().join("notes.bak")
+        ));
+        let shown = report.warnings[0].to_string();
+        assert!(shown.starts_with("plain backup ") && shown.contains("cannot copy"), "{shown}");
+    }
+
+    #[test]
+    fn foreign_files_at_backup_names_survive() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("notes.txt"), "hi").unwrap();
+        fs::write(root.join("notes.bak"), "my own notes").unwrap();
+        let mgr = manager(root, 1_700_000_000);
+        let report = mgr.backup_file_report("notes.txt").unwrap();
+        assert_eq!(fs::read_to_string(root.join("notes.bak")).unwrap(), "my own notes");
+        assert_eq!(fs::read_to_string(&report.path).unwrap(), "hi");
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [Warning::PlainBackup { path, .. }] if *path == root.join("notes.bak")
+        ));
+        assert!(report.warnings[0].to_string().contains("not made by safe_backup"));
+        // The same goes for a hand-made file at the next timestamped name.
+        let next = root.join("notes.txt.1700000001.bak");
+        fs::write(&next, "also mine").unwrap();
+        let later = manager(root, 1_700_000_001);
+        let err = later.backup_file("notes.txt").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(matches!(BackupError::from_io(&err), Some(BackupError::Foreign { .. })));
+        assert_eq!(fs::read_to_string(&next).unwrap(), "also mine");
+        // The plain copy made before that is ours and gets updated.
+        fs::remove_file(root.join("notes.bak")).unwrap();
+        mgr.backup_file("notes.txt").unwrap();
+        fs::write(root.join("notes.txt"), "hi again").unwrap();
+        let report = mgr.backup_file_report("notes.txt").unwrap();
+        assert!(report.warnings.is_empty());
+        assert_eq!(fs::read_to_string(root.join("notes.bak")).unwrap(), "hi again");
+    }
+
+    #[test]
+    fn a_plain_copy_of_another_file_is_not_overwritten() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "text").unwrap();
+        fs::write(root.join("a.md"), "markdown").unwrap();
+        let mgr = manager(root, 100);
+        mgr.backup_file("a.txt").unwrap();
+
+        // Both map to "a.bak": the second backup is made, but its plain copy isn't.
+        let report = mgr.backup_file_report("a.md").unwrap();
+        assert_eq!(fs::read_to_string(&report.path).unwrap(), "markdown");
+        assert_eq!(report.plain, None);
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [Warning::PlainBackup { error, .. }] if error.contains("is the plain backup of a.txt")
+        ));
+        assert_eq!(fs::read_to_string(root.join("a.bak")).unwrap(), "text");
+        assert_eq!(read_backup_meta(root.join("a.bak")).unwrap().original, "a.txt");
+        assert_eq!(fs::read_to_string(mgr.find_latest_backup("a.txt").unwrap()).unwrap(), "text");
+
+        // All or nothing, it is refused outright.
+        let e = mgr.backup_file_with("a.md", &BackupOptions::new().best_effort(false)).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::PlainCollision { .. })), "{e}");
+        assert_eq!(error_code(&e), "plain_collision");
+        // The file the copy belongs to still updates it.
+        fs::write(root.join("a.txt"), "text again").unwrap();
+        assert!(mgr.backup_file_report("a.txt").unwrap().warnings.is_empty());
+        assert_eq!(fs::read_to_string(root.join("a.bak")).unwrap(), "text again");
+    }
+
+    #[test]
+    fn purging_removes_every_backup_of_the_file_and_nothing_else() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let at = |now| manager(root, now);
+        fs::write(root.join("secrets.env"), "v1").unwrap();
+        at(100).backup_file("secrets.env").unwrap();
+        fs::write(root.join("secrets.env"), "v2").unwrap();
+        at(200).backup_file("secrets.env").unwrap();
+        // Sharing a prefix, or a plain copy name without our sidecar, isn't enough.
+        fs::write(root.join("secrets.env.old"), "other").unwrap();
+        at(300).backup_file("secrets.env.old").unwrap();
+        fs::write(root.join("secrets.env.notes.bak"), "foreign").unwrap();
+        let mgr = at(400);
+
+        let opts = DeleteOptions::new().purge_backups(true);
+        let plan = mgr.delete_file_with("secrets.env", &opts.clone().dry_run(true)).unwrap();
+        let names: Vec<_> = plan.purged.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["secrets.env.200.bak", "secrets.env.100.bak", "secrets.bak"]);
+        assert_eq!(plan.deleted, Some(root.join("secrets.env")));
+        assert!(root.join("secrets.env.100.bak").exists());
+
+        assert_eq!(mgr.delete_file_with("secrets.env", &opts).unwrap(), plan);
+        assert!(!root.join("secrets.env").exists() && !root.join("secrets.bak.meta.json").exists());
+        assert!(plan.purged.iter().all(|p| !p.exists()));
+        assert!(mgr.list_backups("secrets.env").unwrap().is_empty());
+        assert_eq!(mgr.list_backups("secrets.env.old").unwrap().len(), 1);
+        assert!(root.join("secrets.env.notes.bak").exists() && root.join("secrets.env.old").exists());
+        let log = mgr.read_log().unwrap();
+        let entries: Vec<_> = log[log.len() - 4..].iter().map(|e| (e.action.as_str(), e.file.as_str())).collect();
+        assert_eq!(
+            entries,
+            [
+                ("delete", "secrets.env"),
+                ("purge", "secrets.env.200.bak"),
+                ("purge", "secrets.env.100.bak"),
+                ("purge", "secrets.bak"),
+            ]
+        );
+        let e = mgr.delete_file_with("secrets.env", &opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn change_checks_and_stats_work_in_memory() {
+        let (mgr, mock) = mock_manager(100);
+        mgr.backup_file("notes.txt").unwrap();
+        assert!(!mgr.check_changed("notes.txt").unwrap().changed());
+        let stats = mgr.stats().unwrap();
+        assert_eq!((stats.originals, stats.backups), (1, 1));
+        assert_eq!(mgr.tracked_originals().unwrap(), ["notes.txt"]);
+        mock.write(Path::new("/work/notes.txt"), b"hello, world").unwrap();
+        assert!(mgr.check_changed("notes.txt").unwrap().changed());
+    }
+
+    #[test]
+    fn a_purge_removes_what_was_confirmed_and_says_how_far_it_got() {
+        let (mgr, mock) = mock_manager(100);
+        mgr.backup_file("notes.txt").unwrap();
+        let later = |now| Config { clock: std::sync::Arc::new(FixedClock(now)), ..Config::default() };
+        BackupManager::with_fs(later(200), std::sync::Arc::new(mock.clone())).backup_file("notes.txt").unwrap();
+        let opts = DeleteOptions::new().purge_backups(true).dry_run(true);
+        let plan = mgr.delete_file_with("notes.txt", &opts).unwrap();
+        assert_eq!(plan.purged.len(), 3, "{plan:?}");
+
+        // A backup made after the plan was confirmed stays.
+        BackupManager::with_fs(later(300), std::sync::Arc::new(mock.clone())).backup_file("notes.txt").unwrap();
+        mock.fail("remove_file", "/work/notes.txt.100.bak", io::ErrorKind::PermissionDenied);
+        let e = mgr.delete_planned("notes.txt", plan.clone()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        let Some(BackupError::PurgePartial { purged, not_purged, .. }) = BackupError::from_io(&e) else {
+            panic!("{e}")
+        };
+        assert_eq!((purged.as_slice(), not_purged.as_slice()), (&plan.purged[..1], &plan.purged[1..]));
+        assert!(mock.contents("/work/notes.txt").is_none() && mock.contents(&plan.purged[0]).is_none());
+        assert!(mock.contents("/work/notes.txt.100.bak").is_some());
+
+        mock.clear_failures();
+        let done = mgr.delete_planned("notes.txt", plan.clone()).unwrap();
+        assert_eq!((done.deleted, done.purged.as_slice()), (None, &plan.purged[1..]));
+        let left: Vec<u64> = mgr.list_backups("notes.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(left, [300]);
+    }
+
+    #[test]
+    fn a_file_modified_within_the_minimum_age_is_not_backed_up() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("live.db"), "half written").unwrap();
+        let modified = fs::metadata(root.join("live.db")).unwrap().modified().unwrap();
+        let modified = modified.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let manager = |now| {
+            BackupManager::new(Config { min_age_secs: Some(60), ..config(root, now) })
+        };
+
+        let e = manager(modified + 10).backup_file("live.db").unwrap_err();
+        let Some(BackupError::TooRecent { age, min_age, .. }) = BackupError::from_io(&e) else { panic!("{e}") };
+        assert_eq!((*age, *min_age), (10, 60));
+        assert_eq!((error_code(&e), exit_code(&e)), ("too_recent", 5));
+        assert!(!root.join("live.bak").exists());
+        // A scheduled cycle leaves it for the next one instead of failing.
+        let r = manager(modified + 10).run_cycle(&[PathBuf::from("live.db")]).unwrap();
+        assert_eq!(r.too_recent, [PathBuf::from("live.db")]);
+        assert_eq!(r.summary(), "ok (0 backed up, 0 unchanged, 1 too recent, 0 failed)");
+        // So do scripts and directory backups, going on with the rest.
+        fs::create_dir(root.join("dir")).unwrap();
+        let long_ago = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(modified - 3600);
+        for f in ["old.txt", "dir/old.txt", "dir/live.db"] {
+            let file = fs::File::create(root.join(f)).unwrap();
+            if f.ends_with("old.txt") {
+                file.set_modified(long_ago).unwrap();
+            }
+        }
+        fs::write(root.join("job.txt"), "backup live.db\nbackup old.txt\n").unwrap();
+        let report = manager(modified + 10).run_script(root.join("job.txt"), false).unwrap();
+        assert!(report.all_ok(), "{}", report.batch);
+        assert_eq!((report.results.len(), report.batch.succeeded(), report.batch.skipped()), (2, 1, 1));
+        let report = manager(modified + 10).backup_dir_with("dir", &DirOptions::new()).unwrap();
+        assert_eq!((report.files, report.too_recent, report.summary().as_str()), (1, 1, "1 files, 1 too recent"));
+        let skipped = &report.batch.entries[0];
+        assert_eq!((skipped.name.as_str(), skipped.status), ("live.db", crate::batch::BatchStatus::Skipped));
+        assert!(skipped.detail.starts_with("too recent (modified "), "{}", skipped.detail);
+        assert!(!report.path.join("live.db").exists() && report.path.join("old.txt").is_file());
+
+        let bak = manager(modified + 60).backup_file("live.db").unwrap();
+        assert_eq!(fs::read_to_string(bak).unwrap(), "half written");
+    }
+
+    #[test]
+    fn backups_of_backups_are_refused_unless_forced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let at = |ts| {
+            BackupManager::new(Config { plain_keep_extension: true, ..config(root, ts) })
+        };
+        fs::write(root.join("notes.txt"), "original").unwrap();
+        at(100).backup_file("notes.txt").unwrap();
+        // "notes.txt.bak" is now the plain copy, so a tool backup; "old.bak" is hand-made.
+        fs::write(root.join("old.bak"), "mine").unwrap();
+        for name in ["notes.txt.bak", "old.bak", "notes.txt.100.bak"] {
+            let err = at(200).backup_file(name).unwrap_err();
+            assert!(matches!(BackupError::from_io(&err), Some(BackupError::BackupOfBackup { .. })), "{name}");
+            assert!(err.to_string().contains("refusing to back up a backup file (use --force)"));
+        }
+
+        // Forced, the backup is made, but the plain copy isn't written over itself.
+        fs::write(root.join("notes.txt.bak"), "edited by hand").unwrap();
+        at(300).backup_file_with("notes.txt.bak", &BackupOptions::new().force(true)).unwrap();
+        assert_eq!(fs::read_to_string(root.join("notes.txt.bak")).unwrap(), "edited by hand");
+        // The original's backups are untouched by it.
+        let mgr = at(400);
+        assert_eq!(mgr.list_backups("notes.txt").unwrap().len(), 1);
+        assert_eq!(mgr.find_latest_backup("notes.txt").unwrap(), root.join("notes.txt.100.bak"));
+        fs::write(root.join("notes.txt"), "damaged").unwrap();
+        mgr.restore_file("notes.txt").unwrap();
+        assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "original");
+    }
+
+    #[test]
+    fn configured_actor_is_logged_escaped() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("a.txt"), "x").unwrap();
+        let mgr = BackupManager::new(Config {
+            root: Some(tmp.path().to_path_buf()),
+            actor: Some("release \"bot\"".into()),
+            ..Config::default()
+        });
+        mgr.backup_file("a.txt").unwrap();
+        let log = fs::read_to_string(tmp.path().join("logfile.txt")).unwrap();
+        assert!(log.contains(r#""user":"release \"bot\"""#), "{log}");
+    }
+
+    #[test]
+    fn same_timestamp_tie_break_is_stable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let t = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        for name in ["data.txt.100.bak", "data.txt.copy.100.bak", "data.txt.99.bak"] {
+            fs::File::create(root.join(name)).unwrap().set_modified(t).unwrap();
+            write_meta(&RealFs, "backup", &root.join(name), Path::new("data.txt"), &root.join(name), Compression::None, DigestAlgo::Sha256).unwrap();
+        }
+        // Equal ts and mtime: the lexically greatest name wins, every time.
+        for _ in 0..5 {
+            assert_eq!(manager(root, 100).find_latest_backup("data.txt").unwrap(), root.join("data.txt.copy.100.bak"));
+        }
+        // A later mtime beats the name order.
+        fs::File::options()
+            .write(true)
+            .open(root.join("data.txt.100.bak"))
+            .unwrap()
+            .set_modified(t + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(manager(root, 100).find_latest_backup("data.txt").unwrap(), root.join("data.txt.100.bak"));
+        // Picking the newest alone agrees with the full listing.
+        let all = ts_backups(&RealFs, root, Path::new("data.txt"), false, FileStat::is_file).unwrap();
+        let paths: Vec<_> = all.iter().map(|(_, p)| p.file_name().unwrap()).collect();
+        assert_eq!(paths, ["data.txt.100.bak", "data.txt.copy.100.bak", "data.txt.99.bak"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_name_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let name = OsStr::from_bytes(b"r\xE9sum\xE9.txt");
+        fs::write(tmp.path().join(name), "cv").unwrap();
+        let mgr = manager(tmp.path(), 100);
+
+        let bak = mgr.backup_file(name).unwrap();
+        assert!(bak.file_name().unwrap().as_bytes().starts_with(b"r\xE9sum\xE9.txt."));
+        assert_eq!(mgr.find_latest_backup(name).unwrap(), bak);
+
+        fs::remove_file(tmp.path().join(name)).unwrap();
+        let restored = mgr.restore_file(name).unwrap();
+        assert_eq!(restored, tmp.path().join(name));
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "cv");
+
+        let log = fs::read_to_string(tmp.path().join("logfile.txt")).unwrap();
+        assert!(log.contains(r#""file":"r\\xE9sum\\xE9.txt""#), "{log}");
+    }
+
+    #[test]
+    fn plain_backups_keep_extension_when_configured() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            plain_keep_extension: true,
+            ..Config::default()
+        });
+        fs::write(root.join("report.txt"), "text").unwrap();
+        fs::write(root.join("report.csv"), "a,b").unwrap();
+        mgr.backup_file("report.txt").unwrap();
+        mgr.backup_file("report.csv").unwrap();
+        assert_eq!(fs::read_to_string(root.join("report.txt.bak")).unwrap(), "text");
+        assert_eq!(fs::read_to_string(root.join("report.csv.bak")).unwrap(), "a,b");
+        assert!(!root.join("report.bak").exists());
+
+        // With only the plain copies left, each original still restores its own content.
+        for e in fs::read_dir(root).unwrap() {
+            let p = e.unwrap().path();
+            if parse_ts_backup(p.file_name().unwrap(), std::ffi::OsStr::new("report")).is_some() {
+                fs::remove_file(p).unwrap();
+            }
+        }
+        assert_eq!(mgr.find_latest_backup("report.txt").unwrap(), root.join("report.txt.bak"));
+        assert_eq!(mgr.find_latest_backup("report.csv").unwrap(), root.join("report.csv.bak"));
+        fs::remove_file(root.join("report.csv")).unwrap();
+        mgr.restore_file("report.csv").unwrap();
+        assert_eq!(fs::read_to_string(root.join("report.csv")).unwrap(), "a,b");
+    }
+
+    #[test]
+    fn compound_extensions_keep_the_whole_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = manager(root, 100);
+        for name in ["archive.tar.gz", "archive.tar", "jquery.min.js", ".env"] {
+            fs::write(root.join(name), name).unwrap();
+            mgr.backup_file(name).unwrap();
+        }
+        for name in ["archive.tar.gz", "jquery.min.js", ".env"] {
+            assert_eq!(fs::read_to_string(root.join(format!("{name}.bak"))).unwrap(), name);
+        }
+        // "archive.tar" has one extension, so it alone is "archive.bak".
+        assert_eq!(fs::read_to_string(root.join("archive.bak")).unwrap(), "archive.tar");
+
+        // Only plain copies left: each restores its own, and an "archive.tar.bak"
+        // made for "archive.tar.gz" by an earlier version is still found.
+        for e in fs::read_dir(root).unwrap() {
+            let p = e.unwrap().path();
+            if split_backup_name(p.file_name().unwrap()).is_some_and(|(_, ts)| ts.is_some()) {
+                fs::remove_file(p).unwrap();
+            }
+        }
+        fs::remove_file(root.join(".env")).unwrap();
+        mgr.restore_file(".env").unwrap();
+        assert_eq!(fs::read_to_string(root.join(".env")).unwrap(), ".env");
+        for f in ["archive.tar.gz.bak", "archive.tar.gz.bak.meta.json"] {
+            fs::rename(root.join(f), root.join(f.replace("tar.gz", "tar"))).unwrap();
+        }
+        assert_eq!(mgr.find_latest_backup("archive.tar.gz").unwrap(), root.join("archive.tar.bak"));
+        fs::write(root.join("archive.tar.gz"), "changed").unwrap();
+        mgr.restore_file("archive.tar.gz").unwrap();
+        assert_eq!(fs::read_to_string(root.join("archive.tar.gz")).unwrap(), "archive.tar.gz");
+    }
+
+    #[test]
+    fn default_options_match_plain_calls() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let mgr = manager(root, 100);
+        assert_eq!(BackupOptions::new(), BackupOptions::default());
+        assert_eq!(RestoreOptions::new(), RestoreOptions::default());
+
+        let report = mgr.backup_file_with("a.txt", &BackupOptions::new()).unwrap();
+        assert!(root.join("a.bak").exists());
+        assert_eq!(mgr.find_latest_backup("a.txt").unwrap(), report.path);
+        fs::write(root.join("a.txt"), "changed").unwrap();
+        assert_eq!(mgr.restore_file_with("a.txt", &RestoreOptions::new()).unwrap().path, root.join("a.txt"));
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "a");
+    }
+
+    #[test]
+    fn options_change_targets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        let mgr = manager(root, 100);
+        mgr.backup_file_with("b.txt", &BackupOptions::new().plain_copy(false).reflink(false)).unwrap();
+        assert!(!root.join("b.bak").exists());
+
+        let e = mgr.restore_file_with("b.txt", &RestoreOptions::new().to("copy/../b2.txt")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let dest = mgr.restore_file_with("b.txt", &RestoreOptions::new().to("b2.txt")).unwrap().path;
+        assert_eq!(dest, root.join("b2.txt"));
+        assert_eq!(fs::read_to_string(dest).unwrap(), "b");
+    }
+
+    #[test]
+    fn restore_previous_counts_back_from_the_latest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for ts in [100, 200, 300] {
+            fs::write(root.join("n.txt"), format!("v{ts}")).unwrap();
+            manager(root, ts).backup_file("n.txt").unwrap();
+        }
+        let mgr = manager(root, 100);
+        for (n, want) in [(0, "v300"), (2, "v100")] {
+            mgr.restore_file_with("n.txt", &RestoreOptions::new().previous(n)).unwrap();
+            assert_eq!(fs::read_to_string(root.join("n.txt")).unwrap(), want);
+        }
+        let e = mgr.restore_file_with("n.txt", &RestoreOptions::new().previous(3)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::NoSuchVersion { available: 3, .. })));
+        assert_eq!(e.to_string(), "restore: no backup 3 versions back for n.txt: it has 3 (0 to 2)");
+    }
+
+    #[test]
+    fn ensuring_a_backup_makes_one_only_if_there_is_none() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("n.txt"), "v1").unwrap();
+        let at = |ts| manager(root, ts);
+        let (first, made) = at(100).ensure_backup("n.txt").unwrap();
+        assert_eq!((first.clone(), made), (root.join("n.txt.100.bak"), true));
+        fs::write(root.join("n.txt"), "v2").unwrap();
+        assert_eq!(at(200).ensure_backup("./n.txt").unwrap(), (first, false));
+        assert!(!root.join("n.txt.200.bak").exists());
+        assert_eq!(at(200).read_log().unwrap().len(), 1);
+        // A plain copy alone counts as well.
+        fs::write(root.join("m.txt"), "m").unwrap();
+        fs::write(root.join("m.bak"), "m").unwrap();
+        let (plain, name) = (root.join("m.bak"), Path::new("m.txt"));
+        write_meta(&RealFs, "backup", &plain, name, &root.join(name), Compression::None, DigestAlgo::Sha256).unwrap();
+        assert_eq!(at(300).ensure_backup("m.txt").unwrap(), (root.join("m.bak"), false));
+        let e = at(300).ensure_backup("missing.txt").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn restore_by_backup_name_uses_recorded_original() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("docs")).unwrap();
+        let orig = root.join("docs/notes.txt");
+        fs::write(&orig, "v1").unwrap();
+        let t = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        fs::File::options().write(true).open(&orig).unwrap().set_modified(t).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&orig, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let mgr = manager(root, 100);
+        let bak = mgr.backup_file("docs/notes.txt").unwrap();
+        assert_eq!(read_backup_meta(&bak).unwrap().original, "docs/notes.txt");
+
+        fs::remove_file(&orig).unwrap();
+        let dest = mgr.restore_file(bak.file_name().unwrap()).unwrap();
+        assert_eq!(dest, orig);
+        assert_eq!(fs::read_to_string(&orig).unwrap(), "v1");
+        let md = fs::metadata(&orig).unwrap();
+        assert_eq!(md.modified().unwrap(), t);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(md.permissions().mode() & 0o7777, 0o640);
+        }
+
+        // The plain backup carries the same record, so it no longer needs a ".restored" name.
+        assert_eq!(mgr.restore_file("notes.bak").unwrap(), orig);
+    }
+
+    #[test]
+    fn restore_targets_come_from_the_sidecar_before_the_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = manager(root, 100);
+        // No extension, several dots, and a numeric extension the name alone would take for a timestamp.
+        for (name, plain) in [("Makefile", "Makefile.bak"), ("a.b.c.txt", "a.b.c.txt.bak"), ("data.2024", "data.bak")] {
+            fs::write(root.join(name), name).unwrap();
+            let bak = mgr.backup_file(name).unwrap();
+            for backup in [bak.file_name().unwrap().to_str().unwrap(), plain] {
+                fs::remove_file(root.join(name)).unwrap();
+                assert_eq!(mgr.restore_file(backup).unwrap(), root.join(name), "{backup}");
+                assert_eq!(fs::read_to_string(root.join(name)).unwrap(), name);
+            }
+        }
+
+        // Without a sidecar only the name is there to go by.
+        fs::copy(root.join("data.bak"), root.join("other.2024.bak")).unwrap();
+        assert_eq!(mgr.restore_file("other.2024.bak").unwrap(), root.join("other"));
+        fs::copy(root.join("Makefile.bak"), root.join("Rakefile.bak")).unwrap();
+        assert_eq!(mgr.restore_file("Rakefile.bak").unwrap(), root.join("Rakefile.restored.100"));
+        // A sidecar naming some other file is not trusted either.
+        fs::copy(root.join("a.b.c.txt.100.bak"), root.join("x.y.100.bak")).unwrap();
+        fs::copy(root.join("a.b.c.txt.100.bak.meta.json"), root.join("x.y.100.bak.meta.json")).unwrap();
+        assert_eq!(mgr.restore_file("x.y.100.bak").unwrap(), root.join("x.y"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restoring_by_backup_name_keeps_bytes_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("cv")).unwrap();
+        let name = Path::new("cv").join(OsStr::from_bytes(b"r\xE9sum\xE9.txt"));
+        fs::write(root.join(&name), "cv").unwrap();
+        let mgr = manager(root, 100);
+        let bak = mgr.backup_file(&name).unwrap();
+        assert_eq!(read_backup_meta(&bak).unwrap().original, "cv/r\\xE9sum\\xE9.txt");
+        fs::remove_file(root.join(&name)).unwrap();
+        // The recorded directory, with the file name as it was on disk.
+        assert_eq!(mgr.restore_file(bak.file_name().unwrap()).unwrap(), root.join(&name));
+        assert_eq!(fs::read_to_string(root.join(&name)).unwrap(), "cv");
+
+        // A directory that isn't UTF-8 either, next to one literally named with a backslash.
+        let dir = OsStr::from_bytes(b"caf\xE9");
+        fs::create_dir(root.join(dir)).unwrap();
+        fs::create_dir(root.join("a\\x41")).unwrap();
+        for name in [Path::new(dir).join("menu.txt"), Path::new("a\\x41").join("b.txt")] {
+            fs::write(root.join(&name), "menu").unwrap();
+            let bak = mgr.backup_file(&name).unwrap();
+            fs::remove_file(root.join(&name)).unwrap();
+            assert_eq!(mgr.restore_file(bak.file_name().unwrap()).unwrap(), root.join(&name));
+            assert_eq!(fs::read_to_string(root.join(&name)).unwrap(), "menu");
+        }
+        assert!(!root.join("caf\\xE9").exists() && !root.join("aA").exists());
+    }
+
+    #[test]
+    fn backups_go_to_backup_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "v1").unwrap();
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            backup_dir: Some("baks".into()),
+            ..Config::default()
+        });
+        let first = mgr.backup_file("a.txt").unwrap();
+        assert_eq!(first.parent().unwrap(), root.join("baks"));
+        assert!(root.join("baks/a.bak").exists() && !root.join("a.bak").exists());
+
+        // Pretend the first backup is older, then restore it by version.
+        let old = root.join("baks/a.txt.100.bak");
+        fs::rename(&first, &old).unwrap();
+        fs::rename(sidecar_for(&first), sidecar_for(&old)).unwrap();
+        fs::write(root.join("a.txt"), "v2").unwrap();
+        mgr.backup_file("a.txt").unwrap();
+        mgr.restore_file_with("a.txt", &RestoreOptions::new().version(100)).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "v1");
+        let e = mgr.restore_file_with("a.txt", &RestoreOptions::new().version(7)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn compressed_backup_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let text = "line of text\n".repeat(500);
+        fs::write(root.join("big.log"), &text).unwrap();
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            compression: Compression::Gzip,
+            ..Config::default()
+        });
+        let bak = mgr.backup_file("big.log").unwrap();
+        assert!(fs::metadata(&bak).unwrap().len() < text.len() as u64);
+        assert_eq!(read_backup_meta(&bak).unwrap().compression, Compression::Gzip);
+        mgr.verify_backup(bak.file_name().unwrap()).unwrap();
+        // The plain copy stays readable as-is.
+        assert_eq!(fs::read_to_string(root.join("big.bak")).unwrap(), text);
+
+        fs::write(root.join("big.log"), "oops").unwrap();
+        mgr.restore_file("big.log").unwrap();
+        assert_eq!(fs::read_to_string(root.join("big.log")).unwrap(), text);
+        assert_eq!(mgr.read_log().unwrap().pop().unwrap().result, "ok (verified)");
+        // Checked as stored, before it is unpacked.
+        fs::OpenOptions::new().append(true).open(&bak).unwrap().write_all(b"junk").unwrap();
+        fs::write(root.join("big.log"), "oops").unwrap();
+        let e = mgr.restore_file("big.log").unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::ChecksumMismatch { .. })), "{e}");
+        assert_eq!(fs::read_to_string(root.join("big.log")).unwrap(), "oops");
+
+        // Per-call option beats the config.
+        let plain = mgr.backup_file_with("big.log", &BackupOptions::new().compress(Compression::None)).unwrap();
+        assert_eq!(read_backup_meta(&plain.path).unwrap().compression, Compression::None);
+    }
+
+    #[test]
+    fn backup_from_reader_restores_like_a_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = manager(root, 100);
+        let report = mgr.backup_from_reader("dumps/db.sql", &b"CREATE TABLE t;\n"[..]).unwrap();
+        assert_eq!(report.bytes, 16);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert_eq!(read_backup_meta(&report.path).unwrap().original, "dumps/db.sql");
+        assert_eq!(mgr.read_log().unwrap()[0].action, "backup");
+        assert_eq!(fs::read_to_string(root.join("db.bak")).unwrap(), "CREATE TABLE t;\n");
+
+        fs::create_dir(root.join("dumps")).unwrap();
+        let dest = mgr.restore_file("dumps/db.sql").unwrap();
+        assert_eq!(dest, root.join("dumps/db.sql"));
+        assert_eq!(fs::read_to_string(dest).unwrap(), "CREATE TABLE t;\n");
+
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("pipe closed"))
+            }
+        }
+        let before = fs::read_dir(root).unwrap().count();
+        assert!(mgr.backup_from_reader("dumps/other.sql", Broken).is_err());
+        assert_eq!(fs::read_dir(root).unwrap().count(), before);
+    }
+
+    #[test]
+    fn cat_streams_backups_untouched() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let binary: Vec<u8> = (0..=255).collect();
+        fs::write(root.join("blob.bin"), &binary).unwrap();
+        let gz = Config { compression: Compression::Gzip, ..config(root, 100) };
+        BackupManager::new(gz).backup_file("blob.bin").unwrap();
+        fs::write(root.join("blob.bin"), "new").unwrap();
+        let mgr = manager(root, 200);
+        mgr.backup_file("blob.bin").unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(mgr.cat_backup("blob.bin", Some(100), &mut out).unwrap(), 256);
+        assert_eq!(out, binary);
+        out.clear();
+        mgr.cat_backup("blob.bin", None, &mut out).unwrap();
+        assert_eq!(out, b"new");
+        out.clear();
+        mgr.cat_backup("blob.bin.100.bak", None, &mut out).unwrap();
+        assert_eq!(out, binary);
+        assert_eq!(mgr.cat_backup("blob.bin", Some(1), &mut out).unwrap_err().kind(), io::ErrorKind::NotFound);
+        // Nothing was restored.
+        assert_eq!(fs::read(root.join("blob.bin")).unwrap(), b"new");
+
+        assert_eq!(mgr.peek_backup("blob.bin", Some(100), 10).unwrap(), binary[..10]);
+        assert_eq!(mgr.peek_backup("blob.bin.100.bak", None, 1000).unwrap(), binary);
+        assert_eq!(mgr.peek_backup("blob.bin", None, 3).unwrap(), b"new");
+        assert_eq!(mgr.peek_backup("blob.bin", None, 0).unwrap(), b"");
+        assert_eq!(mgr.peek_backup("nothing.txt", None, 10).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn decoy_backup_is_not_selected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("data"), "real").unwrap();
+        let mgr = manager(root, 100);
+        let bak = mgr.backup_file("data").unwrap();
+        // A user file matching the pattern, with a newer timestamp than any real backup.
+        fs::write(root.join("data.999999999999.bak"), "decoy").unwrap();
+        assert_eq!(mgr.find_latest_backup("data").unwrap(), bak);
+
+        fs::write(root.join("data"), "changed").unwrap();
+        mgr.restore_file("data").unwrap();
+        assert_eq!(fs::read_to_string(root.join("data")).unwrap(), "real");
+    }
+
+    #[test]
+    fn backup_name_too_long_fails_before_copying() {
+        let tmp = tempfile::tempdir().unwrap();
+        let name = "n".repeat(250);
+        fs::write(tmp.path().join(&name), "x").unwrap();
+        let e = manager(tmp.path(), 100).backup_file(&name).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::NameTooLong { what: "backup name", .. })));
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn report_says_whether_reflinked() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("big.img"), "data").unwrap();
+        let mgr = BackupManager::new(Config {
+            root: Some(tmp.path().to_path_buf()),
+            reflink: false,
+            ..Config::default()
+        });
+        let report = mgr.backup_file_report("big.img").unwrap();
+        assert!(!report.reflinked);
+        assert_eq!(fs::read_to_string(&report.path).unwrap(), "data");
+
+        // With reflinks allowed the result is filesystem-dependent, but the content never is.
+        let report = manager(tmp.path(), 100).backup_file_report("big.img").unwrap();
+        assert!(cfg!(feature = "reflink") || !report.reflinked);
+        assert_eq!(fs::read_to_string(&report.path).unwrap(), "data");
+    }
+
+    #[cfg(all(target_os = "linux", feature = "xattrs"))]
+    #[test]
+    fn xattrs_survive_backup_and_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("tagged.txt");
+        fs::write(&file, "x").unwrap();
+        if xattr::set(&file, "user.colour", b"red").is_err() {
+            eprintln!("skipped: {} does not support user xattrs", tmp.path().display());
+            return;
+        }
+        let mgr = BackupManager::new(Config { preserve_xattrs: true, ..config(tmp.path(), 100) });
+        let report = mgr.backup_file_report("tagged.txt").unwrap();
+        assert_eq!(report.warnings, []);
+        let meta = read_backup_meta(&report.path).unwrap();
+        assert_eq!(meta.xattrs.get("user.colour").map(String::as_str), Some("726564"));
+        assert_eq!(xattr::get(&report.path, "user.colour").unwrap().as_deref(), Some(&b"red"[..]));
+
+        fs::remove_file(&file).unwrap();
+        let restored = mgr.restore_file_with("tagged.txt", &RestoreOptions::default()).unwrap();
+        assert_eq!(restored.warnings, []);
+        assert_eq!(xattr::get(&file, "user.colour").unwrap().as_deref(), Some(&b"red"[..]));
+    }
+
+    #[cfg(not(feature = "xattrs"))]
+    #[test]
+    fn xattrs_without_the_feature_only_warn() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("a.txt"), "x").unwrap();
+        let mgr = BackupManager::new(Config { preserve_xattrs: true, ..config(tmp.path(), 100) });
+        let report = mgr.backup_file_report("a.txt").unwrap();
+        assert!(matches!(&report.warnings[..], [Warning::Xattrs { .. }]), "{:?}", report.warnings);
+        assert!(read_backup_meta(&report.path).unwrap().xattrs.is_empty());
+    }
+
+    /// A manager on a fresh in-memory file system at "/work", with `notes.txt` in it.
+    fn mock_manager(now: u64) -> (BackupManager, vfs::MockFs) {
+        let mock = vfs::MockFs::new("/work");
+        mock.write(Path::new("/work/notes.txt"), b"hello").unwrap();
+        let config = Config { clock: std::sync::Arc::new(FixedClock(now)), ..Config::default() };
+        (BackupManager::with_fs(config, std::sync::Arc::new(mock.clone())), mock)
+    }
+
+    #[test]
+    fn backs_up_and_restores_in_memory() {
+        let (mgr, mock) = mock_manager(100);
+        let report = mgr.backup_file_report("notes.txt").unwrap();
+        assert_eq!((report.path.as_path(), report.bytes), (Path::new("/work/notes.txt.100.bak"), 5));
+        assert_eq!(report.warnings, []);
+        assert_eq!(mock.contents("/work/notes.bak").unwrap(), b"hello");
+        assert_eq!(mgr.list_backups("notes.txt").unwrap().len(), 1);
+        assert_eq!(mgr.read_log().unwrap()[0].action, "backup");
+
+        mock.set_mode(Path::new("/work/notes.txt"), 0o600).unwrap();
+        mgr.delete_file("notes.txt").unwrap();
+        let restored = mgr.restore_file_with("notes.txt", &RestoreOptions::default()).unwrap();
+        assert_eq!((restored.path.as_path(), restored.warnings), (Path::new("/work/notes.txt"), vec![]));
+        assert_eq!(mock.contents("/work/notes.txt").unwrap(), b"hello");
+        assert_eq!(mock.metadata(Path::new("/work/notes.txt")).unwrap().mode, 0o644);
+        let actions: Vec<String> = mgr.read_log().unwrap().into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, ["backup", "delete", "restore"]);
+        mgr.verify_log_chain().unwrap();
+    }
+
+    #[test]
+    fn finds_the_latest_backup_in_memory() {
+        let (mgr, mock) = mock_manager(100);
+        mgr.backup_file("notes.txt").unwrap();
+        let later = Config { clock: std::sync::Arc::new(FixedClock(200)), ..Config::default() };
+        BackupManager::with_fs(later, std::sync::Arc::new(mock.clone())).backup_file("notes.txt").unwrap();
+        mock.write(Path::new("/work/notes.txt.300.bak"), b"not ours").unwrap();
+        assert_eq!(mgr.find_latest_backup("notes.txt").unwrap(), Path::new("/work/notes.txt.200.bak"));
+
+        // Without timestamped backups, the plain copy is the latest; without that, there is none.
+        for ts in [100, 200] {
+            mock.remove_file(Path::new(&format!("/work/notes.txt.{ts}.bak"))).unwrap();
+        }
+        assert_eq!(mgr.find_latest_backup("notes.txt").unwrap(), Path::new("/work/notes.bak"));
+        mock.remove_file(Path::new("/work/notes.bak")).unwrap();
+        assert_eq!(mgr.find_latest_backup("notes.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn cats_backups_in_memory() {
+        let (mgr, mock) = mock_manager(100);
+        mgr.backup_file("notes.txt").unwrap();
+        mock.write(Path::new("/work/notes.txt"), b"changed").unwrap();
+        let mut out = Vec::new();
+        assert_eq!(mgr.cat_backup("notes.txt", None, &mut out).unwrap(), 5);
+        assert_eq!(out, b"hello");
+        out.clear();
+        mgr.cat_backup("notes.txt.100.bak", None, &mut out).unwrap();
+        assert_eq!(out, b"hello");
+        assert_eq!(mgr.cat_backup("notes.txt.200.bak", None, &mut out).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        mock.fail("open", "/work/notes.txt.100.bak", io::ErrorKind::PermissionDenied);
+        assert_eq!(mgr.cat_backup("notes.txt", None, &mut out).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        // The original is never read.
+        mock.clear_failures();
+        mock.fail("open", "/work/notes.txt", io::ErrorKind::PermissionDenied);
+        assert_eq!(mgr.peek_backup("notes.txt", Some(100), 3).unwrap(), b"hel");
+    }
+
+    #[test]
+    fn names_are_logged_and_recorded_in_canonical_form() {
+        let (mgr, mock) = mock_manager(100);
+        let (path, logical) = mgr.validate_path_parts(" ./docs//a.txt ").unwrap();
+        assert_eq!((path.as_path(), logical.as_str()), (Path::new("/work/docs/a.txt"), "docs/a.txt"));
+        assert!(mgr.validate_path_parts(" ../a.txt").is_err());
+
+        let report = mgr.backup_file_report("  ./notes.txt ").unwrap();
+        assert_eq!(read_meta(&mock, &report.path).unwrap().original, "notes.txt");
+        mgr.delete_file("./notes.txt ").unwrap();
+        assert_eq!(mgr.restore_file(" ./notes.txt ").unwrap(), Path::new("/work/notes.txt"));
+        let files: Vec<String> = mgr.read_log().unwrap().into_iter().map(|e| e.file).collect();
+        assert_eq!(files, ["notes.txt", "notes.txt", "notes.txt"]);
+    }
+
+    #[test]
+    fn failures_partway_through_are_errors_or_warnings() {
+        let denied = io::ErrorKind::PermissionDenied;
+        // The timestamped copy failing fails the backup, and nothing is logged.
+        let (mgr, mock) = mock_manager(100);
+        mock.fail("copy", "/work/notes.txt.100.bak", denied);
+        assert_eq!(mgr.backup_file("notes.txt").unwrap_err().kind(), denied);
+        assert!(mock.contents("/work/logfile.txt").is_none());
+
+        // Once it is made, the plain copy, its sidecar and the log only warn.
+        let (mgr, mock) = mock_manager(100);
+        mock.fail("copy", "/work/notes.bak", denied);
+        mock.fail("open_append", "/work/logfile.txt", denied);
+        let report = mgr.backup_file_report("notes.txt").unwrap();
+        assert!(matches!(&report.warnings[..], [Warning::PlainBackup { .. }, Warning::Log { .. }]), "{report:?}");
+        assert_eq!(mock.contents(&report.path).unwrap(), b"hello");
+        mock.clear_failures();
+        mock.fail("write", "/work/notes.bak.meta.json", denied);
+        let report = BackupManager::with_fs(
+            Config { clock: std::sync::Arc::new(FixedClock(200)), ..Config::default() },
+            std::sync::Arc::new(mock.clone()),
+        )
+        .backup_file_report("notes.txt")
+        .unwrap();
+        assert!(matches!(&report.warnings[..], [Warning::PlainBackup { .. }]), "{report:?}");
+
+        // A restore that can't set the permissions keeps the contents.
+        mock.clear_failures();
+        mock.fail("set_mode", "/work/notes.txt", denied);
+        mgr.delete_file("notes.txt").unwrap();
+        let restored = mgr.restore_file_with("notes.txt", &RestoreOptions::default()).unwrap();
+        assert!(matches!(&restored.warnings[..], [Warning::Metadata { .. }]), "{restored:?}");
+        assert_eq!(mock.contents("/work/notes.txt").unwrap(), b"hello");
+        mock.fail("remove_file", "/work/notes.txt", denied);
+        assert_eq!(mgr.delete_file("notes.txt").unwrap_err().kind(), denied);
+    }
+
+    #[test]
+    fn a_failed_restore_leaves_the_file_as_it_was() {
+        let (mgr, mock) = mock_manager(100);
+        mgr.backup_file("notes.txt").unwrap();
+        mock.write(Path::new("/work/notes.txt"), b"edited").unwrap();
+        mock.fail("write", "/work/notes.txt.*.tmp", io::ErrorKind::StorageFull);
+        let e = mgr.restore_file("notes.txt").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(mock.contents("/work/notes.txt").unwrap(), b"edited");
+        let temps = || mock.files_in("/work").into_iter().filter(|p| p.extension() == Some("tmp".as_ref())).count();
+        assert_eq!(temps(), 0);
+
+        // Nor does a failed swap touch it; once the rename goes through, it is restored.
+        mock.clear_failures();
+        mock.fail("rename", "/work/notes.txt", io::ErrorKind::PermissionDenied);
+        assert!(mgr.restore_file("notes.txt").is_err());
+        assert_eq!(mock.contents("/work/notes.txt").unwrap(), b"edited");
+        mock.clear_failures();
+        mgr.restore_file("notes.txt").unwrap();
+        assert_eq!(mock.contents("/work/notes.txt").unwrap(), b"hello");
+        assert_eq!(temps(), 0);
+    }
+
+    #[test]
+    fn a_conflict_strategy_decides_what_happens_to_the_file_there() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let file = root.join("notes.txt");
+        fs::write(&file, "hello").unwrap();
+        let mgr = manager(root, 100);
+        mgr.backup_file("notes.txt").unwrap();
+        fs::write(&file, "edited").unwrap();
+        let with = |strategy| mgr.restore_file_with("notes.txt", &RestoreOptions::new().on_conflict(strategy));
+
+        let report = with(ConflictStrategy::Skip).unwrap();
+        assert_eq!((report.outcome, report.moved), (RestoreOutcome::Skipped, None));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "edited");
+        assert_eq!(mgr.read_log().unwrap().last().unwrap().result, "skipped");
+        let e = with(ConflictStrategy::Fail).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::DestinationExists { .. })), "{e}");
+        assert_eq!(error_code(&e), "destination_exists");
+
+        let report = with(ConflictStrategy::RenameExisting).unwrap();
+        let aside = root.join("notes.txt.pre-restore.100");
+        assert_eq!((report.outcome, report.moved.as_ref()), (RestoreOutcome::RenamedExisting, Some(&aside)));
+        assert_eq!(fs::read_to_string(&aside).unwrap(), "edited");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "hello");
+        // Now that it matches the backup, failing has nothing to refuse.
+        assert_eq!(with(ConflictStrategy::Fail).unwrap().outcome, RestoreOutcome::Unchanged);
+        let last = mgr.read_log().unwrap().pop().unwrap();
+        assert_eq!(last.result, "unchanged");
+        assert!(!last.failed());
+        assert_eq!(with(ConflictStrategy::Overwrite).unwrap().outcome, RestoreOutcome::Overwritten);
+    }
+
+    #[test]
+    fn all_or_nothing_backups_roll_back_when_the_plain_copy_fails() {
+        let denied = io::ErrorKind::PermissionDenied;
+        let opts = BackupOptions::new().best_effort(false);
+        let only_original = [PathBuf::from("/work/notes.txt")];
+        let (mgr, mock) = mock_manager(100);
+        mock.fail("write", "/work/notes.bak", denied);
+        assert_eq!(mgr.backup_file_with("notes.txt", &opts).unwrap_err().kind(), denied);
+        assert_eq!(mock.files_in("/work"), only_original);
+        mock.clear_failures();
+        mock.fail("write", "/work/notes.bak.meta.json", denied);
+        assert_eq!(mgr.backup_file_with("notes.txt", &opts).unwrap_err().kind(), denied);
+        assert_eq!(mock.files_in("/work"), only_original);
+
+        // A hand-made "notes.bak" in the way is left alone, and so is nothing else.
+        mock.clear_failures();
+        mock.write(Path::new("/work/notes.bak"), b"mine").unwrap();
+        assert!(mgr.backup_file_with("notes.txt", &opts).is_err());
+        assert_eq!(mock.files_in("/work").len(), 2);
+        assert_eq!(mock.contents("/work/notes.bak").unwrap(), b"mine");
+
+        mock.remove_file(Path::new("/work/notes.bak")).unwrap();
+        let report = mgr.backup_file_with("notes.txt", &opts).unwrap();
+        assert_eq!(report.plain.as_deref(), Some(Path::new("/work/notes.bak")));
+        assert_eq!(mgr.read_log().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn copies_are_hashed_as_they_are_written() {
+        let (mgr, mock) = mock_manager(100);
+        let report = mgr.backup_file_report("notes.txt").unwrap();
+        let expected = digest::read_digest(&b"hello"[..], DigestAlgo::Sha256).unwrap();
+        assert_eq!(read_meta(&mock, &report.path).unwrap().digest.as_deref(), Some(expected.as_str()));
+        assert_eq!(read_meta(&mock, Path::new("/work/notes.bak")).unwrap().digest, Some(expected.clone()));
+
+        // Restoring checks what it copies against the sidecar, and reads the copy back.
+        let restored = mgr.restore_file_with("notes.txt.100.bak", &RestoreOptions::default()).unwrap();
+        assert_eq!(restored.warnings, []);
+        assert_eq!(mgr.read_log().unwrap().pop().unwrap().result, "ok (verified)");
+        mock.write(&report.path, b"jello").unwrap();
+        let e = mgr.restore_file_with("notes.txt.100.bak", &RestoreOptions::default()).unwrap_err();
+        let Some(BackupError::ChecksumMismatch { expected: e, .. }) = BackupError::from_io(&e) else { panic!("{e}") };
+        assert_eq!(*e, expected);
+        assert_eq!(mock.contents("/work/notes.txt").unwrap(), b"hello");
+        assert!(mock.files_in("/work").iter().all(|p| p.extension() != Some("tmp".as_ref())));
+        assert_eq!(mgr.read_log().unwrap().pop().unwrap().result, "failed (checksum mismatch)");
+        // Unless a mismatch is allowed, with a warning.
+        let opts = RestoreOptions::new().allow_mismatch(true);
+        let restored = mgr.restore_file_with("notes.txt.100.bak", &opts).unwrap();
+        assert!(
+            matches!(&restored.warnings[..], [Warning::DigestMismatch { expected: e, .. }] if *e == expected),
+            "{restored:?}"
+        );
+        assert_eq!(mock.contents("/work/notes.txt").unwrap(), b"jello");
+
+        // Verifying a write re-reads the backup only, and a bad one is removed.
+        let config = Config { verify_writes: true, ..mgr.config().clone() };
+        let mgr = BackupManager::with_fs(config, std::sync::Arc::new(mock.clone()));
+        let jello = digest::read_digest(&b"jello"[..], DigestAlgo::Sha256).unwrap();
+        mgr.verify_written("backup", &report.path, &jello).unwrap();
+        let e = mgr.verify_written("backup", &report.path, &expected).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::Corrupt { .. })), "{e}");
+        assert!(mock.contents(&report.path).is_none());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "acls"))]
+    #[test]
+    fn acls_survive_backup_and_restore() {
+        use posix_acl::{PosixACL, Qualifier, ACL_READ, ACL_WRITE};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("shared.txt");
+        fs::write(&file, "x").unwrap();
+        let mut acl = PosixACL::read_acl(&file).unwrap();
+        acl.set(Qualifier::User(4242), ACL_READ | ACL_WRITE);
+        if acl.write_acl(&file).is_err() {
+            eprintln!("skipped: {} does not support ACLs", tmp.path().display());
+            return;
+        }
+        let mgr = BackupManager::new(Config { preserve_acls: true, ..config(tmp.path(), 100) });
+        let report = mgr.backup_file_report("shared.txt").unwrap();
+        assert_eq!(report.warnings, []);
+        let meta = read_backup_meta(&report.path).unwrap();
+        assert!(meta.acl.contains(&"user:4242:rw-".to_string()), "{:?}", meta.acl);
+        // The backup itself grants the named user nothing.
+        assert_eq!(PosixACL::read_acl(&report.path).unwrap().get(Qualifier::User(4242)), None);
+
+        fs::remove_file(&file).unwrap();
+        let restored = mgr.restore_file_with("shared.txt", &RestoreOptions::default()).unwrap();
+        assert_eq!(restored.warnings, []);
+        assert_eq!(PosixACL::read_acl(&file).unwrap().get(Qualifier::User(4242)), Some(ACL_READ | ACL_WRITE));
+    }
+
+    #[cfg(not(feature = "acls"))]
+    #[test]
+    fn acls_without_the_feature_only_warn() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("a.txt"), "x").unwrap();
+        let mgr = BackupManager::new(Config { preserve_acls: true, ..config(tmp.path(), 100) });
+        let report = mgr.backup_file_report("a.txt").unwrap();
+        assert!(matches!(&report.warnings[..], [Warning::Acl { .. }]), "{:?}", report.warnings);
+        assert!(read_backup_meta(&report.path).unwrap().acl.is_empty());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn reserved_names_warn_or_reject_off_windows() {
+        use std::sync::{Arc, Mutex};
+
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("aux.txt"), "x").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = Arc::clone(&seen);
+        let mgr = BackupManager::new(Config {
+            root: Some(tmp.path().to_path_buf()),
+            on_event: Some(Arc::new(move |e: &Event| seen2.lock().unwrap().push(e.clone()))),
+            ..Config::default()
+        });
+        mgr.backup_file("aux.txt").unwrap();
+        assert_eq!(
+            seen.lock().unwrap().as_slice(),
+            [Event::ReservedName { name: "aux.txt".into(), device: "AUX" }]
+        );
+
+        let strict = BackupManager::new(Config { reject_reserved_names: true, ..mgr.config().clone() });
+        let e = strict.backup_file("aux.txt").unwrap_err();
+        assert_eq!(e.to_string(), "invalid file name \"aux.txt\": reserved Windows device name");
+    }
+
+    #[test]
+    fn outcome_tells_created_from_unchanged_and_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("a.txt"), "one").unwrap();
+        let at = |ts| manager(tmp.path(), ts);
+        let opts = BackupOptions::new().if_changed(true).skip_missing(true);
+
+        let first = at(100).backup_file_with("a.txt", &opts).unwrap();
+        assert_eq!(first.outcome, BackupOutcome::Created);
+        let again = at(200).backup_file_with("a.txt", &opts).unwrap();
+        assert_eq!((again.outcome, &again.path, again.bytes), (BackupOutcome::Unchanged, &first.path, 3));
+        assert_eq!(at(200).list_backups("a.txt").unwrap().len(), 1);
+
+        fs::write(tmp.path().join("a.txt"), "two!").unwrap();
+        let changed = at(300).backup_file_with("a.txt", &opts).unwrap();
+        assert_eq!(changed.outcome, BackupOutcome::Created);
+        assert_ne!(changed.path, first.path);
+
+        let gone = at(400).backup_file_with("gone.txt", &opts).unwrap();
+        assert_eq!((gone.outcome, gone.path), (BackupOutcome::Skipped, tmp.path().join("gone.txt")));
+        // Without the options every call makes a backup, and a missing file fails.
+        assert_eq!(at(500).backup_file_report("a.txt").unwrap().outcome, BackupOutcome::Created);
+        assert!(at(600).backup_file("gone.txt").is_err());
+    }
+
+    #[test]
+    fn invalid_name_is_quoted() {
+        let msg = error_chain(&validate_path("../etc/passwd").unwrap_err());
+        assert_eq!(msg, "invalid file name \"../etc/passwd\": parent traversal not allowed");
+    }
 }
@@ -0,0 +1,1086 @@
+//! JSONL action log (`<root>/logfile.txt`): writing and reading.
+//!
+//! Each entry carries `prev_hash`, the SHA-256 of the line before it (of
+//! [`GENESIS_HASH`] for the first), so editing or deleting a line breaks the
+//! chain at the next one; see [`BackupManager::verify_log_chain`]. Each line
+//! also says which version of the entry format wrote it; see [`LogEntry`].
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::error::{error_chain, BackupError, Context};
+use crate::event::Event;
+use crate::naming::display_name;
+use crate::rotate::{read_history, rotate};
+use crate::tidy::TIDY_DIR;
+use crate::vfs::{FileSystem, RealFs};
+use crate::BackupManager;
+
+/// Log file name inside the root directory.
+pub(crate) const LOG_FILE: &str = "logfile.txt";
+
+/// `prev_hash` of the first entry of a log.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Entry format written by this version of the tool; see [`LogEntry`].
+pub const LOG_VERSION: u32 = 2;
+
+/// One line of the action log, in the current shape whatever version wrote it.
+///
+/// Entry formats, by the line's `"v"`:
+/// - 1 (no `"v"`): `ts`, `user`, `action`, `file`, `result`; lines written
+///   since chaining began also have `prev_hash`.
+/// - 2: `"v": 2` first, then the fields of 1, with `prev_hash` always there;
+///   `user` may be left out (see [`LogUser::Omitted`]) and reads as empty.
+///
+/// Reading upgrades older lines in memory (a version 1 line without a hash gets
+/// `prev_hash: None`). Fields added by later versions are ignored, so lines
+/// from a newer tool still read; a line missing a field its version requires
+/// is not an entry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawEntry")]
+pub struct LogEntry {
+    /// Format version of the line the entry was read from, or is written as.
+    pub v: u32,
+    pub ts: u64,
+    /// Who did it, as [`LogUser`] had it recorded: a name, a pseudonym, or empty.
+    pub user: String,
+    pub action: String,
+    pub file: String,
+    pub result: String,
+    /// Hex SHA-256 of the previous line; `None` in entries written before chaining.
+    pub prev_hash: Option<String>,
+}
+
+impl LogEntry {
+    /// Whether the action failed: its result is neither "ok", "auto",
+    /// "skipped" nor "unchanged", with or without details after them.
+    pub fn failed(&self) -> bool {
+        let word = self.result.split([' ', '(', ':']).next().unwrap_or_default();
+        !matches!(word, "ok" | "auto" | "skipped" | "unchanged")
+    }
+}
+
+/// How the log records who did something (`Config::log_user`). Readers take
+/// all three alike: [`LogEntry::user`] is the name, the pseudonym or empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogUser {
+    /// The name from [`BackupManager::actor`].
+    #[default]
+    Full,
+    /// "anon-" and 16 hex digits of the SHA-256 of the name with a salt, so
+    /// one user's entries still go together without naming them. The salt is
+    /// made once, in ".safe_backup/log_salt" under the root; keep it private.
+    Hashed,
+    /// No `user` field at all.
+    Omitted,
+}
+
+impl FromStr for LogUser {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "hashed" | "hash" => Ok(Self::Hashed),
+            "omitted" | "omit" | "none" => Ok(Self::Omitted),
+            other => Err(format!("unknown log user form {other:?} (expected full, hashed or omitted)")),
+        }
+    }
+}
+
+impl fmt::Display for LogUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Full => "full",
+            Self::Hashed => "hashed",
+            Self::Omitted => "omitted",
+        })
+    }
+}
+
+/// Which entries [`BackupManager::read_log_filtered`] returns: those meeting
+/// every condition set, oldest first. `Default` (= `new()`) keeps them all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    pub(crate) action: Option<String>,
+    pub(crate) file: Option<String>,
+    pub(crate) since: Option<u64>,
+    pub(crate) last: Option<usize>,
+    pub(crate) failed_only: bool,
+}
+
+impl LogFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only entries of `action`, e.g. "backup".
+    pub fn action(mut self, action: impl Into<String>) -> Self {
+        self.action = Some(action.into());
+        self
+    }
+
+    /// Only entries for `file`, as logged: the name relative to the root.
+    pub fn file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// Only entries written at or after `ts` (Unix seconds).
+    pub fn since(mut self, ts: u64) -> Self {
+        self.since = Some(ts);
+        self
+    }
+
+    /// Only the last `n` of the entries the other conditions leave.
+    pub fn last(mut self, n: usize) -> Self {
+        self.last = Some(n);
+        self
+    }
+
+    /// Only entries whose action failed; see [`LogEntry::failed`].
+    pub fn failed_only(mut self, on: bool) -> Self {
+        self.failed_only = on;
+        self
+    }
+
+    /// Whether `entry` meets every condition but `last`.
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        self.action.as_ref().is_none_or(|a| entry.action == *a)
+            && self.file.as_ref().is_none_or(|f| entry.file == *f)
+            && self.since.is_none_or(|ts| entry.ts >= ts)
+            && (!self.failed_only || entry.failed())
+    }
+
+    /// Whether entries written before all of `entries` could still be kept:
+    /// `since` isn't already passed and `last` isn't already filled.
+    pub(crate) fn reaches_before(&self, entries: &[LogEntry]) -> bool {
+        let passed = self.since.is_some_and(|ts| entries.first().is_some_and(|e| e.ts < ts));
+        let filled = self.last.is_some_and(|n| entries.iter().filter(|e| self.matches(e)).count() >= n);
+        !passed && !filled
+    }
+
+    /// The entries of `entries` this filter keeps, in their order.
+    pub fn apply(&self, entries: Vec<LogEntry>) -> Vec<LogEntry> {
+        let mut kept: Vec<LogEntry> = entries.into_iter().filter(|e| self.matches(e)).collect();
+        if let Some(n) = self.last {
+            kept.drain(..kept.len().saturating_sub(n));
+        }
+        kept
+    }
+}
+
+/// A line as written, before checking it against its version.
+#[derive(Deserialize)]
+struct RawEntry {
+    v: Option<u32>,
+    ts: u64,
+    #[serde(default)]
+    user: Option<String>,
+    action: String,
+    file: String,
+    result: String,
+    #[serde(default)]
+    prev_hash: Option<String>,
+}
+
+impl TryFrom<RawEntry> for LogEntry {
+    type Error = String;
+
+    fn try_from(raw: RawEntry) -> Result<Self, String> {
+        let v = raw.v.unwrap_or(1);
+        match v {
+            0 => return Err("log entry version 0".into()),
+            1 if raw.user.is_none() => return Err("version 1 log entry without user".into()),
+            1 => {}
+            _ if raw.prev_hash.is_none() => return Err(format!("version {v} log entry without prev_hash")),
+            _ => {}
+        }
+        let RawEntry { ts, user, action, file, result, prev_hash, .. } = raw;
+        Ok(LogEntry { v, ts, user: user.unwrap_or_default(), action, file, result, prev_hash })
+    }
+}
+
+/// How [`parse_log_line`] read one line of the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedLine {
+    /// A valid entry of its version.
+    Ok(LogEntry),
+    /// An entry salvaged from a line that isn't a valid one, with what was wrong with it.
+    Recoverable(LogEntry, Vec<String>),
+    /// A line with no entry in it.
+    Skip(SkipReason),
+}
+
+/// Why a line of the log holds no entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SkipReason {
+    /// Empty or only whitespace.
+    Blank,
+    /// No JSON object in it, e.g. a write cut short or a name the first
+    /// writer didn't escape.
+    NotJson,
+    /// A JSON object without this field, or with one of the wrong type.
+    MissingField(&'static str),
+    /// `"v"` is 0 or not a version number.
+    BadVersion,
+    /// A version 2 or later entry without `prev_hash`.
+    Unchained,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blank => f.write_str("blank line"),
+            Self::NotJson => f.write_str("not JSON"),
+            Self::MissingField(field) => write!(f, "no {field:?}"),
+            Self::BadVersion => f.write_str("bad version"),
+            Self::Unchained => f.write_str("no prev_hash"),
+        }
+    }
+}
+
+/// Read one line of the log, salvaging what it can from lines that are not
+/// valid entries. Logs written by earlier versions, and lines torn by two
+/// writers appending at once, get:
+/// - `ts` given as a string or a fraction read as whole seconds;
+/// - a missing or non-string `user` or `result` read as empty;
+/// - a line holding text around an entry (the rest of another write) read as
+///   the first whole entry in it.
+///
+/// `action` and `file` can't be made up, so a line without them is skipped.
+/// Every salvaged entry is `Recoverable`, listing what was wrong.
+pub fn parse_log_line(line: &str) -> ParsedLine {
+    if line.trim().is_empty() {
+        return ParsedLine::Skip(SkipReason::Blank);
+    }
+    if let Ok(entry) = serde_json::from_str(line) {
+        return ParsedLine::Ok(entry);
+    }
+    let mut reason = SkipReason::NotJson;
+    for (start, _) in line.match_indices('{') {
+        let mut objects = serde_json::Deserializer::from_str(&line[start..]).into_iter::<Map<String, Value>>();
+        let Some(Ok(object)) = objects.next() else { continue };
+        let end = start + objects.byte_offset();
+        match salvage(&object) {
+            Ok((entry, mut issues)) => {
+                if start > 0 {
+                    issues.insert(0, format!("{start} bytes of another write before the entry"));
+                }
+                if !line[end..].trim().is_empty() {
+                    issues.push(format!("{} bytes of another write after the entry", line[end..].trim_end().len()));
+                }
+                return ParsedLine::Recoverable(entry, issues);
+            }
+            Err(r) if reason == SkipReason::NotJson => reason = r,
+            Err(_) => {}
+        }
+    }
+    ParsedLine::Skip(reason)
+}
+
+/// An entry from a JSON object that didn't deserialize as one, with what was
+/// made up or converted; see [`parse_log_line`].
+fn salvage(object: &Map<String, Value>) -> Result<(LogEntry, Vec<String>), SkipReason> {
+    let mut issues = Vec::new();
+    let v = match object.get("v") {
+        None => 1,
+        Some(v) => v.as_u64().and_then(|v| u32::try_from(v).ok()).filter(|v| *v > 0).ok_or(SkipReason::BadVersion)?,
+    };
+    let ts = match object.get("ts") {
+        Some(Value::Number(n)) if n.is_u64() => n.as_u64(),
+        Some(Value::Number(n)) => n.as_f64().filter(|f| *f >= 0.0).map(|f| {
+            issues.push(format!("\"ts\" {n} is not whole seconds"));
+            f as u64
+        }),
+        Some(Value::String(s)) => s.trim().parse().ok().inspect(|_| issues.push(format!("\"ts\" {s:?} is a string"))),
+        _ => None,
+    }
+    .ok_or(SkipReason::MissingField("ts"))?;
+    let required = |field: &'static str| match object.get(field) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        _ => Err(SkipReason::MissingField(field)),
+    };
+    let (action, file) = (required("action")?, required("file")?);
+    let mut optional = |field: &str| match object.get(field) {
+        Some(Value::String(s)) => s.clone(),
+        _ => {
+            issues.push(format!("no {field:?}"));
+            String::new()
+        }
+    };
+    let (user, result) = (optional("user"), optional("result"));
+    let prev_hash = object.get("prev_hash").and_then(Value::as_str).map(str::to_string);
+    if v > 1 && prev_hash.is_none() {
+        return Err(SkipReason::Unchained);
+    }
+    Ok((LogEntry { v, ts, user, action, file, result, prev_hash }, issues))
+}
+
+/// A line of the log that isn't a valid entry, e.g. from a writer that didn't
+/// escape its JSON or from a write cut short.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptLine {
+    /// 1-based line number.
+    pub line: usize,
+    pub text: String,
+    /// Why no entry could be read from it.
+    pub reason: SkipReason,
+}
+
+/// A line of the log an entry was salvaged from; see [`parse_log_line`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredLine {
+    /// 1-based line number.
+    pub line: usize,
+    /// What was wrong with it.
+    pub issues: Vec<String>,
+}
+
+/// Every entry of a log and every line that isn't one; see [`BackupManager::scan_log`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogScan {
+    /// Valid and salvaged entries, in the order of their lines.
+    pub entries: Vec<LogEntry>,
+    /// Lines entries were salvaged from.
+    pub recovered: Vec<RecoveredLine>,
+    /// Lines with no entry in them.
+    pub corrupt: Vec<CorruptLine>,
+}
+
+impl LogScan {
+    /// How many lines were skipped for each reason, most common first.
+    pub fn skipped(&self) -> Vec<(SkipReason, usize)> {
+        let mut counts = BTreeMap::new();
+        for c in &self.corrupt {
+            *counts.entry(c.reason).or_insert(0) += 1;
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by_key(|&(_, n)| Reverse(n));
+        counts
+    }
+}
+
+/// Outcome of [`BackupManager::repair_log`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogRepair {
+    /// Entries left in the log.
+    pub kept: usize,
+    /// Lines removed because they weren't entries.
+    pub dropped: usize,
+    /// Copy of the log as it was before the repair; `None` if nothing needed repairing.
+    pub backup: Option<PathBuf>,
+}
+
+/// Check the hash chain of `<root>/logfile.txt`; see [`BackupManager::verify_log_chain`].
+pub fn verify_log_chain(root: impl AsRef<Path>) -> io::Result<usize> {
+    let path = root.as_ref().join(LOG_FILE);
+    verify_chain(&path, &read_log_text(&RealFs, &path)?)
+}
+
+/// Read all entries of `<root>/logfile.txt` and its rotated segments, oldest
+/// first, with those salvaged from damaged lines (see [`parse_log_line`]); a
+/// missing log is empty. Lines with no entry are skipped;
+/// [`BackupManager::scan_log`] reports them. So is a damaged archive; a
+/// manager reports it as `Event::LogArchiveSkipped`.
+pub fn read_log(root: impl AsRef<Path>) -> io::Result<Vec<LogEntry>> {
+    read_history(&RealFs, &root.as_ref().join(LOG_FILE), &LogFilter::new(), &mut |_, _| {})
+}
+
+pub(crate) fn read_log_file(fs: &dyn FileSystem, path: &Path) -> io::Result<Vec<LogEntry>> {
+    Ok(scan(&read_log_text(fs, path)?).entries)
+}
+
+pub(crate) fn scan(text: &str) -> LogScan {
+    let mut scan = LogScan::default();
+    for (i, line) in text.lines().enumerate() {
+        match parse_log_line(line) {
+            ParsedLine::Ok(entry) => scan.entries.push(entry),
+            ParsedLine::Recoverable(entry, issues) => {
+                scan.entries.push(entry);
+                scan.recovered.push(RecoveredLine { line: i + 1, issues });
+            }
+            ParsedLine::Skip(reason) => scan.corrupt.push(CorruptLine { line: i + 1, text: line.to_string(), reason }),
+        }
+    }
+    scan
+}
+
+/// The lines of `text` that are entries, and the entries salvaged from
+/// others written out anew, chained anew from the first chained one on
+/// wherever a dropped or rewritten line broke the chain; with the number dropped.
+fn repaired(text: &str) -> (Vec<String>, usize) {
+    let mut out: Vec<String> = Vec::new();
+    let mut dropped = 0;
+    let mut chained = false;
+    for line in text.lines() {
+        let (mut entry, valid) = match parse_log_line(line) {
+            ParsedLine::Ok(entry) => (entry, true),
+            ParsedLine::Recoverable(entry, _) => (entry, false),
+            ParsedLine::Skip(_) => {
+                dropped += 1;
+                continue;
+            }
+        };
+        chained |= entry.prev_hash.is_some();
+        let prev = out.last().map_or_else(|| GENESIS_HASH.to_string(), |l| line_hash(l));
+        if !chained && !valid {
+            out.push(format_entry(&entry));
+        } else if !chained || (valid && entry.prev_hash.as_deref() == Some(prev.as_str())) {
+            out.push(line.to_string());
+        } else {
+            entry.prev_hash = Some(prev);
+            out.push(format_entry(&entry));
+        }
+    }
+    (out, dropped)
+}
+
+/// Put the entries of the log `older` before those of `newer`, chained anew
+/// where they meet, and remove `older`. Lines that aren't entries are dropped,
+/// as by [`BackupManager::repair_log`]. Both logs are locked as for an append
+/// throughout, so an entry being logged waits rather than being lost.
+pub(crate) fn merge_logs(fs: &dyn FileSystem, op: &'static str, older: &Path, newer: &Path) -> io::Result<()> {
+    let (mut text, _older_lock) = fs.open_append_locked(older).at(op, "cannot open log", older)?;
+    let (newer_text, _newer_lock) = fs.open_append_locked(newer).at(op, "cannot open log", newer)?;
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(&newer_text);
+    let body: String = repaired(&text).0.iter().map(|l| format!("{l}\n")).collect();
+    let tmp = crate::vfs::unique_temp(newer);
+    fs.write(&tmp, body.as_bytes())
+        .at(op, "cannot write", &tmp)
+        .and_then(|_| fs.rename(&tmp, newer).copying(op, &tmp, newer))
+        .inspect_err(|_| {
+            let _ = fs.remove_file(&tmp);
+        })?;
+    fs.remove_file(older).at(op, "cannot remove", older)
+}
+
+/// The whole log; a missing log is empty.
+fn read_log_text(fs: &dyn FileSystem, path: &Path) -> io::Result<String> {
+    match fs.read_to_string(path) {
+        Ok(t) => Ok(t),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).at("log", "cannot read log", path),
+    }
+}
+
+/// Hex SHA-256 of one log line, without its line ending.
+pub(crate) fn line_hash(line: &str) -> String {
+    Sha256::digest(line.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Check the hash chain of the log `text`; returns the number of chained entries.
+/// Unchained lines at the start (from before chaining) are accepted as they are;
+/// after the first chained entry, every line must be a chained entry whose
+/// `prev_hash` matches the line before it.
+fn verify_chain(path: &Path, text: &str) -> io::Result<usize> {
+    let mut prev: Option<&str> = None;
+    let mut chained = 0;
+    for (i, line) in text.lines().enumerate() {
+        let entry = serde_json::from_str::<LogEntry>(line).ok();
+        let tampered = |reason: &'static str| -> io::Error {
+            BackupError::LogTampered { path: path.to_path_buf(), line: i + 1, reason }.into()
+        };
+        match entry.and_then(|e| e.prev_hash) {
+            Some(hash) => {
+                let expected = prev.map_or_else(|| GENESIS_HASH.to_string(), line_hash);
+                if hash != expected {
+                    return Err(tampered("previous entry was altered or removed"));
+                }
+                chained += 1;
+            }
+            None if chained > 0 => return Err(tampered("entry is not chained")),
+            None => {}
+        }
+        prev = Some(line);
+    }
+    Ok(chained)
+}
+
+impl BackupManager {
+    /// The log file: `config.log_file` under `root`, or `<root>/logfile.txt`.
+    pub(crate) fn log_path(&self, root: &Path) -> PathBuf {
+        let default = if self.config.tidy { crate::tidy::TIDY_LOG } else { LOG_FILE };
+        root.join(self.config.log_file.as_deref().unwrap_or(Path::new(default)))
+    }
+
+    /// Entries of this manager's log, archived segments included; see [`read_log`].
+    pub fn read_log(&self) -> io::Result<Vec<LogEntry>> {
+        self.read_log_filtered(&LogFilter::new())
+    }
+
+    /// Entries of this manager's log that `filter` keeps; see [`read_log`](Self::read_log).
+    /// Archived segments are only opened while `since` or `last` could reach into them;
+    /// a damaged one is skipped with an `Event::LogArchiveSkipped`.
+    pub fn read_log_filtered(&self, filter: &LogFilter) -> io::Result<Vec<LogEntry>> {
+        self.flush_log()?;
+        read_history(&*self.fs, &self.log_path(&self.root()?), filter, &mut |path, e| {
+            self.emit(Event::LogArchiveSkipped { path: path.to_path_buf(), error: error_chain(e) })
+        })
+    }
+
+    /// Entries of this manager's live log together with the lines that aren't
+    /// entries, which [`read_log`](Self::read_log) skips silently.
+    pub fn scan_log(&self) -> io::Result<LogScan> {
+        self.flush_log()?;
+        Ok(scan(&read_log_text(&*self.fs, &self.log_path(&self.root()?))?))
+    }
+
+    /// Rewrite the log keeping only the lines that are entries, after copying
+    /// it to `<log>.<ts>.orig`. Entries after a dropped line are chained anew,
+    /// so [`verify_log_chain`](Self::verify_log_chain) passes again; the copy
+    /// keeps the evidence. The repair itself is logged. A log with nothing to
+    /// drop or re-chain is left alone. Refused with `Config::log_audit`. The
+    /// log is locked as for an append from reading it until it is replaced,
+    /// so an entry being logged meanwhile waits rather than being lost.
+    pub fn repair_log(&self) -> io::Result<LogRepair> {
+        if self.config.log_audit {
+            let reason = "log: repair is off while the log is audited (Config::log_audit)";
+            return Err(io::Error::new(io::ErrorKind::Unsupported, reason));
+        }
+        self.flush_log()?;
+        let root = self.root()?;
+        let path = self.log_path(&root);
+        if !self.fs.exists(&path) {
+            return Ok(LogRepair { kept: 0, dropped: 0, backup: None });
+        }
+        let (text, lock) = self.fs.open_append_locked(&path).at("repair_log", "cannot open log", &path)?;
+        let (lines, dropped) = repaired(&text);
+        let mut report = LogRepair { kept: lines.len(), dropped, backup: None };
+        if text.lines().eq(lines.iter().map(String::as_str)) {
+            return Ok(report);
+        }
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".{}.orig", self.now()));
+        let backup = PathBuf::from(backup);
+        self.fs.copy(&path, &backup).copying("repair_log", &path, &backup)?;
+        let tmp = crate::vfs::unique_temp(&path);
+        let body: String = lines.iter().map(|l| format!("{l}\n")).collect();
+        self.fs
+            .write(&tmp, body.as_bytes())
+            .at("repair_log", "cannot write", &tmp)
+            .and_then(|_| self.fs.rename(&tmp, &path).copying("repair_log", &tmp, &path))
+            .inspect_err(|_| {
+                let _ = self.fs.remove_file(&tmp);
+            })?;
+        drop(lock);
+        report.backup = Some(backup);
+        let name = Path::new(path.file_name().unwrap_or(path.as_os_str()));
+        self.log_action(&root, "repair_log", name, &format!("ok ({} kept, {dropped} dropped)", report.kept))?;
+        Ok(report)
+    }
+
+    /// Name recorded in the log: `config.actor`, else `$SAFE_BACKUP_USER`, else the OS user.
+    pub fn actor(&self) -> String {
+        resolve_actor(self.config.actor.as_deref(), std::env::var("SAFE_BACKUP_USER").ok().as_deref())
+    }
+
+    /// What goes in the `user` field of entries, per `Config::log_user`; empty for none.
+    pub(crate) fn logged_user(&self, root: &Path) -> io::Result<String> {
+        Ok(match self.config.log_user {
+            LogUser::Full => self.actor(),
+            LogUser::Hashed => pseudonym(&self.log_salt(root)?, &self.actor()),
+            LogUser::Omitted => String::new(),
+        })
+    }
+
+    /// The salt in "<root>/.safe_backup/log_salt", made from 32 random bytes the
+    /// first time. Written in full beside it and hard-linked into place, which
+    /// fails if one is there already, so processes starting at once agree on
+    /// one and none reads it half written.
+    fn log_salt(&self, root: &Path) -> io::Result<Vec<u8>> {
+        let dir = root.join(TIDY_DIR);
+        let path = dir.join("log_salt");
+        let read = || -> io::Result<Vec<u8>> {
+            let text = fs::read_to_string(&path).at("log", "cannot read log salt", &path)?;
+            match text.trim() {
+                hex if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(hex.as_bytes().to_vec()),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "not 64 hex digits")).at("log", "bad log salt", &path),
+            }
+        };
+        if path.exists() {
+            return read();
+        }
+        self.create_dirs("log", &dir, "cannot create directory")?;
+        let mut salt = [0u8; 32];
+        getrandom::fill(&mut salt).map_err(io::Error::other).at("log", "cannot make log salt", &path)?;
+        let hex: String = salt.iter().map(|b| format!("{b:02x}")).collect();
+        let tmp = crate::vfs::unique_temp(&path);
+        let linked = crate::vfs::private_options()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)
+            .and_then(|mut f| f.write_all(format!("{hex}\n").as_bytes()))
+            .and_then(|_| fs::hard_link(&tmp, &path));
+        let _ = fs::remove_file(&tmp);
+        match linked {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e).at("log", "cannot create log salt", &path),
+            _ => read(),
+        }
+    }
+
+    /// Check that no entry of this manager's live log was altered or removed
+    /// since it was written; a rotated-out segment is chained on its own and
+    /// not checked. Returns the number of chained entries; a broken link is
+    /// `BackupError::LogTampered` naming the first bad line. Cutting entries off
+    /// the end of the log leaves a valid chain; it is only detected, as
+    /// `BackupError::LogTruncated`, with `Config::log_audit`.
+    pub fn verify_log_chain(&self) -> io::Result<usize> {
+        self.flush_log()?;
+        let root = self.root()?;
+        let path = self.log_path(&root);
+        let text = read_log_text(&*self.fs, &path)?;
+        let chained = verify_chain(&path, &text)?;
+        self.check_audit(&root, &path, &text)?;
+        Ok(chained)
+    }
+
+    /// Minimal JSONL logger in <root>/logfile.txt (see [`log_path`](Self::log_path))
+    /// `file` is recorded via `display_name`, so non-UTF-8 bytes appear as `\xNN`.
+    /// Each line is chained to the one before it through `prev_hash`. The log
+    /// is locked from reading that line until the new one is written in one
+    /// piece, so processes logging at once neither tear lines nor fork the chain.
+    /// A log grown past `Config::log_max_bytes` is then rotated, still locked,
+    /// and the rotation logged in the new one; see [`prune_log`](Self::prune_log).
+    pub(crate) fn log_action(&self, root: &Path, action: &str, file: &Path, result: &str) -> io::Result<()> {
+        if let Some(logged) = self.log_to_logger(action, file, result) {
+            return logged;
+        }
+        let max_bytes = self.config.log_max_bytes.filter(|_| !self.config.log_audit);
+        let Some(segment) = self.write_entry(root, action, file, result, max_bytes)? else {
+            return Ok(());
+        };
+        let name = Path::new(segment.file_name().unwrap_or(segment.as_os_str()));
+        self.append_log(root, "rotate_log", name, "ok")?;
+        self.prune_log_in(root).map(drop)
+    }
+
+    /// [`log_action`](Self::log_action) that never rotates, for entries
+    /// about the rotation itself.
+    pub(crate) fn append_log(&self, root: &Path, action: &str, file: &Path, result: &str) -> io::Result<()> {
+        self.write_entry(root, action, file, result, None).map(drop)
+    }
+
+    /// Append one entry; then, if the log holds more than it and is past
+    /// `max_bytes`, rotate it, returning the segment it became.
+    fn write_entry(
+        &self,
+        root: &Path,
+        action: &str,
+        file: &Path,
+        result: &str,
+        max_bytes: Option<u64>,
+    ) -> io::Result<Option<PathBuf>> {
+        let path = self.log_path(root);
+        if let (true, Some(dir)) = (self.config.tidy, path.parent()) {
+            self.create_dirs("log", dir, "cannot create log directory")?;
+        }
+        let (text, mut f) = self.fs.open_append_locked(&path).at("log", "cannot open log", &path)?;
+        self.check_audit(root, &path, &text)?;
+        if text.is_empty() {
+            self.restrict("log", &path, None)?;
+        }
+        let prev_hash = text.lines().last().map_or_else(|| GENESIS_HASH.to_string(), line_hash);
+        let entry = LogEntry {
+            v: LOG_VERSION,
+            ts: self.now(),
+            user: self.logged_user(root)?,
+            action: action.to_string(),
+            file: display_name(file.as_os_str()).into_owned(),
+            result: result.to_string(),
+            prev_hash: Some(prev_hash),
+        };
+        let line = format!("{}\n", format_entry(&entry));
+        f.write_all(line.as_bytes()).at("log", "cannot write log", &path)?;
+        self.record_audit(root, &path, &text, &line)?;
+        let size = (text.len() + line.len()) as u64;
+        if text.is_empty() || max_bytes.is_none_or(|max| size <= max) {
+            return Ok(None);
+        }
+        let segment = rotate(&*self.fs, &path)?;
+        drop(f);
+        Ok(Some(segment))
+    }
+}
+
+/// `entry` as one line of the log in the format of its version, without the line ending.
+pub(crate) fn format_entry(e: &LogEntry) -> String {
+    let (action, file, result) = (json_escape(&e.action), json_escape(&e.file), json_escape(&e.result));
+    let v = if e.v > 1 { format!("\"v\":{},", e.v) } else { String::new() };
+    let user = match e.user.as_str() {
+        "" if e.v > 1 => String::new(),
+        user => format!("\"user\":\"{}\",", json_escape(user)),
+    };
+    let prev_hash = e.prev_hash.as_deref().map_or_else(String::new, |h| format!(",\"prev_hash\":\"{h}\""));
+    let fields = format!("{user}\"action\":\"{action}\",\"file\":\"{file}\",\"result\":\"{result}\"");
+    format!("{{{v}\"ts\":{},{fields}{prev_hash}}}", e.ts)
+}
+
+/// The [`LogUser::Hashed`] form of `user`.
+fn pseudonym(salt: &[u8], user: &str) -> String {
+    let hash = Sha256::new().chain_update(salt).chain_update(user.as_bytes()).finalize();
+    format!("anon-{}", hash[..8].iter().map(|b| format!("{b:02x}")).collect::<String>())
+}
+
+/// First non-blank of the configured actor and the env override, else `whoami`.
+fn resolve_actor(configured: Option<&str>, env: Option<&str>) -> String {
+    [configured, env]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .find(|s| !s.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(whoami::username)
+}
+
+/// Escape a string for embedding in a JSON string literal.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn actor_precedence() {
+        assert_eq!(resolve_actor(Some("ci-deploy"), Some("runner")), "ci-deploy");
+        assert_eq!(resolve_actor(None, Some("alice")), "alice");
+        assert_eq!(resolve_actor(Some("  "), Some("alice")), "alice");
+        assert_eq!(resolve_actor(None, None), whoami::username());
+    }
+
+    #[test]
+    fn written_lines_read_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mgr = BackupManager::new(crate::Config {
+            root: Some(tmp.path().to_path_buf()),
+            actor: Some("tester".into()),
+            ..crate::Config::default()
+        });
+        mgr.log_action(tmp.path(), "backup", Path::new("a \"quoted\" name.txt"), "ok").unwrap();
+        fs::write(tmp.path().join("x"), "").unwrap();
+        let mut f = OpenOptions::new().append(true).open(tmp.path().join(LOG_FILE)).unwrap();
+        writeln!(f, "not json").unwrap();
+        let entries = read_log(tmp.path()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file, "a \"quoted\" name.txt");
+        assert_eq!(entries[0].user, "tester");
+    }
+
+    #[test]
+    fn users_can_be_pseudonymized_or_left_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = |actor: &str, log_user| {
+            BackupManager::new(crate::Config {
+                root: Some(root.to_path_buf()),
+                actor: Some(actor.into()),
+                log_user,
+                ..crate::Config::default()
+            })
+        };
+        for actor in ["alice", "alice", "bob"] {
+            mgr(actor, LogUser::Hashed).log_action(root, "backup", Path::new("a"), "ok").unwrap();
+        }
+        mgr("alice", LogUser::Omitted).log_action(root, "backup", Path::new("a"), "ok").unwrap();
+        mgr("alice", LogUser::Full).log_action(root, "backup", Path::new("a"), "ok").unwrap();
+
+        let text = fs::read_to_string(root.join(LOG_FILE)).unwrap();
+        assert!(!text.lines().take(4).any(|l| l.contains("alice")), "{text}");
+        assert!(!text.lines().nth(3).unwrap().contains("\"user\""), "{text}");
+        let scanned = mgr("alice", LogUser::Full).scan_log().unwrap();
+        assert!(scanned.corrupt.is_empty() && scanned.recovered.is_empty(), "{scanned:?}");
+        let users: Vec<&str> = scanned.entries.iter().map(|e| e.user.as_str()).collect();
+        assert!(users[0].starts_with("anon-") && users[0].len() == 21, "{users:?}");
+        assert_eq!(users[1], users[0]);
+        assert_ne!(users[2], users[0]);
+        assert_eq!(&users[3..], ["", "alice"]);
+        assert_eq!(scanned.entries.iter().map(format_entry).collect::<Vec<_>>(), text.lines().collect::<Vec<_>>());
+        mgr("alice", LogUser::Full).verify_log_chain().unwrap();
+
+        // The salt is made once per root.
+        assert!(root.join(".safe_backup/log_salt").is_file());
+        let other = tempfile::tempdir().unwrap();
+        let any = mgr("alice", LogUser::Hashed);
+        assert_ne!(pseudonym(&any.log_salt(other.path()).unwrap(), "alice"), users[0]);
+        assert_eq!(pseudonym(&any.log_salt(root).unwrap(), "alice"), users[0]);
+
+        // Processes making it at once all read the one that was made, never an empty one.
+        let fresh = tempfile::tempdir().unwrap();
+        let salts: Vec<Vec<u8>> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8).map(|_| s.spawn(|| any.log_salt(fresh.path()).unwrap())).collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert!(salts.iter().all(|salt| salt.len() == 64 && *salt == salts[0]));
+        assert_eq!(fs::read_dir(fresh.path().join(".safe_backup")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn reads_every_entry_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log = tmp.path().join(LOG_FILE);
+        let mgr =
+            BackupManager::new(crate::Config { root: Some(tmp.path().to_path_buf()), ..crate::Config::default() });
+        // Version 1 without and with a hash, then lines of this version.
+        let v1 = r#"{"ts":1,"user":"u","action":"backup","file":"old","result":"ok"}"#;
+        let v1_chained = format!(
+            r#"{{"ts":2,"user":"u","action":"backup","file":"old","result":"ok","prev_hash":"{}"}}"#,
+            line_hash(v1)
+        );
+        fs::write(&log, format!("{v1}\n{v1_chained}\n")).unwrap();
+        mgr.log_action(tmp.path(), "backup", Path::new("new"), "ok").unwrap();
+        let text = fs::read_to_string(&log).unwrap();
+        assert!(text.lines().nth(2).unwrap().starts_with(r#"{"v":2,"ts":"#));
+        // A later version's extra field is skipped; version 2 without a hash is not an entry.
+        let v3 = concat!(
+            r#"{"v":3,"ts":4,"user":"u","action":"backup","file":"f","result":"ok","#,
+            r#""prev_hash":"00","digest":"ab"}"#
+        );
+        let v2_unchained = r#"{"v":2,"ts":5,"user":"u","action":"backup","file":"f","result":"ok"}"#;
+        fs::write(&log, format!("{text}{v3}\n{v2_unchained}\n")).unwrap();
+
+        let scanned = mgr.scan_log().unwrap();
+        let versions: Vec<u32> = scanned.entries.iter().map(|e| e.v).collect();
+        assert_eq!(versions, [1, 1, 2, 3]);
+        assert_eq!(scanned.entries[0].prev_hash, None);
+        assert_eq!(scanned.entries[1].prev_hash, Some(line_hash(v1)));
+        assert_eq!(scanned.entries[2].file, "new");
+        assert_eq!(
+            scanned.corrupt,
+            [CorruptLine { line: 5, text: v2_unchained.to_string(), reason: SkipReason::Unchained }]
+        );
+
+        // Mixed versions chain; repairing keeps each line's version.
+        fs::write(&log, &text).unwrap();
+        assert_eq!(mgr.verify_log_chain().unwrap(), 2);
+        fs::write(&log, format!("{v1}\nnot json\n{}", text.lines().nth(2).unwrap())).unwrap();
+        mgr.repair_log().unwrap();
+        let versions: Vec<u32> = mgr.read_log().unwrap().iter().map(|e| e.v).collect();
+        assert_eq!(versions, [1, 2, 2]);
+        mgr.verify_log_chain().unwrap();
+    }
+
+    #[test]
+    fn reads_logs_of_the_first_version() {
+        let text = include_str!("../tests/fixtures/logs/v1_original.txt");
+        let scanned = scan(text);
+        let actions: Vec<&str> = scanned.entries.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(actions, ["backup", "restore", "delete"]);
+        assert!(scanned.entries.iter().all(|e| e.v == 1 && e.prev_hash.is_none() && e.user == "bardan"));
+        assert!(scanned.recovered.is_empty());
+        // That writer didn't escape names.
+        assert_eq!(scanned.corrupt.iter().map(|c| (c.line, c.reason)).collect::<Vec<_>>(), [(3, SkipReason::NotJson)]);
+    }
+
+    #[test]
+    fn salvages_torn_and_irregular_lines() {
+        let text = include_str!("../tests/fixtures/logs/torn_writes.txt");
+        let lines: Vec<&str> = text.lines().collect();
+        let issues = |i: usize| match parse_log_line(lines[i]) {
+            ParsedLine::Recoverable(_, issues) => issues,
+            other => panic!("line {}: {other:?}", i + 1),
+        };
+        assert_eq!(issues(0), [r#""ts" "1699990000" is a string"#]);
+        assert_eq!(issues(1), [r#"no "user""#]);
+        assert_eq!(issues(2), ["33 bytes of another write before the entry"]);
+        assert_eq!(issues(6), ["20 bytes of another write after the entry"]);
+        assert_eq!(issues(7), [r#""ts" 1699990007.5 is not whole seconds"#]);
+
+        let scanned = scan(text);
+        let files: Vec<(u64, &str, &str)> =
+            scanned.entries.iter().map(|e| (e.ts, e.user.as_str(), e.file.as_str())).collect();
+        assert_eq!(
+            files,
+            [
+                (1699990000, "ci", "a.txt"),
+                (1699990001, "", "b.txt"),
+                (1699990003, "cron", "c.txt"),
+                (1699990005, "ci", "d.txt"),
+                (1699990007, "ci", "d.txt"),
+            ]
+        );
+        assert_eq!(scanned.recovered.iter().map(|r| r.line).collect::<Vec<_>>(), [1, 2, 3, 7, 8]);
+        let skipped: Vec<(usize, SkipReason)> = scanned.corrupt.iter().map(|c| (c.line, c.reason)).collect();
+        assert_eq!(skipped, [(4, SkipReason::NotJson), (5, SkipReason::Blank), (6, SkipReason::MissingField("file"))]);
+        assert_eq!(scanned.skipped().iter().map(|(_, n)| n).sum::<usize>(), 3);
+
+        // Repairing writes the salvaged entries out as valid ones.
+        let (repaired, dropped) = repaired(text);
+        assert_eq!(dropped, 3);
+        let rescanned = scan(&repaired.join("\n"));
+        assert_eq!((rescanned.entries, rescanned.recovered.len()), (scanned.entries, 0));
+    }
+
+    #[test]
+    fn filters_compose() {
+        let tmp = tempfile::tempdir().unwrap();
+        let entry = |ts, action: &str, file: &str, result: &str| {
+            let (action, file, result) = (action.to_string(), file.to_string(), result.to_string());
+            format_entry(&LogEntry { v: 1, ts, user: "u".into(), action, file, result, prev_hash: None })
+        };
+        let lines = [
+            entry(100, "backup", "a.txt", "ok"),
+            entry(200, "backup", "b.txt", "auto"),
+            entry(300, "restore", "a.txt", "ok"),
+            entry(400, "checkpoint", "pre", "failed (restored 1 of 2 files)"),
+            entry(500, "backup", "a.txt", "ok"),
+            entry(600, "schedule", "*", "skipped (previous cycle still running)"),
+        ];
+        fs::write(tmp.path().join(LOG_FILE), lines.join("\n")).unwrap();
+        let mgr =
+            BackupManager::new(crate::Config { root: Some(tmp.path().to_path_buf()), ..crate::Config::default() });
+        let ts = |filter: LogFilter| -> Vec<u64> {
+            mgr.read_log_filtered(&filter).unwrap().iter().map(|e| e.ts).collect()
+        };
+
+        assert_eq!(ts(LogFilter::new()).len(), 6);
+        assert_eq!(ts(LogFilter::new().action("backup")), [100, 200, 500]);
+        assert_eq!(ts(LogFilter::new().action("backup").file("a.txt")), [100, 500]);
+        assert_eq!(ts(LogFilter::new().file("a.txt").since(300)), [300, 500]);
+        assert_eq!(ts(LogFilter::new().action("backup").last(2)), [200, 500]);
+        assert_eq!(ts(LogFilter::new().last(10).since(550)), [600]);
+        assert_eq!(ts(LogFilter::new().failed_only(true)), [400]);
+        assert!(ts(LogFilter::new().failed_only(true).action("backup")).is_empty());
+    }
+
+    #[test]
+    fn concurrent_writers_keep_lines_whole_and_chained() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                s.spawn(move || {
+                    // A manager each, as separate processes would have.
+                    let mgr =
+                        BackupManager::new(crate::Config { root: Some(root.to_path_buf()), ..crate::Config::default() });
+                    for i in 0..25 {
+                        let name = format!("thread {t} file {i} {}", "x".repeat(200));
+                        mgr.log_action(root, "backup", Path::new(&name), "ok").unwrap();
+                    }
+                });
+            }
+        });
+        let text = fs::read_to_string(root.join(LOG_FILE)).unwrap();
+        assert_eq!(text.lines().count(), 200);
+        for line in text.lines() {
+            assert!(matches!(parse_log_line(line), ParsedLine::Ok(_)), "{line}");
+        }
+        assert_eq!(verify_log_chain(root).unwrap(), 200);
+    }
+
+    #[test]
+    fn chain_detects_edits_and_removals() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log = tmp.path().join(LOG_FILE);
+        // An entry from before chaining is accepted as the start.
+        let legacy = r#"{"ts":1,"user":"u","action":"backup","file":"old","result":"ok"}"#;
+        fs::write(&log, format!("{legacy}\n")).unwrap();
+        let mgr =
+            BackupManager::new(crate::Config { root: Some(tmp.path().to_path_buf()), ..crate::Config::default() });
+        for name in ["a", "b", "c"] {
+            mgr.log_action(tmp.path(), "backup", Path::new(name), "ok").unwrap();
+        }
+        assert_eq!(mgr.verify_log_chain().unwrap(), 3);
+        assert_eq!(read_log(tmp.path()).unwrap()[1].prev_hash.as_deref().map(str::len), Some(64));
+
+        let good = fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = good.lines().collect();
+        let check = |text: String| {
+            fs::write(&log, text).unwrap();
+            let e = mgr.verify_log_chain().unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            match BackupError::from_io(&e) {
+                Some(BackupError::LogTampered { line, .. }) => *line,
+                other => panic!("{other:?}"),
+            }
+        };
+        assert_eq!(check(good.replace("\"file\":\"b\"", "\"file\":\"x\"")), 4);
+        assert_eq!(check(format!("{}\n{}\n{}\n", lines[0], lines[1], lines[3])), 3);
+        assert_eq!(check(format!("{good}not json\n")), 5);
+    }
+
+    #[test]
+    fn corrupt_lines_are_reported_and_repaired() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log = tmp.path().join(LOG_FILE);
+        let mgr = crate::test_support::manager(tmp.path(), 77);
+        mgr.log_action(tmp.path(), "backup", Path::new("a"), "ok").unwrap();
+        // What the unescaped writer produced for a name with quotes.
+        let bad = r#"{"ts":5,"user":"u","action":"backup","file":"say "hi".txt","result":"ok"}"#;
+        writeln!(OpenOptions::new().append(true).open(&log).unwrap(), "{bad}").unwrap();
+        mgr.log_action(tmp.path(), "backup", Path::new("b"), "ok").unwrap();
+        mgr.log_action(tmp.path(), "delete", Path::new("b"), "ok").unwrap();
+        let before = fs::read_to_string(&log).unwrap();
+
+        let scanned = mgr.scan_log().unwrap();
+        assert_eq!(scanned.entries.len(), 3);
+        assert_eq!(scanned.corrupt, [CorruptLine { line: 2, text: bad.to_string(), reason: SkipReason::NotJson }]);
+        assert!(mgr.verify_log_chain().is_err());
+
+        let report = mgr.repair_log().unwrap();
+        let orig = tmp.path().join(format!("{LOG_FILE}.77.orig"));
+        assert_eq!(report, LogRepair { kept: 3, dropped: 1, backup: Some(orig.clone()) });
+        assert_eq!(fs::read_to_string(&orig).unwrap(), before);
+        assert_eq!(mgr.verify_log_chain().unwrap(), 4);
+        let entries = mgr.read_log().unwrap();
+        let files: Vec<&str> = entries.iter().map(|e| e.file.as_str()).collect();
+        assert_eq!(files, ["a", "b", "b", LOG_FILE]);
+        assert_eq!(entries[3].result, "ok (3 kept, 1 dropped)");
+
+        assert_eq!(mgr.repair_log().unwrap(), LogRepair { kept: 4, dropped: 0, backup: None });
+    }
+
+    #[test]
+    fn entries_logged_during_a_repair_are_kept() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        let log = root.join(LOG_FILE);
+        let mgr = BackupManager::new(crate::Config { root: Some(root.clone()), ..crate::Config::default() });
+        mgr.log_action(&root, "start", Path::new("a"), "ok").unwrap();
+        let logger = {
+            let (mgr, root) = (mgr.clone(), root.clone());
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    mgr.log_action(&root, "backup", Path::new("a"), "ok").unwrap();
+                }
+            })
+        };
+        for _ in 0..20 {
+            let (_, mut f) = RealFs.open_append_locked(&log).unwrap();
+            f.write_all(b"not json\n").unwrap();
+            drop(f);
+            mgr.repair_log().unwrap();
+        }
+        logger.join().unwrap();
+        let backups = mgr.read_log().unwrap().into_iter().filter(|e| e.action == "backup").count();
+        assert_eq!(backups, 100);
+    }
+}
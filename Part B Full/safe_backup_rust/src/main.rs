@@ -1,5 +1,612 @@
-use std::io::{self, Write};
-use safe_backup::{backup_file, restore_file, delete_file, validate_path};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use safe_backup::{
+    capabilities, error_chain, error_code, exit_code, parse_script, resolve_settings, BackupEntry, BackupManager,
+    BackupOptions, BackupOutcome, BatchReport, CancellationToken, Compression, Config, ConflictStrategy, DeleteOptions,
+    DigestAlgo, DirOptions, Event, HookOutput, LogEntry, LogFilter, LogUser, RepoStats, RestoreOptions, RestoreOutcome,
+    ScriptAction, Settings, SftpConfig, StrayKind, TimeZone, Warning, WatchOptions, ENV_SFTP_HOST, VERSION,
+};
+use serde_json::json;
+
+/// Set while a cancellable operation runs; Ctrl-C then cancels it instead of exiting.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Config file read from the current directory unless `--config` says otherwise.
+const DEFAULT_CONFIG: &str = "safe_backup.json";
+
+/// Timestamped file backups with a JSON action log.
+///
+/// Without a subcommand, starts the interactive prompt.
+///
+/// Settings come from, in order of precedence: command-line flags, the
+/// SAFE_BACKUP_DIR / SAFE_BACKUP_LOG / SAFE_BACKUP_KEEP / SAFE_BACKUP_COMPRESS
+/// environment variables, the config file, built-in defaults.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// JSON config file with any of "backup_dir", "log", "keep", "compress", "exclude", "log_user",
+    /// "log_audit", "time_zone", "pre_hook", "post_hook"
+    #[arg(long, global = true, value_name = "FILE", default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+    /// Keep backups in DIR (relative to the current directory unless absolute) [env: SAFE_BACKUP_DIR]
+    #[arg(long, global = true, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+    /// Write the action log to FILE [env: SAFE_BACKUP_LOG]
+    #[arg(long, global = true, value_name = "FILE")]
+    log: Option<PathBuf>,
+    /// How the log records who did something: full, hashed (a salted pseudonym) or omitted
+    #[arg(long, global = true, value_name = "FORM")]
+    log_user: Option<LogUser>,
+    /// Show dates in utc or local time [default: local]
+    #[arg(long, global = true, value_name = "ZONE")]
+    time_zone: Option<TimeZone>,
+    /// Run PROGRAM with the action and the file's path before each file backup, restore and delete;
+    /// if it fails, the action is not done
+    #[arg(long, global = true, value_name = "PROGRAM")]
+    pre_hook: Option<PathBuf>,
+    /// Run PROGRAM the same way after each of them succeeds; a failure only warns
+    #[arg(long, global = true, value_name = "PROGRAM")]
+    post_hook: Option<PathBuf>,
+    /// Refuse to write to the log once it is found shorter than after the last write (turns rotation off)
+    #[arg(long, global = true)]
+    log_audit: bool,
+    /// Rotate the log once it grows past SIZE (e.g. 10M), gzipping the old part to "<log>.N.gz"
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    log_max_size: Option<u64>,
+    /// Keep at most N gzipped log archives, removing the oldest
+    #[arg(long, global = true, value_name = "N")]
+    log_archives: Option<usize>,
+    /// Keep at most N backups per file: after each backup, and for `prune` [env: SAFE_BACKUP_KEEP]
+    #[arg(long, global = true, value_name = "N")]
+    keep: Option<usize>,
+    /// Store timestamped backups compressed: none or gzip [env: SAFE_BACKUP_COMPRESS]
+    #[arg(long, global = true, value_name = "KIND")]
+    compress: Option<Compression>,
+    /// Digest recorded for new backups: sha256, sha1, blake3 or crc32 [default: sha256]
+    #[arg(long, global = true, value_name = "ALGO")]
+    digest: Option<DigestAlgo>,
+    /// Store backups of files larger than SIZE as SIZE-byte pieces; `--chunk-size` alone
+    /// means 1G, else give it as `--chunk-size=512M`
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    #[arg(num_args = 0..=1, require_equals = true, default_missing_value = "1G")]
+    chunk_size: Option<u64>,
+    /// Store file backups as pieces in a shared object store, each distinct piece once
+    /// (reclaim space with `gc`)
+    #[arg(long, global = true)]
+    dedup: bool,
+    /// Also use backups of the old tool, "<name>.YYYYMMDDHHMMSS.bk" and "<name>.bk"
+    /// (convert them for good with `migrate`)
+    #[arg(long, global = true)]
+    legacy_bk: bool,
+    /// After each backup, remove that file's backups older than DAYS days
+    #[arg(long, global = true, value_name = "DAYS")]
+    max_age: Option<u64>,
+    /// Refuse to back up a file modified less than this long ago (scheduled cycles try it
+    /// again next time): seconds, or a number with s, m, h or d (e.g. 5m)
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_interval)]
+    min_age: Option<Duration>,
+    /// Give up on a backup or restore taking longer than this, removing what it wrote:
+    /// seconds, or a number with s, m, h or d (e.g. 30s)
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_interval)]
+    timeout: Option<Duration>,
+    /// Read each copied backup back and check it against the digest taken while copying
+    #[arg(long, global = true)]
+    verify_writes: bool,
+    /// Hash files to tell whether they changed even when their size and mtime match the latest backup
+    #[arg(long, global = true)]
+    paranoid: bool,
+    /// Keep extended attributes (tags, SELinux labels) in backups and restore them
+    /// (Unix, built with the `xattrs` feature)
+    #[arg(long, global = true)]
+    xattrs: bool,
+    /// Keep POSIX ACLs (access for named users and groups) in backups and restore them
+    /// (Linux, built with the `acls` feature)
+    #[arg(long, global = true)]
+    acls: bool,
+    /// Keep the action log in ".safe_backup/" instead of the current directory
+    #[arg(long, global = true)]
+    tidy: bool,
+    /// Keep timestamped backups in ".safe_backup/backups/", leaving only the plain
+    /// copies next to the files (backups made before are still found; see `migrate-layout`)
+    #[arg(long, global = true)]
+    tidy_backups: bool,
+    /// Don't ask before deleting files or removing backups; required when not run from a terminal
+    #[arg(long, short = 'y', global = true)]
+    yes: bool,
+    /// For a backup of several files or `restore-all`: print only the totals, not a line per file
+    #[arg(long, short, global = true)]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Back up a file, or a whole directory tree
+    Backup(BackupArgs),
+    /// Back up a file unless it has a backup already; print the new or existing one
+    Ensure { name: PathBuf },
+    /// Back up data piped to stdin as a new backup of NAME, e.g. `pg_dump db | safe_backup backup-stdin db.sql`
+    BackupStdin { name: PathBuf },
+    /// Restore a file from its latest (or a given) backup
+    Restore(RestoreArgs),
+    /// Restore the latest backup of every tracked file
+    RestoreAll {
+        /// For each file that is there, as `restore --on-conflict`
+        #[arg(long, value_name = "STRATEGY", default_value_t = ConflictStrategy::Overwrite)]
+        on_conflict: ConflictStrategy,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete a file
+    Delete {
+        name: PathBuf,
+        /// Delete even if the file has no backup
+        #[arg(long)]
+        force: bool,
+        /// Also remove all of the file's backups, leaving no copy of it
+        #[arg(long)]
+        purge: bool,
+        /// With --purge: only list what would be removed
+        #[arg(long, requires = "purge")]
+        dry_run: bool,
+        /// Move the file to the trash instead of removing it (see `trash empty`)
+        #[arg(long)]
+        trash: bool,
+    },
+    /// Rename a file, keeping its backups: those made under OLD count as backups of NEW
+    Rename {
+        old: PathBuf,
+        new: PathBuf,
+        /// Also rename the backups made under OLD where that can be done safely
+        #[arg(long)]
+        migrate_backups: bool,
+    },
+    /// Back files up automatically whenever they change, until Ctrl-C
+    Watch {
+        /// Files to watch; patterns like "*.txt" are expanded
+        #[arg(required = true)]
+        names: Vec<String>,
+        /// Wait until a file has been quiet this long before backing it up
+        #[arg(long, value_name = "MS", default_value_t = 500)]
+        debounce: u64,
+        /// Poll modification times every MS instead of using filesystem notifications
+        #[arg(long, value_name = "MS")]
+        poll: Option<u64>,
+    },
+    /// Back files up on a fixed interval, skipping unchanged ones, until stopped
+    Schedule {
+        /// Files to back up; patterns like "*.txt" are expanded
+        #[arg(required = true)]
+        names: Vec<String>,
+        /// Interval between cycles: seconds, or a number with s, m, h or d (e.g. 1h)
+        #[arg(long, value_name = "INTERVAL", value_parser = parse_interval, required_unless_present = "once")]
+        every: Option<Duration>,
+        /// Run a single cycle and exit (for cron)
+        #[arg(long)]
+        once: bool,
+        /// Unix only: detach and keep running in the background; stop it with SIGTERM
+        #[arg(long, conflicts_with = "once")]
+        daemon: bool,
+        /// Print how each file was found changed or unchanged
+        #[arg(long, short)]
+        verbose: bool,
+    },
+    /// Run a script of operations, one per line ("backup notes.txt", "restore
+    /// data.csv", "delete scratch.tmp"); lines starting with # are comments
+    Run {
+        script: PathBuf,
+        /// Run the remaining lines after one fails
+        #[arg(long)]
+        keep_going: bool,
+    },
+    /// Write a backup's contents to stdout without restoring it
+    Cat {
+        /// The original's name, or a backup file name
+        name: PathBuf,
+        /// The backup with this timestamp instead of the latest
+        #[arg(long, value_name = "TS")]
+        version: Option<u64>,
+    },
+    /// Show the start of a backup as text, or as a hex dump if it isn't text
+    Peek {
+        /// The original's name, or a backup file name
+        name: PathBuf,
+        /// The backup with this timestamp instead of the latest
+        #[arg(long, value_name = "TS")]
+        version: Option<u64>,
+        /// How much to show
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "4K")]
+        bytes: u64,
+    },
+    /// List the timestamped backups of a file, newest first
+    List {
+        name: PathBuf,
+        #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+        format: ListFormat,
+        /// Same as `--format json`
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+    },
+    /// List the timestamped backups of every file whose name contains SUBSTR, ignoring case,
+    /// and the other backups whose note does
+    Find {
+        substring: String,
+        #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+        format: ListFormat,
+        /// Same as `--format json`
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+    },
+    /// Replace the note of a backup (see `backup --note`); an empty TEXT removes it
+    Annotate {
+        /// A backup file name, e.g. "notes.txt.1700000000.bak"
+        backup: PathBuf,
+        text: String,
+    },
+    /// Show one backup: its original, when it was made, size, digest, layout and note
+    Info {
+        /// A backup file name, e.g. "notes.txt.1700000000.bak"
+        backup: PathBuf,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check backups against the digests recorded when they were made
+    Verify {
+        /// An original (checks all its backups) or a backup file name
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        name: Option<PathBuf>,
+        /// Check every backup of every file ever backed up, skipping the reading of
+        /// backup files unchanged in size and mtime since the last such run
+        #[arg(long)]
+        all: bool,
+        /// With --all: read every backup file again
+        #[arg(long, requires = "all")]
+        rehash: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove all but the newest `--keep` backups of a file
+    Prune {
+        name: PathBuf,
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove objects of the dedup store that no backup refers to any more
+    Gc {
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove restore leftovers ("<name>.restored.<ts>"), empty backups and temporary files
+    Clean {
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rename the old tool's ".bk" backups to "<name>.<ts>.bak", keeping their order
+    Migrate {
+        /// Only show what would be renamed
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move a file's backups older than DAYS days into one "<name>.archive.<ts>.tar.gz"
+    /// (the newest backup always stays)
+    Compact {
+        name: PathBuf,
+        #[arg(long, value_name = "DAYS")]
+        older_than: u64,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Put the backups in an archive made by `compact` back, and remove the archive
+    Extract {
+        /// The archive's file name in the backup directory
+        archive: PathBuf,
+    },
+    /// Move the log and backups left in the current directory into ".safe_backup/"
+    /// (with --tidy and/or --tidy-backups)
+    MigrateLayout {
+        /// Only show what would be moved
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show totals over all backups
+    Stats {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the action log
+    #[command(args_conflicts_with_subcommands = true)]
+    Log {
+        #[command(subcommand)]
+        show: Option<LogCommand>,
+        #[arg(long)]
+        json: bool,
+        /// Check that no entry was altered or removed instead of printing the log
+        #[arg(long, conflicts_with = "json")]
+        verify: bool,
+        /// Remove lines that aren't valid entries, keeping a copy of the log as it was
+        #[arg(long, conflicts_with_all = ["json", "verify"])]
+        repair: bool,
+    },
+    /// Back up a set of files under a name, to put them all back together later
+    Checkpoint {
+        #[command(subcommand)]
+        action: CheckpointCommand,
+    },
+    /// Manage the files `delete --trash` moved to the trash
+    Trash {
+        #[command(subcommand)]
+        action: TrashCommand,
+    },
+    /// Print a shell completion script
+    Completions { shell: clap_complete::Shell },
+    /// Print the version and what this build can do, as JSON
+    Capabilities,
+}
+
+#[derive(Subcommand)]
+enum LogCommand {
+    /// Show the log as a table, keeping only the entries every given filter matches
+    Show {
+        /// Only entries of this action, e.g. backup, restore, delete
+        #[arg(long)]
+        action: Option<String>,
+        /// Only entries for this file, as logged (relative to the working directory)
+        #[arg(long, value_name = "NAME")]
+        file: Option<String>,
+        /// Only entries from this day on, in the zone of --time-zone
+        #[arg(long, value_name = "YYYY-MM-DD", value_parser = parse_date)]
+        since: Option<chrono::NaiveDate>,
+        /// Only the last N of the entries the other filters keep
+        #[arg(long, value_name = "N")]
+        last: Option<usize>,
+        /// Only entries whose result is not "ok", "auto", "skipped" or "unchanged"
+        #[arg(long)]
+        failed_only: bool,
+        /// Print the entries as one JSON array
+        #[arg(long)]
+        json: bool,
+    },
+    /// Gzip rotated log segments and remove archives beyond --log-archives
+    Prune,
+}
+
+#[derive(Subcommand)]
+enum CheckpointCommand {
+    /// Back up FILES together as the checkpoint NAME
+    Create {
+        name: String,
+        /// Files to include; patterns like "*.rs" are expanded
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Put every file of a checkpoint back; nothing changes if any of its backups is missing or damaged
+    Restore {
+        name: String,
+        /// For each file that is there, as `restore --on-conflict`
+        #[arg(long, value_name = "STRATEGY", default_value_t = ConflictStrategy::Overwrite)]
+        on_conflict: ConflictStrategy,
+    },
+    /// List checkpoints, oldest first
+    List {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove a checkpoint; its backups stay
+    Delete { name: String },
+}
+
+#[derive(Subcommand)]
+enum TrashCommand {
+    /// Remove trashed files for good: all of them, or those trashed longer ago than --older-than
+    Empty {
+        /// Seconds, or a number with s, m, h or d (e.g. 30d)
+        #[arg(long, value_name = "DURATION", value_parser = parse_interval)]
+        older_than: Option<Duration>,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+impl Command {
+    /// Whether the output was asked for as JSON, so an error is reported as JSON too.
+    fn json(&self) -> bool {
+        match self {
+            Command::List { json, format, .. } | Command::Find { json, format, .. } => {
+                *json || matches!(format, ListFormat::Json)
+            }
+            Command::Verify { json, .. }
+            | Command::Info { json, .. }
+            | Command::Prune { json, .. }
+            | Command::Gc { json, .. }
+            | Command::Clean { json, .. }
+            | Command::Migrate { json, .. }
+            | Command::Compact { json, .. }
+            | Command::MigrateLayout { json, .. }
+            | Command::Stats { json } => *json,
+            Command::Backup(args) => args.json,
+            Command::RestoreAll { json, .. } => *json,
+            Command::Capabilities => true,
+            Command::Log { json, show, .. } => *json || matches!(show, Some(LogCommand::Show { json: true, .. })),
+            Command::Checkpoint { action: CheckpointCommand::List { json } } => *json,
+            Command::Trash { action: TrashCommand::Empty { json, .. } } => *json,
+            _ => false,
+        }
+    }
+}
+
+/// Flags of `backup`; one per `BackupOptions` setter, plus `--resume` for directories.
+#[derive(Args)]
+struct BackupArgs {
+    /// A file or directory, or a glob pattern (e.g. "*.txt") to back up each file it matches
+    name: PathBuf,
+    /// With a glob pattern: print the results as JSON
+    #[arg(long)]
+    json: bool,
+    /// Always make a byte copy, never a copy-on-write reflink
+    #[arg(long)]
+    no_reflink: bool,
+    /// Don't refresh the plain "<stem>.bak" copy
+    #[arg(long)]
+    no_plain: bool,
+    /// Directories only: continue an interrupted backup instead of starting over
+    #[arg(long)]
+    resume: bool,
+    /// Directories only: leave out entries matching this gitignore-style pattern
+    /// (relative to the directory; repeatable; added to the config file's "exclude")
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Directories only: match --exclude patterns with or without regard to case
+    /// [default: insensitive on Windows and macOS, sensitive elsewhere]
+    #[arg(long, value_name = "MODE")]
+    exclude_case: Option<ExcludeCase>,
+    /// Directories only: descend at most N levels; 0 copies only the files directly inside
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+    /// Directories only: include dotfiles and dot-directories
+    #[arg(long, overrides_with = "no_hidden")]
+    hidden: bool,
+    /// Directories only: skip dotfiles and dot-directories (the default)
+    #[arg(long)]
+    no_hidden: bool,
+    /// Directories only: copy what symlinks point to instead of skipping them
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Directories only: copy and hash up to N files at once (0 counts as 1) [default: one per CPU]
+    #[arg(long, short, value_name = "N")]
+    jobs: Option<usize>,
+    /// Files only: also upload the backup to the SFTP server from the config file's "sftp"
+    /// or SAFE_BACKUP_SFTP_HOST / _PORT / _USER / _PASSWORD / _KEY / _DIR (needs the `sftp` feature)
+    #[arg(long)]
+    sftp: bool,
+    /// Files only: if the file only grew since its last backup, store just the new
+    /// tail as "<name>.<ts>.delta"; otherwise make a full backup
+    #[arg(long, conflicts_with = "sftp")]
+    append: bool,
+    /// Files only: back up even a file that is a backup itself (a ".bak", or one safe_backup made)
+    #[arg(long)]
+    force: bool,
+    /// Files only: if the plain "<stem>.bak" copy can't be written, fail and remove the
+    /// timestamped backup too instead of keeping it with a warning
+    #[arg(long)]
+    all_or_nothing: bool,
+    /// Files only: make no backup if the file matches its latest one, and print that one
+    #[arg(long, conflicts_with = "append")]
+    if_changed: bool,
+    /// Files only: succeed without a backup if the file does not exist
+    #[arg(long, conflicts_with = "append")]
+    skip_missing: bool,
+    /// Files only: record why the backup was made, shown by `list` and matched by `find`
+    #[arg(long, value_name = "TEXT", conflicts_with = "append")]
+    note: Option<String>,
+}
+
+impl BackupArgs {
+    fn options(&self) -> BackupOptions {
+        let mut opts = BackupOptions::new()
+            .plain_copy(!self.no_plain)
+            .force(self.force)
+            .best_effort(!self.all_or_nothing)
+            .if_changed(self.if_changed)
+            .skip_missing(self.skip_missing);
+        if self.no_reflink {
+            opts = opts.reflink(false);
+        }
+        if let Some(note) = &self.note {
+            opts = opts.note(note);
+        }
+        opts
+    }
+
+    fn dir_options(&self) -> DirOptions {
+        let opts = DirOptions::new().resume(self.resume).hidden(self.hidden).follow_symlinks(self.follow_symlinks);
+        let mut opts = self.exclude.iter().fold(opts, |o, p| o.exclude(p));
+        if let Some(n) = self.max_depth {
+            opts = opts.max_depth(n);
+        }
+        if let Some(n) = self.jobs {
+            opts = opts.jobs(n);
+        }
+        if let Some(case) = self.exclude_case {
+            opts = opts.case_sensitive(case == ExcludeCase::Sensitive);
+        }
+        opts
+    }
+}
+
+/// Values of `backup --exclude-case`.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ExcludeCase {
+    Sensitive,
+    Insensitive,
+}
+
+/// Flags of `restore`; one per `RestoreOptions` setter.
+#[derive(Args)]
+struct RestoreArgs {
+    /// The original's name, or a backup file name
+    name: PathBuf,
+    /// Restore into this file instead
+    #[arg(long, value_name = "NAME")]
+    to: Option<PathBuf>,
+    /// Restore the backup with this timestamp instead of the latest
+    #[arg(long, value_name = "TS")]
+    version: Option<u64>,
+    /// Restore the N-th newest backup instead (0 = latest, 1 = the one before, ...)
+    #[arg(long, visible_alias = "offset", value_name = "N", conflicts_with = "version")]
+    previous: Option<usize>,
+    /// Restore the newest backup whose note contains TEXT, ignoring case
+    #[arg(long, value_name = "TEXT", conflicts_with_all = ["version", "previous"])]
+    note_match: Option<String>,
+    /// Only check that the backup is intact and readable; write nothing
+    #[arg(long)]
+    check: bool,
+    /// If the file is there: overwrite it, skip it, rename it to "<name>.pre-restore.<ts>" first,
+    /// or fail unless it matches the backup
+    #[arg(long, value_name = "STRATEGY", default_value_t = ConflictStrategy::Overwrite)]
+    on_conflict: ConflictStrategy,
+    /// Keep the restored file even if it doesn't match the backup's recorded digest (with a warning)
+    #[arg(long)]
+    allow_mismatch: bool,
+}
+
+impl RestoreArgs {
+    fn options(&self) -> RestoreOptions {
+        let mut opts = RestoreOptions::new();
+        if let Some(to) = &self.to {
+            opts = opts.to(to);
+        }
+        if let Some(v) = self.version {
+            opts = opts.version(v);
+        }
+        if let Some(n) = self.previous {
+            opts = opts.previous(n);
+        }
+        if let Some(text) = &self.note_match {
+            opts = opts.note_match(text);
+        }
+        opts.on_conflict(self.on_conflict).allow_mismatch(self.allow_mismatch)
+    }
+}
 
 fn prompt(s: &str) -> io::Result<String> {
     print!("{s}");
@@ -9,7 +616,164 @@ fn prompt(s: &str) -> io::Result<String> {
     Ok(buf.trim().to_string())
 }
 
-fn main() -> io::Result<()> {
+/// Print a batch's results: as JSON, only its totals, or a line per file then
+/// the totals. Whether every file went without failing.
+fn print_batch(report: &BatchReport, json: bool, quiet: bool) -> bool {
+    if json {
+        println!("{}", serde_json::to_string(report).unwrap_or_default());
+    } else if quiet {
+        println!("{}", report.totals());
+    } else {
+        print!("{report}");
+    }
+    report.all_ok()
+}
+
+/// Warn about the events worth a user's attention; the others are progress.
+fn print_event(event: &Event) {
+    if let Event::LogArchiveSkipped { path, error } = event {
+        eprintln!("[warn] skipped the damaged log archive {}: {error}", path.display());
+    }
+}
+
+fn print_warnings(warnings: &[Warning]) {
+    for w in warnings {
+        eprintln!("[warn] {w}");
+    }
+}
+
+fn file_name(p: &std::path::Path) -> String {
+    p.file_name().unwrap_or(p.as_os_str()).to_string_lossy().into_owned()
+}
+
+/// Ask whether to go ahead with `what` (e.g. "Delete /abs/path") unless `yes`.
+/// Without a terminal to ask on, refuse.
+fn confirm(what: &str, yes: bool) -> io::Result<bool> {
+    let stdin = io::stdin();
+    let terminal = stdin.is_terminal();
+    confirm_from(what, yes, terminal.then(|| stdin.lock()))
+}
+
+/// [`confirm`], reading the answer from `input` (`None`: not a terminal).
+fn confirm_from(what: &str, yes: bool, input: Option<impl BufRead>) -> io::Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+    let Some(mut input) = input else {
+        eprintln!("[error] {what}: not confirmed, and there is no terminal to ask on; pass --yes to go ahead");
+        return Ok(false);
+    };
+    print!("{what}? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let ok = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+    if !ok {
+        println!("Aborted.");
+    }
+    Ok(ok)
+}
+
+/// Unless `yes`, warn and ask before a restore overwrites a file modified
+/// after the backup it restores was made. Whether to go ahead.
+fn confirm_restore(mgr: &BackupManager, name: &Path, opts: &RestoreOptions, yes: bool) -> io::Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+    let Some(c) = mgr.restore_conflict(name, opts)? else { return Ok(true) };
+    let time = |t| mgr.config().time_zone.format(t);
+    eprintln!(
+        "[warn] {} was modified {}, after its backup {} was made {}",
+        absolute(&c.destination),
+        time(c.modified),
+        file_name(&c.source),
+        time(c.backed_up)
+    );
+    confirm(&format!("Overwrite {} with the older backup", absolute(&c.destination)), false)
+}
+
+/// `path` as an absolute path, for showing what is about to be removed.
+fn absolute(path: &Path) -> String {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()).display().to_string()
+}
+
+fn run_checkpoint(mgr: &BackupManager, yes: bool, action: CheckpointCommand) -> io::Result<bool> {
+    match action {
+        CheckpointCommand::Create { name, files } => {
+            let cp = mgr.create_checkpoint(&name, &expand_globs(&files)?)?;
+            for f in &cp.files {
+                println!("{}\t{}", f.file, f.backup);
+            }
+            println!("checkpoint {name}: {} file(s)", cp.files.len());
+        }
+        CheckpointCommand::Restore { name, on_conflict } => {
+            let cp = mgr.read_checkpoint(&name)?;
+            for f in &cp.files {
+                println!("{}", f.file);
+            }
+            let when = mgr.config().time_zone.rfc3339(cp.created).unwrap_or_else(|| cp.created.to_string());
+            if !confirm(&format!("Put these {} file(s) back as of {when}", cp.files.len()), yes)? {
+                return Ok(false);
+            }
+            let report = mgr.restore_checkpoint_with(&name, on_conflict)?;
+            let left = |o: &RestoreOutcome| matches!(o, RestoreOutcome::Skipped | RestoreOutcome::Unchanged);
+            for (file, outcome) in report.files.iter().filter(|(_, o)| left(o)) {
+                eprintln!("{outcome}: {}", file.display());
+            }
+            let restored = report.files.iter().filter(|(_, o)| !left(o)).count();
+            println!("checkpoint {name}: restored {restored} file(s)");
+            print_warnings(&report.warnings);
+        }
+        CheckpointCommand::List { json } => {
+            let all = mgr.list_checkpoints()?;
+            if json {
+                println!("{}", serde_json::to_string(&all).map_err(io::Error::other)?);
+            } else {
+                for cp in &all {
+                    let time = mgr.config().time_zone.format(cp.created);
+                    println!("{time}  {:>3} file(s)  {}", cp.files.len(), cp.name);
+                }
+            }
+        }
+        CheckpointCommand::Delete { name } => {
+            mgr.delete_checkpoint(&name)?;
+            println!("checkpoint {name} deleted; its backups are kept");
+        }
+    }
+    Ok(true)
+}
+
+/// Restore every tracked file after listing them and asking for confirmation.
+/// Whether none failed.
+fn run_restore_all(mgr: &BackupManager) -> io::Result<bool> {
+    let names = mgr.tracked_originals()?;
+    if names.is_empty() {
+        println!("Nothing to restore.");
+        return Ok(true);
+    }
+    println!("This will overwrite {} file(s) with their latest backup:", names.len());
+    for n in &names {
+        println!("  {}", n.to_string_lossy());
+    }
+    if !prompt("Type 'yes' to continue: ")?.eq_ignore_ascii_case("yes") {
+        println!("Aborted.");
+        return Ok(true);
+    }
+    Ok(print_batch(&mgr.restore_all_report(ConflictStrategy::Overwrite)?, false, false))
+}
+
+/// Run an operation that Ctrl-C can stop cleanly.
+fn run_cancellable<T>(cancel: &CancellationToken, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    cancel.reset();
+    RUNNING.store(true, Ordering::SeqCst);
+    let r = f();
+    RUNNING.store(false, Ordering::SeqCst);
+    r
+}
+
+/// The original prompt-driven loop. Whether every restore-all run in it succeeded.
+fn interactive(mgr: &BackupManager, cancel: &CancellationToken, yes: bool) -> io::Result<bool> {
+    let mut all_ok = true;
     loop {
         let filename = prompt("Please enter your file name: ")?;
         if filename.eq_ignore_ascii_case("exit") || filename.eq_ignore_ascii_case("quit") {
@@ -17,28 +781,1126 @@ fn main() -> io::Result<()> {
             break;
         }
 
-        if let Err(e) = validate_path(&filename) {
-            eprintln!("[error] {e}");
+        if filename.eq_ignore_ascii_case("restore-all") {
+            match run_restore_all(mgr) {
+                Ok(ok) => all_ok &= ok,
+                Err(e) => {
+                    eprintln!("[error] {}", error_chain(&e));
+                    all_ok = false;
+                }
+            }
+            println!();
             continue;
         }
 
-        let command = prompt("Please enter your command (backup, restore, delete): ")?;
-        match command.to_lowercase().as_str() {
-            "backup" => match backup_file(&filename) {
-                Ok(path) => println!("Your backup created: {}", path.file_name().unwrap().to_string_lossy()),
-                Err(e) => eprintln!("[error] {e}"),
+        let path = match mgr.validate_path(&filename) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("[error] {}", error_chain(&e));
+                continue;
+            }
+        };
+
+        let command = prompt("Please enter your command (backup, restore [previous N], delete, backup-dir, restore-dir): ")?;
+        let command = command.to_lowercase();
+        let (command, previous) = match command.split_whitespace().collect::<Vec<_>>()[..] {
+            ["restore", "previous", n] => match n.parse::<usize>() {
+                Ok(n) => ("restore", Some(n)),
+                Err(_) => {
+                    eprintln!("[error] previous needs a number, e.g. restore previous 2");
+                    continue;
+                }
+            },
+            _ => (command.as_str(), None),
+        };
+        let restore_opts = match previous {
+            Some(n) => RestoreOptions::new().previous(n),
+            None => RestoreOptions::new(),
+        };
+        match command {
+            "backup" => match mgr.backup_file_with(&filename, &BackupOptions::new()) {
+                Ok(report) => {
+                    println!("Your backup created: {}", file_name(&report.path));
+                    print_warnings(&report.warnings);
+                }
+                Err(e) => eprintln!("[error] {}", error_chain(&e)),
+            },
+            "restore" => match confirm_restore(mgr, Path::new(&filename), &restore_opts, yes) {
+                Ok(true) => match mgr.restore_file_with(&filename, &restore_opts) {
+                    Ok(report) => {
+                        println!("Your file has been restored: {}", file_name(&report.path));
+                        print_warnings(&report.warnings);
+                    }
+                    Err(e) => eprintln!("[error] {}", error_chain(&e)),
+                },
+                Ok(false) => {}
+                Err(e) => eprintln!("[error] {}", error_chain(&e)),
+            },
+            "backup-dir" => match run_cancellable(cancel, || mgr.backup_dir(&filename)) {
+                Ok(path) => println!("Your directory backup created: {}", file_name(&path)),
+                Err(e) => eprintln!("[error] {}", error_chain(&e)),
             },
-            "restore" => match restore_file(&filename) {
-                Ok(dest) => println!("Your file has been restored: {}", dest.file_name().unwrap().to_string_lossy()),
-                Err(e) => eprintln!("[error] {e}"),
+            "restore-dir" => match run_cancellable(cancel, || mgr.restore_dir(&filename)) {
+                Ok(_) => println!("Your directory has been restored: {filename}"),
+                Err(e) => eprintln!("[error] {}", error_chain(&e)),
             },
-            "delete" => match delete_file(&filename) {
-                Ok(_) => println!("Deleted: {filename}"),
-                Err(e) => eprintln!("[error] {e}"),
+            "delete" => match confirm(&format!("Delete {}", absolute(&path)), yes) {
+                Ok(true) => match mgr.delete_file_with(&filename, &DeleteOptions::new()) {
+                    Ok(report) => {
+                        println!("Deleted: {filename}");
+                        print_warnings(&report.warnings);
+                    }
+                    Err(e) => eprintln!("[error] {}", error_chain(&e)),
+                },
+                Ok(false) => {}
+                Err(e) => eprintln!("[error] {}", error_chain(&e)),
             },
             other => eprintln!("[error] unknown command: {other}"),
         }
         println!();
     }
-    Ok(())
+    Ok(all_ok)
+}
+
+/// Run one subcommand. Per-item failures are printed here; returns whether all succeeded.
+/// Destructive commands ask first unless `yes`.
+fn run(
+    mgr: &BackupManager,
+    cancel: &CancellationToken,
+    sftp: Option<&SftpConfig>,
+    yes: bool,
+    quiet: bool,
+    command: Command,
+) -> io::Result<bool> {
+    match command {
+        Command::Backup(args) if is_glob(&args.name) => {
+            let names = expand_globs(&[args.name.to_string_lossy().into_owned()])?;
+            let report = run_cancellable(cancel, || Ok(mgr.backup_many(&names, &args.options())))?;
+            return Ok(print_batch(&report, args.json, quiet));
+        }
+        Command::RestoreAll { on_conflict, json } => {
+            let n = mgr.tracked_originals()?.len();
+            let overwrites = on_conflict == ConflictStrategy::Overwrite && n > 0;
+            if overwrites && !confirm(&format!("Restore {n} file(s), overwriting the working copies"), yes)? {
+                return Ok(false);
+            }
+            let report = run_cancellable(cancel, || mgr.restore_all_report(on_conflict))?;
+            return Ok(print_batch(&report, json, quiet));
+        }
+        Command::Backup(args) => {
+            let is_dir = mgr.validate_path(&args.name)?.is_dir();
+            if args.sftp && is_dir {
+                eprintln!("[error] --sftp uploads file backups only");
+                return Ok(false);
+            }
+            if args.append && is_dir {
+                eprintln!("[error] --append works on files only");
+                return Ok(false);
+            }
+            if args.note.is_some() && is_dir {
+                eprintln!("[error] --note works on files only");
+                return Ok(false);
+            }
+            if args.sftp {
+                let Some(sftp) = sftp else {
+                    eprintln!("[error] --sftp needs a server: set {ENV_SFTP_HOST} or \"sftp\" in the config file");
+                    return Ok(false);
+                };
+                let report = mgr.backup_file_remote_with(&args.name, &args.options(), sftp)?;
+                println!("{}", report.backup.path.display());
+                println!("uploaded {} file(s) to {}", report.uploaded.len(), report.target);
+                print_warnings(&report.backup.warnings);
+            } else if args.append {
+                let report = mgr.backup_append_delta(&args.name)?;
+                println!("{}", report.path.display());
+                print_warnings(&report.warnings);
+            } else if is_dir {
+                let report = run_cancellable(cancel, || mgr.backup_dir_with(&args.name, &args.dir_options()))?;
+                if !args.json {
+                    println!("{}", report.path.display());
+                }
+                let ok = print_batch(&report.batch, args.json, quiet);
+                print_warnings(&report.warnings);
+                return Ok(ok);
+            } else {
+                let report = run_cancellable(cancel, || mgr.backup_file_with(&args.name, &args.options()))?;
+                match report.outcome {
+                    BackupOutcome::Created => println!("{}", report.path.display()),
+                    BackupOutcome::Unchanged => {
+                        println!("{}", report.path.display());
+                        eprintln!("unchanged since this backup; none made");
+                    }
+                    BackupOutcome::Skipped => eprintln!("skipped: {} does not exist", report.path.display()),
+                }
+                print_warnings(&report.warnings);
+            }
+        }
+        Command::Ensure { name } => {
+            let (path, made) = mgr.ensure_backup(&name)?;
+            println!("{}", path.display());
+            if !made {
+                eprintln!("already backed up; none made");
+            }
+        }
+        Command::BackupStdin { name } => {
+            let stdin = io::stdin();
+            if stdin.is_terminal() {
+                eprintln!("[error] backup-stdin reads from a pipe or file, not the terminal");
+                return Ok(false);
+            }
+            let report = mgr.backup_from_reader(&name, stdin.lock())?;
+            println!("{}\t{} bytes", report.path.display(), report.bytes);
+            print_warnings(&report.warnings);
+        }
+        Command::Restore(args) if args.check => {
+            let check = mgr.check_restore(&args.name, &args.options())?;
+            println!("source:      {}", check.source.display());
+            println!("destination: {}", check.destination.display());
+            println!("expected:    {}", check.expected.as_deref().unwrap_or("-"));
+            println!("actual:      {}", check.actual);
+            println!("status:      {}", check.status);
+            return Ok(check.is_ok());
+        }
+        Command::Restore(args) => {
+            // Only overwriting replaces the file without asking for it another way.
+            let overwrites = args.on_conflict == ConflictStrategy::Overwrite;
+            if overwrites && !confirm_restore(mgr, &args.name, &args.options(), yes)? {
+                return Ok(false);
+            }
+            let report = mgr.restore_file_with(&args.name, &args.options())?;
+            match report.outcome {
+                RestoreOutcome::Restored | RestoreOutcome::Overwritten => println!("{}", report.path.display()),
+                RestoreOutcome::RenamedExisting => {
+                    println!("{}", report.path.display());
+                    if let Some(moved) = &report.moved {
+                        eprintln!("the file that was there is now {}", moved.display());
+                    }
+                }
+                RestoreOutcome::Skipped => eprintln!("skipped: {} exists", report.path.display()),
+                RestoreOutcome::Unchanged => {
+                    eprintln!("unchanged: {} already matches the backup", report.path.display())
+                }
+            }
+            print_warnings(&report.warnings);
+        }
+        Command::Delete { name, force, purge: false, trash, .. } => {
+            if !force && mgr.find_latest_backup(&name).is_err() {
+                eprintln!("[error] {} has no backup; use --force to delete it anyway", name.display());
+                return Ok(false);
+            }
+            let path = mgr.validate_path(&name)?;
+            let verb = if trash { "Move to the trash" } else { "Delete" };
+            if path.exists() && !confirm(&format!("{verb} {}", absolute(&path)), yes)? {
+                return Ok(false);
+            }
+            let report = mgr.delete_file_with(&name, &DeleteOptions::new().trash(trash))?;
+            if let Some(trashed) = &report.trashed {
+                println!("trashed as {}", trashed.display());
+            }
+            print_warnings(&report.warnings);
+        }
+        Command::Delete { name, dry_run, trash, .. } => {
+            let opts = DeleteOptions::new().purge_backups(true).trash(trash);
+            let plan = mgr.delete_file_with(&name, &opts.clone().dry_run(true))?;
+            let verb = if dry_run { "would remove" } else { "removed" };
+            if dry_run {
+                plan.deleted.iter().chain(&plan.purged).for_each(|p| println!("{verb} {}", p.display()));
+                return Ok(true);
+            }
+            let what = match &plan.deleted {
+                Some(path) if trash => {
+                    format!("Move {} to the trash and remove its {} backup(s)", absolute(path), plan.purged.len())
+                }
+                Some(path) => format!("Delete {} and its {} backup(s)", absolute(path), plan.purged.len()),
+                None => format!("Remove the {} backup(s) of {}", plan.purged.len(), name.display()),
+            };
+            if !confirm(&what, yes)? {
+                return Ok(false);
+            }
+            let report = mgr.delete_planned_with(&name, plan, &opts)?;
+            if let Some(trashed) = &report.trashed {
+                println!("trashed as {}", trashed.display());
+            }
+            let removed = report.deleted.iter().filter(|_| !trash).chain(&report.purged);
+            removed.for_each(|p| println!("{verb} {}", p.display()));
+            print_warnings(&report.warnings);
+        }
+        Command::Rename { old, new, migrate_backups } => {
+            let report = mgr.rename_tracked(&old, &new, migrate_backups)?;
+            if !report.renamed_file {
+                eprintln!("{} is not there; only its backups were renamed", old.display());
+            }
+            for (from, to) in &report.migrated {
+                println!("{} -> {}", file_name(from), file_name(to));
+            }
+            println!("{} is now {}", old.display(), new.display());
+        }
+        Command::Watch { names, debounce, poll } => {
+            let names = expand_globs(&names)?;
+            let mut opts = WatchOptions::new().debounce(Duration::from_millis(debounce));
+            if let Some(ms) = poll {
+                opts = opts.poll(Duration::from_millis(ms));
+            }
+            println!("Watching {} file(s); press Ctrl-C to stop.", names.len());
+            let taken = run_cancellable(cancel, || {
+                mgr.watch(&names, &opts, |name, result| match result {
+                    Ok(report) => {
+                        println!("{}", report.path.display());
+                        print_warnings(&report.warnings);
+                    }
+                    Err(e) => eprintln!("[error] {}: {}", name.display(), error_chain(e)),
+                })
+            })?;
+            println!("{taken} backup(s) taken.");
+        }
+        Command::Schedule { daemon: true, .. } => {
+            let pid = daemonize()?;
+            println!("Scheduler running in the background as process {pid}.");
+        }
+        Command::Schedule { names, every, once, verbose, .. } => {
+            let names = expand_globs(&names)?;
+            let mut all_ok = true;
+            let cycles = run_cancellable(cancel, || {
+                mgr.schedule(&names, every.unwrap_or_default(), once, |result| match result {
+                    Ok(report) => {
+                        println!("[cycle] {}", report.summary());
+                        for (name, check) in report.checks.iter().filter(|_| verbose) {
+                            println!("[check] {}: {}", name.display(), check.describe());
+                        }
+                        for (_, r) in &report.backed_up {
+                            print_warnings(&r.warnings);
+                        }
+                        all_ok &= print_batch(&report.batch, false, quiet);
+                    }
+                    Err(e) => {
+                        eprintln!("[error] {}", error_chain(e));
+                        all_ok = false;
+                    }
+                })
+            })?;
+            if !once {
+                println!("{cycles} cycle(s) run.");
+            }
+            return Ok(all_ok || !once);
+        }
+        Command::Run { script, keep_going } => {
+            let steps = parse_script(&script)?;
+            let deletes = steps.iter().filter(|s| s.action == ScriptAction::Delete).count();
+            if deletes > 0 && !confirm(&format!("Run {} ({deletes} delete(s))", script.display()), yes)? {
+                return Ok(false);
+            }
+            let report = mgr.run_steps(&steps, keep_going);
+            let ok = print_batch(&report.batch, false, quiet);
+            if report.skipped > 0 {
+                eprintln!("{} line(s) not run; use --keep-going to run past a failure", report.skipped);
+            }
+            return Ok(ok && report.all_ok());
+        }
+        Command::Cat { name, version } => {
+            let mut out = io::stdout().lock();
+            match mgr.cat_backup(&name, version, &mut out).and_then(|_| out.flush()) {
+                // `safe_backup cat x | head` closing the pipe early is not an error.
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+                r => r?,
+            }
+        }
+        Command::Peek { name, version, bytes } => {
+            let max = usize::try_from(bytes).unwrap_or(usize::MAX);
+            let head = mgr.peek_backup(&name, version, max)?;
+            let text = match std::str::from_utf8(&head) {
+                Ok(s) => Some(s),
+                // Cut off in the middle of a character.
+                Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok(),
+                Err(_) => None,
+            };
+            match text.filter(|t| !t.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))) {
+                Some(t) if t.ends_with('\n') || t.is_empty() => print!("{t}"),
+                Some(t) => println!("{t}"),
+                None => print!("{}", hexdump(&head)),
+            }
+        }
+        Command::List { name, format, json } => {
+            let entries = mgr.list_backups(&name)?;
+            print!("{}", render_list(&entries, if json { ListFormat::Json } else { format }, mgr.config().time_zone));
+        }
+        Command::Find { substring, format, json } => {
+            let entries = mgr.find_backups(&substring)?;
+            print!("{}", render_list(&entries, if json { ListFormat::Json } else { format }, mgr.config().time_zone));
+        }
+        Command::Annotate { backup, text } => {
+            mgr.annotate(&backup, &text)?;
+            println!("{} {}", if text.is_empty() { "removed the note of" } else { "annotated" }, backup.display());
+        }
+        Command::Info { backup, json } => {
+            let (entry, meta) = mgr.backup_info(&backup)?;
+            if json {
+                let path = entry.path.to_string_lossy();
+                println!("{}", json!({ "path": path, "ts": entry.ts, "stored_size": entry.size, "meta": meta }));
+            } else {
+                println!("backup:    {}", entry.path.display());
+                println!("original:  {}", meta.original);
+                println!("made:      {}", mgr.config().time_zone.format(entry.ts));
+                println!("size:      {} ({} stored)", human_size(meta.size), human_size(entry.size));
+                println!("digest:    {}", meta.digest.as_deref().unwrap_or("none"));
+                let layout = json!(meta.layout);
+                println!("stored as: {}, {}", layout.as_str().unwrap_or_default(), meta.compression);
+                if let Some(note) = &meta.note {
+                    println!("note:      {note}");
+                }
+            }
+        }
+        Command::Verify { name, all, rehash, json } => {
+            let results: Vec<(PathBuf, io::Result<()>)> = if all {
+                if rehash {
+                    mgr.clear_digest_cache()?;
+                }
+                run_cancellable(cancel, || mgr.verify_all())?.into_iter().map(|(b, r)| (PathBuf::from(b), r)).collect()
+            } else {
+                let name = name.unwrap_or_default();
+                let backups: Vec<PathBuf> = match mgr.list_backups(&name)? {
+                    v if v.is_empty() => vec![name],
+                    v => v.into_iter().map(|e| PathBuf::from(e.path.file_name().unwrap())).collect(),
+                };
+                backups
+                    .into_iter()
+                    .map(|b| {
+                        let result = mgr.verify_backup(&b);
+                        (b, result)
+                    })
+                    .collect()
+            };
+            let mut all_ok = true;
+            for (b, result) in results {
+                all_ok &= result.is_ok();
+                let code = result.as_ref().err().map(error_code);
+                let error = result.err().map(|e| error_chain(&e));
+                if json {
+                    let ok = error.is_none();
+                    println!("{}", json!({ "backup": b.to_string_lossy(), "ok": ok, "code": code, "error": error }));
+                } else {
+                    match error {
+                        None => println!("ok\t{}", b.display()),
+                        Some(e) => println!("FAILED\t{}\t{e}", b.display()),
+                    }
+                }
+            }
+            return Ok(all_ok);
+        }
+        Command::Prune { name, dry_run, json } => {
+            let Some(keep) = mgr.config().keep_last else {
+                eprintln!("[error] prune needs --keep N (or SAFE_BACKUP_KEEP)");
+                return Ok(false);
+            };
+            if !dry_run {
+                let doomed = mgr.prune_backups(&name, keep, true)?.len();
+                let what = format!("Remove {doomed} backup(s) of {}", absolute(&mgr.validate_path(&name)?));
+                if doomed > 0 && !confirm(&what, yes)? {
+                    return Ok(false);
+                }
+            }
+            let removed = mgr.prune_backups(&name, keep, dry_run)?;
+            if json {
+                let paths: Vec<_> = removed.iter().map(|p| p.to_string_lossy()).collect();
+                println!("{}", json!({ "dry_run": dry_run, "removed": paths }));
+            } else {
+                for p in &removed {
+                    println!("{}{}", if dry_run { "would remove " } else { "removed " }, file_name(p));
+                }
+            }
+        }
+        Command::Gc { dry_run, json } => {
+            let report = mgr.gc(dry_run)?;
+            if json {
+                let paths: Vec<_> = report.removed.iter().map(|p| p.to_string_lossy()).collect();
+                let v = json!({ "dry_run": dry_run, "removed": paths, "bytes": report.bytes, "kept": report.kept });
+                println!("{v}");
+            } else {
+                let verb = if dry_run { "would remove" } else { "removed" };
+                let (n, size) = (report.removed.len(), human_size(report.bytes));
+                println!("{verb} {n} object(s), {size}; {} kept", report.kept);
+            }
+        }
+        Command::Clean { dry_run, json } => {
+            let found = mgr.find_strays()?;
+            if !dry_run && !found.removed.is_empty() {
+                if !json {
+                    for f in &found.removed {
+                        println!("{}\t{}", f.kind.describe(), f.path.display());
+                    }
+                }
+                let what = format!("Remove these {} file(s), {}", found.removed.len(), human_size(found.bytes));
+                if !confirm(&what, yes)? {
+                    return Ok(false);
+                }
+            }
+            let report = if dry_run { found } else { mgr.remove_strays(found)? };
+            if json {
+                let rows: Vec<_> = report
+                    .removed
+                    .iter()
+                    .map(|f| json!({ "path": f.path.to_string_lossy(), "kind": f.kind.describe(), "size": f.size }))
+                    .collect();
+                println!("{}", json!({ "dry_run": dry_run, "removed": rows, "bytes": report.bytes }));
+            } else {
+                if dry_run {
+                    for f in &report.removed {
+                        println!("{}\t{}", f.kind.describe(), f.path.display());
+                    }
+                }
+                let verb = if dry_run { "would remove" } else { "removed" };
+                let counts = [StrayKind::Restored, StrayKind::EmptyBackup, StrayKind::Temp]
+                    .map(|k| format!("{} {}(s)", report.count(k), k.describe()))
+                    .join(", ");
+                println!("{verb} {counts}; {} reclaimed", human_size(report.bytes));
+            }
+        }
+        Command::Migrate { dry_run, json } => {
+            let moves = mgr.migrate_legacy(dry_run)?;
+            if json {
+                let rows: Vec<_> = moves
+                    .iter()
+                    .map(|m| json!({ "from": m.from.to_string_lossy(), "to": m.to.to_string_lossy() }))
+                    .collect();
+                println!("{}", json!({ "dry_run": dry_run, "renamed": rows }));
+            } else {
+                let verb = if dry_run { "would rename" } else { "renamed" };
+                for m in &moves {
+                    println!("{verb}\t{}\t{}", file_name(&m.from), file_name(&m.to));
+                }
+                println!("{verb} {} legacy backup(s)", moves.len());
+            }
+        }
+        Command::MigrateLayout { dry_run, json } => {
+            let config = mgr.config();
+            if !config.tidy && !config.tidy_backups {
+                eprintln!("[error] migrate-layout needs --tidy and/or --tidy-backups (or \"tidy\" in the config file)");
+                return Ok(false);
+            }
+            let moves = mgr.migrate_layout(dry_run)?;
+            if json {
+                let rows: Vec<_> = moves
+                    .iter()
+                    .map(|m| json!({ "from": m.from.to_string_lossy(), "to": m.to.to_string_lossy() }))
+                    .collect();
+                println!("{}", json!({ "dry_run": dry_run, "moved": rows }));
+            } else {
+                let verb = if dry_run { "would move" } else { "moved" };
+                for m in &moves {
+                    println!("{verb}\t{}\t{}", file_name(&m.from), m.to.display());
+                }
+                println!("{verb} {} file(s)", moves.len());
+            }
+        }
+        Command::Compact { name, older_than, json } => {
+            let report = mgr.compact_backups(&name, older_than.saturating_mul(86_400))?;
+            if json {
+                let archive = report.archive.as_deref().map(|a| a.to_string_lossy());
+                let paths: Vec<_> = report.archived.iter().map(|e| e.path.to_string_lossy()).collect();
+                println!("{}", json!({ "archive": archive, "archived": paths }));
+            } else {
+                match &report.archive {
+                    Some(a) => println!("archived {} backup(s) into {}", report.archived.len(), file_name(a)),
+                    None => println!("nothing to archive"),
+                }
+            }
+        }
+        Command::Extract { archive } => {
+            let files = mgr.extract_archive(&archive)?;
+            println!("extracted {} file(s) from {}", files.len(), file_name(&archive));
+        }
+        Command::Stats { json } => {
+            let s = mgr.stats()?;
+            if json {
+                let mut v = serde_json::to_value(&s).map_err(io::Error::other)?;
+                v["average_per_original"] = json!(s.average_per_original());
+                println!("{v}");
+            } else {
+                print!("{}", format_stats(&s, mgr.config().time_zone));
+            }
+        }
+        Command::Log { verify: true, .. } => {
+            let n = mgr.verify_log_chain()?;
+            println!("ok\t{n} chained entries");
+        }
+        Command::Log { repair: true, .. } => {
+            let r = mgr.repair_log()?;
+            match r.backup {
+                Some(orig) => println!("kept {}, dropped {}; original saved as {}", r.kept, r.dropped, orig.display()),
+                None => println!("ok\t{} entries, nothing to repair", r.kept),
+            }
+        }
+        Command::Log { show: Some(LogCommand::Show { action, file, since, last, failed_only, json }), .. } => {
+            let mut filter = LogFilter::new().failed_only(failed_only);
+            if let Some(action) = action {
+                filter = filter.action(action);
+            }
+            if let Some(file) = file {
+                filter = filter.file(file);
+            }
+            if let Some(day) = since {
+                // Before 1970 where the day starts in the configured zone, e.g. 1970-01-01 east of UTC.
+                let ts = mgr.config().time_zone.start_of_day(day).ok_or_else(|| {
+                    let e = format!("--since {day}: starts before 1970 in this time zone");
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                filter = filter.since(ts);
+            }
+            if let Some(n) = last {
+                filter = filter.last(n);
+            }
+            let entries = mgr.read_log_filtered(&filter)?;
+            if json {
+                println!("{}", serde_json::Value::from(entries.iter().map(entry_json).collect::<Vec<_>>()));
+            } else {
+                print!("{}", render_log(&entries, mgr.config().time_zone));
+            }
+        }
+        Command::Log { show: Some(LogCommand::Prune), .. } => {
+            let report = mgr.prune_log()?;
+            for path in &report.compressed {
+                println!("compressed\t{}", path.display());
+            }
+            for path in &report.removed {
+                println!("removed\t{}", path.display());
+            }
+            if report.compressed.is_empty() && report.removed.is_empty() {
+                println!("ok\tnothing to prune");
+            }
+        }
+        Command::Log { json, .. } => {
+            let scan = mgr.scan_log()?;
+            for r in &scan.recovered {
+                let issues = r.issues.join("; ");
+                eprintln!("[warn] log line {} is damaged and was read as far as possible: {issues}", r.line);
+            }
+            for c in &scan.corrupt {
+                eprintln!(
+                    "[warn] log line {} is not a valid entry ({}) and was skipped (see `log --repair`)",
+                    c.line, c.reason
+                );
+            }
+            for e in scan.entries {
+                if json {
+                    println!("{}", entry_json(&e));
+                } else {
+                    println!("{}\t{}\t{}\t{}\t{}", e.ts, e.user, e.action, e.file, e.result);
+                }
+            }
+        }
+        Command::Checkpoint { action } => return run_checkpoint(mgr, yes, action),
+        Command::Trash { action: TrashCommand::Empty { older_than, json } } => {
+            let what = match older_than {
+                Some(age) => format!("Remove files trashed more than {}s ago for good", age.as_secs()),
+                None => "Remove every trashed file for good".to_string(),
+            };
+            if !confirm(&what, yes)? {
+                return Ok(false);
+            }
+            let report = mgr.empty_trash(older_than.map(|d| d.as_secs()))?;
+            if json {
+                println!("{}", json!({ "removed": report.removed, "bytes": report.bytes }));
+            } else {
+                println!("removed {} trashed file(s), {}", report.removed, human_size(report.bytes));
+            }
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "safe_backup", &mut io::stdout());
+        }
+        Command::Capabilities => {
+            let compression = [Compression::None, Compression::Gzip].map(|c| c.to_string());
+            let digests: Vec<String> = ["sha256", "sha1", "blake3", "crc32"]
+                .into_iter()
+                .filter_map(|d| d.parse::<DigestAlgo>().ok().map(|d| d.to_string()))
+                .collect();
+            let caps = json!({
+                "version": VERSION,
+                "features": capabilities(),
+                "compression": compression,
+                "digests": digests,
+            });
+            println!("{caps}");
+        }
+    }
+    Ok(true)
+}
+
+/// Output formats of `list`.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ListFormat {
+    /// Aligned columns with local times and sizes in KiB/MiB/...
+    Table,
+    /// One JSON array; times as Unix seconds, sizes in bytes
+    Json,
+    /// A header line, then one row per backup; times as Unix seconds and RFC 3339
+    Csv,
+}
+
+/// `entries` as `format`, newline-terminated.
+fn render_list(entries: &[BackupEntry], format: ListFormat, zone: TimeZone) -> String {
+    match format {
+        ListFormat::Json => {
+            let rows: Vec<_> = entries
+                .iter()
+                .map(|e| {
+                    let path = e.path.to_string_lossy();
+                    json!({ "path": path, "ts": e.ts, "size": e.size, "renamed_from": e.renamed_from, "note": e.note })
+                })
+                .collect();
+            format!("{}\n", serde_json::Value::from(rows))
+        }
+        ListFormat::Csv => {
+            let mut out = String::from("ts,time,size,name,renamed_from,note\n");
+            for e in entries {
+                let time = zone.rfc3339(e.ts).unwrap_or_default();
+                let from = csv_field(e.renamed_from.as_deref().unwrap_or_default());
+                let note = csv_field(e.note.as_deref().unwrap_or_default());
+                out += &format!("{},{time},{},{},{from},{note}\n", e.ts, e.size, csv_field(&file_name(&e.path)));
+            }
+            out
+        }
+        ListFormat::Table => {
+            let rows: Vec<[String; 4]> = entries
+                .iter()
+                .map(|e| {
+                    let name = match &e.renamed_from {
+                        Some(from) => format!("{} (as {from})", file_name(&e.path)),
+                        None => file_name(&e.path),
+                    };
+                    [zone.format(e.ts), human_size(e.size), name, e.note.clone().unwrap_or_default()]
+                })
+                .collect();
+            // The NOTE column only when some backup has a note.
+            let notes = entries.iter().any(|e| e.note.is_some());
+            let header = ["TIME", "SIZE", "NAME", "NOTE"].map(String::from);
+            let width = |i: usize| rows.iter().chain([&header]).map(|r| r[i].chars().count()).max().unwrap_or(0);
+            let (w0, w1, w2) = (width(0), width(1), width(2));
+            let mut out = String::new();
+            for [time, size, name, note] in [&header].into_iter().chain(&rows) {
+                if notes {
+                    out += format!("{time:<w0$}  {size:>w1$}  {name:<w2$}  {note}").trim_end();
+                    out.push('\n');
+                } else {
+                    out += &format!("{time:<w0$}  {size:>w1$}  {name}\n");
+                }
+            }
+            out
+        }
+    }
+}
+
+/// A log entry as printed by `log --json`.
+fn entry_json(e: &LogEntry) -> serde_json::Value {
+    json!({ "v": e.v, "ts": e.ts, "user": e.user, "action": e.action, "file": e.file, "result": e.result })
+}
+
+/// Log entries as an aligned table with times in `zone`, newline-terminated.
+fn render_log(entries: &[LogEntry], zone: TimeZone) -> String {
+    let rows: Vec<[String; 5]> = entries
+        .iter()
+        .map(|e| [zone.format(e.ts), e.user.clone(), e.action.clone(), e.file.clone(), e.result.clone()])
+        .collect();
+    let header = ["TIME", "USER", "ACTION", "FILE", "RESULT"].map(String::from);
+    let width = |i: usize| rows.iter().chain([&header]).map(|r| r[i].chars().count()).max().unwrap_or(0);
+    let (w0, w1, w2, w3) = (width(0), width(1), width(2), width(3));
+    let mut out = String::new();
+    for [time, user, action, file, result] in [&header].into_iter().chain(&rows) {
+        out += &format!("{time:<w0$}  {user:<w1$}  {action:<w2$}  {file:<w3$}  {result}\n");
+    }
+    out
+}
+
+/// `stats` as two aligned tables: the totals, then the largest backup sets.
+fn format_stats(s: &RepoStats, zone: TimeZone) -> String {
+    let time = |t: Option<u64>| t.map_or("-".to_string(), |t| zone.format(t));
+    let totals = [
+        ("Originals", s.originals.to_string()),
+        ("Backups", s.backups.to_string()),
+        ("Size on disk", human_size(s.bytes)),
+        ("Oldest backup", time(s.oldest)),
+        ("Newest backup", time(s.newest)),
+        ("Last 7 days", s.recent.to_string()),
+        ("Average per original", format!("{:.2}", s.average_per_original())),
+    ];
+    let w = totals.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for (k, v) in totals {
+        out += &format!("{k:<w$}  {v}\n");
+    }
+    if s.largest.is_empty() {
+        return out;
+    }
+    let rows: Vec<[String; 3]> =
+        s.largest.iter().map(|b| [human_size(b.bytes), b.backups.to_string(), b.name.clone()]).collect();
+    let header = ["SIZE".to_string(), "BACKUPS".to_string(), "NAME".to_string()];
+    let width = |i: usize| rows.iter().chain([&header]).map(|r| r[i].chars().count()).max().unwrap_or(0);
+    let (w0, w1) = (width(0), width(1));
+    out += "\nLargest backup sets\n";
+    for [size, count, name] in [&header].into_iter().chain(&rows) {
+        out += &format!("{size:>w0$}  {count:>w1$}  {name}\n");
+    }
+    out
+}
+
+/// `bytes` in B, KiB, MiB, ... with one decimal above bytes.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// `data` as lines of offset, 16 hex bytes and their printable ASCII.
+fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, row) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{b:02x}")).collect();
+        let printable = |b: u8| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' };
+        let ascii: String = row.iter().copied().map(printable).collect();
+        out += &format!("{:08x}  {:<47}  |{ascii}|\n", i * 16, hex.join(" "));
+    }
+    out
+}
+
+/// Quote a CSV field if it needs it (RFC 4180).
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// "90" (seconds), "45s", "30m", "1h" or "2d".
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = num.parse().map_err(|_| format!("expected e.g. 90, 30m or 1h, got {s:?}"))?;
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(format!("unknown unit {unit:?} (use s, m, h or d)")),
+    };
+    match n.checked_mul(secs) {
+        Some(0) => Err("the interval must be greater than zero".to_string()),
+        Some(total) => Ok(Duration::from_secs(total)),
+        None => Err(format!("interval {s:?} is too long")),
+    }
+}
+
+/// "2024-01-01", a day from 1970 on; its start depends on `--time-zone`.
+fn parse_date(s: &str) -> Result<chrono::NaiveDate, String> {
+    let date = chrono::NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("expected a date like 2024-01-01, got {s:?}"))?;
+    match TimeZone::Utc.start_of_day(date) {
+        Some(_) => Ok(date),
+        None => Err(format!("{s:?} is before 1970")),
+    }
+}
+
+/// "4096" (bytes), "64K", "512M", "1G" or "2T", in powers of 1024; "1GiB" and "1GB" mean 1G.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = num.parse().map_err(|_| format!("expected e.g. 4096, 512M or 1G, got {s:?}"))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let shift = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown unit {unit:?} (use K, M, G or T)")),
+    };
+    match n.checked_mul(1 << shift) {
+        Some(0) => Err("the size must be greater than zero".to_string()),
+        Some(total) => Ok(total),
+        None => Err(format!("size {s:?} is too large")),
+    }
+}
+
+/// Start this program again, minus `--daemon`, detached from the terminal in
+/// its own process group. Returns the child's process id.
+#[cfg(unix)]
+fn daemonize() -> io::Result<u32> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command as Process, Stdio};
+
+    let args = std::env::args_os().skip(1).filter(|a| a != "--daemon");
+    let child = Process::new(std::env::current_exe()?)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    Ok(child.id())
+}
+
+#[cfg(not(unix))]
+fn daemonize() -> io::Result<u32> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--daemon is only supported on Unix; run in the foreground"))
+}
+
+/// Whether `name` is a glob pattern rather than a file that is there.
+fn is_glob(name: &Path) -> bool {
+    name.to_str().is_some_and(|n| n.contains(['*', '?', '['])) && !name.exists()
+}
+
+/// Names as given, with glob patterns replaced by the files they match.
+fn expand_globs(names: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    for name in names {
+        if !name.contains(['*', '?', '[']) {
+            out.push(PathBuf::from(name));
+            continue;
+        }
+        let matches = glob::glob(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{name}: {e}")))?;
+        let before = out.len();
+        out.extend(matches.filter_map(Result::ok).filter(|p| p.is_file()));
+        if out.len() == before {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{name}: no files match")));
+        }
+    }
+    Ok(out)
+}
+
+/// Config from the command line, the environment and the config file, by
+/// precedence, and the SFTP server if one is set up.
+fn load_config(cli: &Cli) -> io::Result<(Config, Option<SftpConfig>)> {
+    let flags = Settings {
+        backup_dir: cli.backup_dir.clone(),
+        log: cli.log.clone(),
+        keep: cli.keep,
+        compress: cli.compress,
+        exclude: None,
+        sftp: None,
+        tidy: cli.tidy.then_some(true),
+        tidy_backups: cli.tidy_backups.then_some(true),
+        plain: None,
+        log_user: cli.log_user,
+        log_audit: cli.log_audit.then_some(true),
+        time_zone: cli.time_zone,
+        pre_hook: cli.pre_hook.clone(),
+        post_hook: cli.post_hook.clone(),
+    };
+    let env = std::env::vars().collect();
+    let mut settings = resolve_settings(flags, &env, Settings::from_file(&cli.config)?)?;
+    let sftp = SftpConfig::resolve(&env, settings.sftp.take())?;
+    let config = settings.apply(Config {
+        max_age_secs: cli.max_age.map(|days| days.saturating_mul(86_400)),
+        min_age_secs: cli.min_age.map(|d| d.as_secs()),
+        timeout: cli.timeout,
+        log_max_bytes: cli.log_max_size,
+        log_archives: cli.log_archives,
+        verify_writes: cli.verify_writes,
+        paranoid: cli.paranoid,
+        preserve_xattrs: cli.xattrs,
+        preserve_acls: cli.acls,
+        digest: cli.digest.unwrap_or_default(),
+        chunk_size: cli.chunk_size,
+        dedup: cli.dedup,
+        legacy_bk: cli.legacy_bk,
+        // Hooks run in the terminal, as any program the user starts would.
+        hook_output: HookOutput::Inherit,
+        ..Config::default()
+    });
+    Ok((config, sftp))
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let (config, sftp) = match load_config(&cli) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("[error] {}", error_chain(&e));
+            return ExitCode::from(2);
+        }
+    };
+    let cancel = CancellationToken::new();
+    let flag = cancel.clone();
+    let handler = ctrlc::set_handler(move || {
+        if RUNNING.load(Ordering::SeqCst) {
+            flag.cancel();
+        } else {
+            std::process::exit(130);
+        }
+    });
+    if let Err(e) = handler {
+        eprintln!("[warn] Ctrl-C will not cancel cleanly: {e}");
+    }
+    let mgr = BackupManager::new(Config {
+        cancel: Some(cancel.clone()),
+        on_event: Some(Arc::new(print_event)),
+        ..config
+    });
+
+    let json = cli.command.as_ref().is_some_and(Command::json);
+    let result = match cli.command {
+        None => interactive(&mgr, &cancel, cli.yes),
+        Some(command) => run(&mgr, &cancel, sftp.as_ref(), cli.yes, cli.quiet, command),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("[error] {}", error_chain(&e));
+            if json {
+                println!("{}", json!({ "ok": false, "code": error_code(&e), "error": error_chain(&e) }));
+            }
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_formats() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+        assert_eq!(csv_field("a,b \"c\""), "\"a,b \"\"c\"\"\"");
+
+        let entries = [
+            BackupEntry {
+                path: "n.txt.1700000000.bak".into(),
+                ts: 1_700_000_000,
+                size: 5,
+                renamed_from: None,
+                note: None,
+            },
+            BackupEntry {
+                path: "m.txt.1600000000.bak".into(),
+                ts: 1_600_000_000,
+                size: 123_456,
+                renamed_from: Some("m.txt".into()),
+                note: Some("before TLS, again".into()),
+            },
+        ];
+        let table = render_list(&entries, ListFormat::Table, TimeZone::Utc);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        // Columns line up: every name starts at the same offset.
+        let col = lines[0].find("NAME").unwrap();
+        assert!(lines[1..].iter().all(|l| l[col..].contains(".txt.")), "{table}");
+        assert!(lines[2].ends_with("m.txt.1600000000.bak (as m.txt)  before TLS, again"), "{table}");
+        assert!(lines[0].ends_with("NOTE") && lines[1].ends_with(".txt.1700000000.bak"), "{table}");
+        assert!(lines[2].contains("120.6 KiB"), "{table}");
+        assert!(lines[1].starts_with("2023-11-14 22:13:20"), "{table}");
+
+        let csv = render_list(&entries, ListFormat::Csv, TimeZone::Utc);
+        assert!(csv.starts_with("ts,time,size,name,renamed_from,note\n1700000000,2023-11-14T22:13:20+00:00,"), "{csv}");
+        let last = csv.lines().nth(2).unwrap();
+        assert!(last.ends_with(",123456,m.txt.1600000000.bak,m.txt,\"before TLS, again\""), "{csv}");
+        let json = render_list(&entries, ListFormat::Json, TimeZone::Utc);
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json[1]["size"], 123_456);
+        assert_eq!((&json[0]["renamed_from"], &json[1]["renamed_from"]), (&serde_json::Value::Null, &json!("m.txt")));
+        assert_eq!(json[1]["note"], "before TLS, again");
+    }
+
+    #[test]
+    fn hexdump_rows() {
+        let dump = hexdump(b"\x00\x01hello, world!\xff\x7fAB");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "00000000  00 01 68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 ff  |..hello, world!.|");
+        assert_eq!(lines[1], format!("00000010  7f 41 42{}  |.AB|", " ".repeat(39)));
+        assert_eq!(hexdump(b""), "");
+    }
+
+    #[test]
+    fn confirmation() {
+        assert!(confirm_from("Delete x", false, Some(&b"y\n"[..])).unwrap());
+        assert!(confirm_from("Delete x", false, Some(&b" YES \n"[..])).unwrap());
+        assert!(!confirm_from("Delete x", false, Some(&b"\n"[..])).unwrap());
+        assert!(!confirm_from("Delete x", false, Some(&b""[..])).unwrap());
+        // No terminal: refuse, unless told to go ahead.
+        assert!(!confirm_from("Delete x", false, None::<&[u8]>).unwrap());
+        assert!(confirm_from("Delete x", true, None::<&[u8]>).unwrap());
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_interval("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_interval("2d"), Ok(Duration::from_secs(172_800)));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("1w").unwrap_err().contains("unknown unit"));
+        assert!(parse_interval("h").is_err());
+    }
+
+    #[test]
+    fn dates() {
+        let day = parse_date("2024-01-02").unwrap();
+        assert_eq!(day, chrono::NaiveDate::from_ymd_opt(2024, 1, 2).unwrap());
+        assert_eq!(TimeZone::Utc.start_of_day(day), Some(1_704_153_600));
+        assert_eq!(TimeZone::Local.format(TimeZone::Local.start_of_day(day).unwrap()), "2024-01-02 00:00:00");
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("1969-12-31").unwrap_err().contains("before 1970"));
+    }
+
+    #[test]
+    fn log_table_is_aligned() {
+        let entry = |user: &str, action: &str, file: &str, result: &str| LogEntry {
+            v: 2,
+            ts: 0,
+            user: user.into(),
+            action: action.into(),
+            file: file.into(),
+            result: result.into(),
+            prev_hash: None,
+        };
+        let entries = [entry("ci", "backup", "notes.txt", "ok"), entry("alice", "delete", "a", "ok")];
+        let table = render_log(&entries, TimeZone::Utc);
+        assert!(table.lines().nth(1).unwrap().starts_with("1970-01-01 00:00:00  ci"), "{table}");
+        let cols: Vec<&str> = table.lines().map(|l| &l[19..]).collect();
+        assert_eq!(
+            cols,
+            ["  USER   ACTION  FILE       RESULT", "  ci     backup  notes.txt  ok", "  alice  delete  a          ok"]
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_size("512m"), Ok(512 << 20));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("2GB"), Ok(2 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("1P").unwrap_err().contains("unknown unit"));
+        assert!(parse_size("99999999T").unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn stats_table_is_aligned() {
+        let empty = format_stats(&RepoStats::default(), TimeZone::Local);
+        assert!(empty.contains("Backups               0\n"));
+        assert!(empty.contains("Oldest backup         -\n"));
+        assert!(!empty.contains("Largest"));
+
+        let s = RepoStats {
+            backups: 3,
+            largest: vec![
+                safe_backup::BackupSet { name: "big.iso".into(), backups: 1, bytes: 2048 },
+                safe_backup::BackupSet { name: "a.txt".into(), backups: 12, bytes: 10 },
+            ],
+            ..RepoStats::default()
+        };
+        let table = format_stats(&s, TimeZone::Local);
+        let tail: Vec<&str> = table.lines().skip_while(|l| !l.starts_with("Largest")).skip(1).collect();
+        assert_eq!(tail, ["   SIZE  BACKUPS  NAME", "2.0 KiB        1  big.iso", "   10 B       12  a.txt"]);
+    }
 }
@@ -0,0 +1,395 @@
+//! `<backup>.meta.json` sidecars. They mark backups made by this tool, so a
+//! user file that merely looks like "<name>.<ts>.bak" is never picked for a
+//! restore, and record where the original lived and what it looked like.
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compress::Compression;
+use crate::digest::{read_digest, DigestAlgo};
+use crate::error::Context;
+use crate::format::{is_newer_format, parse_versioned, unversioned, FORMAT_VERSION};
+use crate::naming::display_name;
+use crate::acls::Acl;
+use crate::vfs::{FileStat, FileSystem, RealFs};
+use crate::xattrs::Xattrs;
+
+/// Value of the `tool` field; a sidecar without it does not count.
+const MAGIC: &str = "safe_backup/1";
+
+/// Contents of a `<backup>.meta.json` sidecar.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupMeta {
+    /// Format the sidecar is written in; 1 in sidecars from before the field.
+    #[serde(default = "unversioned")]
+    pub format_version: u32,
+    /// Always `"safe_backup/1"`.
+    pub tool: String,
+    /// Name the backup was made from, relative to the root (`\xNN`-escaped if not UTF-8).
+    pub original: String,
+    /// Absolute path of the original at backup time.
+    pub path: String,
+    /// Size in bytes; 0 for directory backups.
+    pub size: u64,
+    /// Modification time of the original, seconds and nanoseconds since the epoch.
+    pub mtime: u64,
+    pub mtime_nanos: u32,
+    /// Permission bits (`st_mode & 0o7777`); on Windows 0o444 or 0o666 for read-only or not.
+    pub mode: u32,
+    /// Digest of the backup's contents as stored, tagged with its algorithm
+    /// ("sha256:<hex>", "blake3:<hex>", ...); `None` for directory backups.
+    /// Untagged in sidecars from before the tag, where it was always SHA-256.
+    #[serde(alias = "sha256")]
+    pub digest: Option<String>,
+    /// How the contents are stored; absent in sidecars from before compression.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Extended attributes of the original, name → hex value; recorded only
+    /// with `Config::preserve_xattrs`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    /// Access ACL entries of the original beyond the mode bits, like
+    /// "user:1000:r--"; recorded only with `Config::preserve_acls`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl: Vec<String>,
+    /// Where the contents are; for anything but `Whole`, `digest` is that of
+    /// the index or manifest the backup file holds.
+    #[serde(default, skip_serializing_if = "Layout::is_whole")]
+    pub layout: Layout,
+    /// For a `Delta` backup, the file name of the backup it extends, in the same directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// For a directory backup, each file in the tree ("/"-separated, relative
+    /// to it) with its tagged digest, sorted by path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<String, String>,
+    /// Why the backup was made (`BackupOptions::note`), with control
+    /// characters escaped; changed with [`annotate`](crate::BackupManager::annotate).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// How a file backup's contents are laid out on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// The backup file holds the contents.
+    #[default]
+    Whole,
+    /// The backup file is an index of pieces "<backup>.part0001", ... (`Config::chunk_size`).
+    Chunked,
+    /// The backup file is a manifest of objects in the shared store (`Config::dedup`).
+    Deduped,
+    /// The backup file "<name>.<ts>.delta" holds only what was appended since
+    /// the backup named by `base` ([`BackupManager::backup_append_delta`](crate::BackupManager::backup_append_delta)).
+    Delta,
+}
+
+impl Layout {
+    fn is_whole(&self) -> bool {
+        *self == Self::Whole
+    }
+}
+
+/// Just enough of a sidecar to tell ours from anything else.
+#[derive(Deserialize)]
+struct Marker {
+    tool: String,
+    #[serde(default)]
+    original: String,
+}
+
+/// "<backup>.meta.json" next to `backup`.
+pub(crate) fn sidecar_for(backup: &Path) -> PathBuf {
+    let mut name = OsString::from(backup.as_os_str());
+    name.push(".meta.json");
+    PathBuf::from(name)
+}
+
+/// Load the sidecar of `backup`. Fails with NotFound if there is none,
+/// InvalidData if it isn't one of ours and `BackupError::NewerFormat` if a
+/// newer version wrote it.
+pub fn read_backup_meta(backup: impl AsRef<Path>) -> io::Result<BackupMeta> {
+    read_meta(&RealFs, backup.as_ref())
+}
+
+/// [`read_backup_meta`] on `fs`.
+pub(crate) fn read_meta(fs: &dyn FileSystem, backup: &Path) -> io::Result<BackupMeta> {
+    let path = sidecar_for(backup);
+    let text = fs.read_to_string(&path).at("meta", "cannot read metadata", &path)?;
+    let meta: BackupMeta = parse_versioned("meta", "malformed metadata", &path, &text)?;
+    if meta.tool != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown tool {:?}", meta.tool)))
+            .at("meta", "malformed metadata", &path);
+    }
+    Ok(meta)
+}
+
+/// The sidecar of `backup`, or `None` if it has none that is ours. One a
+/// newer version wrote is still an error: what it records isn't guessed at.
+pub(crate) fn sidecar_if_any(fs: &dyn FileSystem, backup: &Path) -> io::Result<Option<BackupMeta>> {
+    match read_meta(fs, backup) {
+        Ok(meta) => Ok(Some(meta)),
+        Err(e) if is_newer_format(&e) => Err(e),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The digest to record for a file backup: taken by reading the backup, or
+/// already known from writing it in one pass with
+/// [`copy_hashed`](crate::vfs::FileSystem::copy_hashed).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BackupDigest {
+    Read(DigestAlgo),
+    Known(String),
+}
+
+impl From<DigestAlgo> for BackupDigest {
+    fn from(algo: DigestAlgo) -> Self {
+        Self::Read(algo)
+    }
+}
+
+/// Permissions recorded for a backup that has no original file (read from a stream).
+const STREAM_MODE: u32 = 0o644;
+
+/// Record `original` (named `name`) as the source of the just-written `backup`.
+/// Files get a digest of the backup, read from it unless already known;
+/// directories only their stats.
+/// Returns the recorded size.
+pub(crate) fn write_meta(
+    fs: &dyn FileSystem,
+    op: &'static str,
+    backup: &Path,
+    name: &Path,
+    original: &Path,
+    compression: Compression,
+    digest: impl Into<BackupDigest>,
+) -> io::Result<u64> {
+    write_meta_for(fs, op, backup, name, original, compression, digest, Layout::Whole, None)
+}
+
+/// [`write_meta`] for a backup laid out as `layout`. With `before`, the
+/// stat of `original` taken before it was copied, the mtime recorded is
+/// that one, so a change made while copying shows as a change.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_meta_for(
+    fs: &dyn FileSystem,
+    op: &'static str,
+    backup: &Path,
+    name: &Path,
+    original: &Path,
+    compression: Compression,
+    digest: impl Into<BackupDigest>,
+    layout: Layout,
+    before: Option<&FileStat>,
+) -> io::Result<u64> {
+    let md = fs.metadata(original).at(op, "cannot stat", original)?;
+    let modified = before.map_or(md.modified, |b| b.modified);
+    let mtime = modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    let digest = match (md.is_file(), digest.into()) {
+        (false, _) => None,
+        (true, BackupDigest::Known(digest)) => Some(digest),
+        (true, BackupDigest::Read(algo)) => {
+            Some(fs.open(backup).and_then(|f| read_digest(f, algo)).at(op, "cannot read", backup)?)
+        }
+    };
+    let abs = std::path::absolute(original).unwrap_or_else(|_| original.to_path_buf());
+    let meta = BackupMeta {
+        format_version: FORMAT_VERSION,
+        tool: MAGIC.to_string(),
+        original: display_name(name.as_os_str()).into_owned(),
+        path: display_name(abs.as_os_str()).into_owned(),
+        size: if md.is_file() { md.len } else { 0 },
+        mtime: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos(),
+        mode: md.mode,
+        digest,
+        compression,
+        xattrs: Xattrs::new(),
+        acl: Vec::new(),
+        layout,
+        base: None,
+        files: BTreeMap::new(),
+        note: None,
+    };
+    store_meta(fs, op, backup, &meta)?;
+    Ok(meta.size)
+}
+
+/// Record a `backup` of `size` bytes read from a stream rather than a file.
+/// It is filed under `name` as if backed up from `original` at time `now`,
+/// with mode 0o644, so restoring it creates an ordinary file. Only `backup` does this.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_stream_meta(
+    fs: &dyn FileSystem,
+    backup: &Path,
+    name: &Path,
+    original: &Path,
+    size: u64,
+    now: u64,
+    compression: Compression,
+    digest: DigestAlgo,
+) -> io::Result<()> {
+    let abs = std::path::absolute(original).unwrap_or_else(|_| original.to_path_buf());
+    let meta = BackupMeta {
+        format_version: FORMAT_VERSION,
+        tool: MAGIC.to_string(),
+        original: display_name(name.as_os_str()).into_owned(),
+        path: display_name(abs.as_os_str()).into_owned(),
+        size,
+        mtime: now,
+        mtime_nanos: 0,
+        mode: STREAM_MODE,
+        digest: Some(fs.open(backup).and_then(|f| read_digest(f, digest)).at("backup", "cannot read", backup)?),
+        compression,
+        xattrs: Xattrs::new(),
+        acl: Vec::new(),
+        layout: Layout::Whole,
+        base: None,
+        files: BTreeMap::new(),
+        note: None,
+    };
+    store_meta(fs, "backup", backup, &meta)
+}
+
+/// Add the original's extended attributes to the sidecar of `backup`.
+pub(crate) fn record_xattrs(fs: &dyn FileSystem, op: &'static str, backup: &Path, xattrs: &Xattrs) -> io::Result<()> {
+    let mut meta = read_meta(fs, backup)?;
+    meta.xattrs = xattrs.clone();
+    store_meta(fs, op, backup, &meta)
+}
+
+/// Record `note`, escaped, in the sidecar of `backup`; `None` removes it.
+pub(crate) fn record_note(
+    fs: &dyn FileSystem,
+    op: &'static str,
+    backup: &Path,
+    note: Option<String>,
+) -> io::Result<()> {
+    let mut meta = read_meta(fs, backup)?;
+    meta.note = note;
+    store_meta(fs, op, backup, &meta)
+}
+
+/// Add the original's access ACL to the sidecar of `backup`.
+pub(crate) fn record_acl(fs: &dyn FileSystem, op: &'static str, backup: &Path, acl: &Acl) -> io::Result<()> {
+    let mut meta = read_meta(fs, backup)?;
+    meta.acl = acl.clone();
+    store_meta(fs, op, backup, &meta)
+}
+
+/// Add the files of the directory backup `backup` and their digests to its sidecar.
+pub(crate) fn record_files(
+    fs: &dyn FileSystem,
+    op: &'static str,
+    backup: &Path,
+    files: BTreeMap<String, String>,
+) -> io::Result<()> {
+    let mut meta = read_meta(fs, backup)?;
+    meta.files = files;
+    store_meta(fs, op, backup, &meta)
+}
+
+/// Mark the delta `backup` as extending `base`, its contents then being `size` bytes in all.
+pub(crate) fn record_base(
+    fs: &dyn FileSystem,
+    op: &'static str,
+    backup: &Path,
+    base: &Path,
+    size: u64,
+) -> io::Result<()> {
+    let mut meta = read_meta(fs, backup)?;
+    meta.base = Some(display_name(base.file_name().unwrap_or(base.as_os_str())).into_owned());
+    meta.size = size;
+    store_meta(fs, op, backup, &meta)
+}
+
+pub(crate) fn store_meta(fs: &dyn FileSystem, op: &'static str, backup: &Path, meta: &BackupMeta) -> io::Result<()> {
+    let path = sidecar_for(backup);
+    let json = serde_json::to_string(meta).map_err(io::Error::other).at(op, "cannot write metadata", &path)?;
+    fs.write(&path, json.as_bytes()).at(op, "cannot write metadata", &path)
+}
+
+/// Put the recorded permissions and mtime back on a restored file.
+pub(crate) fn apply_meta(fs: &dyn FileSystem, op: &'static str, meta: &BackupMeta, dest: &Path) -> io::Result<()> {
+    let mtime = UNIX_EPOCH + Duration::new(meta.mtime, meta.mtime_nanos);
+    fs.set_modified(dest, mtime).at(op, "cannot set modification time", dest)?;
+    fs.set_mode(dest, meta.mode).at(op, "cannot set permissions", dest)
+}
+
+/// Whether `backup` has a valid marker sidecar. Unreadable or foreign sidecars don't count.
+pub(crate) fn is_tool_backup(fs: &dyn FileSystem, backup: &Path) -> bool {
+    marker(fs, backup).is_some()
+}
+
+/// Whether `backup` has a valid marker sidecar recording an original whose
+/// file name is `base`, so a backup of "notes.txt.bak" never passes for one
+/// of "notes.txt" although its name starts the same.
+pub(crate) fn is_tool_backup_of(fs: &dyn FileSystem, backup: &Path, base: &OsStr) -> bool {
+    marker(fs, backup).is_some_and(|m| Path::new(&m.original).file_name() == Some(OsStr::new(&*display_name(base))))
+}
+
+/// The original `backup`'s marker sidecar records, if it has a valid one.
+pub(crate) fn tool_backup_original(fs: &dyn FileSystem, backup: &Path) -> Option<String> {
+    marker(fs, backup).map(|m| m.original)
+}
+
+fn marker(fs: &dyn FileSystem, backup: &Path) -> Option<Marker> {
+    let m: Marker = serde_json::from_str(&fs.read_to_string(&sidecar_for(backup)).ok()?).ok()?;
+    (m.tool == MAGIC).then_some(m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn only_our_sidecars_count() {
+        let tmp = tempfile::tempdir().unwrap();
+        let orig = tmp.path().join("data");
+        let bak = tmp.path().join("data.1.bak");
+        fs::write(&orig, "x").unwrap();
+        fs::write(&bak, "x").unwrap();
+        assert!(!is_tool_backup(&RealFs, &bak));
+        assert_eq!(read_backup_meta(&bak).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        fs::write(sidecar_for(&bak), r#"{"tool":"other","original":"data"}"#).unwrap();
+        assert!(!is_tool_backup(&RealFs, &bak));
+        fs::write(sidecar_for(&bak), "not json").unwrap();
+        assert!(!is_tool_backup(&RealFs, &bak));
+        assert_eq!(read_backup_meta(&bak).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        write_meta(&RealFs, "backup", &bak, Path::new("data"), &orig, Compression::None, DigestAlgo::Sha256).unwrap();
+        assert!(is_tool_backup(&RealFs, &bak));
+        assert_eq!(sidecar_for(&bak), tmp.path().join("data.1.bak.meta.json"));
+    }
+
+    #[test]
+    fn records_stats_and_digest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let orig = tmp.path().join("abc.txt");
+        fs::write(&orig, "abc").unwrap();
+        let bak = tmp.path().join("abc.txt.1.bak");
+        fs::copy(&orig, &bak).unwrap();
+        let abc = Path::new("abc.txt");
+        write_meta(&RealFs, "backup", &bak, abc, &orig, Compression::None, DigestAlgo::Sha256).unwrap();
+
+        let meta = read_backup_meta(&bak).unwrap();
+        assert_eq!(meta.original, "abc.txt");
+        assert_eq!(PathBuf::from(&meta.path), std::path::absolute(&orig).unwrap());
+        assert_eq!(meta.size, 3);
+        assert_eq!(
+            meta.digest.as_deref(),
+            Some("sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        let mtime = fs::metadata(&orig).unwrap().modified().unwrap().duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!((meta.mtime, meta.mtime_nanos), (mtime.as_secs(), mtime.subsec_nanos()));
+    }
+}
@@ -0,0 +1,266 @@
+//! Backup file naming. Names stay `OsStr` throughout so non-UTF-8 file names
+//! work; they are only turned into text for logs and messages (`display_name`).
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::invalid;
+
+/// Trim surrounding whitespace. Only UTF-8 names can be trimmed; others are returned as-is.
+pub(crate) fn trim_name(name: &Path) -> &Path {
+    match name.to_str() {
+        Some(s) => Path::new(s.trim()),
+        None => name,
+    }
+}
+
+/// Printable form of a name for logs and messages. Bytes that are not valid
+/// UTF-8 are shown as `\xNN`, so a lossy conversion is always visible.
+pub(crate) fn display_name(name: &OsStr) -> Cow<'_, str> {
+    if let Some(s) = name.to_str() {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::new();
+    for chunk in name.as_encoded_bytes().utf8_chunks() {
+        out.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{b:02X}"));
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// The name [`display_name`] showed as `text`, with its `\xNN` escapes
+/// turned back into the bytes they stand for. Text that isn't how any name
+/// would be shown, such as a literal "\x41", is the name itself.
+#[cfg(unix)]
+pub(crate) fn name_from_display(text: &str) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    let (src, mut bytes) = (text.as_bytes(), Vec::new());
+    let mut i = 0;
+    while i < src.len() {
+        let hex = src[i..].strip_prefix(b"\\x").and_then(|h| std::str::from_utf8(h.get(..2)?).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(b) => {
+                bytes.push(b);
+                i += 4;
+            }
+            None => {
+                bytes.push(src[i]);
+                i += 1;
+            }
+        }
+    }
+    let name = OsString::from_vec(bytes);
+    if display_name(&name) == text {
+        name
+    } else {
+        OsString::from(text)
+    }
+}
+
+/// Names are always UTF-8 here, so never shown escaped.
+#[cfg(not(unix))]
+pub(crate) fn name_from_display(text: &str) -> OsString {
+    OsString::from(text)
+}
+
+/// Canonical text of a name relative to the root: its components as
+/// [`display_name`] shows them, joined by "/" whatever the platform.
+pub(crate) fn logical_name(rel: &Path) -> String {
+    rel.components().map(|c| display_name(c.as_os_str())).collect::<Vec<_>>().join("/")
+}
+
+/// Last component of `original_name`, the base all its backups are named after.
+pub(crate) fn base_name(original_name: &Path) -> io::Result<&OsStr> {
+    original_name.file_name().ok_or_else(|| invalid(original_name, "no file name component"))
+}
+
+/// Build timestamped "<name>.<ts>.bak" in `root`.
+pub(crate) fn ts_backup_for(root: &Path, original_name: &Path, ts: u64) -> io::Result<PathBuf> {
+    let mut name = OsString::from(base_name(original_name)?);
+    name.push(format!(".{ts}.bak"));
+    Ok(root.join(name))
+}
+
+/// Build append-only "<name>.<ts>.delta" in `root`.
+pub(crate) fn delta_backup_for(root: &Path, original_name: &Path, ts: u64) -> io::Result<PathBuf> {
+    let mut name = OsString::from(base_name(original_name)?);
+    name.push(format!(".{ts}.delta"));
+    Ok(root.join(name))
+}
+
+/// Build "<name>.archive.<ts>.tar.gz" in `root`, holding compacted old backups.
+pub(crate) fn archive_for(root: &Path, original_name: &Path, ts: u64) -> io::Result<PathBuf> {
+    let mut name = OsString::from(base_name(original_name)?);
+    name.push(format!(".archive.{ts}.tar.gz"));
+    Ok(root.join(name))
+}
+
+/// Build convenience "name.bak" in `root`. A name with one extension drops
+/// it ("report.txt" → "report.bak"); any other keeps the whole name, as do
+/// dotfiles: "archive.tar.gz" → "archive.tar.gz.bak", "app.min.js" →
+/// "app.min.js.bak", ".env.local" → ".env.local.bak". With `keep_extension`
+/// every name is kept whole (so "report.txt" and "report.csv" don't collide).
+pub(crate) fn plain_backup_for(root: &Path, original_name: &Path, keep_extension: bool) -> io::Result<PathBuf> {
+    let whole = original_name.file_name();
+    let single = whole.map(OsStr::as_encoded_bytes).is_some_and(|n| {
+        !n.starts_with(b".") && n.iter().filter(|b| **b == b'.').count() == 1
+    });
+    let base = if keep_extension || !single { whole } else { original_name.file_stem() };
+    let mut name = OsString::from(base.unwrap_or(original_name.as_os_str()));
+    name.push(".bak");
+    Ok(root.join(name))
+}
+
+/// Where [`plain_backup_for`] put the plain copy of a name with several
+/// extensions before they were kept: "<stem>.bak", e.g. "archive.tar.bak"
+/// for "archive.tar.gz". `None` where the name is the same now.
+pub(crate) fn old_plain_backup_for(root: &Path, original_name: &Path) -> Option<PathBuf> {
+    let mut name = original_name.file_stem()?.to_os_string();
+    name.push(".bak");
+    let old = root.join(name);
+    (Some(&old) != plain_backup_for(root, original_name, false).ok().as_ref()).then_some(old)
+}
+
+/// Timestamp of "<base>.<...>.<ts>.bak" (or of a "<base>.<ts>.delta") when
+/// `fname` is a timestamped backup of `base`.
+pub(crate) fn parse_ts_backup(fname: &OsStr, base: &OsStr) -> Option<u64> {
+    let bytes = fname.as_encoded_bytes();
+    let rest = bytes.strip_prefix(base.as_encoded_bytes())?.strip_prefix(b".")?;
+    let rest = rest.strip_suffix(b".bak").or_else(|| rest.strip_suffix(b".delta"))?;
+    let ts = rest.rsplit(|b| *b == b'.').next()?;
+    if ts.is_empty() || !ts.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(ts).ok()?.parse().ok()
+}
+
+/// Split a backup file name into (logical original name, timestamp):
+/// "<orig>.<ts>.bak" and "<orig>.<ts>.delta" → (orig, Some(ts)); "<stem>.bak" → (stem, None).
+pub(crate) fn split_backup_name(fname: &OsStr) -> Option<(&OsStr, Option<u64>)> {
+    let p = Path::new(fname);
+    if p.extension() == Some(OsStr::new("delta")) {
+        let inner = Path::new(p.file_stem()?);
+        let ts = inner.extension()?.to_str()?.parse::<u64>().ok()?;
+        return Some((inner.file_stem()?, Some(ts)));
+    }
+    if p.extension() != Some(OsStr::new("bak")) {
+        return None;
+    }
+    let stem = p.file_stem()?;
+    let inner = Path::new(stem);
+    let ts = inner.extension().and_then(OsStr::to_str).and_then(|t| t.parse::<u64>().ok());
+    match (ts, inner.file_stem()) {
+        (Some(ts), Some(logical)) => Some((logical, Some(ts))),
+        _ => Some((stem, None)),
+    }
+}
+
+/// Split a legacy backup name from the old tool into (original name, time):
+/// "<orig>.YYYYMMDDHHMMSS.bk" → (orig, Some(ts)), the date read as local time;
+/// "<orig>.bk" → (orig, None). Unlike "<stem>.bak", both name the whole original.
+pub(crate) fn split_legacy_name(fname: &OsStr) -> Option<(&OsStr, Option<u64>)> {
+    let p = Path::new(fname);
+    if p.extension() != Some(OsStr::new("bk")) {
+        return None;
+    }
+    let stem = p.file_stem()?;
+    let inner = Path::new(stem);
+    match (inner.extension().and_then(OsStr::to_str).and_then(legacy_time), inner.file_stem()) {
+        (Some(ts), Some(logical)) => Some((logical, Some(ts))),
+        _ => Some((stem, None)),
+    }
+}
+
+/// Legacy backup of `base` named `fname`: `Some(Some(ts))` when dated, `Some(None)` when not.
+pub(crate) fn parse_legacy_backup(fname: &OsStr, base: &OsStr) -> Option<Option<u64>> {
+    split_legacy_name(fname).filter(|(logical, _)| *logical == base).map(|(_, ts)| ts)
+}
+
+/// Seconds since the epoch of a local "YYYYMMDDHHMMSS"; the earlier instant
+/// when a DST change makes it ambiguous, UTC when the local time was skipped.
+fn legacy_time(s: &str) -> Option<u64> {
+    use chrono::{NaiveDate, TimeZone};
+    if s.len() != 14 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n = |r: std::ops::Range<usize>| s[r].parse::<u32>().ok();
+    let date = NaiveDate::from_ymd_opt(i32::try_from(n(0..4)?).ok()?, n(4..6)?, n(6..8)?)?;
+    let local = date.and_hms_opt(n(8..10)?, n(10..12)?, n(12..14)?)?;
+    let utc = local.and_utc().timestamp();
+    let ts = chrono::Local.from_local_datetime(&local).earliest().map_or(utc, |t| t.timestamp());
+    u64::try_from(ts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_split() {
+        let base = OsStr::new("notes.txt");
+        assert_eq!(parse_ts_backup(OsStr::new("notes.txt.1700000000.bak"), base), Some(1_700_000_000));
+        assert_eq!(parse_ts_backup(OsStr::new("notes.txt.bak"), base), None);
+        assert_eq!(parse_ts_backup(OsStr::new("notes.txt.x.bak"), base), None);
+        assert_eq!(parse_ts_backup(OsStr::new("other.txt.1.bak"), base), None);
+
+        assert_eq!(split_backup_name(OsStr::new("notes.txt.42.bak")), Some((OsStr::new("notes.txt"), Some(42))));
+        assert_eq!(split_backup_name(OsStr::new("notes.bak")), Some((OsStr::new("notes"), None)));
+        assert_eq!(split_backup_name(OsStr::new("notes.txt")), None);
+
+        assert_eq!(parse_ts_backup(OsStr::new("notes.txt.7.delta"), base), Some(7));
+        assert_eq!(split_backup_name(OsStr::new("notes.txt.7.delta")), Some((OsStr::new("notes.txt"), Some(7))));
+        assert_eq!(split_backup_name(OsStr::new("notes.delta")), None);
+    }
+
+    #[test]
+    fn plain_names_keep_compound_extensions() {
+        let plain = |name: &str| {
+            let path = plain_backup_for(Path::new("r"), Path::new(name), false).unwrap();
+            path.file_name().unwrap().to_string_lossy().into_owned()
+        };
+        assert_eq!(plain("report.txt"), "report.bak");
+        assert_eq!(plain("docs/report.txt"), "report.bak");
+        assert_eq!(plain("archive.tar.gz"), "archive.tar.gz.bak");
+        assert_eq!(plain("jquery.min.js"), "jquery.min.js.bak");
+        assert_eq!(plain(".env"), ".env.bak");
+        assert_eq!(plain(".env.local"), ".env.local.bak");
+        assert_eq!(plain("Makefile"), "Makefile.bak");
+        let old = |name: &str| old_plain_backup_for(Path::new("r"), Path::new(name));
+        assert_eq!(old("archive.tar.gz"), Some(PathBuf::from("r/archive.tar.bak")));
+        assert_eq!(old(".env.local"), Some(PathBuf::from("r/.env.bak")));
+        assert_eq!((old("report.txt"), old(".env")), (None, None));
+    }
+
+    #[test]
+    fn legacy_names() {
+        let ts = legacy_time("20240229123456").unwrap();
+        assert_eq!(legacy_time("20240229123457"), Some(ts + 1));
+        assert!(legacy_time("20240230000000").is_none() && legacy_time("2024022912345").is_none());
+
+        let dated = split_legacy_name(OsStr::new("notes.txt.20240229123456.bk"));
+        assert_eq!(dated, Some((OsStr::new("notes.txt"), Some(ts))));
+        assert_eq!(split_legacy_name(OsStr::new("notes.txt.bk")), Some((OsStr::new("notes.txt"), None)));
+        assert_eq!(split_legacy_name(OsStr::new("notes.txt.1700000000.bak")), None);
+
+        let base = OsStr::new("notes.txt");
+        assert_eq!(parse_legacy_backup(OsStr::new("notes.txt.bk"), base), Some(None));
+        assert_eq!(parse_legacy_backup(OsStr::new("notes.bk"), base), None);
+        assert_eq!(parse_legacy_backup(OsStr::new("other.txt.20240229123456.bk"), base), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_display_with_escapes() {
+        use std::os::unix::ffi::OsStrExt;
+        let name = OsStr::from_bytes(b"caf\xE9.txt");
+        assert_eq!(display_name(name), "caf\\xE9.txt");
+        assert_eq!(display_name(OsStr::new("café.txt")), "café.txt");
+        assert_eq!(name_from_display("caf\\xE9.txt"), name);
+        assert_eq!(name_from_display("a\\x41"), "a\\x41");
+        assert_eq!(name_from_display("caf\\xe9"), "caf\\xe9");
+    }
+}
@@ -0,0 +1,104 @@
+//! Notes on backups, saying why one was made (`BackupOptions::note`), kept in
+//! its sidecar. `list` shows them, `find` matches them, and
+//! `RestoreOptions::note_match` restores by them.
+
+use std::io;
+use std::path::Path;
+
+use crate::error::BackupError;
+use crate::meta::{read_meta, record_note};
+use crate::BackupManager;
+
+/// Longest note taken, in bytes as stored (escaped).
+pub const MAX_NOTE_BYTES: usize = 256;
+
+/// `note` as it is stored: control characters escaped ("\n", "\u{1b}"), so
+/// a note is one line wherever it is shown. Fails with
+/// `BackupError::NoteTooLong` if that is beyond [`MAX_NOTE_BYTES`].
+pub(crate) fn escape_note(note: &str) -> io::Result<String> {
+    let escaped: String =
+        note.chars().map(|c| if c.is_control() { c.escape_default().to_string() } else { c.to_string() }).collect();
+    if escaped.len() > MAX_NOTE_BYTES {
+        let preview = escaped.chars().take(40).chain("...".chars()).collect();
+        return Err(BackupError::NoteTooLong { preview, len: escaped.len(), max: MAX_NOTE_BYTES }.into());
+    }
+    Ok(escaped)
+}
+
+/// Whether the stored `note` contains `wanted`, ignoring case.
+pub(crate) fn note_matches(note: Option<&str>, wanted: &str) -> bool {
+    note.is_some_and(|n| n.to_lowercase().contains(&wanted.to_lowercase()))
+}
+
+impl BackupManager {
+    /// Replace the note of the backup file `backup` (a name like
+    /// "notes.txt.1700000000.bak") with `note`; an empty one removes it.
+    pub fn annotate(&self, backup: impl AsRef<Path>, note: &str) -> io::Result<()> {
+        let root = self.root()?;
+        let path = self.backup_named(&root, backup.as_ref())?;
+        let note = escape_note(note)?;
+        let original = read_meta(&*self.fs, &path)?.original;
+        record_note(&*self.fs, "annotate", &path, Some(note).filter(|n| !n.is_empty()))?;
+        let fname = path.file_name().unwrap_or_default().to_string_lossy();
+        self.log_action(&root, "annotate", Path::new(&original), &format!("ok ({fname})"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use crate::{BackupOptions, RestoreOptions};
+    use std::fs;
+
+    #[test]
+    fn notes_are_listed_found_edited_and_restored_by() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("config.toml"), "tls = false").unwrap();
+        let opts = BackupOptions::new().note("before enabling TLS\nstep 1");
+        manager(root, 100).backup_file_with("config.toml", &opts).unwrap();
+        fs::write(root.join("config.toml"), "tls = true").unwrap();
+        let mgr = manager(root, 200);
+        mgr.backup_file("config.toml").unwrap();
+
+        let list = mgr.list_backups("config.toml").unwrap();
+        let notes: Vec<Option<&str>> = list.iter().map(|e| e.note.as_deref()).collect();
+        assert_eq!(notes, [None, Some("before enabling TLS\\nstep 1")]);
+        // `find` matches notes as well as names.
+        let found = mgr.find_backups("tls").unwrap();
+        assert_eq!(found.iter().map(|e| e.ts).collect::<Vec<_>>(), [100]);
+        assert_eq!(mgr.find_backups("config").unwrap().len(), 2);
+        fs::write(root.join("app.ini"), "cert = none").unwrap();
+        manager(root, 150).backup_file_with("app.ini", &BackupOptions::new().note("TLS cert")).unwrap();
+        let found = mgr.find_backups("tls").unwrap();
+        let names: Vec<_> = found.iter().map(|e| e.path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["app.ini.150.bak", "config.toml.100.bak"]);
+
+        let report = mgr.restore_file_with("config.toml", &RestoreOptions::new().note_match("TLS")).unwrap();
+        assert_eq!(fs::read_to_string(&report.path).unwrap(), "tls = false");
+        let e = mgr.restore_file_with("config.toml", &RestoreOptions::new().note_match("nope")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        mgr.annotate("config.toml.200.bak", "after TLS").unwrap();
+        mgr.annotate("config.toml.100.bak", "").unwrap();
+        let notes: Vec<Option<String>> = mgr.list_backups("config.toml").unwrap().into_iter().map(|e| e.note).collect();
+        assert_eq!(notes, [Some("after TLS".to_string()), None]);
+        let (entry, meta) = mgr.backup_info("config.toml.200.bak").unwrap();
+        assert_eq!((entry.ts, entry.note.as_deref(), meta.original.as_str()), (200, Some("after TLS"), "config.toml"));
+        let last = mgr.read_log().unwrap().pop().unwrap();
+        assert_eq!((last.action.as_str(), last.result.as_str()), ("annotate", "ok (config.toml.100.bak)"));
+        mgr.verify_backup("config.toml.200.bak").unwrap();
+
+        let long = "x".repeat(MAX_NOTE_BYTES + 1);
+        let e = mgr.annotate("config.toml.200.bak", &long).unwrap_err();
+        assert_eq!(crate::error_code(&e), "note_too_long", "{e}");
+        let e = manager(root, 300).backup_file_with("config.toml", &BackupOptions::new().note(long)).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::NoteTooLong { .. })), "{e}");
+        // The limit is on the note as stored: 100 control bytes escape to 500.
+        let e = mgr.annotate("config.toml.200.bak", &"\u{1}".repeat(100)).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::NoteTooLong { len: 500, .. })), "{e}");
+        mgr.annotate("config.toml.200.bak", &"\n".repeat(MAX_NOTE_BYTES / 2)).unwrap();
+        assert_eq!(mgr.list_backups("config.toml").unwrap().len(), 2);
+    }
+}
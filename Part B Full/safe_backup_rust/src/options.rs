@@ -0,0 +1,328 @@
+//! Per-call options for backup, directory backup, restore and watch. `Config` holds what is shared by
+//! every operation of a manager; these hold what may differ from call to call.
+//! `Default` (= `new()`) reproduces the plain `backup_file` / `restore_file`.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cancel::CancellationToken;
+use crate::compress::Compression;
+use crate::conflict::ConflictStrategy;
+
+/// Options for [`BackupManager::backup_file_with`](crate::BackupManager::backup_file_with).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupOptions {
+    pub(crate) reflink: Option<bool>,
+    pub(crate) plain_copy: bool,
+    pub(crate) compress: Option<Compression>,
+    /// Log the backup with result "auto" (made by `watch`) instead of "ok".
+    pub(crate) auto: bool,
+    pub(crate) force: bool,
+    pub(crate) best_effort: bool,
+    pub(crate) if_changed: bool,
+    pub(crate) skip_missing: bool,
+    pub(crate) cancel: Option<CancellationToken>,
+    pub(crate) note: Option<String>,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            reflink: None,
+            plain_copy: true,
+            compress: None,
+            auto: false,
+            force: false,
+            best_effort: true,
+            if_changed: false,
+            skip_missing: false,
+            cancel: None,
+            note: None,
+        }
+    }
+}
+
+impl BackupOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Override `Config::reflink` for this backup.
+    pub fn reflink(mut self, on: bool) -> Self {
+        self.reflink = Some(on);
+        self
+    }
+
+    /// Override `Config::compression` for this backup. A compressed backup is
+    /// never a reflink.
+    pub fn compress(mut self, c: Compression) -> Self {
+        self.compress = Some(c);
+        self
+    }
+
+    /// Also refresh the plain "<stem>.bak" copy (default `true`).
+    pub fn plain_copy(mut self, on: bool) -> Self {
+        self.plain_copy = on;
+        self
+    }
+
+    /// Back the file up even if it is a backup itself: a ".bak" file or one
+    /// this tool made (default `false`: refused). No plain copy is made when
+    /// its name would be the file's own.
+    pub fn force(mut self, on: bool) -> Self {
+        self.force = on;
+        self
+    }
+
+    /// Keep the timestamped backup when the plain copy can't be written,
+    /// with `Warning::PlainBackup` (default `true`). With `false` the backup
+    /// is all or nothing: the timestamped backup and any half-written plain
+    /// copy are removed and the error returned.
+    pub fn best_effort(mut self, on: bool) -> Self {
+        self.best_effort = on;
+        self
+    }
+
+    /// Make no backup when the file matches its latest one, as told by
+    /// [`check_changed`](crate::BackupManager::check_changed); the report's
+    /// outcome is then `BackupOutcome::Unchanged` (default `false`).
+    pub fn if_changed(mut self, on: bool) -> Self {
+        self.if_changed = on;
+        self
+    }
+
+    /// Report a missing file as `BackupOutcome::Skipped` instead of failing
+    /// with `NotFound` (default `false`).
+    pub fn skip_missing(mut self, on: bool) -> Self {
+        self.skip_missing = on;
+        self
+    }
+
+    /// Stop with `BackupError::Cancelled` once `token` is cancelled, as with
+    /// `Config::cancel`: before the backup starts or, for a chunked one,
+    /// while it is copied, leaving no pieces behind.
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Record `text` in the backup's sidecar as why it was made, for `list`,
+    /// `find` and [`RestoreOptions::note_match`]. At most
+    /// [`MAX_NOTE_BYTES`](crate::MAX_NOTE_BYTES) once escaped; a longer one fails the
+    /// backup with `BackupError::NoteTooLong` before anything is written.
+    pub fn note(mut self, text: impl Into<String>) -> Self {
+        self.note = Some(text.into());
+        self
+    }
+}
+
+/// Options for [`BackupManager::backup_dir_with`](crate::BackupManager::backup_dir_with).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirOptions {
+    pub(crate) exclude: Vec<String>,
+    pub(crate) case_sensitive: Option<bool>,
+    pub(crate) hidden: bool,
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) follow_symlinks: bool,
+    pub(crate) resume: bool,
+    pub(crate) jobs: Option<usize>,
+    pub(crate) cancel: Option<CancellationToken>,
+}
+
+impl DirOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leave out entries matching the gitignore-style `pattern`, relative to
+    /// the directory being backed up; added after `Config::exclude`.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Match excludes case-sensitively or not. By default they follow the
+    /// platform: case-insensitive on Windows and macOS, sensitive elsewhere.
+    pub fn case_sensitive(mut self, on: bool) -> Self {
+        self.case_sensitive = Some(on);
+        self
+    }
+
+    /// Include dotfiles and dot-directories (default `false`: they are skipped).
+    pub fn hidden(mut self, on: bool) -> Self {
+        self.hidden = on;
+        self
+    }
+
+    /// Descend at most `levels` directories below the one being backed up;
+    /// 0 copies only the files directly in it. Default: no limit.
+    pub fn max_depth(mut self, levels: usize) -> Self {
+        self.max_depth = Some(levels);
+        self
+    }
+
+    /// Copy what symlinks point to, descending into symlinked directories
+    /// (default `false`: symlinks are skipped). A link back into a directory
+    /// containing it is skipped with `Warning::SymlinkLoop`.
+    pub fn follow_symlinks(mut self, on: bool) -> Self {
+        self.follow_symlinks = on;
+        self
+    }
+
+    /// Continue an interrupted backup, as [`backup_dir_resumable`](crate::BackupManager::backup_dir_resumable).
+    pub fn resume(mut self, on: bool) -> Self {
+        self.resume = on;
+        self
+    }
+
+    /// Copy and hash up to `n` files at once (at least one). Default: one per
+    /// CPU, as `std::thread::available_parallelism` reports them.
+    pub fn jobs(mut self, n: usize) -> Self {
+        self.jobs = Some(n.max(1));
+        self
+    }
+
+    /// Stop between files once `token` is cancelled, as with `Config::cancel`.
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+}
+
+/// Options for [`BackupManager::restore_file_with`](crate::BackupManager::restore_file_with).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreOptions {
+    pub(crate) to: Option<PathBuf>,
+    pub(crate) version: Option<u64>,
+    pub(crate) previous: Option<usize>,
+    pub(crate) note_match: Option<String>,
+    pub(crate) on_conflict: ConflictStrategy,
+    pub(crate) allow_mismatch: bool,
+}
+
+impl RestoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restore into `name` (validated like any other name) instead of the
+    /// default target.
+    pub fn to(mut self, name: impl AsRef<Path>) -> Self {
+        self.to = Some(name.as_ref().to_path_buf());
+        self
+    }
+
+    /// Restore the backup with timestamp `ts` instead of the latest one.
+    /// Only applies when restoring by original name. Replaces `previous` and `note_match`.
+    pub fn version(mut self, ts: u64) -> Self {
+        self.version = Some(ts);
+        self.previous = None;
+        self.note_match = None;
+        self
+    }
+
+    /// Restore the `n`-th newest timestamped backup (0 = latest), in
+    /// `list_backups` order. Only applies when restoring by original name.
+    /// Replaces `version` and `note_match`.
+    pub fn previous(mut self, n: usize) -> Self {
+        self.previous = Some(n);
+        self.version = None;
+        self.note_match = None;
+        self
+    }
+
+    /// Restore the newest backup whose note contains `text`, ignoring case
+    /// (see [`BackupOptions::note`]). Only applies when restoring by original
+    /// name. Replaces `version` and `previous`.
+    pub fn note_match(mut self, text: impl Into<String>) -> Self {
+        self.note_match = Some(text.into());
+        self.version = None;
+        self.previous = None;
+        self
+    }
+
+    /// What to do when the destination already exists (default
+    /// `ConflictStrategy::Overwrite`); the report's outcome says what was done.
+    pub fn on_conflict(mut self, strategy: ConflictStrategy) -> Self {
+        self.on_conflict = strategy;
+        self
+    }
+
+    /// Put restored data in place even if it doesn't match the digest
+    /// recorded for the backup, with `Warning::DigestMismatch` (default
+    /// `false`: it is removed and the restore fails with `ChecksumMismatch`).
+    pub fn allow_mismatch(mut self, on: bool) -> Self {
+        self.allow_mismatch = on;
+        self
+    }
+}
+
+/// Options for [`BackupManager::delete_file_with`](crate::BackupManager::delete_file_with).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeleteOptions {
+    pub(crate) purge_backups: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) trash: bool,
+}
+
+impl DeleteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also remove every backup of the file: its timestamped backups, those
+    /// made under earlier names, and its plain copy when the sidecar says it
+    /// is the file's. Other files next to them are left alone (default `false`).
+    pub fn purge_backups(mut self, on: bool) -> Self {
+        self.purge_backups = on;
+        self
+    }
+
+    /// Only report what would be removed (default `false`).
+    pub fn dry_run(mut self, on: bool) -> Self {
+        self.dry_run = on;
+        self
+    }
+
+    /// Move the file to the trash, ".safe_backup/trash/" in the backup
+    /// directory, instead of removing it, as "<name>.<ts>" with the time it
+    /// was trashed; see [`empty_trash`](crate::BackupManager::empty_trash).
+    /// Purged backups are still removed (default `false`).
+    pub fn trash(mut self, on: bool) -> Self {
+        self.trash = on;
+        self
+    }
+}
+
+/// Options for [`BackupManager::watch`](crate::BackupManager::watch).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchOptions {
+    pub(crate) debounce: Duration,
+    pub(crate) poll: Option<Duration>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self { debounce: Duration::from_millis(500), poll: None }
+    }
+}
+
+impl WatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait until a file has been quiet this long before backing it up, so a
+    /// burst of saves gives one backup (default 500 ms).
+    pub fn debounce(mut self, d: Duration) -> Self {
+        self.debounce = d;
+        self
+    }
+
+    /// Check modification times every `interval` instead of using filesystem
+    /// notifications. Used anyway when notifications are unavailable.
+    pub fn poll(mut self, interval: Duration) -> Self {
+        self.poll = Some(interval);
+        self
+    }
+}
@@ -0,0 +1,96 @@
+//! Permissions of what the tool writes. On Unix, file backups, plain copies
+//! and a new log get `Config::file_mode` (a backup no more than its source
+//! allows), and the directories it creates `Config::dir_mode`. Sidecars, log
+//! archives and other files it writes are created owner-only. Elsewhere
+//! nothing is changed, so nothing is opened up either.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::Context;
+use crate::BackupManager;
+
+impl BackupManager {
+    /// Give `path`, a file this tool wrote, `Config::file_mode`, narrowed to
+    /// `source`'s permission bits when it is a backup of that.
+    pub(crate) fn restrict(&self, op: &'static str, path: &Path, source: Option<u32>) -> io::Result<()> {
+        match self.config.file_mode {
+            Some(mode) if cfg!(unix) => {
+                let mode = source.map_or(mode, |s| mode & s);
+                self.fs.set_mode(path, mode).at(op, "cannot set permissions", path)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create `dir` and its missing parents, giving those it creates
+    /// `Config::dir_mode`; `what` says what failed, as in "cannot create backup directory".
+    pub(crate) fn create_dirs(&self, op: &'static str, dir: &Path, what: &'static str) -> io::Result<()> {
+        let missing: Vec<PathBuf> = dir
+            .ancestors()
+            .take_while(|d| !d.as_os_str().is_empty() && !self.fs.exists(d))
+            .map(Path::to_path_buf)
+            .collect();
+        self.fs.create_dir_all(dir).at(op, what, dir)?;
+        match self.config.dir_mode {
+            Some(mode) if cfg!(unix) => {
+                for d in &missing {
+                    self.fs.set_mode(d, mode).at(op, "cannot set permissions", d)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::{sidecar_for, Config, FixedClock};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    fn source(root: &Path, name: &str, mode: u32) {
+        fs::write(root.join(name), name).unwrap();
+        fs::set_permissions(root.join(name), fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn backups_of_private_files_stay_private() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let config = Config { tidy: true, tidy_backups: true, ..config(root, 100) };
+        let mgr = BackupManager::new(config.clone());
+        source(root, "secret.txt", 0o600);
+        source(root, "shared.txt", 0o644);
+        for name in ["secret.txt", "shared.txt"] {
+            let report = mgr.backup_file_report(name).unwrap();
+            let plain = report.plain.unwrap();
+            for path in [&report.path, &plain, &sidecar_for(&report.path), &sidecar_for(&plain)] {
+                assert_eq!(mode(path), 0o600, "{}", path.display());
+            }
+        }
+        assert_eq!(mode(&root.join(".safe_backup/logfile.txt")), 0o600);
+        assert_eq!(mode(&root.join(".safe_backup")), 0o700);
+        assert_eq!(mode(&root.join(".safe_backup/backups")), 0o700);
+
+        // A wider mode is still narrowed to the source's; none keeps the source's.
+        let wider = BackupManager::new(Config { file_mode: Some(0o640), clock: Arc::new(FixedClock(200)), ..config });
+        assert_eq!(mode(&wider.backup_file("secret.txt").unwrap()), 0o600);
+        assert_eq!(mode(&wider.backup_file("shared.txt").unwrap()), 0o640);
+        let unset = BackupManager::new(Config { file_mode: None, clock: Arc::new(FixedClock(300)), ..wider.config });
+        assert_eq!(mode(&unset.backup_file("shared.txt").unwrap()), 0o644);
+
+        // Compressed backups too.
+        let gzip = BackupManager::new(Config { compression: crate::Compression::Gzip, ..mgr.config.clone() });
+        source(root, "key.pem", 0o644);
+        assert_eq!(mode(&gzip.backup_file("key.pem").unwrap()), 0o600);
+    }
+}
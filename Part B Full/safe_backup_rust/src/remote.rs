@@ -0,0 +1,444 @@
+//! Offsite copies: after the local backup is made, upload it (its pieces if
+//! chunked, its objects if deduplicated, and its sidecar) to a [`RemoteTarget`]
+//! and check each file read back from there against the local one. The local backup stays whatever
+//! happens to the upload. SFTP support needs the `sftp` feature.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::chunk::part_path;
+use crate::digest::{file_digest, DigestAlgo};
+use crate::error::{BackupError, Context};
+use crate::dedup;
+use crate::meta::{read_backup_meta, sidecar_for, Layout};
+use crate::options::BackupOptions;
+use crate::{BackupManager, BackupOutcome, BackupReport};
+
+/// Environment variables read by [`SftpConfig::resolve`].
+pub const ENV_SFTP_HOST: &str = "SAFE_BACKUP_SFTP_HOST";
+pub const ENV_SFTP_PORT: &str = "SAFE_BACKUP_SFTP_PORT";
+pub const ENV_SFTP_USER: &str = "SAFE_BACKUP_SFTP_USER";
+pub const ENV_SFTP_PASSWORD: &str = "SAFE_BACKUP_SFTP_PASSWORD";
+pub const ENV_SFTP_KEY: &str = "SAFE_BACKUP_SFTP_KEY";
+pub const ENV_SFTP_DIR: &str = "SAFE_BACKUP_SFTP_DIR";
+
+/// Somewhere backups can be copied to. Files are addressed by plain file
+/// names inside the target.
+pub trait RemoteTarget {
+    /// Where files go, for messages and the log, e.g. "sftp://me@host:22/backups".
+    /// Must not contain credentials.
+    fn describe(&self) -> String;
+    /// Store the local file `from` as `name`, replacing any file of that name.
+    fn upload(&self, from: &Path, name: &str) -> io::Result<()>;
+    /// Tagged digest (see [`file_digest`]) of the stored `name`, computed with `algo`.
+    fn digest(&self, name: &str, algo: DigestAlgo) -> io::Result<String>;
+}
+
+/// How to reach an SFTP server. From the `"sftp"` object of the config file
+/// and the `SAFE_BACKUP_SFTP_*` variables; see [`SftpConfig::resolve`].
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    /// Login name; empty means the local user name.
+    pub user: String,
+    /// Password, or the passphrase of `key_file`. Without either, the SSH agent is asked.
+    pub password: Option<String>,
+    /// Private key to log in with.
+    pub key_file: Option<PathBuf>,
+    /// OpenSSH known_hosts file the server's key must be listed in; `None`
+    /// means "~/.ssh/known_hosts". Unknown or changed keys are refused.
+    pub known_hosts: Option<PathBuf>,
+    /// Directory on the server uploads go to; must exist. Empty means the login directory.
+    pub remote_dir: String,
+}
+
+impl Default for SftpConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: 22,
+            user: String::new(),
+            password: None,
+            key_file: None,
+            known_hosts: None,
+            remote_dir: String::new(),
+        }
+    }
+}
+
+/// Never shows the password.
+impl fmt::Debug for SftpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("key_file", &self.key_file)
+            .field("known_hosts", &self.known_hosts)
+            .field("remote_dir", &self.remote_dir)
+            .finish()
+    }
+}
+
+impl SftpConfig {
+    /// `file` (the config file's `"sftp"` object) with each `SAFE_BACKUP_SFTP_*`
+    /// variable set in `env` applied on top. `None` if no host is set either
+    /// way; an invalid port is an `InvalidSetting` error naming the variable.
+    pub fn resolve(env: &HashMap<String, String>, file: Option<SftpConfig>) -> io::Result<Option<Self>> {
+        let get = |k: &str| env.get(k).map(|v| v.trim()).filter(|v| !v.is_empty());
+        let mut config = file.unwrap_or_default();
+        if let Some(host) = get(ENV_SFTP_HOST) {
+            config.host = host.to_string();
+        }
+        if let Some(port) = get(ENV_SFTP_PORT) {
+            config.port = port.parse().map_err(|_| BackupError::InvalidSetting {
+                name: ENV_SFTP_PORT.to_string(),
+                value: port.to_string(),
+                reason: "expected a port number".to_string(),
+            })?;
+        }
+        if let Some(user) = get(ENV_SFTP_USER) {
+            config.user = user.to_string();
+        }
+        // Not trimmed: a password may start or end with spaces.
+        if let Some(password) = env.get(ENV_SFTP_PASSWORD).filter(|v| !v.is_empty()) {
+            config.password = Some(password.clone());
+        }
+        if let Some(key) = get(ENV_SFTP_KEY) {
+            config.key_file = Some(PathBuf::from(key));
+        }
+        if let Some(dir) = get(ENV_SFTP_DIR) {
+            config.remote_dir = dir.to_string();
+        }
+        Ok((!config.host.is_empty()).then_some(config))
+    }
+
+    /// The login name: `user`, else the local user name.
+    fn login(&self) -> String {
+        if self.user.is_empty() { whoami::username() } else { self.user.clone() }
+    }
+
+    /// "sftp://user@host:port/dir", without credentials.
+    pub fn describe(&self) -> String {
+        format!("sftp://{}@{}:{}/{}", self.login(), self.host, self.port, self.remote_dir.trim_start_matches('/'))
+    }
+}
+
+/// Outcome of a backup that was also uploaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteReport {
+    /// The local backup, as from [`BackupManager::backup_file_with`].
+    pub backup: BackupReport,
+    /// Where it went ([`RemoteTarget::describe`]).
+    pub target: String,
+    /// Names of the uploaded files, the sidecar last.
+    pub uploaded: Vec<String>,
+}
+
+impl BackupManager {
+    /// [`backup_file`](Self::backup_file), then upload the backup to the SFTP
+    /// server in `sftp`. Needs the `sftp` feature.
+    pub fn backup_file_remote(&self, name: impl AsRef<Path>, sftp: &SftpConfig) -> io::Result<RemoteReport> {
+        self.backup_file_remote_with(name, &BackupOptions::default(), sftp)
+    }
+
+    /// [`backup_file_remote`](Self::backup_file_remote) with per-call options.
+    /// If connecting or uploading fails, the error is `BackupError::Upload`
+    /// naming the local backup, which is kept.
+    pub fn backup_file_remote_with(
+        &self,
+        name: impl AsRef<Path>,
+        opts: &BackupOptions,
+        sftp: &SftpConfig,
+    ) -> io::Result<RemoteReport> {
+        let name = name.as_ref();
+        let backup = self.backup_file_with(name, opts)?;
+        match sftp::connect(sftp) {
+            Ok(target) => self.upload(name, backup, &*target),
+            Err(e) => Err(upload_failed(&backup.path, sftp.describe(), e)),
+        }
+    }
+
+    /// [`backup_file_with`](Self::backup_file_with), then upload the backup to
+    /// `target`; see [`backup_file_remote_with`](Self::backup_file_remote_with).
+    pub fn backup_file_to(
+        &self,
+        name: impl AsRef<Path>,
+        opts: &BackupOptions,
+        target: &dyn RemoteTarget,
+    ) -> io::Result<RemoteReport> {
+        let name = name.as_ref();
+        let backup = self.backup_file_with(name, opts)?;
+        self.upload(name, backup, target)
+    }
+
+    /// Upload the files of the fresh `backup` of `name`, the sidecar last so a
+    /// partial upload has none, and log "upload".
+    fn upload(&self, name: &Path, mut backup: BackupReport, target: &dyn RemoteTarget) -> io::Result<RemoteReport> {
+        let described = target.describe();
+        let mut uploaded = Vec::new();
+        // A missing file that was skipped has no backup to send.
+        if backup.outcome == BackupOutcome::Skipped {
+            return Ok(RemoteReport { backup, target: described, uploaded });
+        }
+        let files = backup_files(&backup.path).map_err(|e| upload_failed(&backup.path, described.clone(), e))?;
+        for file in files {
+            let remote = file.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            upload_verified(target, &file, &remote, self.config.digest)
+                .map_err(|e| upload_failed(&backup.path, described.clone(), e))?;
+            uploaded.push(remote);
+        }
+        self.log_or_warn(&self.root()?, "upload", name, &format!("ok ({described})"), &mut backup.warnings);
+        Ok(RemoteReport { backup, target: described, uploaded })
+    }
+}
+
+/// The files making up `backup`: its pieces if chunked, the objects it lists
+/// if deduplicated, itself, its sidecar.
+fn backup_files(backup: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    match read_backup_meta(backup).map(|m| m.layout) {
+        Ok(Layout::Chunked) => files.extend((1..).map(|n| part_path(backup, n)).take_while(|p| p.is_file())),
+        Ok(Layout::Deduped) => {
+            for object in dedup::objects_of("upload", backup)? {
+                if !files.contains(&object) {
+                    files.push(object);
+                }
+            }
+        }
+        _ => {}
+    }
+    files.push(backup.to_path_buf());
+    files.push(sidecar_for(backup));
+    Ok(files)
+}
+
+/// Upload `file` as `name` and compare the digest of what arrived with the local one.
+fn upload_verified(target: &dyn RemoteTarget, file: &Path, name: &str, algo: DigestAlgo) -> io::Result<()> {
+    let expected = file_digest(file, algo).at("upload", "cannot read", file)?;
+    target.upload(file, name)?;
+    let actual = target.digest(name, algo)?;
+    if actual != expected {
+        let path = PathBuf::from(format!("{}/{name}", target.describe().trim_end_matches('/')));
+        return Err(BackupError::Corrupt { path, expected, actual }.into());
+    }
+    Ok(())
+}
+
+fn upload_failed(local: &Path, target: String, source: io::Error) -> io::Error {
+    BackupError::Upload { local: local.to_path_buf(), target, source }.into()
+}
+
+#[cfg(feature = "sftp")]
+mod sftp {
+    use std::fs::File;
+    use std::io;
+    use std::net::TcpStream;
+    use std::path::{Path, PathBuf};
+
+    use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+
+    use super::{RemoteTarget, SftpConfig};
+    use crate::digest::{DigestAlgo, Hasher};
+    use crate::error::Context;
+
+    /// An open SFTP session.
+    pub(super) struct SftpTarget {
+        described: String,
+        remote_dir: String,
+        sftp: Sftp,
+        // Keeps the connection open for `sftp`.
+        _session: Session,
+    }
+
+    /// Connect to the server in `config`, check its host key and log in.
+    pub(super) fn connect(config: &SftpConfig) -> io::Result<Box<dyn RemoteTarget>> {
+        let described = config.describe();
+        let at = PathBuf::from(&described);
+        let tcp = TcpStream::connect((config.host.as_str(), config.port)).at("upload", "cannot connect to", &at)?;
+        let mut session = Session::new().map_err(io::Error::from).at("upload", "cannot start SSH for", &at)?;
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(io::Error::from).at("upload", "SSH handshake failed with", &at)?;
+        check_host_key(&session, config).at("upload", "cannot trust", &at)?;
+        let user = config.login();
+        let auth = match (&config.key_file, &config.password) {
+            (Some(key), passphrase) => session.userauth_pubkey_file(&user, None, key, passphrase.as_deref()),
+            (None, Some(password)) => session.userauth_password(&user, password),
+            (None, None) => session.userauth_agent(&user),
+        };
+        auth.map_err(io::Error::from).at("upload", "cannot log in to", &at)?;
+        let sftp = session.sftp().map_err(io::Error::from).at("upload", "cannot start SFTP on", &at)?;
+        let remote_dir = config.remote_dir.trim_end_matches('/').to_string();
+        Ok(Box::new(SftpTarget { described, remote_dir, sftp, _session: session }))
+    }
+
+    fn check_host_key(session: &Session, config: &SftpConfig) -> io::Result<()> {
+        let file = match &config.known_hosts {
+            Some(path) => path.clone(),
+            None => home_dir()?.join(".ssh").join("known_hosts"),
+        };
+        let mut known = session.known_hosts()?;
+        known.read_file(&file, KnownHostFileKind::OpenSSH)?;
+        let (key, _) = session.host_key().ok_or_else(|| io::Error::other("the server sent no host key"))?;
+        let refused = |why: &str| Err(io::Error::new(io::ErrorKind::PermissionDenied, why.to_string()));
+        match known.check_port(&config.host, config.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => refused("host key not in known_hosts; connect once with ssh to add it"),
+            CheckResult::Mismatch => refused("HOST KEY CHANGED; refusing to connect"),
+            CheckResult::Failure => refused("host key check failed"),
+        }
+    }
+
+    fn home_dir() -> io::Result<PathBuf> {
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(PathBuf::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory for known_hosts"))
+    }
+
+    impl SftpTarget {
+        fn remote(&self, name: &str) -> PathBuf {
+            if self.remote_dir.is_empty() {
+                PathBuf::from(name)
+            } else {
+                PathBuf::from(format!("{}/{name}", self.remote_dir))
+            }
+        }
+    }
+
+    impl RemoteTarget for SftpTarget {
+        fn describe(&self) -> String {
+            self.described.clone()
+        }
+
+        /// Written under a temporary name, then renamed over `name`.
+        fn upload(&self, from: &Path, name: &str) -> io::Result<()> {
+            let dest = self.remote(name);
+            let tmp = self.remote(&format!("{name}.uploading"));
+            let mut input = File::open(from).at("upload", "cannot open", from)?;
+            let mut out = self.sftp.create(&tmp).map_err(io::Error::from).at("upload", "cannot create", &tmp)?;
+            io::copy(&mut input, &mut out).copying("upload", from, &tmp)?;
+            drop(out);
+            let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+            self.sftp.rename(&tmp, &dest, Some(flags)).map_err(io::Error::from).copying("upload", &tmp, &dest)
+        }
+
+        /// Reads the file back over SFTP; the server needs no tools of its own.
+        fn digest(&self, name: &str, algo: DigestAlgo) -> io::Result<String> {
+            let path = self.remote(name);
+            let mut file = self.sftp.open(&path).map_err(io::Error::from).at("upload", "cannot open", &path)?;
+            let mut hasher = Hasher::new(algo);
+            io::copy(&mut file, &mut hasher).at("upload", "cannot read", &path)?;
+            Ok(hasher.finish())
+        }
+    }
+}
+
+#[cfg(not(feature = "sftp"))]
+mod sftp {
+    use std::io;
+
+    use super::{RemoteTarget, SftpConfig};
+
+    pub(super) fn connect(_config: &SftpConfig) -> io::Result<Box<dyn RemoteTarget>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "uploading over SFTP needs the `sftp` feature"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::Config;
+    use std::cell::Cell;
+    use std::fs;
+
+    /// A directory standing in for the server; can be told to damage uploads.
+    struct DirTarget {
+        dir: PathBuf,
+        damage: Cell<bool>,
+    }
+
+    impl RemoteTarget for DirTarget {
+        fn describe(&self) -> String {
+            format!("dir://{}", self.dir.display())
+        }
+
+        fn upload(&self, from: &Path, name: &str) -> io::Result<()> {
+            fs::copy(from, self.dir.join(name))?;
+            if self.damage.get() {
+                fs::write(self.dir.join(name), "garbage")?;
+            }
+            Ok(())
+        }
+
+        fn digest(&self, name: &str, algo: DigestAlgo) -> io::Result<String> {
+            file_digest(self.dir.join(name), algo)
+        }
+    }
+
+    fn setup() -> (tempfile::TempDir, BackupManager, DirTarget) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("local");
+        fs::create_dir_all(tmp.path().join("server")).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("big.txt"), "0123456789abcdefghij!").unwrap();
+        let mgr = BackupManager::new(Config { chunk_size: Some(10), ..config(&root, 100) });
+        let target = DirTarget { dir: tmp.path().join("server"), damage: Cell::new(false) };
+        (tmp, mgr, target)
+    }
+
+    #[test]
+    fn uploads_every_piece_then_the_sidecar() {
+        let (tmp, mgr, target) = setup();
+        let report = mgr.backup_file_to("big.txt", &BackupOptions::default(), &target).unwrap();
+        let names = ["part0001", "part0002", "part0003"].map(|p| format!("big.txt.100.bak.{p}"));
+        let mut expected = names.to_vec();
+        expected.extend(["big.txt.100.bak".to_string(), "big.txt.100.bak.meta.json".to_string()]);
+        assert_eq!(report.uploaded, expected);
+        assert_eq!(fs::read(tmp.path().join("server").join(&names[2])).unwrap(), b"!");
+        let log = fs::read_to_string(tmp.path().join("local").join("logfile.txt")).unwrap();
+        assert!(log.contains("\"upload\"") && log.contains(&report.target), "{log}");
+    }
+
+    #[test]
+    fn failed_upload_keeps_the_local_backup() {
+        let (tmp, mgr, target) = setup();
+        target.damage.set(true);
+        let e = mgr.backup_file_to("big.txt", &BackupOptions::default(), &target).unwrap_err();
+        let local = tmp.path().join("local").join("big.txt.100.bak");
+        assert!(
+            matches!(BackupError::from_io(&e), Some(BackupError::Upload { local: l, .. }) if *l == local),
+            "{e}"
+        );
+        assert!(crate::error_chain(&e).contains("is corrupt"), "{e}");
+        assert!(local.exists());
+        mgr.verify_backup("big.txt.100.bak").unwrap();
+    }
+
+    #[test]
+    fn environment_overrides_the_config_file() {
+        let env: HashMap<String, String> = [(ENV_SFTP_HOST, "backup.example"), (ENV_SFTP_PASSWORD, "s3cret ")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let file =
+            SftpConfig { host: "old".into(), user: "me".into(), remote_dir: "/srv".into(), ..SftpConfig::default() };
+        let config = SftpConfig::resolve(&env, Some(file)).unwrap().unwrap();
+        assert_eq!((config.host.as_str(), config.port), ("backup.example", 22));
+        assert_eq!(config.password.as_deref(), Some("s3cret "));
+        assert_eq!(config.describe(), "sftp://me@backup.example:22/srv");
+        assert!(!format!("{config:?}").contains("s3cret"));
+        assert_eq!(SftpConfig::resolve(&HashMap::new(), None).unwrap(), None);
+
+        let bad = HashMap::from([(ENV_SFTP_HOST.to_string(), "h".into()), (ENV_SFTP_PORT.to_string(), "ssh".into())]);
+        assert_eq!(SftpConfig::resolve(&bad, None).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,163 @@
+//! Grandfather-father-son retention: thinning out the backups of one original
+//! by age, keeping fewer of them the further back they go.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::delta;
+use crate::versions::{remove_backup, BackupEntry};
+use crate::BackupManager;
+
+const DAY: u64 = 24 * 60 * 60;
+const WEEK: u64 = 7 * DAY;
+
+/// Which backups of an original to keep; see [`BackupManager::apply_retention`].
+/// A backup stays if any rule keeps it, and the newest one always stays, as
+/// does any backup a remaining delta builds on or a checkpoint holds.
+///
+/// Days and weeks are fixed periods counted from the Unix epoch (UTC days;
+/// weeks starting on Thursday), so a backup kept as the representative of
+/// its period stays that as time moves on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep this many newest backups, whatever their age.
+    pub keep_last: usize,
+    /// Keep every backup younger than this many seconds.
+    pub keep_within: u64,
+    /// Keep the newest backup of each day, for this many days back.
+    pub daily: u64,
+    /// Keep the newest backup of each week, for this many weeks back.
+    pub weekly: u64,
+}
+
+impl Default for RetentionPolicy {
+    /// Everything from the last day, one per day for a week, one per week for four weeks.
+    fn default() -> Self {
+        RetentionPolicy { keep_last: 1, keep_within: DAY, daily: 7, weekly: 4 }
+    }
+}
+
+impl RetentionPolicy {
+    /// For timestamps sorted newest first, whether each one is kept at `now`.
+    /// Timestamps in the future count as brand new.
+    pub fn keeps(&self, now: u64, timestamps: &[u64]) -> Vec<bool> {
+        let mut days = HashSet::new();
+        let mut weeks = HashSet::new();
+        timestamps
+            .iter()
+            .enumerate()
+            .map(|(i, &ts)| {
+                let age = now.saturating_sub(ts);
+                // Evaluate every rule, so each period's newest backup claims it.
+                let daily = age < self.daily.saturating_mul(DAY) && days.insert(ts / DAY);
+                let weekly = age < self.weekly.saturating_mul(WEEK) && weeks.insert(ts / WEEK);
+                i == 0 || i < self.keep_last || age < self.keep_within || daily || weekly
+            })
+            .collect()
+    }
+}
+
+/// Outcome of [`BackupManager::apply_retention`]; both lists newest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub kept: Vec<BackupEntry>,
+    pub removed: Vec<BackupEntry>,
+}
+
+impl BackupManager {
+    /// Remove the timestamped backups of `name` that `policy` doesn't keep,
+    /// with their sidecars, judging ages against the configured clock.
+    /// Stops at the first backup that can't be removed.
+    pub fn apply_retention(&self, name: impl AsRef<Path>, policy: &RetentionPolicy) -> io::Result<RetentionReport> {
+        let name = name.as_ref();
+        let root = self.root()?;
+        let entries = self.list_backups(name)?;
+        let ts: Vec<u64> = entries.iter().map(|e| e.ts).collect();
+        let all: Vec<PathBuf> = entries.iter().map(|e| e.path.clone()).collect();
+        let held = self.checkpointed(&root)?;
+        let doomed = all.iter().zip(policy.keeps(self.now(), &ts)).filter(|(p, k)| !k && !held.contains(*p));
+        let doomed = doomed.map(|(p, _)| p.clone());
+        let doomed = delta::spare_bases(&all, doomed.collect());
+        let mut report = RetentionReport::default();
+        for entry in entries {
+            if doomed.contains(&entry.path) { report.removed.push(entry) } else { report.kept.push(entry) }
+        }
+        if report.removed.is_empty() {
+            return Ok(report);
+        }
+        for entry in &report.removed {
+            remove_backup(&*self.fs, &entry.path)?;
+        }
+        self.log_action(&root, "prune", name, &format!("ok ({} removed by retention)", report.removed.len()))?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use crate::meta::write_meta;
+    use crate::{Compression, DigestAlgo};
+    use std::fs;
+
+    // Midnight UTC, a Thursday: the start of both a day and an epoch week.
+    const NOW: u64 = 2_858 * WEEK;
+
+    fn ages(policy: &RetentionPolicy, ages: &[u64]) -> Vec<u64> {
+        let ts: Vec<u64> = ages.iter().map(|a| NOW - a).collect();
+        policy.keeps(NOW, &ts).into_iter().zip(ages).filter(|(k, _)| *k).map(|(_, a)| *a).collect()
+    }
+
+    #[test]
+    fn keeps_all_then_daily_then_weekly() {
+        let hour = 3600;
+        // Hourly backups for five weeks, newest first.
+        let all: Vec<u64> = (0..35 * 24).map(|h| h * hour + 1).collect();
+        let kept = ages(&RetentionPolicy::default(), &all);
+        let mut expected: Vec<u64> = (0..24).map(|h| h * hour + 1).collect();
+        // Then the newest of each earlier day, up to a week back...
+        expected.extend((1..7).map(|d| d * DAY + 1));
+        // ...and of each earlier week, up to four weeks back.
+        expected.extend((1..4).map(|w| w * WEEK + 1));
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn keep_last_and_newest_win_over_age() {
+        let policy = RetentionPolicy { keep_last: 2, keep_within: 0, daily: 0, weekly: 0 };
+        assert_eq!(ages(&policy, &[100 * DAY, 200 * DAY, 300 * DAY]), [100 * DAY, 200 * DAY]);
+        let nothing = RetentionPolicy { keep_last: 0, ..policy };
+        assert_eq!(ages(&nothing, &[100 * DAY, 200 * DAY]), [100 * DAY]);
+        assert_eq!(nothing.keeps(NOW, &[NOW + 5]), [true]);
+    }
+
+    #[test]
+    fn applies_to_the_backups_on_disk() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let stamps = [NOW - 60, NOW - 2 * 3600, NOW - DAY - 60, NOW - DAY - 120, NOW - 10 * DAY, NOW - 60 * DAY];
+        for ts in stamps {
+            let bak = root.join(format!("a.txt.{ts}.bak"));
+            fs::write(&bak, ts.to_string()).unwrap();
+            let fs = &crate::vfs::RealFs;
+            write_meta(fs, "backup", &bak, Path::new("a.txt"), &bak, Compression::None, DigestAlgo::Sha256).unwrap();
+        }
+        let mgr = manager(root, NOW);
+        let report = mgr.apply_retention("a.txt", &RetentionPolicy::default()).unwrap();
+        let kept: Vec<u64> = report.kept.iter().map(|e| e.ts).collect();
+        let removed: Vec<u64> = report.removed.iter().map(|e| e.ts).collect();
+        assert_eq!(kept, [NOW - 60, NOW - 2 * 3600, NOW - DAY - 60, NOW - 10 * DAY]);
+        assert_eq!(removed, [NOW - DAY - 120, NOW - 60 * DAY]);
+        assert!(!root.join(format!("a.txt.{}.bak.meta.json", NOW - 60 * DAY)).exists());
+        let left: Vec<u64> = mgr.list_backups("a.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(left, kept);
+
+        let again = mgr.apply_retention("a.txt", &RetentionPolicy::default()).unwrap();
+        assert!(again.removed.is_empty());
+        let log = mgr.read_log().unwrap();
+        assert_eq!(log.iter().filter(|e| e.action == "prune").count(), 1);
+    }
+}
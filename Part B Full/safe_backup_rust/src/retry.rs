@@ -0,0 +1,192 @@
+//! Retry policy for transient I/O failures (network mounts, busy devices).
+
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::event::{Event, EventSink};
+
+/// How often and how patiently a fallible step is retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one; `1` disables retrying.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled for every further retry.
+    pub base_delay: Duration,
+    /// Upper bound for a single delay.
+    pub max_delay: Duration,
+    /// Randomize each delay into `[delay/2, delay]` to avoid thundering herds.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that runs the step exactly once.
+    pub fn none() -> Self {
+        Self { max_attempts: 1, ..Self::default() }
+    }
+
+    /// Delay before retry number `retry` (1-based), before jitter.
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    fn delay_for(&self, retry: u32) -> Duration {
+        let d = self.backoff(retry);
+        if !self.jitter || d.is_zero() {
+            return d;
+        }
+        let half = d / 2;
+        let span = (d - half).as_nanos() as u64;
+        half + Duration::from_nanos(pseudo_random() % span.max(1))
+    }
+}
+
+/// Cheap jitter source; quality does not matter here.
+fn pseudo_random() -> u64 {
+    let mut x = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64
+        ^ 0x9E37_79B9_7F4A_7C15;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+/// Errors that may succeed when simply tried again.
+/// NotFound, PermissionDenied and InvalidInput are never transient.
+/// Windows sharing violations are: the other program usually lets go quickly.
+/// A cancelled or timed-out operation never is, although it shares its kind
+/// with EINTR or a network timeout.
+pub fn is_transient(e: &io::Error) -> bool {
+    if crate::busy::is_file_busy(e) {
+        return true;
+    }
+    use crate::BackupError::{Cancelled, OutcomeUnknown, TimedOut};
+    if matches!(crate::BackupError::from_io(e), Some(Cancelled { .. } | TimedOut { .. } | OutcomeUnknown { .. })) {
+        return false;
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput => false,
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::ResourceBusy => true,
+        _ => false,
+    }
+}
+
+/// Run `f`, retrying transient failures according to `policy`.
+/// Each retry is reported to `sink`; a final error after retries names the attempt count.
+pub fn with_retry<T>(
+    policy: &RetryPolicy,
+    op: &str,
+    sink: Option<&EventSink>,
+    mut f: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let max = policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < max && is_transient(&e) => {
+                let delay = policy.delay_for(attempt);
+                if let Some(sink) = sink {
+                    sink(&Event::Retry { op: op.to_string(), attempt, delay, error: e.to_string() });
+                }
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) if attempt > 1 => {
+                return Err(io::Error::new(e.kind(), format!("{op} failed after {attempt} attempts: {e}")));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn fast(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy { max_attempts, base_delay: Duration::ZERO, max_delay: Duration::ZERO, jitter: false }
+    }
+
+    #[test]
+    fn transient_error_is_retried_until_success() {
+        let mut calls = 0;
+        let r = with_retry(&fast(3), "copy", None, || {
+            calls += 1;
+            if calls < 3 { Err(io::Error::from(io::ErrorKind::TimedOut)) } else { Ok(calls) }
+        });
+        assert_eq!(r.unwrap(), 3);
+    }
+
+    #[test]
+    fn final_error_reports_attempts() {
+        let mut calls = 0;
+        let err = with_retry(&fast(4), "copy", None, || -> io::Result<()> {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::WouldBlock))
+        })
+        .unwrap_err();
+        assert_eq!(calls, 4);
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains("after 4 attempts"), "{err}");
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        for kind in [io::ErrorKind::NotFound, io::ErrorKind::PermissionDenied, io::ErrorKind::InvalidInput] {
+            let mut calls = 0;
+            let err = with_retry(&fast(5), "copy", None, || -> io::Result<()> {
+                calls += 1;
+                Err(io::Error::from(kind))
+            })
+            .unwrap_err();
+            assert_eq!(calls, 1);
+            assert_eq!(err.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn retries_are_emitted_as_events() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = Arc::clone(&seen);
+        let sink: EventSink = Arc::new(move |e: &Event| seen2.lock().unwrap().push(e.clone()));
+        let _ = with_retry(&fast(3), "rename", Some(&sink), || -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::Interrupted))
+        });
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(matches!(&seen[0], Event::Retry { op, attempt: 1, .. } if op == "rename"));
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let p = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(350),
+            jitter: false,
+        };
+        assert_eq!(p.delay_for(1), Duration::from_millis(100));
+        assert_eq!(p.delay_for(2), Duration::from_millis(200));
+        assert_eq!(p.delay_for(3), Duration::from_millis(350));
+    }
+}
@@ -0,0 +1,265 @@
+//! Log rotation and archiving: once the log passes `Config::log_max_bytes`
+//! it is renamed to the segment "<log>.<n>" (numbered up from 1, the newest
+//! highest), which is then gzipped to "<log>.<n>.gz". Only the newest
+//! `Config::log_archives` archives are kept. Reading the log reads back
+//! through the segments as far as a query needs.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::error::Context;
+use crate::log::{scan, LogEntry, LogFilter};
+use crate::vfs::FileSystem;
+use crate::BackupManager;
+
+/// Outcome of [`BackupManager::prune_log`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogPrune {
+    /// Archives written for segments that weren't compressed yet, oldest first.
+    pub compressed: Vec<PathBuf>,
+    /// Archives removed for being past `Config::log_archives`, oldest first.
+    pub removed: Vec<PathBuf>,
+}
+
+/// A rotated segment of the log.
+#[derive(Debug)]
+pub(crate) struct Segment {
+    n: u64,
+    path: PathBuf,
+    gz: bool,
+}
+
+/// Rotated segments of the log at `log`, oldest first. Where a segment is
+/// there both plain and gzipped (compressing it was interrupted), the plain
+/// one is returned; compressing it again replaces the other.
+pub(crate) fn segments(fs: &dyn FileSystem, log: &Path) -> io::Result<Vec<Segment>> {
+    let (Some(dir), Some(name)) = (log.parent(), log.file_name().and_then(|n| n.to_str())) else {
+        return Ok(Vec::new());
+    };
+    let paths = match fs.read_dir(dir) {
+        Ok(paths) => paths,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).at("log", "cannot list directory", dir),
+    };
+    let mut found: Vec<Segment> = Vec::new();
+    for path in paths {
+        let file = path.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+        let Some(rest) = file.strip_prefix(name).and_then(|r| r.strip_prefix('.')) else { continue };
+        let (num, gz) = rest.strip_suffix(".gz").map_or((rest, false), |num| (num, true));
+        if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let Ok(n) = num.parse() else { continue };
+        found.push(Segment { n, path, gz });
+    }
+    found.sort_by_key(|s| (s.n, s.gz));
+    found.dedup_by_key(|s| s.n);
+    Ok(found)
+}
+
+/// Entries of the log at `log` that `filter` keeps, reading back through its
+/// segments, newest first, only while older entries could still be kept. A
+/// damaged segment (not gzip, or cut short) is passed to `damaged` and skipped.
+pub(crate) fn read_history(
+    fs: &dyn FileSystem,
+    log: &Path,
+    filter: &LogFilter,
+    damaged: &mut dyn FnMut(&Path, &io::Error),
+) -> io::Result<Vec<LogEntry>> {
+    let mut entries = crate::log::read_log_file(fs, log)?;
+    let mut older = segments(fs, log)?;
+    while let Some(segment) = older.pop() {
+        if !filter.reaches_before(&entries) {
+            break;
+        }
+        let text = match read_segment(fs, &segment) {
+            Err(e) if is_damaged(&e) => {
+                damaged(&segment.path, &e);
+                continue;
+            }
+            text => text?,
+        };
+        let mut earlier = scan(&text).entries;
+        earlier.append(&mut entries);
+        entries = earlier;
+    }
+    Ok(filter.apply(entries))
+}
+
+/// Whether reading a segment failed on what it holds rather than on getting at it.
+fn is_damaged(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof)
+}
+
+fn read_segment(fs: &dyn FileSystem, segment: &Segment) -> io::Result<String> {
+    let path = &segment.path;
+    let f = fs.open(path).at("log", "cannot read log", path)?;
+    let mut text = String::new();
+    if segment.gz {
+        GzDecoder::new(f).read_to_string(&mut text)
+    } else {
+        io::BufReader::new(f).read_to_string(&mut text)
+    }
+    .at("log", "cannot read log", path)?;
+    Ok(text)
+}
+
+/// Rename the log at `log` to the next segment, "<log>.<n>"; returns it.
+/// The caller holds the log's lock, so no entry is written in between.
+pub(crate) fn rotate(fs: &dyn FileSystem, log: &Path) -> io::Result<PathBuf> {
+    let n = segments(fs, log)?.last().map_or(1, |s| s.n + 1);
+    let mut segment = log.as_os_str().to_owned();
+    segment.push(format!(".{n}"));
+    let segment = PathBuf::from(segment);
+    fs.rename(log, &segment).copying("rotate_log", log, &segment)?;
+    Ok(segment)
+}
+
+/// Gzip `segment` to "<segment>.gz" through a temporary file, then remove it.
+fn compress(fs: &dyn FileSystem, segment: &Path) -> io::Result<PathBuf> {
+    let mut gz = segment.as_os_str().to_owned();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+    let tmp = crate::vfs::unique_temp(&gz);
+    let mut input = fs.open(segment).at("prune_log", "cannot read log", segment)?;
+    let out = fs.create(&tmp).at("prune_log", "cannot create", &tmp)?;
+    let mut encoder = GzEncoder::new(out, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)
+        .copying("prune_log", segment, &tmp)
+        .and_then(|_| encoder.finish().and_then(|mut f| f.flush()).at("prune_log", "cannot write", &tmp))
+        .and_then(|_| fs.rename(&tmp, &gz).copying("prune_log", &tmp, &gz))
+        .inspect_err(|_| {
+            let _ = fs.remove_file(&tmp);
+        })?;
+    match fs.remove_file(segment) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).at("prune_log", "cannot remove", segment),
+        _ => Ok(gz),
+    }
+}
+
+impl BackupManager {
+    /// Gzip the rotated log segments that aren't compressed yet, then remove
+    /// the oldest archives beyond `Config::log_archives`, logging each removal
+    /// in the live log. Runs by itself after each rotation (see
+    /// `Config::log_max_bytes`). The entries of removed archives are gone
+    /// for good; [`read_log`](Self::read_log) reads those still there.
+    pub fn prune_log(&self) -> io::Result<LogPrune> {
+        self.flush_log()?;
+        self.prune_log_in(&self.root()?)
+    }
+
+    pub(crate) fn prune_log_in(&self, root: &Path) -> io::Result<LogPrune> {
+        let log = self.log_path(root);
+        let mut report = LogPrune::default();
+        for segment in segments(&*self.fs, &log)? {
+            if !segment.gz {
+                report.compressed.push(compress(&*self.fs, &segment.path)?);
+            }
+        }
+        let Some(keep) = self.config.log_archives else { return Ok(report) };
+        let archives = segments(&*self.fs, &log)?;
+        for segment in &archives[..archives.len().saturating_sub(keep)] {
+            self.fs.remove_file(&segment.path).at("prune_log", "cannot remove", &segment.path)?;
+            let name = Path::new(segment.path.file_name().unwrap_or(segment.path.as_os_str()));
+            self.append_log(root, "prune_log", name, "ok (archive removed)")?;
+            report.removed.push(segment.path.clone());
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::Config;
+    use std::fs;
+    use std::sync::Arc;
+
+    fn manager(root: &Path, archives: Option<usize>, now: u64) -> BackupManager {
+        BackupManager::new(Config { log_max_bytes: Some(1), log_archives: archives, ..config(root, now) })
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|n| n.starts_with("logfile"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn files(entries: Vec<LogEntry>) -> Vec<String> {
+        entries.into_iter().map(|e| e.file).collect()
+    }
+
+    #[test]
+    fn rotated_segments_are_gzipped_and_read_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for i in 0..4 {
+            manager(root, None, 100 * i).log_action(root, "backup", Path::new(&format!("f{i}")), "ok").unwrap();
+        }
+        // Past the limit, every entry but the first in a log rotates it.
+        assert_eq!(names(root), ["logfile.txt", "logfile.txt.1.gz", "logfile.txt.2.gz", "logfile.txt.3.gz"]);
+        let mgr = manager(root, None, 400);
+        let all = ["f0", "f1", "logfile.txt.1", "f2", "logfile.txt.2", "f3", "logfile.txt.3"];
+        assert_eq!(files(mgr.read_log().unwrap()), all);
+        assert_eq!(files(crate::read_log(root).unwrap()), all);
+        mgr.verify_log_chain().unwrap();
+
+        // Queries the newest segments answer don't open the older ones.
+        fs::write(root.join("logfile.txt.1.gz"), "not gzip").unwrap();
+        let since = mgr.read_log_filtered(&LogFilter::new().since(200)).unwrap();
+        assert_eq!(files(since), ["f2", "logfile.txt.2", "f3", "logfile.txt.3"]);
+        let last = mgr.read_log_filtered(&LogFilter::new().action("backup").last(2)).unwrap();
+        assert_eq!(files(last), ["f2", "f3"]);
+        // One that does is skipped, and so is one cut short.
+        let gz = fs::read(root.join("logfile.txt.2.gz")).unwrap();
+        fs::write(root.join("logfile.txt.2.gz"), &gz[..gz.len() / 2]).unwrap();
+        let skipped = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = Arc::clone(&skipped);
+        let mgr = BackupManager::new(Config {
+            on_event: Some(Arc::new(move |e: &crate::Event| seen.lock().unwrap().push(e.clone()))),
+            ..mgr.config().clone()
+        });
+        assert_eq!(files(mgr.read_log().unwrap()), ["logfile.txt.2", "f3", "logfile.txt.3"]);
+        let paths: Vec<PathBuf> = skipped
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| match e {
+                crate::Event::LogArchiveSkipped { path, .. } => path.clone(),
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(paths, [root.join("logfile.txt.2.gz"), root.join("logfile.txt.1.gz")]);
+        assert_eq!(files(crate::read_log(root).unwrap()), ["logfile.txt.2", "f3", "logfile.txt.3"]);
+    }
+
+    #[test]
+    fn archives_past_the_limit_are_removed_and_logged() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for i in 0..4 {
+            manager(root, Some(2), 100 * i).log_action(root, "backup", Path::new(&format!("f{i}")), "ok").unwrap();
+        }
+        assert_eq!(names(root), ["logfile.txt", "logfile.txt.2.gz", "logfile.txt.3.gz"]);
+        let mgr = manager(root, Some(2), 400);
+        let entries = mgr.read_log().unwrap();
+        let removal = entries.last().unwrap();
+        assert_eq!((removal.action.as_str(), removal.file.as_str()), ("prune_log", "logfile.txt.1.gz"));
+        assert_eq!(files(entries), ["logfile.txt.1", "f2", "logfile.txt.2", "f3", "logfile.txt.3", "logfile.txt.1.gz"]);
+
+        // A segment left uncompressed is compressed by `log prune`.
+        fs::copy(root.join("logfile.txt"), root.join("logfile.txt.4")).unwrap();
+        let report = mgr.prune_log().unwrap();
+        assert_eq!(report.compressed, [root.join("logfile.txt.4.gz")]);
+        assert_eq!(report.removed, [root.join("logfile.txt.2.gz")]);
+        assert_eq!(names(root), ["logfile.txt", "logfile.txt.3.gz", "logfile.txt.4.gz"]);
+    }
+}
@@ -0,0 +1,371 @@
+//! Interval backups: back a set of files up on a fixed schedule, skipping
+//! those whose contents match their latest backup. Size and mtime are
+//! compared with the latest backup's sidecar first, so a file is only hashed
+//! when they can't tell.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fs2::FileExt;
+
+use crate::batch::{BatchEntry, BatchReport};
+use crate::chunk;
+use crate::dedup;
+use crate::delta;
+use crate::compress::{gunzip_to, Compression};
+use crate::digest::{read_digest, tagged, DigestAlgo, Hasher};
+use crate::error::{error_chain, BackupError, Context};
+use crate::meta::{is_tool_backup, read_meta, Layout};
+use crate::naming::display_name;
+use crate::options::BackupOptions;
+use crate::vfs::{FileStat, FileSystem};
+use crate::{newest_ts_backup, BackupManager, BackupReport};
+
+/// Lock file in the backup directory held for the length of a cycle.
+const LOCK_FILE: &str = ".safe_backup.schedule.lock";
+
+/// Outcome of one scheduled cycle.
+#[derive(Debug, Default)]
+pub struct CycleReport {
+    /// Files that changed and were backed up.
+    pub backed_up: Vec<(PathBuf, BackupReport)>,
+    /// Files identical to their latest backup.
+    pub unchanged: Vec<PathBuf>,
+    /// Files modified within `Config::min_age_secs`, left for a later cycle.
+    pub too_recent: Vec<PathBuf>,
+    /// Files that could not be checked or backed up, with the error chain.
+    pub failed: Vec<(PathBuf, String)>,
+    /// How each file that could be checked was found changed or unchanged.
+    pub checks: Vec<(PathBuf, ChangeCheck)>,
+    /// The cycle did not run because another one (e.g. a slow cron job) held the lock.
+    pub skipped_overlap: bool,
+    /// The same file by file: backed up, skipped as unchanged or too recent, or failed.
+    pub batch: BatchReport,
+}
+
+impl CycleReport {
+    /// One-line summary, as written to the log.
+    pub fn summary(&self) -> String {
+        if self.skipped_overlap {
+            return "skipped (previous cycle still running)".to_string();
+        }
+        let too_recent = match self.too_recent.len() {
+            0 => String::new(),
+            n => format!(", {n} too recent"),
+        };
+        format!(
+            "ok ({} backed up, {} unchanged{too_recent}, {} failed)",
+            self.backed_up.len(),
+            self.unchanged.len(),
+            self.failed.len()
+        )
+    }
+}
+
+/// How [`BackupManager::check_changed`] decided whether a file changed since its latest backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeCheck {
+    /// There is no timestamped backup to compare with, or the file isn't a regular file.
+    NoBackup,
+    /// Its size differs from the recorded one; not hashed.
+    SizeDiffers,
+    /// Its size and mtime match the recorded ones; not hashed.
+    StatMatches,
+    /// Hashed, because the mtime differs, the backup records neither (a
+    /// legacy ".bk") or `Config::paranoid` is set.
+    Hashed { changed: bool },
+}
+
+impl ChangeCheck {
+    /// Whether the file is to be backed up.
+    pub fn changed(self) -> bool {
+        !matches!(self, ChangeCheck::StatMatches | ChangeCheck::Hashed { changed: false })
+    }
+
+    /// Short description for verbose output.
+    pub fn describe(self) -> &'static str {
+        match self {
+            ChangeCheck::NoBackup => "no earlier backup",
+            ChangeCheck::SizeDiffers => "size differs",
+            ChangeCheck::StatMatches => "size and mtime match",
+            ChangeCheck::Hashed { changed: true } => "hashed, contents differ",
+            ChangeCheck::Hashed { changed: false } => "hashed, contents match",
+        }
+    }
+}
+
+impl BackupManager {
+    /// Back `name` up unless [`check_changed`](Self::check_changed) finds it
+    /// unchanged. `None` means it was unchanged.
+    pub fn backup_if_changed(&self, name: impl AsRef<Path>) -> io::Result<Option<BackupReport>> {
+        let name = name.as_ref();
+        if !self.check_changed(name)?.changed() {
+            return Ok(None);
+        }
+        self.backup_file_with(name, &BackupOptions::default()).map(Some)
+    }
+
+    /// Whether `name` changed since its latest timestamped backup. A size
+    /// different from the one in the backup's sidecar means it did, and the
+    /// same size and mtime (as it was before the backup's copy began) mean
+    /// it didn't; otherwise the contents are hashed with the algorithm the
+    /// backup was recorded with. An edit that keeps
+    /// the size and mtime goes unnoticed unless `Config::paranoid` makes
+    /// every same-size file be hashed.
+    pub fn check_changed(&self, name: impl AsRef<Path>) -> io::Result<ChangeCheck> {
+        let name = name.as_ref();
+        let root = self.root()?;
+        let src = self.resolve(&root, name)?;
+        let broot = self.backup_root(&root);
+        let fs = &*self.fs;
+        let latest = newest_ts_backup(fs, &broot, name, self.config.legacy_bk, FileStat::is_file)?;
+        let is_file = fs.metadata(&src).is_ok_and(|m| m.is_file());
+        let (Some(bak), true) = (latest, is_file) else { return Ok(ChangeCheck::NoBackup) };
+        // A legacy ".bk" backup has no sidecar to compare with.
+        if is_tool_backup(fs, &bak) {
+            let meta = read_meta(fs, &bak)?;
+            let md = fs.metadata(&src).at("schedule", "cannot stat", &src)?;
+            if md.len != meta.size {
+                return Ok(ChangeCheck::SizeDiffers);
+            }
+            let recorded = UNIX_EPOCH + Duration::new(meta.mtime, meta.mtime_nanos);
+            if !self.config.paranoid && md.modified == Some(recorded) {
+                return Ok(ChangeCheck::StatMatches);
+            }
+        }
+        let (algo, digest) = content_digest(fs, &bak, self.config.digest)?;
+        let changed = digest != fs.open(&src).and_then(|f| read_digest(f, algo)).at("schedule", "cannot read", &src)?;
+        Ok(ChangeCheck::Hashed { changed })
+    }
+
+    /// One cycle over `names`: each changed file is backed up, unless it is
+    /// too recent for `Config::min_age_secs`, and the summary is logged as
+    /// action "schedule". Another cycle running against
+    /// the same backup directory (another process) makes this one skip.
+    pub fn run_cycle(&self, names: &[PathBuf]) -> io::Result<CycleReport> {
+        let root = self.root()?;
+        let lock_path = self.ensure_backup_root("schedule", &root)?.join(LOCK_FILE);
+        let lock = File::create(&lock_path).at("schedule", "cannot create lock file", &lock_path)?;
+        let started = Instant::now();
+        let mut report = CycleReport::default();
+        if lock.try_lock_exclusive().is_err() {
+            report.skipped_overlap = true;
+        } else {
+            for name in names {
+                let result = self.check_changed(name).and_then(|check| {
+                    report.checks.push((name.clone(), check));
+                    check.changed().then(|| self.backup_file_with(name, &BackupOptions::default())).transpose()
+                });
+                let shown = display_name(name.as_os_str()).into_owned();
+                report.batch.entries.push(match &result {
+                    Ok(Some(r)) => self.backup_entry(shown, Ok(r)),
+                    Ok(None) => BatchEntry::skipped(shown, "unchanged".to_string()),
+                    Err(e) => self.backup_entry(shown, Err(e)),
+                });
+                match result {
+                    Ok(Some(r)) => report.backed_up.push((name.clone(), r)),
+                    Ok(None) => report.unchanged.push(name.clone()),
+                    Err(e) if matches!(BackupError::from_io(&e), Some(BackupError::TooRecent { .. })) => {
+                        report.too_recent.push(name.clone())
+                    }
+                    Err(e) => report.failed.push((name.clone(), error_chain(&e))),
+                }
+            }
+            let _ = FileExt::unlock(&lock);
+        }
+        report.batch.elapsed = started.elapsed();
+        self.log_action(&root, "schedule", Path::new("*"), &report.summary())?;
+        Ok(report)
+    }
+
+    /// Run a cycle over `names` every `every` until `Config::cancel` is set;
+    /// with `once`, run a single cycle. Cycles never overlap: one that takes
+    /// longer than `every` is followed directly by the next. `on_cycle` sees
+    /// each report; returns the number of cycles run.
+    pub fn schedule(
+        &self,
+        names: &[PathBuf],
+        every: Duration,
+        once: bool,
+        mut on_cycle: impl FnMut(&io::Result<CycleReport>),
+    ) -> io::Result<usize> {
+        let mut cycles = 0;
+        while !self.cancelled() {
+            let started = Instant::now();
+            on_cycle(&self.run_cycle(names));
+            cycles += 1;
+            if once {
+                break;
+            }
+            while started.elapsed() < every && !self.cancelled() {
+                std::thread::sleep((every - started.elapsed()).min(Duration::from_millis(100)));
+            }
+        }
+        Ok(cycles)
+    }
+}
+
+/// Tagged digest of a backup's original contents and its algorithm: the
+/// recorded digest for plain backups, the digest in the manifest for
+/// deduplicated ones, the digest of the decompressed, reassembled data for
+/// compressed, chunked or delta ones. Uses the recorded algorithm, else `fallback`.
+pub(crate) fn content_digest(
+    fs: &dyn FileSystem,
+    backup: &Path,
+    fallback: DigestAlgo,
+) -> io::Result<(DigestAlgo, String)> {
+    let read = |algo| fs.open(backup).and_then(|f| read_digest(f, algo)).at("schedule", "cannot read", backup);
+    // A legacy ".bk" backup has no sidecar; it is a plain copy.
+    if !is_tool_backup(fs, backup) {
+        return Ok((fallback, read(fallback)?));
+    }
+    let meta = read_meta(fs, backup)?;
+    if meta.layout == Layout::Deduped {
+        let digest = dedup::read_manifest("schedule", backup)?.digest;
+        let algo = DigestAlgo::of(&digest).unwrap_or(fallback);
+        return Ok((algo, digest));
+    }
+    let recorded = meta.digest.as_deref().map(tagged);
+    let algo = recorded.as_deref().and_then(|d| DigestAlgo::of(d).ok()).unwrap_or(fallback);
+    let digest = match (meta.compression, recorded) {
+        _ if meta.layout == Layout::Chunked => {
+            let mut h = Hasher::new(algo);
+            chunk::read_to("schedule", backup, meta.compression, &mut h)?;
+            h.finish()
+        }
+        _ if meta.layout == Layout::Delta => {
+            let mut h = Hasher::new(algo);
+            delta::read_to("schedule", backup, &mut h)?;
+            h.finish()
+        }
+        (Compression::None, Some(digest)) => digest,
+        (Compression::None, None) => read(algo)?,
+        (Compression::Gzip, _) => {
+            let mut h = Hasher::new(algo);
+            gunzip_to("schedule", backup, &mut h)?;
+            h.finish()
+        }
+    };
+    Ok((algo, digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::{read_backup_meta, Config};
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    fn manager(root: &Path, now: u64, compression: Compression) -> BackupManager {
+        BackupManager::new(Config { compression, keep_last: Some(2), ..config(root, now) })
+    }
+
+    #[test]
+    fn cycles_skip_unchanged_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        let names = [PathBuf::from("a.txt"), PathBuf::from("b.txt"), PathBuf::from("gone.txt")];
+
+        for (t, compression) in [(100, Compression::None), (400, Compression::Gzip)] {
+            let r = manager(root, t, compression).run_cycle(&names).unwrap();
+            assert_eq!(r.unchanged.len() + r.backed_up.len(), 2);
+            assert_eq!(r.failed.len(), 1);
+
+            fs::write(root.join("b.txt"), format!("b {compression}")).unwrap();
+            let r = manager(root, t + 100, compression).run_cycle(&names).unwrap();
+            assert_eq!(r.unchanged, [PathBuf::from("a.txt")]);
+            assert_eq!(r.backed_up.len(), 1);
+            assert_eq!(r.backed_up[0].0, PathBuf::from("b.txt"));
+            assert_eq!(r.summary(), "ok (1 backed up, 1 unchanged, 1 failed)");
+            assert_eq!((r.batch.succeeded(), r.batch.skipped(), r.batch.failed()), (1, 1, 1));
+            assert_eq!(r.batch.entries[1].bytes, format!("b {compression}").len() as u64);
+
+            // Compared against the latest backup, compressed or not.
+            let r = manager(root, t + 200, compression).run_cycle(&names).unwrap();
+            assert!(r.backed_up.is_empty() && r.unchanged.len() == 2, "{r:?}");
+        }
+        let log = crate::read_log(root).unwrap();
+        assert!(log.iter().any(|e| e.action == "schedule" && e.result.starts_with("ok (1 backed up")));
+    }
+
+    #[test]
+    fn size_and_mtime_decide_before_hashing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let path = root.join("a.txt");
+        fs::write(&path, "one").unwrap();
+        let mgr = manager(root, 100, Compression::None);
+        assert_eq!(mgr.check_changed("a.txt").unwrap(), ChangeCheck::NoBackup);
+        let bak = mgr.backup_file_report("a.txt").unwrap().path;
+        let meta = read_backup_meta(&bak).unwrap();
+        let recorded = UNIX_EPOCH + Duration::new(meta.mtime, meta.mtime_nanos);
+        let set_mtime = |t| File::options().write(true).open(&path).unwrap().set_modified(t).unwrap();
+
+        assert_eq!(mgr.check_changed("a.txt").unwrap(), ChangeCheck::StatMatches);
+        // Same size and mtime is trusted without reading the file, unless paranoid.
+        fs::write(&path, "two").unwrap();
+        set_mtime(recorded);
+        assert_eq!(mgr.check_changed("a.txt").unwrap(), ChangeCheck::StatMatches);
+        let paranoid = BackupManager::new(Config { paranoid: true, ..mgr.config.clone() });
+        assert_eq!(paranoid.check_changed("a.txt").unwrap(), ChangeCheck::Hashed { changed: true });
+
+        // A touched file of the same size is hashed.
+        fs::write(&path, "one").unwrap();
+        set_mtime(recorded + Duration::from_secs(60));
+        assert_eq!(mgr.check_changed("a.txt").unwrap(), ChangeCheck::Hashed { changed: false });
+        assert!(mgr.backup_if_changed("a.txt").unwrap().is_none());
+
+        fs::write(&path, "three").unwrap();
+        set_mtime(recorded);
+        let r = manager(root, 200, Compression::None).run_cycle(&[PathBuf::from("a.txt")]).unwrap();
+        assert_eq!(r.checks, [(PathBuf::from("a.txt"), ChangeCheck::SizeDiffers)]);
+        assert_eq!((r.backed_up.len(), ChangeCheck::SizeDiffers.describe()), (1, "size differs"));
+    }
+
+    /// A clock that touches `path` when read, as an edit during the copy would.
+    struct TouchingClock(PathBuf, SystemTime);
+
+    impl crate::Clock for TouchingClock {
+        fn now_unix(&self) -> u64 {
+            File::options().write(true).open(&self.0).unwrap().set_modified(self.1).unwrap();
+            100
+        }
+    }
+
+    #[test]
+    fn a_change_while_copying_is_not_taken_for_none() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let path = root.join("a.txt");
+        fs::write(&path, "one").unwrap();
+        let later = fs::metadata(&path).unwrap().modified().unwrap() + Duration::from_secs(60);
+        let clock = Arc::new(TouchingClock(path.clone(), later));
+        let mgr = BackupManager::new(Config { root: Some(root.to_path_buf()), clock, ..Config::default() });
+        mgr.backup_file("a.txt").unwrap();
+        // The mtime after the copy would have matched; the one from before it doesn't.
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), later);
+        let check = manager(root, 200, Compression::None).check_changed("a.txt").unwrap();
+        assert_eq!(check, ChangeCheck::Hashed { changed: false });
+    }
+
+    #[test]
+    fn overlapping_cycle_is_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let mgr = manager(root, 100, Compression::None);
+        let held = File::create(root.join(LOCK_FILE)).unwrap();
+        held.lock_exclusive().unwrap();
+        let r = mgr.run_cycle(&[PathBuf::from("a.txt")]).unwrap();
+        assert!(r.skipped_overlap && r.backed_up.is_empty());
+        FileExt::unlock(&held).unwrap();
+        assert_eq!(mgr.schedule(&[PathBuf::from("a.txt")], Duration::from_secs(3600), true, |_| {}).unwrap(), 1);
+        assert_eq!(mgr.list_backups("a.txt").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,197 @@
+//! Line-based scripts of file operations, for repeatable batch jobs:
+//!
+//! ```text
+//! # nightly
+//! backup notes.txt
+//! restore data.csv
+//! delete scratch.tmp
+//! ```
+//!
+//! One operation per line, the file name being the rest of the line. Blank
+//! lines and lines starting with `#` are skipped.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::batch::{BatchEntry, BatchReport, BatchStatus};
+use crate::error::{no_backup, BackupError, Context};
+use crate::naming::display_name;
+use crate::{BackupManager, RestoreOptions};
+
+/// What one script line does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptAction {
+    Backup,
+    Restore,
+    /// Delete the working file; refused for a file without a backup.
+    Delete,
+}
+
+impl fmt::Display for ScriptAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Backup => "backup",
+            Self::Restore => "restore",
+            Self::Delete => "delete",
+        })
+    }
+}
+
+/// A parsed script line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptStep {
+    /// 1-based line number in the script.
+    pub line: usize,
+    pub action: ScriptAction,
+    pub name: PathBuf,
+}
+
+/// What [`BackupManager::run_script`] did: a result per step run, with the
+/// backup made, the file restored or the file deleted. A backup refused as
+/// too recent (`Config::min_age_secs`) is an error here but a skip in
+/// `batch`: it neither stops the script nor counts against [`all_ok`](Self::all_ok).
+#[derive(Debug)]
+pub struct ScriptReport {
+    pub results: Vec<(ScriptStep, io::Result<PathBuf>)>,
+    /// Steps not run because an earlier one failed.
+    pub skipped: usize,
+    /// The same step by step, a step not run counting as skipped.
+    pub batch: BatchReport,
+}
+
+impl ScriptReport {
+    pub fn all_ok(&self) -> bool {
+        self.skipped == 0 && self.batch.failed() == 0
+    }
+}
+
+/// Read and check the script at `path`. An unknown command or a missing file
+/// name is `BackupError::ScriptSyntax`, before anything is run.
+pub fn parse_script(path: impl AsRef<Path>) -> io::Result<Vec<ScriptStep>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).at("run", "cannot read script", path)?;
+    let mut steps = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let syntax = |reason| BackupError::ScriptSyntax { path: path.to_path_buf(), line: i + 1, reason };
+        let (command, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let action = match command {
+            "backup" => ScriptAction::Backup,
+            "restore" => ScriptAction::Restore,
+            "delete" => ScriptAction::Delete,
+            _ => return Err(syntax("unknown command (expected backup, restore or delete)").into()),
+        };
+        match name.trim() {
+            "" => return Err(syntax("missing file name").into()),
+            name => steps.push(ScriptStep { line: i + 1, action, name: PathBuf::from(name) }),
+        }
+    }
+    Ok(steps)
+}
+
+impl BackupManager {
+    /// Run the script at `path` (see [`parse_script`]), one step after the
+    /// other. The first failure stops it unless `keep_going`; either way each
+    /// step is logged as if run on its own.
+    pub fn run_script(&self, path: impl AsRef<Path>, keep_going: bool) -> io::Result<ScriptReport> {
+        Ok(self.run_steps(&parse_script(path)?, keep_going))
+    }
+
+    /// [`run_script`](Self::run_script) with the steps already parsed.
+    pub fn run_steps(&self, steps: &[ScriptStep], keep_going: bool) -> ScriptReport {
+        let started = Instant::now();
+        let mut results = Vec::new();
+        let mut batch = BatchReport::default();
+        for step in steps {
+            let name = display_name(step.name.as_os_str()).into_owned();
+            let (result, mut entry) = match step.action {
+                ScriptAction::Backup => {
+                    let r = self.backup_file_report(&step.name);
+                    let entry = self.backup_entry(name, r.as_ref());
+                    (r.map(|r| r.path), entry)
+                }
+                ScriptAction::Restore => {
+                    let r = self.restore_file_with(&step.name, &RestoreOptions::default());
+                    let entry = self.restore_entry(name, r.as_ref());
+                    (r.map(|r| r.path), entry)
+                }
+                ScriptAction::Delete => {
+                    let r = self.delete_backed_up(&step.name);
+                    let entry = match &r {
+                        Ok(path) => BatchEntry::ok(name, path.display().to_string(), 0),
+                        Err(e) => BatchEntry::failed(name, e),
+                    };
+                    (r, entry)
+                }
+            };
+            entry.detail = format!("{} (line {}): {}", step.action, step.line, entry.detail);
+            let failed = entry.status == BatchStatus::Failed;
+            batch.entries.push(entry);
+            results.push((step.clone(), result));
+            if failed && !keep_going {
+                break;
+            }
+        }
+        for step in &steps[results.len()..] {
+            let detail = format!("{} (line {}): not run after a failure", step.action, step.line);
+            batch.entries.push(BatchEntry::skipped(display_name(step.name.as_os_str()).into_owned(), detail));
+        }
+        batch.elapsed = started.elapsed();
+        ScriptReport { skipped: steps.len() - results.len(), results, batch }
+    }
+
+    /// Delete `name` if it can be restored: it has a backup.
+    fn delete_backed_up(&self, name: &Path) -> io::Result<PathBuf> {
+        let path = self.validate_path(name)?;
+        if self.find_latest_backup(name).is_err() {
+            return Err(no_backup("delete", "refusing to delete a file without a backup", &path));
+        }
+        self.delete_file(name)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn scripts_run_until_the_first_failure_unless_told_to_keep_going() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = BackupManager::new(Config { root: Some(root.to_path_buf()), ..Config::default() });
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b c.txt"), "b").unwrap();
+        let script = tmp.path().join("job.txt");
+        fs::write(&script, "# nightly\n\nbackup a.txt\n  delete   b c.txt\nbackup b c.txt\ndelete a.txt\n").unwrap();
+
+        let steps = parse_script(&script).unwrap();
+        let lines: Vec<_> = steps.iter().map(|s| (s.line, s.action, s.name.to_str().unwrap())).collect();
+        assert_eq!(lines[1], (4, ScriptAction::Delete, "b c.txt"));
+        // "b c.txt" has no backup yet, so it is not deleted and the rest is skipped.
+        let report = mgr.run_script(&script, false).unwrap();
+        assert_eq!((report.results.len(), report.skipped), (2, 2));
+        assert!(report.results[0].1.is_ok() && report.results[1].1.is_err());
+        assert_eq!((report.batch.succeeded(), report.batch.skipped(), report.batch.failed()), (1, 2, 1));
+        assert!(report.batch.entries[1].detail.starts_with("delete (line 4): "), "{}", report.batch);
+        assert!(root.join("b c.txt").exists() && root.join("a.txt").exists());
+
+        let report = mgr.run_script(&script, true).unwrap();
+        assert_eq!((report.results.len(), report.skipped), (4, 0));
+        assert!(!report.all_ok());
+        assert!(!root.join("a.txt").exists());
+
+        fs::write(&script, "backup a.txt\ncopy a.txt\n").unwrap();
+        let e = mgr.run_script(&script, true).unwrap_err();
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::ScriptSyntax { line: 2, .. })), "{e}");
+        fs::write(&script, "restore\n").unwrap();
+        assert_eq!(parse_script(&script).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
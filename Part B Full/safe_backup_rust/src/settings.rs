@@ -0,0 +1,251 @@
+//! Layered settings for the command line: CLI flag > environment variable >
+//! config file > built-in default. Each layer is a [`Settings`] with only the
+//! values it sets; [`resolve_settings`] merges them without touching the process env.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::clock::TimeZone;
+use crate::compress::Compression;
+use crate::error::{BackupError, Context};
+use crate::log::LogUser;
+use crate::remote::SftpConfig;
+use crate::Config;
+
+/// Environment variables read by [`Settings::from_env`].
+pub const ENV_BACKUP_DIR: &str = "SAFE_BACKUP_DIR";
+pub const ENV_LOG: &str = "SAFE_BACKUP_LOG";
+pub const ENV_KEEP: &str = "SAFE_BACKUP_KEEP";
+pub const ENV_COMPRESS: &str = "SAFE_BACKUP_COMPRESS";
+
+/// One layer of settings; `None` means "not set here".
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub backup_dir: Option<PathBuf>,
+    pub log: Option<PathBuf>,
+    pub keep: Option<usize>,
+    pub compress: Option<Compression>,
+    /// Exclude patterns for directory backups; only from the config file.
+    pub exclude: Option<Vec<String>>,
+    /// Server for `backup --sftp`; only from the config file, see [`SftpConfig::resolve`]
+    /// for the environment variables.
+    pub sftp: Option<SftpConfig>,
+    /// `Config::tidy`: the log in ".safe_backup".
+    pub tidy: Option<bool>,
+    /// `Config::tidy_backups`: timestamped backups in ".safe_backup/backups".
+    pub tidy_backups: Option<bool>,
+    /// `Config::plain_copy`; only from the config file.
+    pub plain: Option<bool>,
+    /// `Config::log_user`: "full", "hashed" or "omitted"; not from the environment.
+    pub log_user: Option<LogUser>,
+    /// `Config::log_audit`; not from the environment.
+    pub log_audit: Option<bool>,
+    /// `Config::time_zone`: "utc" or "local"; not from the environment.
+    pub time_zone: Option<TimeZone>,
+    /// `Config::pre_hook`; not from the environment.
+    pub pre_hook: Option<PathBuf>,
+    /// `Config::post_hook`; not from the environment.
+    pub post_hook: Option<PathBuf>,
+}
+
+impl Settings {
+    /// Values from `SAFE_BACKUP_DIR`, `SAFE_BACKUP_LOG`, `SAFE_BACKUP_KEEP` and
+    /// `SAFE_BACKUP_COMPRESS` in `env`. Empty values count as unset; invalid ones
+    /// are an `InvalidSetting` error naming the variable.
+    pub fn from_env(env: &HashMap<String, String>) -> io::Result<Self> {
+        let get = |k: &str| env.get(k).map(|v| v.trim()).filter(|v| !v.is_empty());
+        let invalid = |name: &str, value: &str, reason: String| -> io::Error {
+            BackupError::InvalidSetting { name: name.to_string(), value: value.to_string(), reason }.into()
+        };
+        let keep = match get(ENV_KEEP) {
+            Some(v) => Some(v.parse().map_err(|_| invalid(ENV_KEEP, v, "expected a non-negative number".into()))?),
+            None => None,
+        };
+        let compress = match get(ENV_COMPRESS) {
+            Some(v) => Some(v.parse().map_err(|e| invalid(ENV_COMPRESS, v, e))?),
+            None => None,
+        };
+        Ok(Self {
+            backup_dir: get(ENV_BACKUP_DIR).map(PathBuf::from),
+            log: get(ENV_LOG).map(PathBuf::from),
+            keep,
+            compress,
+            exclude: None,
+            sftp: None,
+            tidy: None,
+            tidy_backups: None,
+            plain: None,
+            log_user: None,
+            log_audit: None,
+            time_zone: None,
+            pre_hook: None,
+            post_hook: None,
+        })
+    }
+
+    /// A JSON config file with any of the keys `backup_dir`, `log`, `keep`,
+    /// `compress`, `exclude` (a list of patterns), `sftp` (an object with the
+    /// fields of [`SftpConfig`]), `tidy`, `tidy_backups`, `plain` (booleans),
+    /// `log_user` (see [`LogUser`]), `log_audit` (a boolean), `time_zone`
+    /// ("utc" or "local"), `pre_hook` and `post_hook` (programs).
+    /// A missing file is an empty layer.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).at("config", "cannot read config file", path),
+        };
+        serde_json::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .at("config", "malformed config file", path)
+    }
+
+    /// Each value from `self` if set, else from `lower`.
+    pub fn or(self, lower: Settings) -> Settings {
+        Settings {
+            backup_dir: self.backup_dir.or(lower.backup_dir),
+            log: self.log.or(lower.log),
+            keep: self.keep.or(lower.keep),
+            compress: self.compress.or(lower.compress),
+            exclude: self.exclude.or(lower.exclude),
+            sftp: self.sftp.or(lower.sftp),
+            tidy: self.tidy.or(lower.tidy),
+            tidy_backups: self.tidy_backups.or(lower.tidy_backups),
+            plain: self.plain.or(lower.plain),
+            log_user: self.log_user.or(lower.log_user),
+            log_audit: self.log_audit.or(lower.log_audit),
+            time_zone: self.time_zone.or(lower.time_zone),
+            pre_hook: self.pre_hook.or(lower.pre_hook),
+            post_hook: self.post_hook.or(lower.post_hook),
+        }
+    }
+
+    /// `config` with every value set here applied.
+    pub fn apply(self, mut config: Config) -> Config {
+        if let Some(dir) = self.backup_dir {
+            config.backup_dir = Some(dir);
+        }
+        if let Some(log) = self.log {
+            config.log_file = Some(log);
+        }
+        if let Some(keep) = self.keep {
+            config.keep_last = Some(keep);
+        }
+        if let Some(c) = self.compress {
+            config.compression = c;
+        }
+        if let Some(patterns) = self.exclude {
+            config.exclude = patterns;
+        }
+        if let Some(on) = self.tidy {
+            config.tidy = on;
+        }
+        if let Some(on) = self.tidy_backups {
+            config.tidy_backups = on;
+        }
+        if let Some(on) = self.plain {
+            config.plain_copy = on;
+        }
+        if let Some(form) = self.log_user {
+            config.log_user = form;
+        }
+        if let Some(on) = self.log_audit {
+            config.log_audit = on;
+        }
+        if let Some(zone) = self.time_zone {
+            config.time_zone = zone;
+        }
+        if let Some(program) = self.pre_hook {
+            config.pre_hook = Some(program);
+        }
+        if let Some(program) = self.post_hook {
+            config.post_hook = Some(program);
+        }
+        config
+    }
+}
+
+/// Merge the layers: `cli` > `env` > `file`; whatever none sets keeps its default.
+pub fn resolve_settings(cli: Settings, env: &HashMap<String, String>, file: Settings) -> io::Result<Settings> {
+    Ok(cli.or(Settings::from_env(env)?).or(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn precedence_cli_env_file() {
+        let file = Settings {
+            backup_dir: Some("f".into()),
+            keep: Some(1),
+            log: Some("f.log".into()),
+            exclude: Some(vec!["*.tmp".into()]),
+            ..Settings::default()
+        };
+        let cli = Settings { keep: Some(3), ..Settings::default() };
+        let got = resolve_settings(cli, &env(&[(ENV_KEEP, "2"), (ENV_BACKUP_DIR, "e"), (ENV_COMPRESS, "gzip")]), file)
+            .unwrap();
+        assert_eq!(
+            got,
+            Settings {
+                backup_dir: Some("e".into()),
+                log: Some("f.log".into()),
+                keep: Some(3),
+                compress: Some(Compression::Gzip),
+                exclude: Some(vec!["*.tmp".into()]),
+                sftp: None,
+                tidy: None,
+                tidy_backups: None,
+                plain: None,
+                log_user: None,
+                log_audit: None,
+                time_zone: None,
+                pre_hook: None,
+                post_hook: None,
+            }
+        );
+        let config = got.apply(Config::default());
+        assert_eq!(config.keep_last, Some(3));
+        assert_eq!(config.compression, Compression::Gzip);
+        assert_eq!(config.log_file, Some(PathBuf::from("f.log")));
+        assert_eq!(config.exclude, ["*.tmp"]);
+    }
+
+    #[test]
+    fn invalid_values_name_the_variable() {
+        let e = Settings::from_env(&env(&[(ENV_KEEP, "lots")])).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "SAFE_BACKUP_KEEP=\"lots\": expected a non-negative number");
+        let e = Settings::from_env(&env(&[(ENV_COMPRESS, "zstd")])).unwrap_err();
+        assert!(e.to_string().starts_with("SAFE_BACKUP_COMPRESS=\"zstd\": unknown compression"), "{e}");
+        assert_eq!(Settings::from_env(&env(&[(ENV_KEEP, " ")])).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn config_file_layer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("safe_backup.json");
+        assert_eq!(Settings::from_file(&path).unwrap(), Settings::default());
+        fs::write(&path, r#"{"keep": 5, "compress": "gzip"}"#).unwrap();
+        assert_eq!(
+            Settings::from_file(&path).unwrap(),
+            Settings { keep: Some(5), compress: Some(Compression::Gzip), ..Settings::default() }
+        );
+        fs::write(&path, r#"{"tidy": true, "plain": false}"#).unwrap();
+        let config = Settings::from_file(&path).unwrap().apply(Config::default());
+        assert!(config.tidy && !config.tidy_backups && !config.plain_copy);
+        fs::write(&path, r#"{"kep": 5}"#).unwrap();
+        assert_eq!(Settings::from_file(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,180 @@
+//! The ".safe_backup" directory under the root: with `Config::tidy` it holds
+//! the log, with `Config::tidy_backups` the timestamped backups, so the
+//! project directory keeps only the originals and their plain copies.
+//! [`BackupManager::migrate_layout`] moves what the flat layout left in the root.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compact::members;
+use crate::error::Context;
+use crate::legacy::Migration;
+use crate::meta::is_tool_backup;
+use crate::naming::split_backup_name;
+use crate::BackupManager;
+
+/// The directory under the root, created on demand.
+pub(crate) const TIDY_DIR: &str = ".safe_backup";
+/// The log there, with `Config::tidy`.
+pub(crate) const TIDY_LOG: &str = ".safe_backup/logfile.txt";
+/// Where timestamped backups go with `Config::tidy_backups`.
+pub(crate) const TIDY_BACKUPS: &str = ".safe_backup/backups";
+
+impl BackupManager {
+    /// The root itself, when `Config::tidy_backups` moved the backup directory
+    /// away from it: backups made before are still looked up there.
+    pub(crate) fn flat_root(&self, root: &Path) -> Option<PathBuf> {
+        (self.config.tidy_backups && self.config.backup_dir.is_none()).then(|| root.to_path_buf())
+    }
+
+    /// Where plain copies go: next to the originals with `Config::tidy_backups`,
+    /// else with the timestamped backups.
+    pub(crate) fn plain_root(&self, root: &Path) -> PathBuf {
+        self.flat_root(root).unwrap_or_else(|| self.backup_root(root))
+    }
+
+    /// Move what the flat layout keeps in the root into ".safe_backup": the log
+    /// "logfile.txt" with `Config::tidy`, and with `Config::tidy_backups` every
+    /// timestamped backup with its sidecar and pieces. Deduplicated backups
+    /// stay, since their objects are found next to them. A log already started
+    /// in ".safe_backup" gets the old entries put in front of its own. No
+    /// backup is written over; one already at a destination is an
+    /// `AlreadyExists` error. Moves to a backup directory on another file
+    /// system are copies, each complete before its original is removed.
+    /// With `dry_run`, only report the moves.
+    pub fn migrate_layout(&self, dry_run: bool) -> io::Result<Vec<Migration>> {
+        let root = self.root()?;
+        let mut moves = Vec::new();
+        let old_log = root.join(crate::log::LOG_FILE);
+        if self.config.tidy && self.config.log_file.is_none() && old_log.is_file() {
+            let new_log = root.join(TIDY_LOG);
+            if !dry_run && new_log.exists() {
+                crate::log::merge_logs(&*self.fs, "migrate", &old_log, &new_log)?;
+            } else if !dry_run {
+                self.fs.create_dir_all(&root.join(TIDY_DIR)).at("migrate", "cannot create directory", &root)?;
+                self.fs.move_file(&old_log, &new_log).copying("migrate", &old_log, &new_log)?;
+            }
+            moves.push(Migration { from: old_log, to: new_log });
+        }
+        let Some(flat) = self.flat_root(&root) else { return Ok(moves) };
+        let broot = self.backup_root(&root);
+        let mut backups = Vec::new();
+        for fname in self.fs.read_dir_names(&flat).at("migrate", "cannot list directory", &flat)? {
+            let fname = fname.at("migrate", "cannot list directory", &flat)?;
+            let path = flat.join(&fname);
+            if let Some((logical, Some(_))) = split_backup_name(&fname) {
+                if is_tool_backup(&*self.fs, &path) {
+                    backups.push((PathBuf::from(logical), path));
+                }
+            }
+        }
+        backups.sort();
+        if !dry_run && !backups.is_empty() {
+            self.fs.create_dir_all(&broot).at("migrate", "cannot create backup directory", &broot)?;
+        }
+        for (logical, backup) in backups {
+            let Some(files) = members(&backup)? else { continue };
+            let dests: Vec<PathBuf> = files.iter().map(|f| broot.join(f.file_name().unwrap_or_default())).collect();
+            for dest in &dests {
+                refuse_existing(dest)?;
+            }
+            if !dry_run {
+                for (from, to) in files.iter().zip(&dests) {
+                    self.fs.move_file(from, to).copying("migrate", from, to)?;
+                }
+                self.log_action(&root, "migrate", &logical, &format!("ok (into {TIDY_BACKUPS})"))?;
+            }
+            moves.push(Migration { from: backup, to: dests[0].clone() });
+        }
+        Ok(moves)
+    }
+}
+
+fn refuse_existing(dest: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(dest) {
+        Ok(_) => Err(io::Error::from(io::ErrorKind::AlreadyExists)).at("migrate", "would overwrite", dest),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::{Config, RestoreOptions};
+
+    fn manager(root: &Path, tidy: bool, now: u64) -> BackupManager {
+        BackupManager::new(Config { tidy, tidy_backups: tidy, ..config(root, now) })
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> =
+            fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn tidy_layout_keeps_the_root_clean() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "v1").unwrap();
+        let mgr = manager(root, true, 100);
+        let bak = mgr.backup_file("a.txt").unwrap();
+        assert_eq!(bak, root.join(".safe_backup/backups/a.txt.100.bak"));
+        assert_eq!(names(root), [".safe_backup", "a.bak", "a.bak.meta.json", "a.txt"]);
+        assert_eq!(mgr.read_log().unwrap().len(), 1);
+        assert!(root.join(TIDY_LOG).is_file());
+
+        // Without the plain copy, only the original is left.
+        fs::remove_file(root.join("a.bak")).unwrap();
+        fs::remove_file(root.join("a.bak.meta.json")).unwrap();
+        let bare = BackupManager::new(Config { plain_copy: false, ..manager(root, true, 200).config.clone() });
+        bare.backup_file("a.txt").unwrap();
+        assert_eq!(names(root), [".safe_backup", "a.txt"]);
+        assert_eq!(bare.list_backups("a.txt").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn flat_backups_stay_usable_and_migrate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "flat").unwrap();
+        manager(root, false, 100).backup_file("a.txt").unwrap();
+        fs::write(root.join("a.txt"), "tidy").unwrap();
+        let mgr = manager(root, true, 200);
+        mgr.backup_file("a.txt").unwrap();
+
+        // Both layouts are listed and restorable, by version and by name.
+        let ts: Vec<u64> = mgr.list_backups("a.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(ts, [200, 100]);
+        mgr.restore_file_with("a.txt", &RestoreOptions::new().version(100)).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "flat");
+        mgr.restore_file("a.txt.200.bak").unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "tidy");
+        mgr.restore_file("a.txt.100.bak").unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "flat");
+
+        let planned = mgr.migrate_layout(true).unwrap();
+        assert!(root.join("logfile.txt").exists());
+        let done = mgr.migrate_layout(false).unwrap();
+        assert_eq!(done, planned);
+        assert_eq!(
+            done,
+            [
+                Migration { from: root.join("logfile.txt"), to: root.join(TIDY_LOG) },
+                Migration { from: root.join("a.txt.100.bak"), to: root.join(TIDY_BACKUPS).join("a.txt.100.bak") },
+            ]
+        );
+        assert_eq!(names(root), [".safe_backup", "a.bak", "a.bak.meta.json", "a.txt"]);
+        assert_eq!(names(&root.join(TIDY_BACKUPS)).len(), 4);
+        mgr.verify_backup("a.txt.100.bak").unwrap();
+        // The old log's entries were carried over and the move was logged.
+        let actions: Vec<String> = mgr.read_log().unwrap().into_iter().map(|e| e.action).collect();
+        assert_eq!(actions.first().map(String::as_str), Some("backup"));
+        assert_eq!(actions.last().map(String::as_str), Some("migrate"));
+        mgr.verify_log_chain().unwrap();
+        assert!(mgr.migrate_layout(false).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+//! Deadlines for backups and restores (`Config::timeout`). The work runs on
+//! a thread of its own, on a clone of the manager with a timer token; when
+//! the time is up the token is cancelled, so the work stops at its next check
+//! and removes what it wrote, as a cancelled operation does. Work already
+//! putting its result in place when the time is up gets a moment to finish;
+//! if it does not, the outcome is reported as unknown rather than as a
+//! timeout. A thread stuck in a call that never returns (a hung network
+//! mount) is left behind; it stops and cleans up if the call ever does.
+
+use std::io;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::cancel::{self, Timer};
+use crate::error::BackupError;
+use crate::BackupManager;
+
+/// How long work past its deadline gets to stop, or to finish.
+const GRACE: Duration = Duration::from_secs(1);
+
+impl BackupManager {
+    /// Run `work` for `op` on `path` under `Config::timeout`: past it, fail
+    /// with `BackupError::TimedOut`. Without a timeout, or inside work that
+    /// already runs under one, `work` just runs here.
+    ///
+    /// Work that commits just as the time runs out is not undone: it gets
+    /// [`GRACE`] to answer, and a result it gives then is returned as is.
+    /// Still committing after that, it fails with `BackupError::OutcomeUnknown`.
+    pub(crate) fn with_timeout<T: Send + 'static>(
+        &self,
+        op: &'static str,
+        path: PathBuf,
+        work: impl FnOnce(&BackupManager) -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let Some(limit) = self.config.timeout.filter(|_| self.timer.is_none()) else {
+            return work(self);
+        };
+        let timer = Timer::default();
+        let worker = BackupManager { timer: Some(timer.clone()), ..self.clone() };
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let _ = tx.send(work(&worker));
+        });
+        let answer = match rx.recv_timeout(limit) {
+            Err(RecvTimeoutError::Timeout) => {
+                timer.token.cancel();
+                rx.recv_timeout(GRACE)
+            }
+            answer => answer,
+        };
+        let timed_out = || {
+            let copied = timer.copied.load(Ordering::Relaxed);
+            BackupError::TimedOut { op, path: path.clone(), after: limit, copied }.into()
+        };
+        match answer {
+            Ok(Err(e)) if cancel::is_cancelled(&e) && timer.token.is_cancelled() => Err(timed_out()),
+            Ok(result) => result,
+            // Expired, it can no longer commit, so the timeout is what happened.
+            Err(RecvTimeoutError::Timeout) if timer.expire() => Err(timed_out()),
+            Err(RecvTimeoutError::Timeout) => Err(BackupError::OutcomeUnknown { op, path, after: limit }.into()),
+            Err(RecvTimeoutError::Disconnected) => match handle.join() {
+                Err(payload) => panic::resume_unwind(payload),
+                Ok(()) => unreachable!("the work always sends its result"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, Config};
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    /// A clock that holds the work up at its first reading until the gate opens.
+    struct GatedClock(Mutex<mpsc::Receiver<()>>);
+
+    impl Clock for GatedClock {
+        fn now_unix(&self) -> u64 {
+            let _ = self.0.lock().unwrap().recv();
+            1_700_000_000
+        }
+    }
+
+    fn manager(root: &std::path::Path, clock: Arc<dyn Clock>, timeout: Duration) -> BackupManager {
+        BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            clock,
+            timeout: Some(timeout),
+            ..Config::default()
+        })
+    }
+
+    /// Wait, on the worker, until the timer has given up on it.
+    fn until_cancelled(mgr: &BackupManager) {
+        while !mgr.cancel_for(None).is_set() {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn work_past_the_deadline_times_out_and_leaves_nothing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("notes.txt"), "hello").unwrap();
+
+        let (gate, held) = mpsc::channel();
+        let clock = Arc::new(GatedClock(Mutex::new(held)));
+        let e = manager(root, clock, Duration::from_millis(10)).backup_file("notes.txt").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        let err = BackupError::from_io(&e);
+        assert!(matches!(err, Some(BackupError::TimedOut { op: "backup", copied: 0, .. })), "{e}");
+        assert!(!crate::is_transient(&e));
+        let left: Vec<_> = fs::read_dir(root).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(left, ["notes.txt"]);
+        // The worker is still held; it is left behind, as one stuck on a hung mount would be.
+        std::mem::forget(gate);
+
+        // In time: as without a timeout.
+        let mgr = manager(root, Arc::new(crate::FixedClock(1)), Duration::from_secs(30));
+        let bak = mgr.backup_file("notes.txt").unwrap();
+        assert_eq!(fs::read_to_string(bak).unwrap(), "hello");
+    }
+
+    #[test]
+    fn work_given_up_on_cannot_commit_after() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mgr = manager(tmp.path(), Arc::new(crate::FixedClock(1)), Duration::from_millis(10));
+        let path = tmp.path().join("notes.txt");
+        let e = mgr
+            .with_timeout("backup", path.clone(), move |mgr| {
+                until_cancelled(mgr);
+                mgr.cancel_for(None).commit("backup", &path).map(drop)
+            })
+            .unwrap_err();
+        assert_eq!(crate::error_code(&e), "timed_out", "{e}");
+    }
+
+    #[test]
+    fn work_committing_at_the_deadline_finishes_or_is_unknown() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mgr = manager(tmp.path(), Arc::new(crate::FixedClock(1)), Duration::from_millis(10));
+        let path = tmp.path().join("notes.txt");
+
+        // Committed within the grace: its result stands.
+        let at = path.clone();
+        let done = mgr.with_timeout("backup", path.clone(), move |mgr| {
+            let _committing = mgr.cancel_for(None).commit("backup", &at)?;
+            until_cancelled(mgr);
+            Ok(7)
+        });
+        assert_eq!(done.unwrap(), 7);
+
+        // Still committing after it: not known to have failed.
+        let (gate, held) = mpsc::channel::<()>();
+        let at = path.clone();
+        let e = mgr
+            .with_timeout("backup", path.clone(), move |mgr| {
+                let _committing = mgr.cancel_for(None).commit("backup", &at)?;
+                until_cancelled(mgr);
+                let _ = held.recv();
+                Ok(())
+            })
+            .unwrap_err();
+        drop(gate);
+        assert_eq!((crate::error_code(&e), e.kind()), ("outcome_unknown", io::ErrorKind::TimedOut), "{e}");
+        assert!(!crate::is_transient(&e));
+        let message = "backup: timed out after 10ms while finishing; it may still complete";
+        assert_eq!(e.to_string(), format!("{message}: {}", path.display()));
+    }
+}
@@ -0,0 +1,172 @@
+//! The trash: files deleted with [`DeleteOptions::trash`] (or
+//! [`BackupManager::trash_file`]) are moved to ".safe_backup/trash/" in the
+//! backup directory instead of being removed, under their directory relative
+//! to the root, as "<name>.<ts>" with the time they were trashed.
+//! [`BackupManager::empty_trash`] removes them again once they are old enough.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dedup::STORE_DIR;
+use crate::error::{invalid, Context};
+use crate::naming::display_name;
+use crate::vfs::FileKind;
+use crate::{BackupManager, DeleteOptions};
+
+/// Outcome of [`BackupManager::empty_trash`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrashReport {
+    /// Trashed files removed.
+    pub removed: usize,
+    /// Their total size in bytes.
+    pub bytes: u64,
+}
+
+impl BackupManager {
+    /// Move the file `name` (validated) into the trash as "<name>.<now>" and
+    /// return where it went: [`delete_file_with`](Self::delete_file_with)
+    /// with [`DeleteOptions::trash`].
+    pub fn trash_file(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let report = self.delete_file_with(name, &DeleteOptions::new().trash(true))?;
+        Ok(report.trashed.unwrap_or_default())
+    }
+
+    /// Move the file at `path` in `root` into the trash as "<name>.<now>".
+    /// Fails with `AlreadyExists` if a file of that name was trashed in the
+    /// same second.
+    pub(crate) fn move_to_trash(&self, root: &Path, path: &Path) -> io::Result<PathBuf> {
+        let rel = path.strip_prefix(root).map_err(|_| invalid(path, "not under the root directory"))?;
+        let mut trashed = trash_dir(&self.backup_root(root)).join(rel).into_os_string();
+        trashed.push(format!(".{}", self.now()));
+        let trashed = PathBuf::from(trashed);
+        if self.fs.exists(&trashed) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists)).at("delete", "already in the trash:", &trashed);
+        }
+        if let Some(dir) = trashed.parent() {
+            self.create_dirs("delete", dir, "cannot create trash directory")?;
+        }
+        self.fs.move_file(path, &trashed).copying("delete", path, &trashed)?;
+        Ok(trashed)
+    }
+
+    /// Remove the files trashed more than `older_than` seconds ago (by the
+    /// configured clock), or all of them with `None`. Only names of the
+    /// "<name>.<ts>" form are touched; the directories they were in stay.
+    /// Each removal is logged.
+    pub fn empty_trash(&self, older_than: Option<u64>) -> io::Result<TrashReport> {
+        let root = self.root()?;
+        let trash = trash_dir(&self.backup_root(&root));
+        let cutoff = older_than.map(|age| self.now().saturating_sub(age));
+        let mut report = TrashReport::default();
+        let mut dirs = vec![trash.clone()];
+        while let Some(dir) = dirs.pop() {
+            let names = match self.fs.read_dir_names(&dir) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                names => names.at("empty_trash", "cannot list directory", &dir)?,
+            };
+            for fname in names {
+                let path = dir.join(fname.at("empty_trash", "cannot list directory", &dir)?);
+                let md = self.fs.symlink_metadata(&path).at("empty_trash", "cannot stat", &path)?;
+                if md.kind == FileKind::Dir {
+                    dirs.push(path);
+                    continue;
+                }
+                let Some(ts) = trashed_at(&path) else { continue };
+                if cutoff.is_some_and(|cutoff| ts >= cutoff) {
+                    continue;
+                }
+                self.fs.remove_file(&path).at("empty_trash", "cannot remove", &path)?;
+                let rel = path.strip_prefix(&trash).unwrap_or(&path);
+                self.log_action(&root, "empty_trash", rel, &format!("ok ({} bytes)", md.len))?;
+                report.removed += 1;
+                report.bytes += md.len;
+            }
+        }
+        Ok(report)
+    }
+}
+
+fn trash_dir(broot: &Path) -> PathBuf {
+    broot.join(STORE_DIR).join("trash")
+}
+
+/// When the trashed file at `path` was trashed: the `<ts>` of "<name>.<ts>".
+fn trashed_at(path: &Path) -> Option<u64> {
+    let fname = display_name(path.file_name()?).into_owned();
+    let (name, ts) = fname.rsplit_once('.')?;
+    if name.is_empty() || ts.is_empty() || !ts.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    ts.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::manager;
+    use std::fs;
+
+    /// Trash "a.txt" at 100, "docs/b.txt" at 200 and "c.txt" at 300, plus a
+    /// file of the user's own in the trash.
+    fn fill_trash(root: &Path) -> PathBuf {
+        fs::create_dir(root.join("docs")).unwrap();
+        for (now, name, text) in [(100, "a.txt", "aaaa"), (200, "docs/b.txt", "bb"), (300, "c.txt", "c")] {
+            fs::write(root.join(name), text).unwrap();
+            manager(root, now).trash_file(name).unwrap();
+        }
+        let trash = root.join(".safe_backup/trash");
+        fs::write(trash.join("notes"), "mine").unwrap();
+        trash
+    }
+
+    #[test]
+    fn trash_is_emptied_of_what_is_old_enough() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let trash = fill_trash(root);
+        assert!(!root.join("a.txt").exists());
+        assert_eq!(fs::read_to_string(trash.join("a.txt.100")).unwrap(), "aaaa");
+        assert!(trash.join("docs/b.txt.200").is_file());
+        // The same name, trashed again in the same second, is refused, and the file stays.
+        fs::write(root.join("c.txt"), "again").unwrap();
+        assert_eq!(manager(root, 300).trash_file("c.txt").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(root.join("c.txt").exists());
+        // Under its canonical name, with its backups purged as ever.
+        fs::write(root.join("d.txt"), "d").unwrap();
+        let bak = manager(root, 350).backup_file("d.txt").unwrap();
+        let report = manager(root, 360)
+            .delete_file_with(" ./d.txt ", &DeleteOptions::new().trash(true).purge_backups(true))
+            .unwrap();
+        assert_eq!(report.trashed, Some(trash.join("d.txt.360")));
+        assert!(report.purged.contains(&bak) && !bak.exists());
+
+        // Older than 150s at 400: the first two.
+        let mgr = manager(root, 400);
+        assert_eq!(mgr.empty_trash(Some(150)).unwrap(), TrashReport { removed: 2, bytes: 6 });
+        assert!(!trash.join("a.txt.100").exists() && !trash.join("docs/b.txt.200").exists());
+        assert!(trash.join("c.txt.300").exists() && trash.join("d.txt.360").exists() && trash.join("notes").exists());
+        let log = fs::read_to_string(root.join("logfile.txt")).unwrap();
+        let entry = r#""action":"delete","file":"d.txt","result":"ok (trashed as .safe_backup/trash/d.txt.360)""#;
+        assert!(log.contains(entry), "{log}");
+        assert!(log.contains(r#""action":"empty_trash","file":"docs/b.txt.200","result":"ok (2 bytes)""#), "{log}");
+        // Nothing else is old enough yet.
+        assert_eq!(mgr.empty_trash(Some(150)).unwrap(), TrashReport::default());
+    }
+
+    #[test]
+    fn trash_is_emptied_of_everything() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let trash = fill_trash(root);
+
+        let mgr = manager(root, 400);
+        assert_eq!(mgr.empty_trash(None).unwrap(), TrashReport { removed: 3, bytes: 7 });
+        // Not ours: left alone even when emptying everything.
+        assert!(trash.join("notes").exists() && trash.join("docs").is_dir());
+        assert!(!trash.join("c.txt.300").exists());
+        assert_eq!(mgr.empty_trash(None).unwrap(), TrashReport::default());
+        // Nothing ever trashed: nothing to do.
+        let other = tempfile::tempdir().unwrap();
+        assert_eq!(manager(other.path(), 400).empty_trash(None).unwrap(), TrashReport::default());
+    }
+}
@@ -0,0 +1,277 @@
+//! User-supplied file name validation.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{invalid, BackupError, Context};
+use crate::naming::{display_name, trim_name};
+use crate::vfs::{FileKind, FileSystem};
+
+/// Length limits (in bytes) applied before the OS gets a chance to fail with ENAMETOOLONG.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameLimits {
+    /// Longest single path component; 255 is the limit of ext4, APFS and NTFS.
+    pub max_component: usize,
+    /// Longest resolved path; 1024 is macOS's PATH_MAX, the smallest common one
+    /// once Windows long-path support is enabled.
+    pub max_path: usize,
+}
+
+impl Default for NameLimits {
+    fn default() -> Self {
+        Self { max_component: 255, max_path: 1024 }
+    }
+}
+
+fn too_long(what: &'static str, path: &Path, len: usize, max: usize) -> io::Error {
+    BackupError::NameTooLong { what, input: display_name(path.as_os_str()).into_owned(), len, max }.into()
+}
+
+/// Windows device names. They refer to the device whatever the extension or
+/// directory, so `aux.txt` or `logs/nul.tar.gz` open a device, not a file.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$",
+    "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "COM\u{B9}", "COM\u{B2}", "COM\u{B3}",
+    "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    "LPT\u{B9}", "LPT\u{B2}", "LPT\u{B3}",
+];
+
+/// The reserved device name `component` maps to on Windows, if any: the part
+/// before the first dot, trailing spaces ignored, compared case-insensitively.
+pub(crate) fn reserved_device_name(component: &OsStr) -> Option<&'static str> {
+    let s = component.to_str()?;
+    let stem = s.split('.').next().unwrap_or(s).trim_end_matches(' ');
+    RESERVED_NAMES.iter().copied().find(|r| r.eq_ignore_ascii_case(stem))
+}
+
+/// First component of the (already validated) relative `name` that is a reserved device name.
+pub(crate) fn find_reserved(name: &Path) -> Option<&'static str> {
+    name.components().find_map(|c| match c {
+        Component::Normal(part) => reserved_device_name(part),
+        _ => None,
+    })
+}
+
+/// Check that a generated backup path still fits `limits`; the appended
+/// ".<ts>.bak" can push an otherwise legal name over the edge.
+pub(crate) fn check_backup_name(backup: &Path, limits: &NameLimits) -> io::Result<()> {
+    let len = backup.file_name().map_or(0, |n| n.as_encoded_bytes().len());
+    if len > limits.max_component {
+        return Err(too_long("backup name", backup, len, limits.max_component));
+    }
+    let len = backup.as_os_str().as_encoded_bytes().len();
+    if len > limits.max_path {
+        return Err(too_long("backup path", backup, len, limits.max_path));
+    }
+    Ok(())
+}
+
+/// Resolve `name` under `root`, component by component.
+///
+/// Rejected: empty names, NUL bytes, absolute paths (`RootDir`), drive/UNC
+/// prefixes (`Prefix`), `..` anywhere, and components made only of dots and
+/// spaces (Windows trims those, so `.. ` would act like `..`). Backslash-separated
+/// `..` is rejected on every platform. `.` components are dropped, so
+/// `./a/./b` resolves to `<root>/a/b`; plain names resolve to `<root>/<name>`.
+/// Components and the resolved path must also fit `limits`.
+pub(crate) fn validate_path_in(root: &Path, name: &Path, limits: &NameLimits) -> io::Result<PathBuf> {
+    let trimmed = trim_name(name);
+    if trimmed.as_os_str().is_empty() {
+        return Err(invalid(name, "empty file name"));
+    }
+    if trimmed.as_os_str().as_encoded_bytes().contains(&0) {
+        return Err(invalid(name, "NUL byte in file name"));
+    }
+
+    let mut out = root.to_path_buf();
+    let mut pushed = false;
+    for comp in trimmed.components() {
+        match comp {
+            Component::Prefix(_) | Component::RootDir => return Err(invalid(name, "absolute paths not allowed")),
+            Component::ParentDir => return Err(invalid(name, "parent traversal not allowed")),
+            Component::CurDir => {}
+            Component::Normal(part) => {
+                // On Unix a backslash is an ordinary byte, but the name may be headed for Windows.
+                for seg in part.to_string_lossy().split('\\') {
+                    if seg == ".." {
+                        return Err(invalid(name, "parent traversal not allowed"));
+                    }
+                    if !seg.is_empty() && seg.chars().all(|c| c == '.' || c == ' ') && seg != "." {
+                        return Err(invalid(name, "dot-only path component not allowed"));
+                    }
+                }
+                if cfg!(windows) && reserved_device_name(part).is_some() {
+                    return Err(invalid(name, "reserved Windows device name"));
+                }
+                let len = part.as_encoded_bytes().len();
+                if len > limits.max_component {
+                    return Err(too_long("file name component", Path::new(part), len, limits.max_component));
+                }
+                out.push(part);
+                pushed = true;
+            }
+        }
+    }
+    if !pushed {
+        return Err(invalid(name, "names the directory itself, not a file"));
+    }
+    let len = out.as_os_str().as_encoded_bytes().len();
+    if len > limits.max_path {
+        return Err(too_long("path", &out, len, limits.max_path));
+    }
+    Ok(out)
+}
+
+/// What kind of special file `path` is (after following a symlink), if it is a
+/// FIFO, socket or device: reading one can block or never end.
+pub(crate) fn special_file_kind(
+    fs: &dyn FileSystem,
+    op: &'static str,
+    path: &Path,
+) -> io::Result<Option<&'static str>> {
+    let mut md = fs.symlink_metadata(path).at(op, "cannot stat", path)?;
+    if md.kind == FileKind::Symlink {
+        md = fs.metadata(path).at(op, "cannot stat", path)?;
+    }
+    Ok(match md.kind {
+        FileKind::Special(kind) => Some(kind),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Want {
+        Ok(&'static str),
+        Err(&'static str),
+    }
+    use Want::*;
+
+    fn check(cases: &[(&str, Want)]) {
+        let root = Path::new("base");
+        for (input, want) in cases {
+            let got = validate_path_in(root, Path::new(input), &NameLimits::default());
+            match (want, got) {
+                (Ok(rel), Result::Ok(p)) => assert_eq!(p, root.join(rel), "input {input:?}"),
+                (Err(reason), Result::Err(e)) => {
+                    assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "input {input:?}");
+                    assert!(e.to_string().contains(reason), "input {input:?}: {e}");
+                }
+                (Ok(_), Result::Err(e)) => panic!("input {input:?} rejected: {e}"),
+                (Err(_), Result::Ok(p)) => panic!("input {input:?} accepted as {}", p.display()),
+            }
+        }
+    }
+
+    #[test]
+    fn portable_matrix() {
+        check(&[
+            ("test.txt", Ok("test.txt")),
+            ("  test.txt  ", Ok("test.txt")),
+            ("dir/test.txt", Ok("dir/test.txt")),
+            ("./test.txt", Ok("test.txt")),
+            ("./a/./b.txt", Ok("a/b.txt")),
+            ("a//b", Ok("a/b")),
+            ("a/b/", Ok("a/b")),
+            (".env", Ok(".env")),
+            ("..foo", Ok("..foo")),
+            ("foo..", Ok("foo..")),
+            ("a..b/c", Ok("a..b/c")),
+            ("report.v2.final.txt", Ok("report.v2.final.txt")),
+            ("", Err("empty")),
+            ("   ", Err("empty")),
+            ("..", Err("parent traversal")),
+            (" ..", Err("parent traversal")),
+            ("../x", Err("parent traversal")),
+            ("./../x", Err("parent traversal")),
+            ("foo/../bar", Err("parent traversal")),
+            ("foo/../../bar", Err("parent traversal")),
+            ("a/b/..", Err("parent traversal")),
+            ("..\\x", Err("parent traversal")),
+            ("a\\..\\..\\b", Err("parent traversal")),
+            ("/etc/passwd", Err("absolute")),
+            ("/", Err("absolute")),
+            (".", Err("directory itself")),
+            ("./.", Err("directory itself")),
+            ("...", Err("dot-only")),
+            ("a/... /b", Err("dot-only")),
+            ("a/. ./b", Err("dot-only")),
+            ("nul\0byte", Err("NUL")),
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_matrix() {
+        check(&[
+            // No prefixes on Unix: these are ordinary (if odd) names.
+            ("C:foo", Ok("C:foo")),
+            ("back\\slash", Ok("back\\slash")),
+            // Reserved on Windows only; BackupManager warns (or rejects if configured).
+            ("aux.txt", Ok("aux.txt")),
+        ]);
+    }
+
+    #[test]
+    fn reserved_names_with_and_without_extension() {
+        for name in [
+            "CON", "con", "Con.txt", "PRN.log", "aux", "AUX.tar.gz", "nul", "NUL.", "nul .txt", "COM1", "com9.dat",
+            "LPT1", "lpt3.txt", "COM0", "LPT0.x", "conin$", "CONOUT$.txt", "COM\u{B9}", "lpt\u{B2}.txt",
+        ] {
+            assert!(reserved_device_name(OsStr::new(name)).is_some(), "{name:?} should be reserved");
+        }
+        for name in ["CONSOLE", "con_", "nullable.txt", "COM10", "LPT", "auxiliary.txt", "xCON", ".con"] {
+            assert_eq!(reserved_device_name(OsStr::new(name)), None, "{name:?} is an ordinary name");
+        }
+        assert_eq!(find_reserved(Path::new("logs/Aux.txt")), Some("AUX"));
+        assert_eq!(find_reserved(Path::new("logs/notes.txt")), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_matrix() {
+        check(&[
+            ("dir\\test.txt", Ok("dir\\test.txt")),
+            (".\\test.txt", Ok("test.txt")),
+            ("C:\\Windows\\system32", Err("absolute")),
+            ("C:foo", Err("absolute")),
+            ("\\\\server\\share\\x", Err("absolute")),
+            ("\\\\?\\C:\\x", Err("absolute")),
+            ("\\rooted", Err("absolute")),
+            ("..\\x", Err("parent traversal")),
+            ("a\\..\\b", Err("parent traversal")),
+            ("CON", Err("reserved")),
+            ("aux.txt", Err("reserved")),
+            ("logs\\nul.tar.gz", Err("reserved")),
+            ("Com1 .log", Err("reserved")),
+            ("lpt9", Err("reserved")),
+            ("console.txt", Ok("console.txt")),
+            ("com10", Ok("com10")),
+        ]);
+    }
+
+    #[test]
+    fn length_limits() {
+        let limits = NameLimits { max_component: 10, max_path: 30 };
+        let root = Path::new("/r");
+        assert!(validate_path_in(root, Path::new("abcdefghij"), &limits).is_ok());
+        let e = validate_path_in(root, Path::new("abcdefghijk"), &limits).unwrap_err();
+        assert_eq!(e.to_string(), "file name component too long (11 > 10 bytes): abcdefghijk");
+        let e = validate_path_in(root, Path::new("aaaaaaaa/bbbbbbbb/cccccccc/d"), &limits).unwrap_err();
+        assert!(e.to_string().starts_with("path too long (31 > 30 bytes)"), "{e}");
+
+        // Fine on its own, too long once ".<ts>.bak" is appended.
+        let name = "x".repeat(250);
+        let ok = validate_path_in(root, Path::new(&name), &NameLimits::default()).unwrap();
+        let bak = crate::naming::ts_backup_for(root, &ok, 1_700_000_000).unwrap();
+        let e = check_backup_name(&bak, &NameLimits::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let msg = e.to_string();
+        assert!(msg.starts_with("backup name too long (265 > 255 bytes)"), "{msg}");
+        assert!(msg.contains("shorten the file name by at least 10 bytes"), "{msg}");
+    }
+}
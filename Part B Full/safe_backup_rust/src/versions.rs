@@ -0,0 +1,378 @@
+//! The timestamped backups of one original: listing, pruning, verifying.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cache::DigestCache;
+use crate::chunk;
+use crate::dedup;
+use crate::delta;
+use crate::error::{error_chain, missing, no_backup, BackupError, Context};
+use crate::digest::{file_digest, tagged, DigestAlgo};
+use crate::meta::{read_backup_meta, read_meta, sidecar_for, BackupMeta, Layout};
+use crate::naming::{base_name, display_name, split_backup_name};
+use crate::note::note_matches;
+use crate::vfs::{FileStat, FileSystem};
+use crate::warning::Warning;
+use crate::{ts_backups, ts_backups_in, BackupManager};
+
+/// One timestamped backup of an original.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupEntry {
+    pub path: PathBuf,
+    /// The "<ts>" of "<name>.<ts>.bak", seconds since the epoch.
+    pub ts: u64,
+    pub size: u64,
+    /// The name it was made under, where that is an earlier name of the
+    /// original ([`BackupManager::rename_tracked`]).
+    pub renamed_from: Option<String>,
+    /// Its note ([`BackupOptions::note`](crate::BackupOptions::note)), escaped.
+    pub note: Option<String>,
+}
+
+impl BackupManager {
+    /// Timestamped file backups of `name`, newest first, including those
+    /// made under its earlier names. Plain "<stem>.bak" copies and lookalikes
+    /// without a sidecar are not included. A chunked backup is one entry,
+    /// sized over all its pieces.
+    pub fn list_backups(&self, name: impl AsRef<Path>) -> io::Result<Vec<BackupEntry>> {
+        let name = name.as_ref();
+        let base = base_name(name)?;
+        self.find_ts_backups(&self.root()?, name, FileStat::is_file)?
+            .into_iter()
+            .map(|(ts, path)| {
+                let meta = read_meta(&*self.fs, &path).ok();
+                self.list_entry(base, ts, path, meta)
+            })
+            .collect()
+    }
+
+    /// The [`BackupEntry`] for `path`, the backup made at `ts` of an original
+    /// named `base`, whose sidecar holds `meta`.
+    fn list_entry(&self, base: &OsStr, ts: u64, path: PathBuf, meta: Option<BackupMeta>) -> io::Result<BackupEntry> {
+        let size = stored_size_of(&*self.fs, "list", &path, meta.as_ref())?;
+        let made_as = path.file_name().and_then(split_backup_name).map(|(logical, _)| logical);
+        let (original, note) = meta.map_or((None, None), |m| (Some(m.original), m.note));
+        let renamed_from = original.filter(|_| made_as.is_some_and(|logical| logical != base));
+        Ok(BackupEntry { path, ts, size, renamed_from, note })
+    }
+
+    /// Timestamped backups of every tracked original whose name contains
+    /// `substring`, ignoring case, and of other originals those whose note
+    /// does; by original, then newest first. The other originals' backups are
+    /// found in one scan of the backup directories, not one listing each.
+    pub fn find_backups(&self, substring: &str) -> io::Result<Vec<BackupEntry>> {
+        let root = self.root()?;
+        let wanted = substring.to_lowercase();
+        let originals = self.tracked_originals()?;
+        let by_name = |name: &OsString| display_name(name).to_lowercase().contains(&wanted);
+        let others: Vec<&Path> = originals.iter().filter(|n| !by_name(n)).map(Path::new).collect();
+        let noted = match others.is_empty() {
+            true => Vec::new(),
+            false => self.scan_backup_dirs(&root, &others, |roots, names| {
+                ts_backups_in(&*self.fs, roots, names, false, FileStat::is_file)
+            })?,
+        };
+        let mut found = Vec::new();
+        for name in &originals {
+            if by_name(name) {
+                found.extend(self.list_backups(name)?);
+                continue;
+            }
+            let name = Path::new(name);
+            let named = self.backup_names(&root, name)?;
+            for (ts, path) in &noted {
+                let made_as = path.file_name().and_then(split_backup_name).map(|(logical, _)| logical);
+                if !named.iter().any(|(n, window)| window.contains(ts) && made_as == n.file_name()) {
+                    continue;
+                }
+                let meta = read_meta(&*self.fs, path).ok();
+                if note_matches(meta.as_ref().and_then(|m| m.note.as_deref()), substring) {
+                    found.push(self.list_entry(base_name(name)?, *ts, path.clone(), meta)?);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// The backup file `backup` (a name like "notes.txt.1700000000.bak") as
+    /// `list` shows it, and its sidecar.
+    pub fn backup_info(&self, backup: impl AsRef<Path>) -> io::Result<(BackupEntry, BackupMeta)> {
+        let root = self.root()?;
+        let path = self.backup_named(&root, backup.as_ref())?;
+        let Some((base, Some(ts))) = path.file_name().and_then(split_backup_name) else {
+            return Err(no_backup("info", "not a timestamped backup", &path));
+        };
+        let meta = read_meta(&*self.fs, &path)?;
+        Ok((self.list_entry(base, ts, path.clone(), Some(meta.clone()))?, meta))
+    }
+
+    /// Remove all but the newest `keep` timestamped backups of `name`, with
+    /// their sidecars, sparing older ones that a kept delta builds on or a
+    /// checkpoint holds. Returns
+    /// the removed backups; with `dry_run`, those that would be removed,
+    /// touching nothing.
+    pub fn prune_backups(&self, name: impl AsRef<Path>, keep: usize, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+        let name = name.as_ref();
+        let root = self.root()?;
+        let all: Vec<PathBuf> = self.list_backups(name)?.into_iter().map(|e| e.path).collect();
+        let held = self.checkpointed(&root)?;
+        let old = delta::spare_bases(&all, all.iter().skip(keep).filter(|p| !held.contains(*p)).cloned().collect());
+        if dry_run || old.is_empty() {
+            return Ok(old);
+        }
+        for path in &old {
+            remove_backup(&*self.fs, path)?;
+        }
+        self.log_action(&root, "prune", name, &format!("ok ({} removed)", old.len()))?;
+        Ok(old)
+    }
+
+    /// Apply `Config::max_age_secs` and `Config::keep_last` to the backups of
+    /// `name` after the backup `keep` was made at `now`: a backup goes if it is
+    /// too old or beyond the newest `keep_last`. `keep` itself always stays, and
+    /// so does any backup a remaining delta builds on or a checkpoint holds.
+    /// Runs after a successful backup, so problems only warn.
+    pub(crate) fn prune_after_backup(
+        &self,
+        root: &Path,
+        name: &Path,
+        now: u64,
+        keep: &Path,
+        warnings: &mut Vec<Warning>,
+    ) {
+        let cutoff = self.config.max_age_secs.map(|age| now.saturating_sub(age));
+        let keep_last = self.config.keep_last.unwrap_or(usize::MAX).max(1);
+        let broot = self.backup_root(root);
+        let all = ts_backups(&*self.fs, &broot, name, self.config.legacy_bk, FileStat::is_file);
+        let (all, held) = match all.and_then(|all| Ok((all, self.checkpointed(root)?))) {
+            Ok(found) => found,
+            Err(e) => return warnings.push(Warning::PruneFailed { path: broot, error: error_chain(&e) }),
+        };
+        let expired = all
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, p))| p != keep && !held.contains(p))
+            .filter(|(i, (ts, _))| *i >= keep_last || cutoff.is_some_and(|c| *ts < c))
+            .map(|(_, (_, p))| p.clone())
+            .collect();
+        let paths: Vec<PathBuf> = all.into_iter().map(|(_, p)| p).collect();
+        let expired = delta::spare_bases(&paths, expired);
+        let mut removed = Vec::new();
+        for path in expired {
+            match remove_backup(&*self.fs, &path) {
+                Ok(()) => removed.push(path),
+                Err(e) => warnings.push(Warning::PruneFailed { path, error: error_chain(&e) }),
+            }
+        }
+        if removed.is_empty() {
+            return;
+        }
+        if let Err(e) = self.log_action(root, "prune", name, &format!("ok ({} expired)", removed.len())) {
+            warnings.push(Warning::Log { error: error_chain(&e) });
+        }
+        warnings.push(Warning::Pruned { removed });
+    }
+
+    /// Check a backup (named relative to the backup directory) against the
+    /// digest in its sidecar, each piece of a chunked backup against its index,
+    /// the reassembled contents of a deduplicated one against its manifest,
+    /// and every link of a delta's chain.
+    /// A mismatch is `BackupError::Corrupt`; a missing piece or object, NotFound.
+    pub fn verify_backup(&self, backup: impl AsRef<Path>) -> io::Result<()> {
+        let path = self.backup_named(&self.root()?, backup.as_ref())?;
+        self.verify_path(&path, None)
+    }
+
+    /// [`verify_backup`](Self::verify_backup) of the backup at `path`, taking
+    /// the digest of the backup file itself from `cache` when given one.
+    pub(crate) fn verify_path(&self, path: &Path, cache: Option<&mut DigestCache>) -> io::Result<()> {
+        let path = path.to_path_buf();
+        if !path.is_file() {
+            return Err(no_backup("verify", "backup file not found", &path));
+        }
+        let meta = read_backup_meta(&path)?;
+        let expected = meta.digest.ok_or_else(|| missing("verify", "no digest recorded for", &path))?;
+        let algo = DigestAlgo::of(&expected)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .at("verify", "malformed digest for", &path)?;
+        let expected = tagged(&expected);
+        let actual = match cache {
+            Some(cache) => cache.digest(&path, algo, self.now()),
+            None => file_digest(&path, algo),
+        }
+        .at("verify", "cannot read", &path)?;
+        if actual != expected {
+            return Err(BackupError::Corrupt { path, expected, actual }.into());
+        }
+        match meta.layout {
+            Layout::Whole => {}
+            Layout::Chunked => {
+                chunk::verify_chunks("verify", &path, self.cancel_for(None))?;
+            }
+            Layout::Delta => {
+                delta::verify_chain("verify", &path)?;
+            }
+            Layout::Deduped => {
+                dedup::verify_deduped("verify", &path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bytes `backup` takes on disk, over all pieces if it is chunked. A
+/// deduplicated backup counts its manifest only: its objects are shared.
+pub(crate) fn stored_size(fs: &dyn FileSystem, op: &'static str, backup: &Path) -> io::Result<u64> {
+    stored_size_of(fs, op, backup, read_meta(fs, backup).ok().as_ref())
+}
+
+/// [`stored_size`] of `backup`, whose sidecar holds `meta`.
+fn stored_size_of(fs: &dyn FileSystem, op: &'static str, backup: &Path, meta: Option<&BackupMeta>) -> io::Result<u64> {
+    match meta {
+        Some(meta) if meta.layout == Layout::Chunked => Ok(chunk::read_index(op, backup)?.stored_size()),
+        _ => Ok(fs.metadata(backup).at(op, "cannot stat", backup)?.len),
+    }
+}
+
+/// Remove a backup file, the pieces of a chunked one, and its sidecar.
+pub(crate) fn remove_backup(fs: &dyn FileSystem, path: &Path) -> io::Result<()> {
+    chunk::remove_chunked(fs, path).at("prune", "cannot remove", path)?;
+    let sidecar = sidecar_for(path);
+    fs.remove_file(&sidecar).or_else(ignore_missing).at("prune", "cannot remove", &sidecar)
+}
+
+fn ignore_missing(e: io::Error) -> io::Result<()> {
+    if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+    use crate::meta::write_meta;
+    use crate::{Compression, Config};
+    use std::fs;
+
+    fn setup(root: &Path) -> BackupManager {
+        fs::write(root.join("a.txt"), "a").unwrap();
+        for ts in [100, 300, 200] {
+            let bak = root.join(format!("a.txt.{ts}.bak"));
+            fs::write(&bak, format!("v{ts}")).unwrap();
+            let fs = &crate::vfs::RealFs;
+            write_meta(fs, "backup", &bak, Path::new("a.txt"), &bak, Compression::None, DigestAlgo::Sha256).unwrap();
+        }
+        BackupManager::new(Config { root: Some(root.to_path_buf()), ..Config::default() })
+    }
+
+    #[test]
+    fn lists_newest_first_and_prunes_the_rest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = setup(root);
+        let ts: Vec<u64> = mgr.list_backups("a.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(ts, [300, 200, 100]);
+
+        let doomed = mgr.prune_backups("a.txt", 1, true).unwrap();
+        assert_eq!(doomed, [root.join("a.txt.200.bak"), root.join("a.txt.100.bak")]);
+        assert!(root.join("a.txt.100.bak").exists());
+
+        assert_eq!(mgr.prune_backups("a.txt", 1, false).unwrap(), doomed);
+        assert!(!root.join("a.txt.100.bak").exists() && !root.join("a.txt.100.bak.meta.json").exists());
+        assert_eq!(mgr.list_backups("a.txt").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn finds_backups_by_part_of_the_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mgr = setup(root);
+        fs::write(root.join("Notes.md"), "n").unwrap();
+        let notes = mgr.backup_file("Notes.md").unwrap();
+        let found: Vec<PathBuf> = mgr.find_backups("NOTE").unwrap().into_iter().map(|e| e.path).collect();
+        assert_eq!(found, [notes]);
+        assert_eq!(mgr.find_backups(".").unwrap().len(), 4);
+        assert!(mgr.find_backups("nothing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn backups_past_max_age_are_pruned_after_backup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        setup(root);
+        let day = 86_400;
+        let mgr = BackupManager::new(Config { max_age_secs: Some(150), ..config(root, 350) });
+        let report = mgr.backup_file_report("a.txt").unwrap();
+        assert_eq!(report.path, root.join("a.txt.350.bak"));
+        // 350 - 150 = 200: only the backup stamped 100 has expired.
+        let ts: Vec<u64> = mgr.list_backups("a.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(ts, [350, 300, 200]);
+        assert_eq!(report.warnings, [Warning::Pruned { removed: vec![root.join("a.txt.100.bak")] }]);
+
+        // Even a backup older than the limit itself survives its own run.
+        let mgr = BackupManager::new(Config {
+            max_age_secs: Some(0),
+            clock: std::sync::Arc::new(crate::FixedClock(day)),
+            ..mgr.config().clone()
+        });
+        mgr.backup_file("a.txt").unwrap();
+        let ts: Vec<u64> = mgr.list_backups("a.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(ts, [day]);
+    }
+
+    #[test]
+    fn keep_last_and_max_age_compose() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        setup(root);
+        let mgr = BackupManager::new(Config { keep_last: Some(3), max_age_secs: Some(120), ..config(root, 400) });
+        mgr.backup_file("a.txt").unwrap();
+        // Count alone keeps 400, 300, 200; age alone drops 100 and 200.
+        let ts: Vec<u64> = mgr.list_backups("a.txt").unwrap().iter().map(|e| e.ts).collect();
+        assert_eq!(ts, [400, 300]);
+    }
+
+    #[test]
+    fn verify_detects_modified_backup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mgr = setup(tmp.path());
+        mgr.verify_backup("a.txt.300.bak").unwrap();
+        fs::write(tmp.path().join("a.txt.300.bak"), "tampered").unwrap();
+        let e = mgr.verify_backup("a.txt.300.bak").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(BackupError::from_io(&e), Some(BackupError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn verify_uses_the_recorded_algorithm() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), "abc").unwrap();
+        let algos = crate::digest::built_in();
+        for (ts, &algo) in (1..).zip(&algos) {
+            let mgr = BackupManager::new(Config { digest: algo, ..config(root, ts) });
+            let bak = mgr.backup_file("a.txt").unwrap();
+            let digest = read_backup_meta(&bak).unwrap().digest.unwrap();
+            assert!(digest.starts_with(&format!("{algo}:")), "{digest}");
+        }
+        // Whatever the manager's own setting, each backup is checked with its own algorithm.
+        let mgr = BackupManager::new(Config { root: Some(root.to_path_buf()), ..Config::default() });
+        for ts in 1..=algos.len() {
+            mgr.verify_backup(format!("a.txt.{ts}.bak")).unwrap();
+        }
+        assert_eq!(mgr.list_backups("a.txt").unwrap().len(), algos.len());
+        for (ts, algo) in (1..).zip(&algos).skip(1) {
+            fs::write(root.join(format!("a.txt.{ts}.bak")), "abd").unwrap();
+            let e = mgr.verify_backup(format!("a.txt.{ts}.bak")).unwrap_err();
+            assert!(e.to_string().contains(&format!("is corrupt ({algo}:")), "{e}");
+        }
+
+        // Sidecars from before the tag hold a bare SHA-256.
+        let sidecar = sidecar_for(&root.join("a.txt.1.bak"));
+        let json = fs::read_to_string(&sidecar).unwrap().replace("\"digest\":\"sha256:", "\"sha256\":\"");
+        assert!(json.contains("\"sha256\":\"ba78"), "{json}");
+        fs::write(&sidecar, json).unwrap();
+        mgr.verify_backup("a.txt.1.bak").unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+//! Non-fatal problems reported alongside a successful result.
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// Something that went wrong after the main step of an operation had already
+/// succeeded. The error text is the full `error_chain` of the cause.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The convenience "<stem>.bak" copy (or its sidecar) could not be updated.
+    PlainBackup { path: PathBuf, error: String },
+    /// The data restored from a backup does not match the digest in its
+    /// sidecar, and was kept (`RestoreOptions::allow_mismatch`).
+    DigestMismatch { path: PathBuf, expected: String, actual: String },
+    /// Recorded permissions or mtime could not be put back on a restored file.
+    Metadata { path: PathBuf, error: String },
+    /// Extended attributes could not be read, recorded or set.
+    Xattrs { path: PathBuf, error: String },
+    /// The ACL could not be read, recorded or set.
+    Acl { path: PathBuf, error: String },
+    /// A followed symlink leads back into a directory containing it, so it was skipped.
+    SymlinkLoop { path: PathBuf },
+    /// The action was not written to the log.
+    Log { error: String },
+    /// Backups past `Config::max_age_secs` or `Config::keep_last` were removed.
+    Pruned { removed: Vec<PathBuf> },
+    /// An expired backup could not be removed.
+    PruneFailed { path: PathBuf, error: String },
+    /// `Config::post_hook` failed after the action on `path`.
+    PostHook { path: PathBuf, error: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PlainBackup { path, error } => write!(f, "plain backup {} not updated: {error}", path.display()),
+            Self::DigestMismatch { path, expected, actual } => {
+                let path = path.display();
+                write!(f, "restored {path} does not match its backup's digest (expected {expected}, got {actual})")
+            }
+            Self::Metadata { path, error } => {
+                write!(f, "permissions/mtime of {} not restored: {error}", path.display())
+            }
+            Self::Xattrs { path, error } => {
+                write!(f, "extended attributes of {} not preserved: {error}", path.display())
+            }
+            Self::Acl { path, error } => write!(f, "ACL of {} not preserved: {error}", path.display()),
+            Self::SymlinkLoop { path } => write!(f, "symlink {} loops back into its own tree; skipped", path.display()),
+            Self::Log { error } => write!(f, "action not logged: {error}"),
+            Self::Pruned { removed } => write!(f, "pruned {} expired backup(s)", removed.len()),
+            Self::PruneFailed { path, error } => write!(f, "expired backup {} not removed: {error}", path.display()),
+            Self::PostHook { path, error } => write!(f, "post-hook after {} failed: {error}", path.display()),
+        }
+    }
+}
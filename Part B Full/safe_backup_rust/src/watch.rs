@@ -0,0 +1,205 @@
+//! Watch mode: back files up automatically whenever they change.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::options::{BackupOptions, WatchOptions};
+use crate::{BackupManager, BackupReport};
+
+/// How often the cancel flag and pending debounces are checked.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Poll interval when notifications are unavailable and none was configured.
+const DEFAULT_POLL: Duration = Duration::from_millis(250);
+
+/// What a save changes: size and mtime. `None` while the file is missing.
+type Fingerprint = Option<(u64, SystemTime)>;
+
+fn fingerprint(path: &Path) -> Fingerprint {
+    fs::metadata(path).ok().map(|m| (m.len(), m.modified().unwrap_or(SystemTime::UNIX_EPOCH)))
+}
+
+/// One watched file: the name it is backed up under and where it lives.
+struct Watched {
+    name: PathBuf,
+    /// Canonical parent joined with the file name, as notifications report it.
+    path: PathBuf,
+    backed_up: Fingerprint,
+}
+
+impl BackupManager {
+    /// Back up each of `names` every time it changes, until `Config::cancel`
+    /// is set (without a cancel flag this never returns). Saves within
+    /// `opts.debounce` of each other give one backup; a change that leaves
+    /// size and mtime as they were gives none. Backups are logged with result
+    /// "auto" and pruned by `Config::keep_last` / `max_age_secs` as usual.
+    ///
+    /// Uses filesystem notifications, falling back to polling modification
+    /// times where they are unavailable. The action log is skipped if named
+    /// (e.g. by a glob), since every backup changes it. `on_backup` sees every
+    /// attempt; returns how many succeeded.
+    pub fn watch(
+        &self,
+        names: &[PathBuf],
+        opts: &WatchOptions,
+        mut on_backup: impl FnMut(&Path, &io::Result<BackupReport>),
+    ) -> io::Result<usize> {
+        let root = self.root()?;
+        let log = canonical(&self.log_path(&root));
+        let mut files = Vec::new();
+        for name in names {
+            let path = canonical(&self.resolve(&root, name)?);
+            if path != log {
+                files.push(Watched { name: name.clone(), backed_up: fingerprint(&path), path });
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let watcher = match opts.poll {
+            Some(_) => None,
+            None => start_watcher(&files, tx).ok(),
+        };
+        let poll = watcher.is_none().then(|| opts.poll.unwrap_or(DEFAULT_POLL));
+        let mut seen: Vec<Fingerprint> = files.iter().map(|f| f.backed_up).collect();
+        let mut last_poll = Instant::now();
+        // Index of a changed file → time of its latest change.
+        let mut pending: HashMap<usize, Instant> = HashMap::new();
+        let mut taken = 0;
+
+        while !self.cancelled() {
+            match poll {
+                Some(interval) => {
+                    std::thread::sleep(TICK.min(interval));
+                    if last_poll.elapsed() >= interval {
+                        last_poll = Instant::now();
+                        for (i, f) in files.iter().enumerate() {
+                            let fp = fingerprint(&f.path);
+                            if fp != seen[i] {
+                                seen[i] = fp;
+                                pending.insert(i, Instant::now());
+                            }
+                        }
+                    }
+                }
+                None => {
+                    if let Ok(Ok(event)) = rx.recv_timeout(TICK) {
+                        for p in event.paths {
+                            if let Some(i) = files.iter().position(|f| f.path == p) {
+                                pending.insert(i, Instant::now());
+                            }
+                        }
+                    }
+                }
+            }
+
+            let due: Vec<usize> =
+                pending.iter().filter(|(_, t)| t.elapsed() >= opts.debounce).map(|(i, _)| *i).collect();
+            for i in due {
+                pending.remove(&i);
+                let f = &mut files[i];
+                let fp = fingerprint(&f.path);
+                if fp.is_none() || fp == f.backed_up {
+                    continue;
+                }
+                let auto = BackupOptions { auto: true, ..BackupOptions::default() };
+                let result = self.backup_file_with(&f.name, &auto);
+                if result.is_ok() {
+                    f.backed_up = fp;
+                    taken += 1;
+                }
+                on_backup(&f.name, &result);
+            }
+        }
+        drop(watcher);
+        Ok(taken)
+    }
+}
+
+/// `path` with its parent directory canonicalized, as notifications report it;
+/// the file itself may come and go.
+fn canonical(path: &Path) -> PathBuf {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let parent = fs::canonicalize(parent).unwrap_or_else(|_| parent.to_path_buf());
+    parent.join(path.file_name().unwrap_or_default())
+}
+
+/// Watch the directories holding `files`: editors often save by replacing
+/// the file, which a watch on the file itself would miss.
+fn start_watcher(
+    files: &[Watched],
+    tx: mpsc::Sender<notify::Result<notify::Event>>,
+) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    let dirs: HashSet<&Path> = files.iter().filter_map(|f| f.path.parent()).collect();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CancellationToken, Config};
+
+    fn watch_briefly(root: &Path, opts: WatchOptions, edit: impl FnOnce() + Send + 'static) -> usize {
+        let cancel = CancellationToken::new();
+        let mgr = BackupManager::new(Config {
+            root: Some(root.to_path_buf()),
+            keep_last: Some(2),
+            cancel: Some(cancel.clone()),
+            ..Config::default()
+        });
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            edit();
+            std::thread::sleep(Duration::from_millis(1200));
+            cancel.cancel();
+        });
+        let n = mgr.watch(&[PathBuf::from("notes.txt")], &opts, |_, r| assert!(r.is_ok(), "{r:?}")).unwrap();
+        stopper.join().unwrap();
+        n
+    }
+
+    #[test]
+    fn burst_of_saves_gives_one_auto_backup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        fs::write(root.join("notes.txt"), "v0").unwrap();
+        let file = root.join("notes.txt");
+        let opts = WatchOptions::new().debounce(Duration::from_millis(300)).poll(Duration::from_millis(20));
+        let n = watch_briefly(&root, opts, move || {
+            for v in ["v1", "v22", "v333"] {
+                fs::write(&file, v).unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+        assert_eq!(n, 1);
+        let entries = crate::read_log(&root).unwrap();
+        assert_eq!((entries.len(), entries[0].result.as_str()), (1, "auto"));
+        let backups = BackupManager::new(Config { root: Some(root.clone()), ..Config::default() });
+        assert_eq!(fs::read_to_string(&backups.list_backups("notes.txt").unwrap()[0].path).unwrap(), "v333");
+    }
+
+    #[test]
+    fn unchanged_file_is_not_backed_up() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        fs::write(root.join("notes.txt"), "v0").unwrap();
+        let file = root.join("notes.txt");
+        let opts = WatchOptions::new().debounce(Duration::from_millis(100));
+        // Opening and reading notify watchers of access but changes nothing.
+        let n = watch_briefly(&root, opts, move || {
+            fs::read(&file).unwrap();
+        });
+        assert_eq!(n, 0);
+    }
+}
@@ -0,0 +1,92 @@
+//! Extended attributes (macOS tags, SELinux labels, `user.*` values), which
+//! `fs::copy` drops. Read and written with the `xattrs` feature on Unix;
+//! elsewhere every call fails with Unsupported.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// Attribute name → value as lowercase hex, as stored in the sidecar.
+pub(crate) type Xattrs = BTreeMap<String, String>;
+
+/// All extended attributes of `path`. Names that are not UTF-8 are an InvalidData error.
+#[cfg(all(unix, feature = "xattrs"))]
+pub(crate) fn read(op: &'static str, path: &Path) -> io::Result<Xattrs> {
+    use crate::error::Context;
+
+    let mut attrs = Xattrs::new();
+    for name in xattr::list(path).at(op, "cannot list extended attributes of", path)? {
+        let key = name.to_str().map(str::to_string).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("attribute name {name:?} is not UTF-8"))
+        });
+        let value = xattr::get(path, &name);
+        match (key, value) {
+            (Ok(key), Ok(Some(value))) => {
+                attrs.insert(key, to_hex(&value));
+            }
+            // Removed between listing and reading.
+            (Ok(_), Ok(None)) => {}
+            (Err(e), _) | (_, Err(e)) => return Err(e).at(op, "cannot read extended attributes of", path),
+        }
+    }
+    Ok(attrs)
+}
+
+/// Set each of `attrs` on `path`, replacing existing values.
+#[cfg(all(unix, feature = "xattrs"))]
+pub(crate) fn apply(op: &'static str, attrs: &Xattrs, path: &Path) -> io::Result<()> {
+    use crate::error::Context;
+
+    for (name, hex) in attrs {
+        from_hex(hex)
+            .and_then(|value| xattr::set(path, name, &value))
+            .at(op, "cannot set extended attributes on", path)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(all(unix, feature = "xattrs")))]
+pub(crate) fn read(_op: &'static str, _path: &Path) -> io::Result<Xattrs> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(unix, feature = "xattrs")))]
+pub(crate) fn apply(_op: &'static str, _attrs: &Xattrs, _path: &Path) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(unix, feature = "xattrs")))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "extended attributes need the `xattrs` feature on Unix")
+}
+
+#[cfg_attr(not(all(unix, feature = "xattrs")), allow(dead_code))]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg_attr(not(all(unix, feature = "xattrs")), allow(dead_code))]
+fn from_hex(hex: &str) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("malformed attribute value {hex:?}"));
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()).ok_or_else(invalid))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip_through_hex() {
+        let value = b"\x00tag\xff";
+        assert_eq!(to_hex(value), "00746167ff");
+        assert_eq!(from_hex(&to_hex(value)).unwrap(), value);
+        assert_eq!(from_hex("abc").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(from_hex("zz").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,122 @@
+//! The library writes nothing to stdout or stderr of its own: everything it
+//! has to say comes back in return values, events and the log. Each
+//! operation below runs in a child process whose output must stay empty.
+//! Without the test harness, which would print to both itself.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use safe_backup::{
+    BackupManager, BackupOptions, Config, ConflictStrategy, DeleteOptions, DirOptions, Event, FixedClock,
+    RestoreOptions,
+};
+
+/// Set in the child, which runs the operations.
+const CHILD: &str = "SAFE_BACKUP_SILENT_CHILD";
+
+fn main() {
+    if std::env::var_os(CHILD).is_some() {
+        operations();
+        return;
+    }
+    let exe = std::env::current_exe().unwrap();
+    let out = Command::new(exe).env(CHILD, "1").output().unwrap();
+    let (stdout, stderr) = (String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+    assert!(out.status.success(), "operations failed: {}\n{stderr}", out.status);
+    assert!(stdout.is_empty() && stderr.is_empty(), "the library printed:\n{stdout}{stderr}");
+}
+
+fn manager(root: &Path, now: u64, events: &Arc<Mutex<Vec<Event>>>) -> BackupManager {
+    let sink = events.clone();
+    let mut config = Config {
+        root: Some(root.to_path_buf()),
+        clock: Arc::new(FixedClock(now)),
+        on_event: Some(Arc::new(move |e: &Event| sink.lock().unwrap().push(e.clone()))),
+        ..Config::default()
+    };
+    if cfg!(unix) {
+        config.post_hook = Some(root.join("hook.sh"));
+    }
+    BackupManager::new(config)
+}
+
+/// Everything a front end might call, successes and failures alike.
+fn operations() {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    hook(root);
+    fs::write(root.join("a.txt"), "one").unwrap();
+    fs::write(root.join("b.txt"), "two").unwrap();
+    fs::create_dir(root.join("dir")).unwrap();
+    fs::write(root.join("dir/c.txt"), "three").unwrap();
+
+    let mgr = manager(root, 100, &events);
+    mgr.backup_file_with("a.txt", &BackupOptions::new().note("first")).unwrap();
+    mgr.ensure_backup("b.txt").unwrap();
+    mgr.backup_dir_with("dir", &DirOptions::new()).unwrap();
+    fs::write(root.join("a.txt"), "one, edited").unwrap();
+    let mgr = manager(root, 200, &events);
+    let report = mgr.backup_many(&[PathBuf::from("a.txt"), PathBuf::from("missing.txt")], &BackupOptions::new());
+    assert_eq!((report.succeeded(), report.failed()), (1, 1));
+
+    mgr.list_backups("a.txt").unwrap();
+    mgr.find_backups("first").unwrap();
+    mgr.tracked_originals().unwrap();
+    mgr.stats().unwrap();
+    mgr.verify_backup("a.txt.100.bak").unwrap();
+    mgr.verify_all().unwrap();
+    mgr.check_restore("a.txt", &RestoreOptions::new()).unwrap();
+    mgr.cat_backup("a.txt", Some(100), &mut io::sink()).unwrap();
+    mgr.restore_file_with("a.txt", &RestoreOptions::new().note_match("first")).unwrap();
+    mgr.restore_file_with("b.txt", &RestoreOptions::new().on_conflict(ConflictStrategy::Skip)).unwrap();
+    mgr.restore_all_with(ConflictStrategy::RenameExisting).unwrap();
+    mgr.restore_dir("dir").unwrap();
+    mgr.prune_backups("a.txt", 1, false).unwrap();
+    mgr.annotate("a.txt.200.bak", "second").unwrap();
+    mgr.backup_info("a.txt.200.bak").unwrap();
+    mgr.rename_tracked("b.txt", "c.txt", true).unwrap();
+    mgr.delete_file_with("c.txt", &DeleteOptions::new().purge_backups(true)).unwrap();
+    mgr.gc(false).unwrap();
+    mgr.clean(true).unwrap();
+    mgr.read_log().unwrap();
+    mgr.verify_log_chain().unwrap();
+
+    // Failures come back as errors, not messages.
+    assert!(mgr.backup_file("missing.txt").is_err());
+    assert!(mgr.backup_file("a.txt.200.bak").is_err());
+    assert!(mgr.restore_file("never.txt").is_err());
+    assert!(mgr.backup_file("../outside.txt").is_err());
+    assert!(mgr.verify_backup("nothing.txt.1.bak").is_err());
+
+    // As it does with the default hook output and no event sink.
+    let quiet = BackupManager::new(Config {
+        root: Some(root.to_path_buf()),
+        clock: Arc::new(FixedClock(300)),
+        post_hook: cfg!(unix).then(|| root.join("hook.sh")),
+        ..Config::default()
+    });
+    quiet.backup_file("a.txt").unwrap();
+    quiet.restore_file("a.txt").unwrap();
+
+    // The hook spoke, through the event sink.
+    if cfg!(unix) {
+        let events = events.lock().unwrap();
+        assert!(events.iter().any(|e| matches!(e, Event::HookOutput { stderr: true, .. })), "{events:?}");
+    }
+}
+
+/// A post-hook that writes to both stdout and stderr.
+#[cfg(unix)]
+fn hook(root: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    let path = root.join("hook.sh");
+    fs::write(&path, "#!/bin/sh\necho \"$1 $2\"\necho 'hook says hi' >&2\n").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[cfg(not(unix))]
+fn hook(_: &Path) {}